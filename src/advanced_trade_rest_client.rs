@@ -1,46 +1,95 @@
-use crate::{rest_client::Client, models::{RestEndpoint, Products}, config_builder::CoinbaseConfig, sig_gen::create_rest_signature};
-use anyhow::{bail, Result};
-use log::{debug}; // removed `error`
+use crate::{product_filter::ProductFilter, rest_client::{path_segment, Client, QueryBuilder}, models::{Accounts, BestBidAsk, CancelOrderResult, CancelOrdersResponse, CancelReport, ConvertTrade, ConvertTradeResponse, CreateOrderResponse, EditOrderPreview, EditOrderResponse, Fills, FuturesBalanceSummary, FuturesBalanceSummaryResponse, MoveFundsResponse, OrderPreview, OrderRequest, Orders, Portfolio, PortfolioBreakdown, PortfolioBreakdownResponse, PortfolioResponse, Portfolios, PriceBook, ProductBookResponse, ProductData, ProductsQuery, RestEndpoint, Products, ServerTime, TransactionSummary}, config_builder::CoinbaseConfig, clock::{ClockOffset, ClockSample}, sig_gen::create_rest_signature};
+use anyhow::{anyhow, bail, Result};
+use log::{debug, error, warn};
 use reqwest::header::{HeaderMap, HeaderValue};
-use std::time::{SystemTime, UNIX_EPOCH};
+use reqwest::Method;
+use serde::de::DeserializeOwned;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// Most orders `POST /brokerage/orders/batch_cancel` accepts in one request
+pub const BATCH_CANCEL_LIMIT: usize = 100;
+
+/// Time between two status checks of `wait_for_convert_trade`
+pub const CONVERT_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 pub struct AdvancedTradeRESTClient {
     client: Client,
     key: String,
     secret: String,
+    clock: ClockOffset,
+    /// Portfolio account, order and fill calls are scoped to
+    portfolio: Option<String>,
 }
 
 impl AdvancedTradeRESTClient {
+    /// Builds a client for `host` with the credentials of `CoinbaseConfig::new` and the default
+    /// transport, use `from_config` for anything else
     pub fn new(host: &str) -> AdvancedTradeRESTClient {
         let config = CoinbaseConfig::new();
 
         AdvancedTradeRESTClient {
-            client: Client::new(
-                host.to_string(), 
-            ),
+            client: Client::new(host.to_string()).with_metrics(config.metrics),
             key: config.api_key,
             secret: config.api_secret,
+            clock: config.clock,
+            portfolio: None,
         }
     }
 
+    /// Builds a client for `config.rest_url` using the credentials and transport settings of `config`
+    pub fn from_config(config: &CoinbaseConfig) -> Result<AdvancedTradeRESTClient> {
+        Ok(AdvancedTradeRESTClient {
+            client: Client::with_transport(
+                config.rest_url.clone(),
+                &config.transport,
+            )?.with_metrics(config.metrics.clone()),
+            key: config.api_key.clone(),
+            secret: config.api_secret.clone(),
+            clock: config.clock.clone(),
+            portfolio: None,
+        })
+    }
+
+    /// Scopes account, order and fill calls to the portfolio `uuid`
+    /// 
+    /// Balances, open orders, fills and `cancel_all` then only see that portfolio, and orders are
+    /// placed in it, so an `OrderManager` or `OrderValidator` built on the client is too.
+    pub fn with_portfolio(mut self, uuid: &str) -> Self {
+        self.portfolio = Some(uuid.to_string());
+        self
+    }
+
+    /// Returns the portfolio of `with_portfolio`, `None` for the default portfolio
+    pub fn portfolio(&self) -> Option<&str> {
+        self.portfolio.as_deref()
+    }
+
+    /// Returns the clock offset signatures are timestamped with, `CoinbaseConfig.clock` of its config
+    pub fn clock(&self) -> ClockOffset {
+        self.clock.clone()
+    }
+
     /// Builds the headers based on the Coinbase Advanced Trade API specification
     /// 
     /// Builds a `HeaderMap` of the following values:
     /// * `CB-ACCESS-KEY`: User Coinbase API Key as pulled from the `CoinbaseConfig`
     /// * `CB-ACCESS-SIGN`: User generated signature which is further described by `sig_gen.rs`
-    /// * `CB-ACCESS-TIMESTAMP`: System timestamp pulled from `SystemTime`
+    /// * `CB-ACCESS-TIMESTAMP`: System timestamp corrected by the measured `ClockOffset`, see `sync_clock`
     /// 
     /// # Arguments
     /// * `rmethod`: REST method e.g. `"GET"`, `"POST"`
-    /// * `rpath`: The request path + the REST endpoints e.g `"/api/v3/brokerage/products"
-    /// * `rbody`: Relevant request body - in our case, `None`
+    /// * `rpath`: The request path + the REST endpoint + the query string e.g `"/api/v3/brokerage/products?limit=5"`
+    /// * `rbody`: The exact JSON body sent, `""` for requests without one
     /// 
     /// # Returns
     /// 
     /// `Result<HeaderMap>` which can simply be applied later in a `.build_headers()` context
     pub fn build_headers_with_signature(&self, rmethod: &str, rpath:&str, rbody: &str) -> Result<HeaderMap> {
         // signature creation
-        let rts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
+        let rts = self.clock.timestamp();
         let signature = create_rest_signature(
             rts.as_str(), 
             rmethod, 
@@ -59,37 +108,594 @@ impl AdvancedTradeRESTClient {
         Ok(custom_headers)
     }
 
-    // returns all product information
-    pub async fn get_available_products(&self) -> Result<Products> {
-        let api_endpoints: RestEndpoint = RestEndpoint{ 
-            endpoint_url: String::from("/brokerage/products/"), 
-            method: String::from("GET"), 
-            resource: None,
+    /// Signs and sends the request described by `api_endpoints`
+    /// 
+    /// The signature covers the path with its query string and the exact bytes of the body, so an
+    /// endpoint only has to declare its method, path, query and body. Endpoints this client has no
+    /// method for yet can be sent the same way.
+    pub async fn send_endpoint<T: DeserializeOwned>(&self, api_endpoints: RestEndpoint) -> Result<T> {
+        let method = match Method::from_bytes(api_endpoints.method.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => bail!("Invalid REST method {}", api_endpoints.method),
         };
-        let header_map = match self.build_headers_with_signature(
-            &api_endpoints.method, 
-            format!("{}{}", self.client.extract_request_path().unwrap(), &api_endpoints.endpoint_url).as_str(), 
-            ""
-        ) {
+        let resource = api_endpoints.resource.as_deref();
+        let route = api_endpoints.route.as_deref().unwrap_or(&api_endpoints.endpoint_url);
+        let request_path = self.client.canonical_path(&api_endpoints.endpoint_url, resource)?;
+        let body = api_endpoints.body.as_deref().unwrap_or_default();
+        let header_map = match self.build_headers_with_signature(method.as_str(), &request_path, body) {
             Ok(header_map) => header_map,
             Err(_) => {
                 bail!("Ill-defined headers");
             }
         };
 
-        let result = self.client
-            .get(
-                api_endpoints.endpoint_url.as_str(), 
-                header_map,
-                api_endpoints.resource)
-            .await;
+        self.client
+            .request(method, &api_endpoints.endpoint_url, route, resource, header_map, api_endpoints.body)
+            .await
+    }
+
+    /// Adds the `product_id` and `cursor` parameters shared by the paginated list endpoints to
+    /// `query`, scoped to the portfolio of `with_portfolio`
+    fn page_query(&self, query: QueryBuilder, product_id: Option<&str>, cursor: Option<&str>) -> Option<String> {
+        query
+            .optional("product_id", product_id)
+            .optional("cursor", cursor.filter(|c| !c.is_empty()))
+            .optional("retail_portfolio_id", self.portfolio.as_deref())
+            .build()
+    }
+
+    /// Adds `portfolio` to an order body
+    fn scoped_body(&self, mut body: serde_json::Value) -> serde_json::Value {
+        if let (Some(portfolio), Some(fields)) = (&self.portfolio, body.as_object_mut()) {
+            fields.insert("retail_portfolio_id".to_string(), portfolio.clone().into());
+        }
+        body
+    }
+
+    // returns all product information
+    pub async fn get_available_products(&self) -> Result<Products> {
+        self.list_products(&ProductsQuery::default()).await
+    }
+
+    /// Returns the products matching `query`, filtered by the server
+    /// 
+    /// # Example
+    /// 
+    /// ```no_run
+    /// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+    /// use rs_coinbase_pairs_handler::models::ProductsQuery;
+    /// 
+    /// # async fn example(client: AdvancedTradeRESTClient) -> anyhow::Result<()> {
+    /// let query = ProductsQuery::new().with_product_type("FUTURE").with_contract_expiry_type("PERPETUAL");
+    /// for product in client.list_products(&query).await?.products {
+    ///     println!("{} funding {:?}", product.product_id, product.funding_rate());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn list_products(&self, query: &ProductsQuery) -> Result<Products> {
+        let resource = QueryBuilder::new()
+            .optional("product_type", query.product_type.as_deref())
+            .repeated("product_ids", &query.product_ids)
+            .optional("limit", query.limit)
+            .optional("offset", query.offset)
+            .optional("contract_expiry_type", query.contract_expiry_type.as_deref())
+            .build();
+        let api_endpoints = RestEndpoint::get("/brokerage/products/").with_resource(resource);
 
-        match result {
+        match self.send_endpoint(api_endpoints).await {
             Ok(symbols) => Ok(symbols),
             Err(e) => bail!(format!("Error retrieving products: {:?}", e)),
         }
     }
 
+    /// Returns the product `product_id` e.g. `"BTC-USD"`
+    pub async fn get_product(&self, product_id: &str) -> Result<ProductData> {
+        let api_endpoints = RestEndpoint::get(format!("/brokerage/products/{}", path_segment(product_id)))
+            .with_route("/brokerage/products/{product_id}");
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(product) => Ok(product),
+            Err(e) => bail!(format!("Error retrieving product {}: {:?}", product_id, e)),
+        }
+    }
+
+    /// Returns the best bid and ask of every product in `product_ids`, all in one request
+    /// 
+    /// # Arguments
+    /// * `product_ids`: Products to quote e.g. `["BTC-USD", "ETH-USD"]`, every product when empty
+    /// 
+    /// # Example
+    /// 
+    /// ```no_run
+    /// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+    /// 
+    /// # async fn example(client: AdvancedTradeRESTClient) -> anyhow::Result<()> {
+    /// let product_ids = client.get_available_symbols().await?;
+    /// for book in client.get_best_bid_ask(&product_ids).await? {
+    ///     println!("{} {:?} / {:?}", book.product_id, book.best_bid(), book.best_ask());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn get_best_bid_ask(&self, product_ids: &[String]) -> Result<Vec<PriceBook>> {
+        let api_endpoints = RestEndpoint::get("/brokerage/best_bid_ask")
+            .with_resource(QueryBuilder::new().repeated("product_ids", product_ids).build());
+
+        match self.send_endpoint::<BestBidAsk>(api_endpoints).await {
+            Ok(best_bid_ask) => Ok(best_bid_ask.pricebooks),
+            Err(e) => bail!(format!("Error retrieving best bid and ask: {:?}", e)),
+        }
+    }
+
+    /// Returns a snapshot of the book of `product_id`
+    /// 
+    /// # Arguments
+    /// * `product_id`: Product of the book e.g. `"BTC-USD"`
+    /// * `limit`: Most levels returned per side, the server default when `None`
+    pub async fn get_product_book(&self, product_id: &str, limit: Option<u32>) -> Result<PriceBook> {
+        let api_endpoints = RestEndpoint::get("/brokerage/product_book")
+            .with_resource(QueryBuilder::new().param("product_id", product_id).optional("limit", limit).build());
+
+        match self.send_endpoint::<ProductBookResponse>(api_endpoints).await {
+            Ok(response) => Ok(response.pricebook),
+            Err(e) => bail!(format!("Error retrieving product book: {:?}", e)),
+        }
+    }
+
+    /// Returns one page of historical orders, newest first
+    /// 
+    /// # Arguments
+    /// * `product_id`: Only return orders for this product e.g. `"ETH-USD"`
+    /// * `cursor`: `Orders.cursor` of the previous page
+    pub async fn list_orders(&self, product_id: Option<&str>, cursor: Option<&str>) -> Result<Orders> {
+        let api_endpoints = RestEndpoint::get("/brokerage/orders/historical/batch")
+            .with_resource(self.page_query(QueryBuilder::new(), product_id, cursor));
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(orders) => Ok(orders),
+            Err(e) => bail!(format!("Error retrieving orders: {:?}", e)),
+        }
+    }
+
+    /// Returns one page of orders that are still `OPEN`, newest first
+    /// 
+    /// # Arguments
+    /// * `product_id`: Only return orders for this product e.g. `"ETH-USD"`
+    /// * `cursor`: `Orders.cursor` of the previous page
+    pub async fn list_open_orders(&self, product_id: Option<&str>, cursor: Option<&str>) -> Result<Orders> {
+        let api_endpoints = RestEndpoint::get("/brokerage/orders/historical/batch")
+            .with_resource(self.page_query(QueryBuilder::new().param("order_status", "OPEN"), product_id, cursor));
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(orders) => Ok(orders),
+            Err(e) => bail!(format!("Error retrieving open orders: {:?}", e)),
+        }
+    }
+
+    /// Returns one page of fills, newest first
+    /// 
+    /// # Arguments
+    /// * `product_id`: Only return fills for this product e.g. `"ETH-USD"`
+    /// * `cursor`: `Fills.cursor` of the previous page
+    pub async fn list_fills(&self, product_id: Option<&str>, cursor: Option<&str>) -> Result<Fills> {
+        let api_endpoints = RestEndpoint::get("/brokerage/orders/historical/fills")
+            .with_resource(self.page_query(QueryBuilder::new(), product_id, cursor));
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(fills) => Ok(fills),
+            Err(e) => bail!(format!("Error retrieving fills: {:?}", e)),
+        }
+    }
+
+    /// Returns one page of accounts, i.e. one wallet per currency
+    /// 
+    /// # Arguments
+    /// * `cursor`: `Accounts.cursor` of the previous page
+    pub async fn list_accounts(&self, cursor: Option<&str>) -> Result<Accounts> {
+        let api_endpoints = RestEndpoint::get("/brokerage/accounts")
+            .with_resource(self.page_query(QueryBuilder::new(), None, cursor));
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(accounts) => Ok(accounts),
+            Err(e) => bail!(format!("Error retrieving accounts: {:?}", e)),
+        }
+    }
+
+    /// Available balance per currency over every page of accounts
+    pub async fn available_balances(&self) -> Result<BTreeMap<String, f64>> {
+        let mut balances = BTreeMap::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.list_accounts(cursor.as_deref()).await?;
+            for (currency, available) in page.available_balances() {
+                *balances.entry(currency).or_insert(0.0) += available;
+            }
+            if !page.has_next || page.cursor.is_empty() {
+                return Ok(balances);
+            }
+            cursor = Some(page.cursor);
+        }
+    }
+
+    /// Places `order`
+    /// 
+    /// Coinbase deduplicates orders by `client_order_id`, so sending the same request again after
+    /// a timeout returns the order placed the first time instead of placing a second one.
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<CreateOrderResponse>` - `success` is false with the reason if Coinbase refused the order
+    pub async fn create_order(&self, order: &OrderRequest) -> Result<CreateOrderResponse> {
+        let api_endpoints = RestEndpoint::post("/brokerage/orders");
+
+        let body = self.scoped_body(serde_json::to_value(order)?);
+
+        match self.send_endpoint(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response),
+            Err(e) => bail!(format!("Error creating order: {:?}", e)),
+        }
+    }
+
+    /// Asks Coinbase what `order` would cost and whether it would be accepted, without placing it
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<OrderPreview>` - totals and fees, with the reasons it would fail in `errs`
+    pub async fn preview_order(&self, order: &OrderRequest) -> Result<OrderPreview> {
+        let api_endpoints = RestEndpoint::post("/brokerage/orders/preview");
+        let body = self.scoped_body(serde_json::json!({
+            "product_id": order.product_id,
+            "side": order.side,
+            "order_configuration": order.order_configuration,
+        }));
+
+        match self.send_endpoint(api_endpoints.with_body(&body)?).await {
+            Ok(preview) => Ok(preview),
+            Err(e) => bail!(format!("Error previewing order: {:?}", e)),
+        }
+    }
+
+    /// Changes the price and size of an open limit order
+    /// 
+    /// # Arguments
+    /// * `order_id`: Order to edit
+    /// * `price`: New limit price e.g. `"1675.14"`
+    /// * `size`: New base size e.g. `"0.5"`
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<EditOrderResponse>` - `success` is false with the reasons in `errors` if Coinbase refused
+    pub async fn edit_order(&self, order_id: &str, price: &str, size: &str) -> Result<EditOrderResponse> {
+        let api_endpoints = RestEndpoint::post("/brokerage/orders/edit");
+        let body = serde_json::json!({ "order_id": order_id, "price": price, "size": size });
+
+        match self.send_endpoint(api_endpoints.with_body(&body)?).await {
+            Ok(edit) => Ok(edit),
+            Err(e) => bail!(format!("Error editing order: {:?}", e)),
+        }
+    }
+
+    /// Same as `edit_order` but only reports what the edit would cost and whether it would be accepted
+    pub async fn edit_order_preview(&self, order_id: &str, price: &str, size: &str) -> Result<EditOrderPreview> {
+        let api_endpoints = RestEndpoint::post("/brokerage/orders/edit_preview");
+        let body = serde_json::json!({ "order_id": order_id, "price": price, "size": size });
+
+        match self.send_endpoint(api_endpoints.with_body(&body)?).await {
+            Ok(preview) => Ok(preview),
+            Err(e) => bail!(format!("Error previewing order edit: {:?}", e)),
+        }
+    }
+
+    /// Cancels up to `BATCH_CANCEL_LIMIT` orders in one request
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<Vec<CancelOrderResult>>` - one result per order, in the order Coinbase reports them
+    pub async fn cancel_orders(&self, order_ids: &[String]) -> Result<Vec<CancelOrderResult>> {
+        if order_ids.len() > BATCH_CANCEL_LIMIT {
+            bail!("At most {} orders can be cancelled at once, got {}", BATCH_CANCEL_LIMIT, order_ids.len());
+        }
+        let api_endpoints = RestEndpoint::post("/brokerage/orders/batch_cancel");
+        let body = serde_json::json!({ "order_ids": order_ids });
+
+        match self.send_endpoint::<CancelOrdersResponse>(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response.results),
+            Err(e) => bail!(format!("Error cancelling orders: {:?}", e)),
+        }
+    }
+
+    /// Cancels every open order, or only those of the products matching `filter`
+    /// 
+    /// Collects all pages of open orders first, then cancels them in batches of
+    /// `BATCH_CANCEL_LIMIT`. A batch that fails as a whole is reported as failed for each of its
+    /// orders and does not stop the remaining batches.
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<CancelReport>` - the outcome per order, an error only if the open orders could not be listed
+    pub async fn cancel_all(&self, filter: Option<&ProductFilter>) -> Result<CancelReport> {
+        let products: Option<BTreeSet<String>> = match filter {
+            Some(filter) => Some(filter.select(&self.get_available_products().await?.products).into_iter().collect()),
+            None => None,
+        };
+
+        let mut order_ids = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = self.list_open_orders(None, cursor.as_deref()).await?;
+            order_ids.extend(
+                page.orders
+                    .into_iter()
+                    .filter(|order| products.as_ref().is_none_or(|products| products.contains(&order.product_id)))
+                    .map(|order| order.order_id),
+            );
+            if !page.has_next || page.cursor.is_empty() {
+                break;
+            }
+            cursor = Some(page.cursor);
+        }
+
+        let mut report = CancelReport::default();
+        for batch in order_ids.chunks(BATCH_CANCEL_LIMIT) {
+            match self.cancel_orders(batch).await {
+                Ok(results) => report.results.extend(results),
+                Err(e) => {
+                    error!("{}", e);
+                    report.results.extend(batch.iter().map(|order_id| CancelOrderResult {
+                        success: false,
+                        failure_reason: e.to_string(),
+                        order_id: order_id.clone(),
+                    }));
+                },
+            }
+        }
+        if !report.failed().is_empty() {
+            warn!("{} of {} orders could not be cancelled", report.failed().len(), report.results.len());
+        }
+        Ok(report)
+    }
+
+    /// Returns the portfolios of the API key's user
+    /// 
+    /// # Arguments
+    /// * `portfolio_type`: Only return portfolios of this type, e.g. `"DEFAULT"` or `"CONSUMER"`
+    pub async fn list_portfolios(&self, portfolio_type: Option<&str>) -> Result<Vec<Portfolio>> {
+        let api_endpoints = RestEndpoint::get("/brokerage/portfolios")
+            .with_resource(QueryBuilder::new().optional("portfolio_type", portfolio_type).build());
+
+        match self.send_endpoint::<Portfolios>(api_endpoints).await {
+            Ok(portfolios) => Ok(portfolios.portfolios),
+            Err(e) => bail!(format!("Error retrieving portfolios: {:?}", e)),
+        }
+    }
+
+    /// Creates an empty portfolio called `name`
+    pub async fn create_portfolio(&self, name: &str) -> Result<Portfolio> {
+        let api_endpoints = RestEndpoint::post("/brokerage/portfolios");
+        let body = serde_json::json!({ "name": name });
+
+        match self.send_endpoint::<PortfolioResponse>(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response.portfolio),
+            Err(e) => bail!(format!("Error creating portfolio: {:?}", e)),
+        }
+    }
+
+    /// Transfers funds between two portfolios of the same user
+    /// 
+    /// # Arguments
+    /// * `value`: Amount to move e.g. `"250.00"`
+    /// * `currency`: Currency of `value` e.g. `"USD"`
+    /// * `source_portfolio_uuid`: Portfolio the funds are taken from
+    /// * `target_portfolio_uuid`: Portfolio the funds are added to
+    pub async fn move_funds(
+        &self,
+        value: &str,
+        currency: &str,
+        source_portfolio_uuid: &str,
+        target_portfolio_uuid: &str,
+    ) -> Result<MoveFundsResponse> {
+        let api_endpoints = RestEndpoint::post("/brokerage/portfolios/move_funds");
+        let body = serde_json::json!({
+            "funds": { "value": value, "currency": currency },
+            "source_portfolio_uuid": source_portfolio_uuid,
+            "target_portfolio_uuid": target_portfolio_uuid,
+        });
+
+        match self.send_endpoint(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response),
+            Err(e) => bail!(format!("Error moving funds: {:?}", e)),
+        }
+    }
+
+    /// Renames the portfolio `portfolio_uuid` to `name`
+    pub async fn edit_portfolio(&self, portfolio_uuid: &str, name: &str) -> Result<Portfolio> {
+        let api_endpoints = RestEndpoint::put(format!("/brokerage/portfolios/{}", path_segment(portfolio_uuid)))
+            .with_route("/brokerage/portfolios/{portfolio_uuid}")
+            .with_body(&serde_json::json!({ "name": name }))?;
+
+        match self.send_endpoint::<PortfolioResponse>(api_endpoints).await {
+            Ok(response) => Ok(response.portfolio),
+            Err(e) => bail!(format!("Error editing portfolio: {:?}", e)),
+        }
+    }
+
+    /// Deletes the portfolio `portfolio_uuid`, which must hold no funds
+    pub async fn delete_portfolio(&self, portfolio_uuid: &str) -> Result<()> {
+        let api_endpoints = RestEndpoint::delete(format!("/brokerage/portfolios/{}", path_segment(portfolio_uuid)))
+            .with_route("/brokerage/portfolios/{portfolio_uuid}");
+
+        match self.send_endpoint::<serde_json::Value>(api_endpoints).await {
+            Ok(_) => Ok(()),
+            Err(e) => bail!(format!("Error deleting portfolio: {:?}", e)),
+        }
+    }
+
+    /// Returns the balances and positions of the portfolio `portfolio_uuid`
+    pub async fn get_portfolio_breakdown(&self, portfolio_uuid: &str) -> Result<PortfolioBreakdown> {
+        let api_endpoints = RestEndpoint::get(format!("/brokerage/portfolios/{}", path_segment(portfolio_uuid)))
+            .with_route("/brokerage/portfolios/{portfolio_uuid}");
+
+        match self.send_endpoint::<PortfolioBreakdownResponse>(api_endpoints).await {
+            Ok(response) => Ok(response.breakdown),
+            Err(e) => bail!(format!("Error retrieving portfolio breakdown: {:?}", e)),
+        }
+    }
+
+    /// Returns the trading volume, fees and fee tier of the last 30 days
+    /// 
+    /// # Arguments
+    /// * `product_type`: Only count products of this type, `"SPOT"` or `"FUTURE"`
+    pub async fn get_transaction_summary(&self, product_type: Option<&str>) -> Result<TransactionSummary> {
+        let api_endpoints = RestEndpoint::get("/brokerage/transaction_summary")
+            .with_resource(QueryBuilder::new().optional("product_type", product_type).build());
+
+        match self.send_endpoint(api_endpoints).await {
+            Ok(summary) => Ok(summary),
+            Err(e) => bail!(format!("Error retrieving transaction summary: {:?}", e)),
+        }
+    }
+
+    /// Returns the balances and margin of the futures account
+    pub async fn get_futures_balance_summary(&self) -> Result<FuturesBalanceSummary> {
+        let api_endpoints = RestEndpoint::get("/brokerage/cfm/balance_summary");
+
+        match self.send_endpoint::<FuturesBalanceSummaryResponse>(api_endpoints).await {
+            Ok(response) => Ok(response.balance_summary),
+            Err(e) => bail!(format!("Error retrieving futures balance summary: {:?}", e)),
+        }
+    }
+
+    /// Quotes converting `amount` of `from_currency` into `to_currency`, e.g. USD to USDC
+    /// 
+    /// # Arguments
+    /// * `from_currency`: Currency debited e.g. `"USD"`
+    /// * `to_currency`: Currency credited e.g. `"USDC"`
+    /// * `amount`: Amount of `from_currency` to convert e.g. `"100.00"`
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<ConvertTrade>` - A trade in `Created` status, to pass to `commit_convert_trade` before the quote expires
+    pub async fn create_convert_quote(&self, from_currency: &str, to_currency: &str, amount: &str) -> Result<ConvertTrade> {
+        let api_endpoints = RestEndpoint::post("/brokerage/convert/quote");
+        let body = serde_json::json!({
+            "from_account": from_currency,
+            "to_account": to_currency,
+            "amount": amount,
+        });
+
+        match self.send_endpoint::<ConvertTradeResponse>(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response.trade),
+            Err(e) => bail!(format!("Error creating convert quote: {:?}", e)),
+        }
+    }
+
+    /// Accepts the quote `trade_id` returned by `create_convert_quote`
+    pub async fn commit_convert_trade(&self, trade_id: &str, from_currency: &str, to_currency: &str) -> Result<ConvertTrade> {
+        let api_endpoints = RestEndpoint::post(format!("/brokerage/convert/trade/{}", path_segment(trade_id)))
+            .with_route("/brokerage/convert/trade/{trade_id}");
+        let body = serde_json::json!({ "from_account": from_currency, "to_account": to_currency });
+
+        match self.send_endpoint::<ConvertTradeResponse>(api_endpoints.with_body(&body)?).await {
+            Ok(response) => Ok(response.trade),
+            Err(e) => bail!(format!("Error committing convert trade: {:?}", e)),
+        }
+    }
+
+    /// Returns the convert trade `trade_id` and its current status
+    pub async fn get_convert_trade(&self, trade_id: &str, from_currency: &str, to_currency: &str) -> Result<ConvertTrade> {
+        let resource = QueryBuilder::new().param("from_account", from_currency).param("to_account", to_currency).build();
+        let api_endpoints = RestEndpoint::get(format!("/brokerage/convert/trade/{}", path_segment(trade_id)))
+            .with_route("/brokerage/convert/trade/{trade_id}")
+            .with_resource(resource);
+
+        match self.send_endpoint::<ConvertTradeResponse>(api_endpoints).await {
+            Ok(response) => Ok(response.trade),
+            Err(e) => bail!(format!("Error retrieving convert trade: {:?}", e)),
+        }
+    }
+
+    /// Checks the convert trade `trade_id` every `CONVERT_POLL_INTERVAL` until it completes or is cancelled
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<ConvertTrade>` - The trade in a terminal status, or an error once `timeout` elapsed
+    pub async fn wait_for_convert_trade(
+        &self,
+        trade_id: &str,
+        from_currency: &str,
+        to_currency: &str,
+        timeout: Duration,
+    ) -> Result<ConvertTrade> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let trade = self.get_convert_trade(trade_id, from_currency, to_currency).await?;
+            if trade.status.is_terminal() {
+                return Ok(trade);
+            }
+            if tokio::time::Instant::now() + CONVERT_POLL_INTERVAL > deadline {
+                bail!("Convert trade {} still {:?} after {:?}", trade_id, trade.status, timeout);
+            }
+            tokio::time::sleep(CONVERT_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Quotes, commits and waits for a conversion of `amount` of `from_currency` into `to_currency`
+    /// 
+    /// # Returns
+    /// 
+    /// `Result<ConvertTrade>` - The settled trade; a `Canceled` status is returned as is
+    pub async fn convert(&self, from_currency: &str, to_currency: &str, amount: &str, timeout: Duration) -> Result<ConvertTrade> {
+        let quote = self.create_convert_quote(from_currency, to_currency, amount).await?;
+        let trade = self.commit_convert_trade(&quote.id, from_currency, to_currency).await?;
+        if trade.status.is_terminal() {
+            return Ok(trade);
+        }
+        self.wait_for_convert_trade(&trade.id, from_currency, to_currency, timeout).await
+    }
+
+    /// Returns the server time, which does not require a signature
+    pub async fn get_server_time(&self) -> Result<ServerTime> {
+        match self.client.get("/brokerage/time", HeaderMap::new(), None).await {
+            Ok(time) => Ok(time),
+            Err(e) => bail!(format!("Error retrieving server time: {:?}", e)),
+        }
+    }
+
+    /// Measures the offset of the local clock to the server clock and signs with it from now on
+    ///
+    /// # Returns
+    ///
+    /// `Result<ClockSample>` - the measured offset and the round trip it was measured over
+    pub async fn sync_clock(&self) -> Result<ClockSample> {
+        let sent_at = SystemTime::now();
+        let server_time = self.get_server_time().await?;
+        let received_at = SystemTime::now();
+
+        let server_time = server_time
+            .time()
+            .ok_or_else(|| anyhow!("Invalid server time {:?}", server_time.iso))?;
+        let sample = self.clock.record(sent_at, received_at, server_time);
+        self.client.metrics().record_clock_offset(sample.offset);
+        Ok(sample)
+    }
+
+    /// Calls `sync_clock` every `interval` on the current tokio runtime
+    ///
+    /// Failed measurements are logged and the previous offset is kept. Abort the returned handle
+    /// to stop.
+    pub fn spawn_clock_sync(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.sync_clock().await {
+                    error!("Error syncing clock: {}", e);
+                }
+            }
+        })
+    }
+
     // returns a list of available symbols
     pub async fn get_available_symbols(&self) -> Result<Vec<String>> {
         let symbols = match self.get_available_products().await {
@@ -104,4 +710,4 @@ impl AdvancedTradeRESTClient {
 
         Ok(symbols_list)
     }
-}
\ No newline at end of file
+}
@@ -1,14 +1,21 @@
-use crate::{advanced_trade_rest_client::AdvancedTradeRESTClient, config_builder::CoinbaseConfig, models, websocket, sig_gen};
+use crate::{advanced_trade_rest_client::AdvancedTradeRESTClient, config_builder::{CoinbaseConfig, TransportConfig}, health::FeedHealth, metrics::Metrics, models, product_filter::ProductFilter, product_registry::{ProductRegistry, DEFAULT_PRODUCT_TTL}, replay::{ReplaySource, ReplaySpeed}, sink::MessageSink, websocket, sig_gen};
 use anyhow::{bail, Result};
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
 use std::net::TcpStream;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+use tracing::{field, info_span, Instrument, Span};
 use tungstenite::handshake::client::Response;
 use tungstenite::protocol::WebSocket;
 use tungstenite::{stream::MaybeTlsStream, Message};
 
+/// How often a `SubscribeProducts::Filter` is matched against the product list, with or without traffic
+const PRODUCT_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
 #[derive(Deserialize, Debug)]
 #[serde(untagged)]
 enum AdvancedTradeEvents {
@@ -22,6 +29,8 @@ enum AdvancedTradeEvents {
 pub enum SubscribeProducts {
     All, 
     Custom(Vec<String>),
+    /// Products matching the filter, re-evaluated whenever the product list refreshes
+    Filter(ProductFilter),
 }
 
 
@@ -29,9 +38,23 @@ pub struct AdvancedTradeWebSockets {
     exchange: String,
     channels: Vec<String>,
     product_ids: SubscribeProducts,
-    client: AdvancedTradeRESTClient,
+    products: ProductRegistry,
+    /// Products the current connection is subscribed to
+    subscribed: Vec<String>,
+    /// `ProductRegistry::generation` the subscriptions were resolved from
+    subscribed_generation: u64,
+    /// Earliest time `SubscribeProducts::Filter` is matched against the product list again
+    next_product_check: Instant,
     key: String,
     secret: String,
+    websocket_url: String,
+    transport: TransportConfig,
+    running: Arc<AtomicBool>,
+    sinks: Vec<Box<dyn MessageSink>>,
+    metrics: Metrics,
+    health: FeedHealth,
+    /// `sequence_num` of the last message on the current connection
+    last_sequence_num: Option<u64>,
 }
 
 impl AdvancedTradeWebSockets {
@@ -39,33 +62,150 @@ impl AdvancedTradeWebSockets {
         channels: Vec<String>, 
         product_ids: SubscribeProducts, 
     ) -> AdvancedTradeWebSockets {
-        let config = CoinbaseConfig::new();
+        AdvancedTradeWebSockets::from_config(channels, product_ids, CoinbaseConfig::new()).unwrap()
+    }
 
-        AdvancedTradeWebSockets {
+    /// Builds the feed against `config.websocket_url`, resolving products through `config.rest_url`
+    pub fn from_config(
+        channels: Vec<String>, 
+        product_ids: SubscribeProducts, 
+        config: CoinbaseConfig,
+    ) -> Result<AdvancedTradeWebSockets> {
+        Ok(AdvancedTradeWebSockets {
             exchange: "coinbase-advanced-trade".to_string(),
             channels,
             product_ids,
-            client: AdvancedTradeRESTClient::new("https://api.coinbase.com/api/v3"),
+            products: ProductRegistry::new(Arc::new(AdvancedTradeRESTClient::from_config(&config)?), DEFAULT_PRODUCT_TTL),
+            subscribed: Vec::new(),
+            subscribed_generation: 0,
+            next_product_check: Instant::now(),
             key: config.api_key,
             secret: config.api_secret,
-        }
+            websocket_url: config.websocket_url,
+            transport: config.transport,
+            running: Arc::new(AtomicBool::new(true)),
+            sinks: Vec::new(),
+            metrics: config.metrics,
+            health: FeedHealth::default(),
+            last_sequence_num: None,
+        })
+    }
+
+    /// Returns the registry `SubscribeProducts::All` resolves products through
+    ///
+    /// The registry is shared, so changes can be subscribed to or refreshed in the background.
+    pub fn product_registry(&self) -> ProductRegistry {
+        self.products.clone()
+    }
+
+    /// Replaces the product registry, e.g. to share one registry between several feeds
+    pub fn set_product_registry(&mut self, products: ProductRegistry) {
+        self.products = products;
+    }
+
+    /// Returns the metrics the feed records to, `CoinbaseConfig.metrics` of its config
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Returns the connection and subscription state the feed keeps current, for `HealthServer`
+    pub fn health(&self) -> FeedHealth {
+        self.health.clone()
+    }
+
+    /// Registers a sink that receives every frame handled by the event loop
+    pub fn add_sink(&mut self, sink: Box<dyn MessageSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Returns the flag the event loop checks between messages
+    ///
+    /// Storing `false` stops `run` after the next received message, flushing every sink. A feed
+    /// following a `SubscribeProducts::Filter` also stops on a quiet connection.
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.running.clone()
     }
 
     pub async fn run(&mut self) -> Result<()> {
-        let keep_running = AtomicBool::new(true);
+        let keep_running = self.running.clone();
 
+        self.health.set_running(true);
         if let Err(e) = self.event_loop(&keep_running).await {
             error!("Error: {}", e);
         }
+        self.health.set_connected(false);
+        self.health.set_running(false);
         info!("[{}] Loop stopped running", &self.exchange);
 
+        self.flush_sinks();
+
         Ok(())
     }
 
+    /// Plays recorded frames through the same parsing and sinks as `run`
+    ///
+    /// Sinks see the original receive timestamps, so a replay produces the same output as the
+    /// live session did. Replayed frames are not recorded to `metrics` or `health`, which keep
+    /// describing the live connection. Honours `stop_handle` between frames and flushes every
+    /// sink at the end.
+    ///
+    /// # Returns
+    ///
+    /// `Result<u64>` - the number of frames replayed, an error before any frame when `speed`
+    /// fails `ReplaySpeed::validate`
+    pub async fn replay(&mut self, source: ReplaySource, speed: ReplaySpeed) -> Result<u64> {
+        speed.validate()?;
+        info!("[{}] Starting replay at {:?}", &self.exchange, speed);
+        let started = Instant::now();
+        let mut first_received_at = None;
+        let mut frames = 0;
+
+        for frame in source {
+            if !self.running.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let received_at = frame.received_at();
+            let first = *first_received_at.get_or_insert(received_at);
+            let recorded_gap = received_at.duration_since(first).unwrap_or_default();
+            if let Some(delay) = speed.delay(recorded_gap) {
+                let due = started + delay;
+                if due > Instant::now() {
+                    tokio::time::sleep_until(due.into()).await;
+                }
+            }
+
+            self.dispatch(&frame.frame, received_at);
+            frames += 1;
+        }
+
+        info!("[{}] Replayed {} frames", &self.exchange, frames);
+        self.flush_sinks();
+
+        Ok(frames)
+    }
+
+    fn flush_sinks(&mut self) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.flush() {
+                error!("Error flushing sink: {}", e);
+            }
+        }
+    }
+
+    /// Span every log line of the `number`th connection is recorded under
+    fn connection_span(&self, number: u64) -> Span {
+        info_span!("connection", exchange = %self.exchange, url = %self.websocket_url, connection = number)
+    }
+
     async fn event_loop(&mut self, running: &AtomicBool) -> Result<()> {
         // get connected 
-        info!("Establishing connection...");
-        let mut socket = match self.connect().await {
+        let mut connections: u64 = 1;
+        let mut connection = self.connection_span(connections);
+        let mut socket = match async {
+            info!("Establishing connection...");
+            self.connect().await
+        }.instrument(connection.clone()).await {
             Ok(socket_ok) => {
                 socket_ok
             },
@@ -73,42 +213,78 @@ impl AdvancedTradeWebSockets {
                 bail!("Error: {}", e)
             }
         };
+        self.health.set_connected(true);
+
+        async {
+            self.sync_clock().await;
+            self.subscribe_to_channel(&mut socket.0).await;
+        }.instrument(connection.clone()).await;
 
-        self.subscribe_to_channel(&mut socket.0).await;
+        // a filter follows the product list refreshed in the background, not from the read loop
+        let _refresh = self.follows_product_filter().then(|| AbortOnDrop(self.products.spawn_refresh()));
 
         // engage event loop
         info!("Starting event loop...");
         while running.load(Ordering::Relaxed) {
+            if Instant::now() >= self.next_product_check {
+                connection.in_scope(|| self.follow_product_filter(&mut socket.0));
+            }
+
             // get messages
             let message = match socket.0.read_message() {
                 Ok(msg) => {
                     msg
                 },
+                // the read timeout of a filter only wakes the loop up to check the product list
+                Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    continue;
+                },
                 Err(e) => {
-                    error!("Error: {}", e);
-                    info!("[{}] Reconnecting WebSocket due to error.", &self.exchange);
-                    socket = match self.connect().await {
+                    connection.in_scope(|| {
+                        error!("Error: {}", e);
+                        info!("[{}] Reconnecting WebSocket due to error.", &self.exchange);
+                    });
+                    self.metrics.record_reconnect();
+                    self.health.set_connected(false);
+                    self.last_sequence_num = None;
+                    connections += 1;
+                    connection = self.connection_span(connections);
+                    socket = match self.connect().instrument(connection.clone()).await {
                         Ok(socket) => socket,
                         Err(e) => {
                             bail!("Error: {}", e)
                         }
                     };
+                    self.health.set_connected(true);
+                    async {
+                        self.sync_clock().await;
+                        self.subscribe_to_channel(&mut socket.0).await;
+                    }.instrument(connection.clone()).await;
                     continue;
                 }
             };
 
             // handle messages
+            let received_at = SystemTime::now();
             match message {
                 Message::Text(msg) => {
-                    if let Err(e) = self.handle_msg(&msg, &mut socket.0).await {
-                        error!("Error on handling stream message: {}", e);
+                    let span = info_span!(
+                        parent: &connection,
+                        "message",
+                        channel = field::Empty,
+                        sequence_num = field::Empty,
+                        product_id = field::Empty,
+                    );
+                    if let Err(e) = self.handle_msg(&msg, received_at).instrument(span.clone()).await {
+                        span.in_scope(|| error!("Error on handling stream message: {}", e));
                         continue;
                     }
                 },
                 // We can ignore these message because tungstenite takes care of them for us.
                 Message::Ping(_) | Message::Pong(_) | Message::Binary(_) => (),
                 Message::Close(e) => {
-                    error!("Disconnected {:?}", e);
+                    connection.in_scope(|| error!("Disconnected {:?}", e));
+                    self.health.set_connected(false);
                     continue;
                 },
                 // throwing a catch just in case
@@ -122,21 +298,57 @@ impl AdvancedTradeWebSockets {
     }
 
     async fn handle_msg(
-        &self, 
+        &mut self, 
         msg: &str, 
-        _socket: &mut WebSocket<MaybeTlsStream<TcpStream>>
+        received_at: SystemTime,
     ) -> Result<()> {
+        self.health.record_frame(received_at);
+        match self.dispatch(msg, received_at) {
+            Some(AdvancedTradeEvents::GenericEvent(event)) => {
+                self.record_metrics(&event, received_at);
+                self.health.record_message(&event, received_at);
+            },
+            Some(_) => (),
+            None => self.metrics.record_parse_failure(),
+        }
+        Ok(())
+    }
+
+    /// Parses `msg` and passes it to every sink, leaving `metrics` and `health` untouched
+    ///
+    /// # Returns
+    ///
+    /// `Option<AdvancedTradeEvents>` - the parsed event, `None` if `msg` could not be parsed
+    fn dispatch(&mut self, msg: &str, received_at: SystemTime) -> Option<AdvancedTradeEvents> {
+        for sink in self.sinks.iter_mut() {
+            if let Err(e) = sink.on_frame(received_at, msg) {
+                error!("Error in sink on frame: {}", e);
+            }
+        }
+
         let advanced_trade_event: AdvancedTradeEvents = match serde_json::from_str(msg) {
             Ok(deserialized_event) => deserialized_event,
             Err(e) => {
                 error!("Error unpacking advanced trade websocket event: {:?}", e);
-                AdvancedTradeEvents::Unknown
+                return None;
             },
         };
 
-        match advanced_trade_event {
+        match &advanced_trade_event {
             AdvancedTradeEvents::GenericEvent(event) => {
+                let span = Span::current();
+                span.record("channel", event.channel.as_str());
+                span.record("sequence_num", event.sequence_num);
+                let product_ids: Vec<&str> = event.product_ids().into_iter().collect();
+                if !product_ids.is_empty() {
+                    span.record("product_id", product_ids.join(",").as_str());
+                }
                 info!("{:?}", event);
+                for sink in self.sinks.iter_mut() {
+                    if let Err(e) = sink.on_message(received_at, event) {
+                        error!("Error in sink on message: {}", e);
+                    }
+                }
 
                 // ideally I'd like to display the different event types differently
                 // I might want to abstract this code out into a message handler
@@ -164,76 +376,178 @@ impl AdvancedTradeWebSockets {
             }
 
         }
-        Ok(())
+        Some(advanced_trade_event)
     }
 
-    async fn subscribe_to_channel(&self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) {
-        let products: Vec<String> = match &self.product_ids {
-            SubscribeProducts::All => {
-                self.client.get_available_symbols().await.unwrap()
-            },
-            SubscribeProducts::Custom(products) => {
-                products.clone()
+    fn record_metrics(&mut self, event: &models::GenericMessage, received_at: SystemTime) {
+        let product_ids: Vec<&str> = event.product_ids().into_iter().collect();
+        self.metrics.record_message(&event.channel, &product_ids);
+
+        if let Some(last) = self.last_sequence_num {
+            if event.sequence_num > last + 1 {
+                self.metrics.record_sequence_gap(event.sequence_num - last - 1);
+            }
+        }
+        self.last_sequence_num = Some(event.sequence_num);
+
+        if let Some(sent_at) = event.exchange_time() {
+            let received_at = self.products.client().clock().to_server_time(received_at);
+            // the remaining error of the clock offset can still put the exchange timestamp after the receive time
+            let latency = received_at.duration_since(SystemTime::from(sent_at)).unwrap_or_default();
+            self.metrics.record_message_latency(&event.channel, latency);
+        }
+    }
+
+    /// Measures the clock offset subscriptions are signed with, keeping the previous one on failure
+    async fn sync_clock(&self) {
+        if let Err(e) = self.products.client().sync_clock().await {
+            error!("[{}] Unable to sync clock, signing with the previous offset: {}", &self.exchange, e);
+        }
+    }
+
+    /// Resolves `product_ids` to the products to subscribe to
+    async fn resolve_products(&self) -> Result<Vec<String>> {
+        match &self.product_ids {
+            SubscribeProducts::All => self.products.product_ids().await,
+            SubscribeProducts::Custom(products) => Ok(products.clone()),
+            SubscribeProducts::Filter(filter) => Ok(filter.select(&self.products.products().await?)),
+        }
+    }
+
+    async fn subscribe_to_channel(&mut self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) {
+        let products = match self.resolve_products().await {
+            Ok(products) => products,
+            Err(e) => {
+                error!("[{}] Unable to resolve products: {}", &self.exchange, e);
+                return;
             }
         };
 
-        let mut channels_clone = self.channels.clone();
-        let mut current_channel = channels_clone.pop();
-        
-        while let Some(channel) = current_channel.clone() {
-            for product in &products {
-                if socket.can_write() {
-                    // let subscribe_channel = format!("{}{}", channel, product);
-                    info!(
-                        "[{}] Subscribing to [{}] for product: {}", 
-                        &self.exchange, 
-                        channel,
-                        product
-                    );
-                    // generate signature
-                    let current_ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs().to_string();
-                    let msg = models::ChannelSubscriptionMessage {
-                        msg_type: "subscribe".to_string(),
-                        product_ids: vec![product.clone()],
-                        channel: channel.clone(),
-                        api_key: self.key.clone(),
-                        timestamp: current_ts.clone(),
-                        signature: sig_gen::create_ws_signature(
-                            current_ts, 
-                            channel.clone(), 
-                            vec![product.clone()], 
-                            self.secret.clone().as_bytes()
-                        )
-                    };
-                    let json = serde_json::to_string(&msg).unwrap();
-                    let message = Message::Text(json);
-                    match socket.write_message(message) {
-                        Ok(_) => {
-                            std::thread::sleep(std::time::Duration::from_millis(100));
-                            continue;
-                        },
-                        Err(e) => {
-                            error!("Error occurred for symbol: {}", product);
-                            error!("Error: {}", e);
-                            continue;
-                        }
-                    }
-                } else {
-                    error!("Cannot write to socket.")
-                }
+        self.health.expect_subscriptions(&self.channels, &products);
+        self.send_subscriptions(socket, "subscribe", &products);
+        self.subscribed = products;
+        self.subscribed_generation = self.products.generation();
+        self.next_product_check = Instant::now() + PRODUCT_CHECK_INTERVAL;
+    }
+
+    fn follows_product_filter(&self) -> bool {
+        matches!(self.product_ids, SubscribeProducts::Filter(_))
+    }
+
+    /// Moves the subscriptions of a `SubscribeProducts::Filter` to the products it matches now
+    ///
+    /// Runs every `PRODUCT_CHECK_INTERVAL` whether or not messages arrive, and only reads the
+    /// cached products: `event_loop` refreshes them in the background with
+    /// `ProductRegistry::spawn_refresh`, and refreshes made elsewhere are picked up as well.
+    fn follow_product_filter(&mut self, socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) {
+        self.next_product_check = Instant::now() + PRODUCT_CHECK_INTERVAL;
+        let filter = match &self.product_ids {
+            SubscribeProducts::Filter(filter) => filter.clone(),
+            _ => return,
+        };
+
+        let generation = self.products.generation();
+        if generation == self.subscribed_generation {
+            return;
+        }
+        self.subscribed_generation = generation;
+
+        let selected = filter.select(&self.products.cached());
+        let added: Vec<String> = selected.iter().filter(|p| !self.subscribed.contains(p)).cloned().collect();
+        let removed: Vec<String> = self.subscribed.iter().filter(|p| !selected.contains(p)).cloned().collect();
+        if !added.is_empty() || !removed.is_empty() {
+            info!(
+                "[{}] Filter {} now adds {:?} and removes {:?}",
+                &self.exchange,
+                filter,
+                added,
+                removed
+            );
+            self.health.expect_subscriptions(&self.channels, &selected);
+            self.send_subscriptions(socket, "unsubscribe", &removed);
+            self.send_subscriptions(socket, "subscribe", &added);
+        }
+        self.subscribed = selected;
+    }
+
+    /// Sends one signed `msg_type` message, `"subscribe"` or `"unsubscribe"`, per channel for all
+    /// of `products`
+    ///
+    /// Runs inside the event loop, so it never waits between messages: a filter that swaps in
+    /// hundreds of products costs one message per channel, not one per product.
+    fn send_subscriptions(
+        &self,
+        socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+        msg_type: &str,
+        products: &[String],
+    ) {
+        if products.is_empty() {
+            return;
+        }
+        let clock = self.products.client().clock();
+
+        for channel in &self.channels {
+            if !socket.can_write() {
+                error!("Cannot write to socket.");
+                return;
+            }
+            let _span = info_span!(
+                "subscription",
+                exchange = %self.exchange,
+                msg_type,
+                channel = %channel,
+                product_id = %products.join(","),
+            ).entered();
+            info!(
+                "[{}] Sending {} to [{}] for products: {:?}", 
+                &self.exchange, 
+                msg_type,
+                channel,
+                products
+            );
+            // generate signature
+            let current_ts = clock.timestamp();
+            let msg = models::ChannelSubscriptionMessage {
+                msg_type: msg_type.to_string(),
+                product_ids: products.to_vec(),
+                channel: channel.clone(),
+                api_key: self.key.clone(),
+                timestamp: current_ts.clone(),
+                signature: sig_gen::create_ws_signature(
+                    current_ts, 
+                    channel.clone(), 
+                    products.to_vec(), 
+                    self.secret.clone().as_bytes()
+                )
+            };
+            let json = serde_json::to_string(&msg).unwrap();
+            if let Err(e) = socket.write_message(Message::Text(json)) {
+                error!("Error occurred for channel: {}", channel);
+                error!("Error: {}", e);
             }
-            current_channel = channels_clone.pop();
         }
     }
 
     async fn connect(&mut self) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
-        let websocket_urls = vec!["wss://advanced-trade-ws.coinbase.com".to_string()];
+        let websocket_urls = vec![self.websocket_url.clone()];
 
-        if let Ok(con) = websocket::connect_wss(&self.exchange, websocket_urls) {
+        if let Ok(mut con) = websocket::connect_wss_with_transport(&self.exchange, websocket_urls, &self.transport) {
+            if self.follows_product_filter() {
+                websocket::set_read_timeout(&mut con.0, Some(PRODUCT_CHECK_INTERVAL))?;
+            }
             return Ok(con);
         }
 
         bail!("Unable to connect.");
     }
 
-}
\ No newline at end of file
+}
+
+/// Aborts the background task when the event loop returns, however it returns
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,498 @@
+use crate::currency_graph::{CurrencyGraph, PairQuote, Side, Venue};
+use crate::fees::FeeModel;
+use crate::models::{GenericMessage, WebsocketEvent};
+use crate::sink::MessageSink;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+pub use crate::fees::DEFAULT_TAKER_FEE;
+
+/// Default age after which a quote no longer counts towards an opportunity
+pub const DEFAULT_MAX_QUOTE_AGE: Duration = Duration::from_secs(5);
+
+/// Settings of an `ArbitrageDetector`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArbitrageConfig {
+    /// Fee charged on every leg, as a fraction of the amount received
+    pub taker_fee: f64,
+    /// Smallest edge after fees worth reporting, e.g. `0.001` for 10 bps
+    pub min_edge: f64,
+    /// Currencies cycles start and end in, every currency when empty
+    pub start_currencies: Vec<String>,
+    /// Cycles with a quote older than this are skipped, `None` accepts quotes of any age
+    pub max_quote_age: Option<Duration>,
+}
+
+impl ArbitrageConfig {
+    pub fn new(taker_fee: f64) -> Self {
+        ArbitrageConfig {
+            taker_fee,
+            min_edge: 0.0,
+            start_currencies: Vec::new(),
+            max_quote_age: Some(DEFAULT_MAX_QUOTE_AGE),
+        }
+    }
+
+    /// Charges the taker rate of the account's fee tier on every leg
+    pub fn from_fees(fees: &FeeModel) -> Self {
+        ArbitrageConfig::new(fees.taker_rate)
+    }
+}
+
+impl Default for ArbitrageConfig {
+    fn default() -> Self {
+        ArbitrageConfig::new(DEFAULT_TAKER_FEE)
+    }
+}
+
+/// One trade of an `ArbitrageOpportunity`, sized to the opportunity's start amount
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArbitrageLeg {
+    pub product_id: String,
+    pub from: String,
+    pub to: String,
+    pub side: Side,
+    /// Best bid or ask the leg executes at
+    pub price: f64,
+    /// Base currency traded
+    pub size: f64,
+    /// Units of `from` paid
+    pub amount_in: f64,
+    /// Units of `to` received after fees
+    pub amount_out: f64,
+}
+
+/// Cycle of trades that returns more of the start currency than it spends
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ArbitrageOpportunity {
+    /// Currencies of the cycle joined by `>`, e.g. `"USD>BTC>ETH>USD"`
+    pub cycle: String,
+    pub start_currency: String,
+    pub legs: Vec<ArbitrageLeg>,
+    /// Product of the leg rates before fees
+    pub gross_rate: f64,
+    /// Product of the leg rates after fees
+    pub net_rate: f64,
+    /// Expected return after fees, `net_rate - 1`
+    pub edge: f64,
+    /// Largest amount of the start currency the top of every book can absorb
+    pub start_amount: f64,
+    /// Expected profit in the start currency when trading `start_amount`
+    pub profit: f64,
+    pub detected_at: SystemTime,
+}
+
+/// Products of a cycle in trading order, starting from `currencies[0]`
+#[derive(Debug, Clone)]
+struct Cycle {
+    id: String,
+    currencies: Vec<String>,
+    products: Vec<String>,
+}
+
+/// Rate and capacity of converting out of `from` through `pair` at the top of book
+///
+/// Unlike `PairQuote::leg_from` this never falls back to the last price, as an opportunity is
+/// only real if both the price and the size on the book are known.
+fn executable_leg(pair: &PairQuote, from: &str) -> Option<(Side, f64, f64, f64)> {
+    if from == pair.base {
+        let (bid, bid_size) = (pair.bid?, pair.bid_size?);
+        (bid > 0.0 && bid_size > 0.0).then_some((Side::Sell, bid, bid, bid_size))
+    } else if from == pair.quote {
+        let (ask, ask_size) = (pair.ask?, pair.ask_size?);
+        (ask > 0.0 && ask_size > 0.0).then_some((Side::Buy, ask, 1.0 / ask, ask_size * ask))
+    } else {
+        None
+    }
+}
+
+/// Finds every cycle of three pairs through the graph, both directions included
+fn find_cycles(pairs: &[PairQuote], start_currencies: &[String]) -> Vec<Cycle> {
+    let mut adjacency: HashMap<&str, Vec<&PairQuote>> = HashMap::new();
+    for pair in pairs {
+        adjacency.entry(&pair.base).or_default().push(pair);
+        adjacency.entry(&pair.quote).or_default().push(pair);
+    }
+    let other = |pair: &'_ PairQuote, currency: &str| -> String {
+        if pair.base == currency { pair.quote.clone() } else { pair.base.clone() }
+    };
+
+    let starts: BTreeSet<&str> = if start_currencies.is_empty() {
+        adjacency.keys().copied().collect()
+    } else {
+        start_currencies.iter().map(String::as_str).collect()
+    };
+
+    let mut cycles = Vec::new();
+    for start in starts {
+        for first in adjacency.get(start).into_iter().flatten() {
+            let a = other(first, start);
+            for second in adjacency.get(a.as_str()).into_iter().flatten() {
+                let b = other(second, &a);
+                if second.product_id == first.product_id || b == start {
+                    continue;
+                }
+                for third in adjacency.get(b.as_str()).into_iter().flatten() {
+                    if third.product_id == second.product_id || other(third, &b) != start {
+                        continue;
+                    }
+                    // without fixed start currencies, rotations of a cycle are the same trade
+                    if start_currencies.is_empty() && (a.as_str() < start || b.as_str() < start) {
+                        continue;
+                    }
+                    let currencies = vec![start.to_string(), a.clone(), b.clone(), start.to_string()];
+                    cycles.push(Cycle {
+                        id: currencies.join(">"),
+                        currencies,
+                        products: vec![first.product_id.clone(), second.product_id.clone(), third.product_id.clone()],
+                    });
+                }
+            }
+        }
+    }
+    cycles
+}
+
+/// Triangular arbitrage detector over the best bid and ask of a `CurrencyGraph`
+///
+/// Register it with `AdvancedTradeWebSockets::add_sink` after the sinks that keep the graph
+/// current, e.g. the graph itself for tickers or `OrderBooks::publish_to` for `level2`. Every
+/// message touching a product of a cycle re-evaluates that cycle, and an opportunity is sent
+/// when a cycle turns profitable or its edge or size changes while it stays profitable. Cycles
+/// follow the pairs of the graph, so products listed or delisted through
+/// `CurrencyGraph::follow_registry` enter or leave them on the next message.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::arbitrage::{ArbitrageConfig, ArbitrageDetector};
+/// use rs_coinbase_pairs_handler::currency_graph::CurrencyGraph;
+/// use rs_coinbase_pairs_handler::order_book::OrderBooks;
+///
+/// # let products = Vec::new();
+/// let graph = CurrencyGraph::new(&products);
+/// let books = OrderBooks::new().publish_to(graph.clone());
+/// // graph.follow_registry(&registry);
+/// let mut config = ArbitrageConfig::new(0.004);
+/// config.start_currencies = vec!["USD".to_string()];
+/// let (detector, mut opportunities) = ArbitrageDetector::new(graph, config);
+/// // feed.add_sink(Box::new(books));
+/// // feed.add_sink(Box::new(detector));
+/// ```
+pub struct ArbitrageDetector {
+    graph: CurrencyGraph,
+    config: ArbitrageConfig,
+    cycles: Vec<Cycle>,
+    /// Product to the index of every cycle trading it
+    by_product: HashMap<String, Vec<usize>>,
+    /// Last opportunity sent per cycle, dropped once the cycle stops being profitable
+    open: HashMap<String, (f64, f64)>,
+    /// `CurrencyGraph::generation` the cycles were built from
+    graph_generation: u64,
+    sender: UnboundedSender<ArbitrageOpportunity>,
+}
+
+impl ArbitrageDetector {
+    /// Creates a detector over the current pairs of `graph`
+    ///
+    /// # Returns
+    ///
+    /// `(ArbitrageDetector, UnboundedReceiver<ArbitrageOpportunity>)` - The detector and the receiving end of its opportunities
+    pub fn new(graph: CurrencyGraph, config: ArbitrageConfig) -> (ArbitrageDetector, UnboundedReceiver<ArbitrageOpportunity>) {
+        let (sender, receiver) = unbounded_channel();
+        let mut detector = ArbitrageDetector {
+            graph,
+            config,
+            cycles: Vec::new(),
+            by_product: HashMap::new(),
+            open: HashMap::new(),
+            graph_generation: 0,
+            sender,
+        };
+        detector.refresh_cycles();
+        (detector, receiver)
+    }
+
+    /// Rebuilds the cycles after the pairs of the graph changed
+    ///
+    /// `on_update` does it by itself whenever the graph's pairs were added or removed since, e.g.
+    /// by `CurrencyGraph::follow_registry`, so calling it is only needed to evaluate right away.
+    pub fn refresh_cycles(&mut self) {
+        self.graph_generation = self.graph.generation();
+        // conversions settle asynchronously, too slow for a cycle
+        let pairs: Vec<PairQuote> = self.graph.pairs().into_iter().filter(|p| p.venue == Venue::OrderBook).collect();
+        self.cycles = find_cycles(&pairs, &self.config.start_currencies);
+        self.cycles.sort_by(|a, b| a.id.cmp(&b.id));
+        self.by_product.clear();
+        for (index, cycle) in self.cycles.iter().enumerate() {
+            for product_id in &cycle.products {
+                self.by_product.entry(product_id.clone()).or_default().push(index);
+            }
+        }
+        self.open.retain(|id, _| self.cycles.iter().any(|c| &c.id == id));
+    }
+
+    /// Identifiers of the cycles watched, e.g. `"USD>BTC>ETH>USD"`
+    pub fn cycles(&self) -> Vec<String> {
+        self.cycles.iter().map(|c| c.id.clone()).collect()
+    }
+
+    /// Whether `pair` was quoted within `max_quote_age`
+    fn is_fresh(&self, pair: &PairQuote) -> bool {
+        match (self.config.max_quote_age, pair.updated_at) {
+            (None, _) => true,
+            (Some(max_age), Some(updated_at)) => updated_at.elapsed().unwrap_or_default() <= max_age,
+            (Some(_), None) => false,
+        }
+    }
+
+    fn evaluate_cycle(&self, cycle: &Cycle) -> Option<ArbitrageOpportunity> {
+        let fee_factor = 1.0 - self.config.taker_fee;
+        let mut quotes = Vec::with_capacity(cycle.products.len());
+        for (product_id, from) in cycle.products.iter().zip(&cycle.currencies) {
+            let pair = self.graph.pair(product_id)?;
+            if !self.is_fresh(&pair) {
+                return None;
+            }
+            quotes.push(executable_leg(&pair, from)?);
+        }
+
+        let gross_rate: f64 = quotes.iter().map(|(_, _, rate, _)| rate).product();
+        let net_rate = gross_rate * fee_factor.powi(quotes.len() as i32);
+        let edge = net_rate - 1.0;
+        if edge <= self.config.min_edge {
+            return None;
+        }
+
+        // capacity of each leg in units of the start currency
+        let mut start_amount = f64::INFINITY;
+        let mut reached = 1.0;
+        for (_, _, rate, capacity) in &quotes {
+            start_amount = start_amount.min(capacity / reached);
+            reached *= rate * fee_factor;
+        }
+
+        let mut amount = start_amount;
+        let mut legs = Vec::with_capacity(quotes.len());
+        for (i, (side, price, rate, _)) in quotes.into_iter().enumerate() {
+            let amount_out = amount * rate * fee_factor;
+            legs.push(ArbitrageLeg {
+                product_id: cycle.products[i].clone(),
+                from: cycle.currencies[i].clone(),
+                to: cycle.currencies[i + 1].clone(),
+                side,
+                price,
+                size: match side {
+                    Side::Sell => amount,
+                    Side::Buy => amount * rate,
+                },
+                amount_in: amount,
+                amount_out,
+            });
+            amount = amount_out;
+        }
+
+        Some(ArbitrageOpportunity {
+            cycle: cycle.id.clone(),
+            start_currency: cycle.currencies[0].clone(),
+            legs,
+            gross_rate,
+            net_rate,
+            edge,
+            start_amount,
+            profit: amount - start_amount,
+            detected_at: SystemTime::now(),
+        })
+    }
+
+    /// Every cycle that is profitable after fees right now, best edge first
+    pub fn evaluate(&self) -> Vec<ArbitrageOpportunity> {
+        let mut opportunities: Vec<ArbitrageOpportunity> =
+            self.cycles.iter().filter_map(|c| self.evaluate_cycle(c)).collect();
+        opportunities.sort_by(|a, b| b.edge.total_cmp(&a.edge));
+        opportunities
+    }
+
+    /// Re-evaluates the cycles trading any of `product_ids`, sending new or changed opportunities
+    pub fn on_update(&mut self, product_ids: &BTreeSet<String>) {
+        if self.graph.generation() != self.graph_generation {
+            self.refresh_cycles();
+        }
+        let indices: BTreeSet<usize> = product_ids
+            .iter()
+            .filter_map(|p| self.by_product.get(p))
+            .flatten()
+            .copied()
+            .collect();
+
+        for index in indices {
+            let cycle = &self.cycles[index];
+            match self.evaluate_cycle(cycle) {
+                Some(opportunity) => {
+                    let key = (opportunity.net_rate, opportunity.start_amount);
+                    if self.open.get(&cycle.id) != Some(&key) {
+                        self.open.insert(cycle.id.clone(), key);
+                        // a dropped receiver only means nobody is listening
+                        let _ = self.sender.send(opportunity);
+                    }
+                },
+                None => {
+                    self.open.remove(&cycle.id);
+                },
+            }
+        }
+    }
+}
+
+impl MessageSink for ArbitrageDetector {
+    fn on_message(&mut self, _received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        let mut product_ids = BTreeSet::new();
+        for event in &message.events {
+            match event {
+                WebsocketEvent::SnapshotEvent(snapshot) => {
+                    product_ids.extend(snapshot.tickers.iter().map(|t| t.product_id.clone()));
+                },
+                WebsocketEvent::UpdateEvent(update) => {
+                    product_ids.extend(update.tickers.iter().map(|t| t.product_id.clone()));
+                },
+                WebsocketEvent::Level2Event(level2) => {
+                    product_ids.insert(level2.product_id.clone());
+                },
+                _ => (),
+            }
+        }
+        if !product_ids.is_empty() {
+            self.on_update(&product_ids);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::ProductData;
+
+    fn graph() -> CurrencyGraph {
+        let graph = CurrencyGraph::default();
+        graph.insert_pair(PairQuote::new("BTC-USD", "BTC", "USD"));
+        graph.insert_pair(PairQuote::new("ETH-BTC", "ETH", "BTC"));
+        graph.insert_pair(PairQuote::new("ETH-USD", "ETH", "USD"));
+        graph.update_quote("BTC-USD", Some((19_990.0, Some(2.0))), Some((20_000.0, Some(0.5))));
+        graph.update_quote("ETH-BTC", Some((0.0699, Some(50.0))), Some((0.07, Some(40.0))));
+        // ETH bid is rich against ETH-BTC * BTC-USD = 1400
+        graph.update_quote("ETH-USD", Some((1_410.0, Some(3.0))), Some((1_411.0, Some(10.0))));
+        graph
+    }
+
+    fn usd_config(taker_fee: f64) -> ArbitrageConfig {
+        let mut config = ArbitrageConfig::new(taker_fee);
+        config.start_currencies = vec!["USD".to_string()];
+        config
+    }
+
+    #[test]
+    fn finds_each_triangle_once_per_direction() {
+        let (detector, _) = ArbitrageDetector::new(graph(), usd_config(0.0));
+        assert_eq!(detector.cycles(), vec!["USD>BTC>ETH>USD", "USD>ETH>BTC>USD"]);
+
+        let (detector, _) = ArbitrageDetector::new(graph(), ArbitrageConfig::new(0.0));
+        assert_eq!(detector.cycles(), vec!["BTC>ETH>USD>BTC", "BTC>USD>ETH>BTC"]);
+    }
+
+    #[test]
+    fn sizes_opportunities_to_top_of_book_depth() {
+        let (detector, _) = ArbitrageDetector::new(graph(), usd_config(0.001));
+        let opportunities = detector.evaluate();
+        assert_eq!(opportunities.len(), 1);
+
+        let opportunity = &opportunities[0];
+        assert_eq!(opportunity.cycle, "USD>BTC>ETH>USD");
+        let sides: Vec<Side> = opportunity.legs.iter().map(|l| l.side).collect();
+        assert_eq!(sides, vec![Side::Buy, Side::Buy, Side::Sell]);
+        assert!((opportunity.gross_rate - 1_410.0 / 20_000.0 / 0.07).abs() < 1e-12);
+        assert!((opportunity.net_rate - opportunity.gross_rate * 0.999f64.powi(3)).abs() < 1e-12);
+
+        // the 3 ETH bid on ETH-USD limits the cycle before the 0.5 BTC ask does
+        let last = opportunity.legs.last().unwrap();
+        assert!((last.size - 3.0).abs() < 1e-9);
+        assert!(opportunity.legs[0].size < 0.5);
+        assert!((opportunity.profit - opportunity.start_amount * opportunity.edge).abs() < 1e-6);
+    }
+
+    #[test]
+    fn skips_cycles_with_stale_quotes() {
+        let graph = graph();
+        let mut stale = graph.pair("ETH-USD").unwrap();
+        stale.updated_at = Some(SystemTime::now() - Duration::from_secs(60));
+        graph.insert_pair(stale);
+
+        let (detector, _) = ArbitrageDetector::new(graph.clone(), usd_config(0.0));
+        assert!(detector.evaluate().is_empty());
+
+        let mut config = usd_config(0.0);
+        config.max_quote_age = None;
+        let (detector, _) = ArbitrageDetector::new(graph, config);
+        assert_eq!(detector.evaluate().len(), 1);
+    }
+
+    #[test]
+    fn fees_remove_the_edge() {
+        let (detector, _) = ArbitrageDetector::new(graph(), usd_config(DEFAULT_TAKER_FEE));
+        assert!(detector.evaluate().is_empty());
+    }
+
+    #[test]
+    fn sends_opportunities_when_they_open_or_change() {
+        let graph = graph();
+        let (mut detector, mut opportunities) = ArbitrageDetector::new(graph.clone(), usd_config(0.0));
+        let products: BTreeSet<String> = ["ETH-USD".to_string()].into();
+
+        detector.on_update(&products);
+        detector.on_update(&products);
+        assert_eq!(opportunities.try_recv().unwrap().cycle, "USD>BTC>ETH>USD");
+        assert!(opportunities.try_recv().is_err());
+
+        graph.update_quote("ETH-USD", Some((1_399.0, Some(3.0))), Some((1_400.5, Some(10.0))));
+        detector.on_update(&products);
+        assert!(opportunities.try_recv().is_err());
+
+        graph.update_quote("ETH-USD", Some((1_410.0, Some(1.0))), None);
+        detector.on_update(&products);
+        assert!((opportunities.try_recv().unwrap().legs[2].size - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn cycles_follow_listings_and_delistings() {
+        let products = [
+            ProductData::test("BTC-USD", "BTC", "USD"),
+            ProductData::test("ETH-BTC", "ETH", "BTC"),
+            ProductData::test("ETH-USD", "ETH", "USD"),
+        ];
+        let graph = CurrencyGraph::new(&products[..2]);
+        let (mut detector, mut opportunities) = ArbitrageDetector::new(graph.clone(), usd_config(0.0));
+        assert!(detector.cycles().is_empty());
+
+        graph.set_products(&products);
+        graph.update_quote("BTC-USD", Some((19_990.0, Some(2.0))), Some((20_000.0, Some(0.5))));
+        graph.update_quote("ETH-BTC", Some((0.0699, Some(50.0))), Some((0.07, Some(40.0))));
+        graph.update_quote("ETH-USD", Some((1_410.0, Some(3.0))), Some((1_411.0, Some(10.0))));
+        detector.on_update(&["ETH-USD".to_string()].into());
+        assert_eq!(detector.cycles(), vec!["USD>BTC>ETH>USD", "USD>ETH>BTC>USD"]);
+        assert_eq!(opportunities.try_recv().unwrap().cycle, "USD>BTC>ETH>USD");
+
+        // prices alone leave the cycles as they are
+        let generation = graph.generation();
+        graph.update_quote("ETH-USD", Some((1_411.0, Some(3.0))), None);
+        assert_eq!(graph.generation(), generation);
+
+        graph.set_products(&[products[0].clone(), products[2].clone()]);
+        detector.on_update(&["ETH-USD".to_string()].into());
+        assert!(detector.cycles().is_empty());
+        assert!(detector.evaluate().is_empty());
+        assert!(opportunities.try_recv().is_err());
+    }
+}
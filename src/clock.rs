@@ -0,0 +1,120 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{info, warn};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Largest difference between `CB-ACCESS-TIMESTAMP` and the server clock Coinbase accepts
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(30);
+
+/// One measurement of the server clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// Server clock minus local clock
+    pub offset: TimeDelta,
+    /// Round trip of the request the offset was measured with, which bounds its error
+    pub round_trip: Duration,
+    /// Local time of the measurement
+    pub measured_at: SystemTime,
+}
+
+/// Estimated offset between the local clock and the Coinbase server clock
+///
+/// Signatures of the REST client and the feed take their timestamp from `now`, so once a
+/// sample is recorded, e.g. by `AdvancedTradeRESTClient::sync_clock`, a drifting local clock no
+/// longer causes `Unauthorized` responses. Without a sample the local clock is used as-is.
+/// Clones share the estimate.
+#[derive(Debug, Clone, Default)]
+pub struct ClockOffset {
+    sample: Arc<RwLock<Option<ClockSample>>>,
+}
+
+fn millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+impl ClockOffset {
+    /// Records a server clock reading taken between `sent_at` and `received_at`
+    ///
+    /// The server is assumed to have read its clock halfway through the round trip.
+    ///
+    /// # Returns
+    ///
+    /// `ClockSample` - the new estimate, which replaces the previous one
+    pub fn record(&self, sent_at: SystemTime, received_at: SystemTime, server_time: DateTime<Utc>) -> ClockSample {
+        let round_trip = received_at.duration_since(sent_at).unwrap_or_default();
+        let midpoint = sent_at + round_trip / 2;
+        let sample = ClockSample {
+            offset: TimeDelta::milliseconds(server_time.timestamp_millis() - millis(midpoint)),
+            round_trip,
+            measured_at: received_at,
+        };
+
+        let skew = sample.offset.abs().to_std().unwrap_or_default();
+        if skew > MAX_CLOCK_SKEW {
+            warn!(
+                "Local clock is {} ms off the server clock, more than the {} s Coinbase accepts. Correcting signature timestamps.",
+                sample.offset.num_milliseconds(),
+                MAX_CLOCK_SKEW.as_secs()
+            );
+        } else {
+            info!("Local clock is {} ms off the server clock (round trip {} ms)", sample.offset.num_milliseconds(), round_trip.as_millis());
+        }
+
+        *self.sample.write().unwrap() = Some(sample);
+        sample
+    }
+
+    /// Last recorded sample, `None` until the clock was measured
+    pub fn sample(&self) -> Option<ClockSample> {
+        *self.sample.read().unwrap()
+    }
+
+    /// Server clock minus local clock, zero until the clock was measured
+    pub fn offset(&self) -> TimeDelta {
+        self.sample().map_or(TimeDelta::zero(), |s| s.offset)
+    }
+
+    /// Converts a local time, e.g. when a message was received, to server time
+    pub fn to_server_time(&self, local: SystemTime) -> SystemTime {
+        let offset = self.offset();
+        match offset.to_std() {
+            Ok(ahead) => local + ahead,
+            Err(_) => local - offset.abs().to_std().unwrap_or_default(),
+        }
+    }
+
+    /// Current server time as estimated from the local clock
+    pub fn now(&self) -> SystemTime {
+        self.to_server_time(SystemTime::now())
+    }
+
+    /// Current server time in Unix seconds, the format of `CB-ACCESS-TIMESTAMP`
+    pub fn timestamp(&self) -> String {
+        self.now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn corrects_by_the_offset_at_the_middle_of_the_round_trip() {
+        let clock = ClockOffset::default();
+        let sent_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(clock.to_server_time(sent_at), sent_at);
+
+        // the server read its clock 45 s behind the local clock, 100 ms into a 200 ms round trip
+        let server_time = DateTime::from_timestamp_millis(1_700_000_000_000 - 45_000 + 100).unwrap();
+        let sample = clock.record(sent_at, sent_at + Duration::from_millis(200), server_time);
+
+        assert_eq!(sample.offset, TimeDelta::seconds(-45));
+        assert_eq!(sample.round_trip, Duration::from_millis(200));
+        assert_eq!(clock.to_server_time(sent_at), sent_at - Duration::from_secs(45));
+        let drift = clock.timestamp().parse::<i64>().unwrap() - Utc::now().timestamp();
+        assert!((-46..=-44).contains(&drift), "{}", drift);
+    }
+}
@@ -0,0 +1,626 @@
+use crate::fees::{FeeModel, Liquidity};
+use crate::models::{GenericMessage, ProductData, WebsocketEvent};
+use crate::product_registry::ProductRegistry;
+use crate::sink::MessageSink;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// Default number of pairs a conversion may go through
+pub const DEFAULT_MAX_LEGS: usize = 3;
+
+/// How long `insert_conversion` keeps a convert quote, which Coinbase expires within seconds
+pub const DEFAULT_CONVERSION_TTL: Duration = Duration::from_secs(10);
+
+/// Direction of a trade on a pair, from the point of view of the base currency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    /// Pay quote currency to receive base currency, at the ask
+    Buy,
+    /// Pay base currency to receive quote currency, at the bid
+    Sell,
+}
+
+/// Where a leg executes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Venue {
+    /// Order book of a listed product, paying the taker fee
+    #[default]
+    OrderBook,
+    /// Convert quote, e.g. USD to USDC, whose rate is net of its fees
+    Convert,
+}
+
+/// Latest known prices of a product, as an edge of the currency graph
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairQuote {
+    pub product_id: String,
+    pub base: String,
+    pub quote: String,
+    pub venue: Venue,
+    pub bid: Option<f64>,
+    pub bid_size: Option<f64>,
+    pub ask: Option<f64>,
+    pub ask_size: Option<f64>,
+    /// Last traded price, used for any side of the book that is unknown
+    pub last: Option<f64>,
+    pub updated_at: Option<SystemTime>,
+    /// When the rate stops being honoured, `None` for order books which hold until repriced
+    pub expires_at: Option<SystemTime>,
+}
+
+impl PairQuote {
+    pub fn new(product_id: &str, base: &str, quote: &str) -> Self {
+        PairQuote {
+            product_id: product_id.to_string(),
+            base: base.to_string(),
+            quote: quote.to_string(),
+            venue: Venue::OrderBook,
+            bid: None,
+            bid_size: None,
+            ask: None,
+            ask_size: None,
+            last: None,
+            updated_at: None,
+            expires_at: None,
+        }
+    }
+
+    /// Conversion from `from` to `to` at `rate` units of `to` per unit of `from`
+    ///
+    /// A convert quote only goes one way, so only the bid is set and the pair cannot be crossed
+    /// from `to` back to `from`; that takes a quote of its own. Its product id is
+    /// `"{from}-{to}-CONVERT"` so it never replaces the order book of a listed product. Routes
+    /// stop going through it at `expires_at`.
+    pub fn conversion(from: &str, to: &str, rate: f64, expires_at: SystemTime) -> Self {
+        PairQuote {
+            venue: Venue::Convert,
+            bid: Some(rate),
+            updated_at: Some(SystemTime::now()),
+            expires_at: Some(expires_at),
+            ..PairQuote::new(&format!("{}-{}-CONVERT", from, to), from, to)
+        }
+    }
+
+    /// Whether the rate is no longer honoured at `now`
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn best_bid(&self) -> Option<f64> {
+        self.bid.or(self.last).filter(|p| *p > 0.0)
+    }
+
+    pub fn best_ask(&self) -> Option<f64> {
+        self.ask.or(self.last).filter(|p| *p > 0.0)
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_bid()? + self.best_ask()?) / 2.0)
+    }
+
+    /// Converts out of `from` through this pair, crossing the spread
+    ///
+    /// # Returns
+    ///
+    /// `Option<RateLeg>` - `None` when `from` is not a currency of the pair or the price needed is unknown
+    pub fn leg_from(&self, from: &str) -> Option<RateLeg> {
+        let mid = match self.venue {
+            // a conversion has no spread, its rate is already net of its fees
+            Venue::Convert => self.best_bid()?,
+            Venue::OrderBook => self.mid()?,
+        };
+        if from == self.base {
+            let bid = self.best_bid()?;
+            Some(RateLeg {
+                product_id: self.product_id.clone(),
+                from: self.base.clone(),
+                to: self.quote.clone(),
+                side: Side::Sell,
+                venue: self.venue,
+                price: bid,
+                rate: bid,
+                mid_rate: mid,
+            })
+        } else if from == self.quote {
+            let ask = self.best_ask()?;
+            Some(RateLeg {
+                product_id: self.product_id.clone(),
+                from: self.quote.clone(),
+                to: self.base.clone(),
+                side: Side::Buy,
+                venue: self.venue,
+                price: ask,
+                rate: 1.0 / ask,
+                mid_rate: 1.0 / mid,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// One conversion step of a `CrossRate`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLeg {
+    pub product_id: String,
+    pub from: String,
+    pub to: String,
+    pub side: Side,
+    #[serde(default)]
+    pub venue: Venue,
+    /// Bid or ask of `product_id` the leg executes at
+    pub price: f64,
+    /// Units of `to` received per unit of `from`
+    pub rate: f64,
+    /// Same as `rate` but at the mid price
+    pub mid_rate: f64,
+}
+
+/// Best conversion found between two currencies
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CrossRate {
+    pub from: String,
+    pub to: String,
+    /// Units of `to` received per unit of `from` after crossing every spread
+    pub rate: f64,
+    /// Rate along the same legs at mid prices
+    pub mid_rate: f64,
+    /// Fraction of value lost to spreads, `1 - rate / mid_rate`
+    pub effective_spread: f64,
+    pub legs: Vec<RateLeg>,
+}
+
+impl CrossRate {
+    fn from_legs(from: &str, to: &str, legs: Vec<RateLeg>) -> CrossRate {
+        let rate: f64 = legs.iter().map(|l| l.rate).product();
+        let mid_rate: f64 = legs.iter().map(|l| l.mid_rate).product();
+        CrossRate {
+            from: from.to_string(),
+            to: to.to_string(),
+            rate,
+            mid_rate,
+            effective_spread: 1.0 - rate / mid_rate,
+            legs,
+        }
+    }
+
+    /// `rate` after paying the taker fee of `fees` on every order book leg
+    pub fn net_rate(&self, fees: &FeeModel) -> f64 {
+        let book_legs = self.legs.iter().filter(|l| l.venue == Venue::OrderBook).count();
+        self.rate * fees.net_factor(book_legs, Liquidity::Taker)
+    }
+}
+
+#[derive(Default)]
+struct GraphState {
+    pairs: HashMap<String, PairQuote>,
+    /// Currency to the products it is the base or quote of
+    adjacency: HashMap<String, Vec<String>>,
+    /// Bumped whenever a pair is added, removed or changes currencies
+    generation: u64,
+}
+
+impl GraphState {
+    fn insert_pair(&mut self, pair: PairQuote) {
+        match self.pairs.get(&pair.product_id) {
+            Some(previous) if previous.base == pair.base && previous.quote == pair.quote => (),
+            _ => self.generation += 1,
+        }
+        for currency in [&pair.base, &pair.quote] {
+            let products = self.adjacency.entry(currency.clone()).or_default();
+            if !products.contains(&pair.product_id) {
+                products.push(pair.product_id.clone());
+            }
+        }
+        self.pairs.insert(pair.product_id.clone(), pair);
+    }
+}
+
+/// Graph of currencies connected by tradable products, kept current by live tickers
+///
+/// Clones share state, so one clone can be registered as a sink with
+/// `AdvancedTradeWebSockets::add_sink` while others answer `rate` queries.
+#[derive(Clone, Default)]
+pub struct CurrencyGraph {
+    state: Arc<RwLock<GraphState>>,
+}
+
+impl CurrencyGraph {
+    /// Builds the graph from `ProductData`, skipping products with trading disabled
+    pub fn new(products: &[ProductData]) -> CurrencyGraph {
+        let graph = CurrencyGraph::default();
+        graph.set_products(products);
+        graph
+    }
+
+    /// Replaces the pairs of the graph, keeping the prices of products that are still listed
+    ///
+    /// Conversions are not products and are kept as they are until they expire.
+    pub fn set_products(&self, products: &[ProductData]) {
+        let mut state = self.state.write().unwrap();
+        let mut previous = std::mem::take(&mut state.pairs);
+        let before: BTreeSet<(String, String, String)> =
+            previous.values().map(|p| (p.product_id.clone(), p.base.clone(), p.quote.clone())).collect();
+        let generation = state.generation;
+        state.adjacency.clear();
+
+        for product in products.iter().filter(|p| !p.trading_disabled) {
+            let pair = match previous.remove(&product.product_id) {
+                Some(pair) if pair.base == product.base_currency_id && pair.quote == product.quote_currency_id => pair,
+                _ => PairQuote::new(&product.product_id, &product.base_currency_id, &product.quote_currency_id),
+            };
+            state.insert_pair(pair);
+        }
+        let now = SystemTime::now();
+        for pair in previous.into_values().filter(|p| p.venue == Venue::Convert && !p.is_expired(now)) {
+            state.insert_pair(pair);
+        }
+
+        let after: BTreeSet<(String, String, String)> =
+            state.pairs.values().map(|p| (p.product_id.clone(), p.base.clone(), p.quote.clone())).collect();
+        state.generation = if before == after { generation } else { generation + 1 };
+    }
+
+    /// Keeps the pairs in line with `registry`, calling `set_products` after every refresh that
+    /// lists, delists or otherwise changes a product
+    ///
+    /// Runs on the current tokio runtime. Abort the returned handle to stop.
+    pub fn follow_registry(&self, registry: &ProductRegistry) -> JoinHandle<()> {
+        let graph = self.clone();
+        let registry = registry.clone();
+        let mut changes = registry.subscribe();
+        tokio::spawn(async move {
+            if registry.generation() > 0 {
+                graph.set_products(&registry.cached());
+            }
+            while changes.recv().await.is_some() {
+                // a refresh sends its changes together, one `set_products` covers them all
+                while changes.try_recv().is_ok() {}
+                graph.set_products(&registry.cached());
+            }
+        })
+    }
+
+    /// Number of times a pair was added, removed or changed currencies, to tell whether
+    /// anything derived from the set of pairs must be rebuilt
+    ///
+    /// Prices and repriced conversions leave it as it is.
+    pub fn generation(&self) -> u64 {
+        self.state.read().unwrap().generation
+    }
+
+    /// Adds or replaces a single pair, e.g. a conversion that is not listed as a product
+    pub fn insert_pair(&self, pair: PairQuote) {
+        self.state.write().unwrap().insert_pair(pair);
+    }
+
+    /// Adds or reprices the conversion from `from` to `to`, e.g. at the rate of a convert quote,
+    /// for `DEFAULT_CONVERSION_TTL`
+    pub fn insert_conversion(&self, from: &str, to: &str, rate: f64) {
+        self.insert_conversion_until(from, to, rate, SystemTime::now() + DEFAULT_CONVERSION_TTL);
+    }
+
+    /// Same as `insert_conversion` for a quote that expires at `expires_at`
+    pub fn insert_conversion_until(&self, from: &str, to: &str, rate: f64, expires_at: SystemTime) {
+        self.insert_pair(PairQuote::conversion(from, to, rate, expires_at));
+    }
+
+    /// Sets the top of book of `product_id`, ignoring unknown products
+    pub fn update_quote(
+        &self,
+        product_id: &str,
+        bid: Option<(f64, Option<f64>)>,
+        ask: Option<(f64, Option<f64>)>,
+    ) {
+        let mut state = self.state.write().unwrap();
+        if let Some(pair) = state.pairs.get_mut(product_id) {
+            if let Some((price, size)) = bid {
+                pair.bid = Some(price);
+                pair.bid_size = size;
+            }
+            if let Some((price, size)) = ask {
+                pair.ask = Some(price);
+                pair.ask_size = size;
+            }
+            pair.updated_at = Some(SystemTime::now());
+        }
+    }
+
+    /// Forgets every price of `product_id`, e.g. when the book it was taken from is out of sync
+    pub fn clear_quote(&self, product_id: &str) {
+        let mut state = self.state.write().unwrap();
+        if let Some(pair) = state.pairs.get_mut(product_id) {
+            pair.bid = None;
+            pair.bid_size = None;
+            pair.ask = None;
+            pair.ask_size = None;
+            pair.last = None;
+            pair.updated_at = None;
+        }
+    }
+
+    /// Sets the last traded price of `product_id`, ignoring unknown products
+    pub fn update_price(&self, product_id: &str, price: f64) {
+        let mut state = self.state.write().unwrap();
+        if let Some(pair) = state.pairs.get_mut(product_id) {
+            pair.last = Some(price);
+            pair.updated_at = Some(SystemTime::now());
+        }
+    }
+
+    pub fn pair(&self, product_id: &str) -> Option<PairQuote> {
+        self.state.read().unwrap().pairs.get(product_id).cloned()
+    }
+
+    pub fn pairs(&self) -> Vec<PairQuote> {
+        self.state.read().unwrap().pairs.values().cloned().collect()
+    }
+
+    pub fn currencies(&self) -> BTreeSet<String> {
+        self.state.read().unwrap().adjacency.keys().cloned().collect()
+    }
+
+    /// Best rate from `from` to `to` through at most `DEFAULT_MAX_LEGS` pairs
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rs_coinbase_pairs_handler::currency_graph::{CurrencyGraph, PairQuote};
+    ///
+    /// let graph = CurrencyGraph::default();
+    /// graph.insert_pair(PairQuote::new("ETH-BTC", "ETH", "BTC"));
+    /// graph.insert_pair(PairQuote::new("BTC-EUR", "BTC", "EUR"));
+    /// graph.update_price("ETH-BTC", 0.07);
+    /// graph.update_price("BTC-EUR", 20000.0);
+    ///
+    /// let rate = graph.rate("ETH", "EUR").unwrap();
+    /// assert_eq!(rate.legs.len(), 2);
+    /// assert!((rate.rate - 1400.0).abs() < 1e-9);
+    /// ```
+    pub fn rate(&self, from: &str, to: &str) -> Option<CrossRate> {
+        self.rate_with_max_legs(from, to, DEFAULT_MAX_LEGS)
+    }
+
+    /// Best rate from `from` to `to` through at most `max_legs` pairs
+    ///
+    /// Paths never visit a currency twice and skip expired conversions. Among the paths found, the
+    /// one yielding the most `to` per unit of `from` after spreads wins.
+    pub fn rate_with_max_legs(&self, from: &str, to: &str, max_legs: usize) -> Option<CrossRate> {
+        self.search(from, to, max_legs, |leg| leg.rate)
+    }
+
+    /// Cheapest route from `from` to `to` through at most `DEFAULT_MAX_LEGS` pairs once fees are paid
+    ///
+    /// Order book legs pay the taker fee of `fees` while conversions are free, so a conversion
+    /// is chosen over the order books whenever it leaves more of `to` after fees.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rs_coinbase_pairs_handler::currency_graph::{CurrencyGraph, PairQuote, Venue};
+    /// use rs_coinbase_pairs_handler::fees::FeeModel;
+    ///
+    /// let graph = CurrencyGraph::default();
+    /// graph.insert_pair(PairQuote::new("USDC-USD", "USDC", "USD"));
+    /// graph.update_quote("USDC-USD", Some((1.0, None)), Some((1.0001, None)));
+    /// graph.insert_conversion("USD", "USDC", 1.0);
+    ///
+    /// let route = graph.route("USD", "USDC", &FeeModel::default()).unwrap();
+    /// assert_eq!(route.legs[0].venue, Venue::Convert);
+    /// ```
+    pub fn route(&self, from: &str, to: &str, fees: &FeeModel) -> Option<CrossRate> {
+        let fee_factor = fees.net_factor(1, Liquidity::Taker);
+        self.search(from, to, DEFAULT_MAX_LEGS, |leg| match leg.venue {
+            Venue::OrderBook => leg.rate * fee_factor,
+            Venue::Convert => leg.rate,
+        })
+    }
+
+    /// Path from `from` to `to` maximizing the product of `weight` over its legs
+    fn search(&self, from: &str, to: &str, max_legs: usize, weight: impl Fn(&RateLeg) -> f64) -> Option<CrossRate> {
+        if from == to {
+            return Some(CrossRate::from_legs(from, to, Vec::new()));
+        }
+
+        let state = self.state.read().unwrap();
+        let now = SystemTime::now();
+        // best known path per currency, searched one leg at a time
+        let mut frontier: HashMap<String, (f64, Vec<RateLeg>)> = HashMap::new();
+        frontier.insert(from.to_string(), (0.0, Vec::new()));
+        let mut best: Option<(f64, Vec<RateLeg>)> = None;
+
+        for _ in 0..max_legs {
+            let mut next: HashMap<String, (f64, Vec<RateLeg>)> = HashMap::new();
+            for (currency, (log_rate, legs)) in &frontier {
+                let products = match state.adjacency.get(currency) {
+                    Some(products) => products,
+                    None => continue,
+                };
+                for product_id in products {
+                    let pair = &state.pairs[product_id];
+                    if pair.is_expired(now) {
+                        continue;
+                    }
+                    let leg = match pair.leg_from(currency) {
+                        Some(leg) if leg.rate > 0.0 => leg,
+                        _ => continue,
+                    };
+                    if leg.to == from || legs.iter().any(|l| l.from == leg.to) {
+                        continue;
+                    }
+
+                    let candidate = log_rate + weight(&leg).ln();
+                    let reached = leg.to.clone();
+                    let mut path = legs.clone();
+                    path.push(leg);
+                    if reached == to {
+                        if best.as_ref().is_none_or(|(existing, _)| candidate > *existing) {
+                            best = Some((candidate, path));
+                        }
+                    } else if next.get(&reached).is_none_or(|(existing, _)| candidate > *existing) {
+                        next.insert(reached, (candidate, path));
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        best.map(|(_, legs)| CrossRate::from_legs(from, to, legs))
+    }
+}
+
+fn parse_price(value: &Option<String>) -> Option<f64> {
+    value.as_deref()?.parse().ok()
+}
+
+impl MessageSink for CurrencyGraph {
+    fn on_message(&mut self, _received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        let apply = |product_id: &str, price: &str, bid: &Option<String>, bid_size: &Option<String>, ask: &Option<String>, ask_size: &Option<String>| {
+            if let Ok(price) = price.parse() {
+                self.update_price(product_id, price);
+            }
+            let bid = parse_price(bid).map(|p| (p, parse_price(bid_size)));
+            let ask = parse_price(ask).map(|p| (p, parse_price(ask_size)));
+            if bid.is_some() || ask.is_some() {
+                self.update_quote(product_id, bid, ask);
+            }
+        };
+
+        for event in &message.events {
+            match event {
+                WebsocketEvent::SnapshotEvent(snapshot) => {
+                    for t in &snapshot.tickers {
+                        apply(&t.product_id, &t.price, &t.best_bid, &t.best_bid_quantity, &t.best_ask, &t.best_ask_quantity);
+                    }
+                },
+                WebsocketEvent::UpdateEvent(update) => {
+                    for t in &update.tickers {
+                        apply(&t.product_id, &t.price, &t.best_bid, &t.best_bid_quantity, &t.best_ask, &t.best_ask_quantity);
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> CurrencyGraph {
+        let graph = CurrencyGraph::new(&[
+            ProductData::test("BTC-USD", "BTC", "USD"),
+            ProductData::test("ETH-BTC", "ETH", "BTC"),
+            ProductData::test("ETH-USD", "ETH", "USD"),
+            ProductData::test("SOL-EUR", "SOL", "EUR"),
+        ]);
+        graph.update_quote("BTC-USD", Some((20_000.0, None)), Some((20_010.0, None)));
+        graph.update_quote("ETH-BTC", Some((0.07, None)), Some((0.0701, None)));
+        graph
+    }
+
+    #[test]
+    fn converts_through_intermediate_pairs() {
+        let graph = graph();
+
+        let rate = graph.rate("ETH", "USD").unwrap();
+        let products: Vec<&str> = rate.legs.iter().map(|l| l.product_id.as_str()).collect();
+        assert_eq!(products, vec!["ETH-BTC", "BTC-USD"]);
+        assert_eq!(rate.legs[0].side, Side::Sell);
+        assert!((rate.rate - 1400.0).abs() < 1e-9);
+        assert!(rate.effective_spread > 0.0 && rate.effective_spread < 0.002);
+
+        let inverse = graph.rate("USD", "ETH").unwrap();
+        assert_eq!(inverse.legs[0].side, Side::Buy);
+        assert!((inverse.rate - 1.0 / 20_010.0 / 0.0701).abs() < 1e-12);
+    }
+
+    #[test]
+    fn prefers_the_better_path() {
+        let graph = graph();
+        graph.update_quote("ETH-USD", Some((1_450.0, None)), Some((1_451.0, None)));
+        assert_eq!(graph.rate("ETH", "USD").unwrap().legs.len(), 1);
+
+        graph.update_quote("ETH-USD", Some((1_300.0, None)), Some((1_301.0, None)));
+        assert_eq!(graph.rate("ETH", "USD").unwrap().legs.len(), 2);
+    }
+
+    #[test]
+    fn reports_unreachable_or_unpriced_currencies() {
+        let graph = graph();
+        assert!(graph.rate("ETH", "EUR").is_none());
+        assert!(graph.rate("SOL", "EUR").is_none());
+        assert_eq!(graph.rate("BTC", "BTC").unwrap().rate, 1.0);
+        assert!(graph.rate_with_max_legs("ETH", "USD", 1).is_none());
+    }
+
+    #[test]
+    fn routes_through_a_conversion_when_it_beats_the_books() {
+        let graph = CurrencyGraph::new(&[
+            ProductData::test("USDT-USD", "USDT", "USD"),
+            ProductData::test("USDT-USDC", "USDT", "USDC"),
+        ]);
+        graph.update_quote("USDT-USD", Some((0.9999, None)), Some((1.0001, None)));
+        graph.update_quote("USDT-USDC", Some((1.0, None)), Some((1.0002, None)));
+        let fees = FeeModel::new(0.0, 0.001);
+        assert_eq!(graph.route("USD", "USDC", &fees).unwrap().legs.len(), 2);
+
+        graph.insert_conversion("USD", "USDC", 0.9995);
+        let route = graph.route("USD", "USDC", &fees).unwrap();
+        assert_eq!(route.legs.len(), 1);
+        assert_eq!((route.legs[0].venue, route.legs[0].side), (Venue::Convert, Side::Sell));
+        assert_eq!(route.net_rate(&fees), 0.9995);
+        // before fees the books still look better
+        assert_eq!(graph.rate("USD", "USDC").unwrap().legs.len(), 2);
+
+        graph.set_products(&[ProductData::test("USDT-USD", "USDT", "USD")]);
+        assert_eq!(graph.rate("USD", "USDC").unwrap().legs[0].product_id, "USD-USDC-CONVERT");
+        // the quote only converts one way
+        assert!(graph.rate("USDC", "USD").is_none());
+        graph.insert_conversion("USDC", "USD", 0.9995);
+        assert_eq!(graph.route("USDC", "USD", &fees).unwrap().legs[0].product_id, "USDC-USD-CONVERT");
+    }
+
+    #[test]
+    fn skips_expired_conversions() {
+        let graph = CurrencyGraph::new(&[
+            ProductData::test("USDT-USD", "USDT", "USD"),
+            ProductData::test("USDT-USDC", "USDT", "USDC"),
+        ]);
+        graph.update_quote("USDT-USD", Some((0.9999, None)), Some((1.0001, None)));
+        graph.update_quote("USDT-USDC", Some((1.0, None)), Some((1.0002, None)));
+        let fees = FeeModel::new(0.0, 0.001);
+
+        graph.insert_conversion_until("USD", "USDC", 0.9995, SystemTime::now() - Duration::from_secs(1));
+        assert!(graph.pair("USD-USDC-CONVERT").unwrap().is_expired(SystemTime::now()));
+        let route = graph.route("USD", "USDC", &fees).unwrap();
+        assert!(route.legs.iter().all(|l| l.venue == Venue::OrderBook));
+
+        graph.insert_conversion_until("USD", "USDC", 0.9995, SystemTime::now() + Duration::from_millis(50));
+        assert_eq!(graph.route("USD", "USDC", &fees).unwrap().legs[0].venue, Venue::Convert);
+        std::thread::sleep(Duration::from_millis(60));
+        assert_eq!(graph.route("USD", "USDC", &fees).unwrap().legs.len(), 2);
+        // a product refresh drops it instead of carrying it forward
+        graph.set_products(&[ProductData::test("USDT-USD", "USDT", "USD")]);
+        assert!(graph.pair("USD-USDC-CONVERT").is_none());
+    }
+
+    #[test]
+    fn follows_live_tickers() {
+        let mut graph = graph();
+        let message: GenericMessage = serde_json::from_str(r#"{"channel":"ticker","client_id":"","timestamp":"","sequence_num":0,"events":[{"type":"update","tickers":[{"type":"ticker","product_id":"SOL-EUR","price":"20.5","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"1","best_bid":"20.4","best_bid_quantity":"3","best_ask":"20.6","best_ask_quantity":"4"}]}]}"#).unwrap();
+
+        graph.on_message(SystemTime::now(), &message).unwrap();
+
+        let pair = graph.pair("SOL-EUR").unwrap();
+        assert_eq!((pair.bid, pair.ask, pair.last), (Some(20.4), Some(20.6), Some(20.5)));
+        assert_eq!(graph.rate("SOL", "EUR").unwrap().rate, 20.4);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::models::{OrderSide, TransactionSummary};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// Taker fee of the lowest Advanced Trade volume tier, as a fraction of notional
+pub const DEFAULT_TAKER_FEE: f64 = 0.006;
+/// Maker fee of the lowest Advanced Trade volume tier, as a fraction of notional
+pub const DEFAULT_MAKER_FEE: f64 = 0.004;
+
+/// Whether an order took liquidity from the book or rested on it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Liquidity {
+    Maker,
+    Taker,
+}
+
+/// All-in cost of an execution, in the quote currency
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ExecutionCost {
+    /// Size times price
+    pub notional: f64,
+    pub fee: f64,
+    /// Quote paid for a buy, `notional + fee`, or received for a sell, `notional - fee`
+    pub total: f64,
+    /// `total` per unit of base
+    pub effective_price: f64,
+}
+
+/// Fee rates of the account, as reported by the transaction summary
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+/// use rs_coinbase_pairs_handler::fees::{FeeModel, Liquidity};
+/// use rs_coinbase_pairs_handler::models::OrderSide;
+///
+/// # async fn example(client: AdvancedTradeRESTClient) -> anyhow::Result<()> {
+/// let fees = FeeModel::fetch(&client).await?;
+/// let cost = fees.execution_cost(OrderSide::Buy, 0.5, 1675.14, Liquidity::Taker);
+/// println!("{} USD all-in at {} per ETH", cost.total, cost.effective_price);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeeModel {
+    pub maker_rate: f64,
+    pub taker_rate: f64,
+    /// e.g. `"$10K-$50K"`, empty for the defaults
+    pub pricing_tier: String,
+}
+
+impl FeeModel {
+    pub fn new(maker_rate: f64, taker_rate: f64) -> FeeModel {
+        FeeModel { maker_rate, taker_rate, pricing_tier: String::new() }
+    }
+
+    /// Reads the rates of the fee tier in `summary`
+    pub fn from_summary(summary: &TransactionSummary) -> Result<FeeModel> {
+        let rate = |name: &str, value: &str| {
+            value
+                .parse::<f64>()
+                .map_err(|_| anyhow!("Invalid {} {:?} in transaction summary", name, value))
+        };
+        Ok(FeeModel {
+            maker_rate: rate("maker_fee_rate", &summary.fee_tier.maker_fee_rate)?,
+            taker_rate: rate("taker_fee_rate", &summary.fee_tier.taker_fee_rate)?,
+            pricing_tier: summary.fee_tier.pricing_tier.clone(),
+        })
+    }
+
+    /// Fetches the current fee tier of the account
+    pub async fn fetch(client: &AdvancedTradeRESTClient) -> Result<FeeModel> {
+        FeeModel::from_summary(&client.get_transaction_summary(None).await?)
+    }
+
+    /// Fee as a fraction of notional
+    pub fn rate(&self, liquidity: Liquidity) -> f64 {
+        match liquidity {
+            Liquidity::Maker => self.maker_rate,
+            Liquidity::Taker => self.taker_rate,
+        }
+    }
+
+    /// Fee charged on `notional`, in the same currency
+    pub fn fee(&self, notional: f64, liquidity: Liquidity) -> f64 {
+        notional * self.rate(liquidity)
+    }
+
+    /// Cost of trading `size` base at `price`
+    pub fn execution_cost(&self, side: OrderSide, size: f64, price: f64, liquidity: Liquidity) -> ExecutionCost {
+        let notional = size * price;
+        let fee = self.fee(notional, liquidity);
+        let total = match side {
+            OrderSide::Buy => notional + fee,
+            OrderSide::Sell => notional - fee,
+        };
+        ExecutionCost {
+            notional,
+            fee,
+            total,
+            effective_price: if size > 0.0 { total / size } else { price },
+        }
+    }
+
+    /// Share of an amount left after converting it `legs` times, e.g. through a `CrossRate`
+    pub fn net_factor(&self, legs: usize, liquidity: Liquidity) -> f64 {
+        (1.0 - self.rate(liquidity)).powi(legs as i32)
+    }
+}
+
+impl Default for FeeModel {
+    /// Rates of the lowest volume tier, until the account's are fetched
+    fn default() -> Self {
+        FeeModel::new(DEFAULT_MAKER_FEE, DEFAULT_TAKER_FEE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prices_buys_and_sells_all_in() {
+        let fees = FeeModel::new(0.0025, 0.004);
+        let buy = fees.execution_cost(OrderSide::Buy, 2.0, 1000.0, Liquidity::Taker);
+        assert_eq!((buy.notional, buy.fee, buy.total, buy.effective_price), (2000.0, 8.0, 2008.0, 1004.0));
+        let sell = fees.execution_cost(OrderSide::Sell, 2.0, 1000.0, Liquidity::Maker);
+        assert_eq!((sell.fee, sell.total, sell.effective_price), (5.0, 1995.0, 997.5));
+        assert!((fees.net_factor(3, Liquidity::Taker) - 0.996f64.powi(3)).abs() < 1e-15);
+    }
+}
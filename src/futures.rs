@@ -0,0 +1,80 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::models::{FcmBalanceSummary, GenericMessage, WebsocketEvent};
+use crate::sink::MessageSink;
+use anyhow::Result;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Default)]
+struct BalanceState {
+    latest: Option<(SystemTime, FcmBalanceSummary)>,
+    subscribers: Vec<UnboundedSender<FcmBalanceSummary>>,
+}
+
+/// Latest balance summary of the futures account, kept current by the `futures_balance_summary` channel
+///
+/// Clones share state, so one clone can be registered as a sink with
+/// `AdvancedTradeWebSockets::add_sink` while others read the balances.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+/// use rs_coinbase_pairs_handler::advanced_trade_websocket::AdvancedTradeWebSockets;
+/// use rs_coinbase_pairs_handler::futures::FuturesBalances;
+///
+/// # async fn example(client: AdvancedTradeRESTClient, mut feed: AdvancedTradeWebSockets) -> anyhow::Result<()> {
+/// let balances = FuturesBalances::fetch(&client).await?;
+/// feed.add_sink(Box::new(balances.clone()));
+/// let mut updates = balances.subscribe();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct FuturesBalances {
+    state: Arc<Mutex<BalanceState>>,
+}
+
+impl FuturesBalances {
+    /// Starts from the balance summary of the REST API, until the channel sends its snapshot
+    pub async fn fetch(client: &AdvancedTradeRESTClient) -> Result<FuturesBalances> {
+        let balances = FuturesBalances::default();
+        balances.update(SystemTime::now(), FcmBalanceSummary::from(&client.get_futures_balance_summary().await?));
+        Ok(balances)
+    }
+
+    pub fn latest(&self) -> Option<FcmBalanceSummary> {
+        self.state.lock().unwrap().latest.as_ref().map(|(_, summary)| summary.clone())
+    }
+
+    /// When the latest summary was received
+    pub fn updated_at(&self) -> Option<SystemTime> {
+        self.state.lock().unwrap().latest.as_ref().map(|(received_at, _)| *received_at)
+    }
+
+    /// Receives every summary from now on
+    pub fn subscribe(&self) -> UnboundedReceiver<FcmBalanceSummary> {
+        let (sender, receiver) = unbounded_channel();
+        self.state.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    fn update(&self, received_at: SystemTime, summary: FcmBalanceSummary) {
+        let mut state = self.state.lock().unwrap();
+        // a dropped receiver only means nobody is listening anymore
+        state.subscribers.retain(|s| s.send(summary.clone()).is_ok());
+        state.latest = Some((received_at, summary));
+    }
+}
+
+impl MessageSink for FuturesBalances {
+    fn on_message(&mut self, received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        for event in &message.events {
+            if let WebsocketEvent::FuturesBalanceEvent(event) = event {
+                self.update(received_at, event.fcm_balance_summary.clone());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+use crate::models::{GenericMessage, Subscriptions, WebsocketEvent};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+use tiny_http::{Header, Response, Server};
+
+/// Limits that decide when the feed or a product counts as unhealthy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthThresholds {
+    /// Age of the last message after which a product is reported stale
+    pub stale_after: Duration,
+    /// Time without any frame or connection after which the feed is no longer live
+    pub liveness_timeout: Duration,
+}
+
+impl Default for HealthThresholds {
+    fn default() -> Self {
+        HealthThresholds {
+            stale_after: Duration::from_secs(60),
+            liveness_timeout: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Last message of one product
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProductHealth {
+    pub product_id: String,
+    /// RFC 3339 receive time of the last message, `None` if nothing arrived yet
+    pub last_message_at: Option<String>,
+    pub age_ms: Option<u64>,
+    pub stale: bool,
+}
+
+/// Snapshot of the feed state, served as JSON by `HealthServer`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// The event loop runs and saw a frame or connected within `liveness_timeout`
+    pub live: bool,
+    /// Connected and every subscription was acknowledged
+    pub ready: bool,
+    pub running: bool,
+    pub connected: bool,
+    pub reconnects: u64,
+    pub last_frame_age_ms: Option<u64>,
+    /// Subscriptions sent but not acknowledged yet, per channel
+    pub pending_subscriptions: BTreeMap<String, Vec<String>>,
+    pub products: Vec<ProductHealth>,
+}
+
+#[derive(Debug)]
+struct HealthState {
+    running: bool,
+    connected: bool,
+    reconnects: u64,
+    /// Last frame received, connection made or start of the loop
+    last_activity: SystemTime,
+    last_frame_at: Option<SystemTime>,
+    expected: BTreeMap<String, BTreeSet<String>>,
+    acknowledged: Subscriptions,
+    /// Subscribed products, `None` until their first message
+    last_message_at: BTreeMap<String, Option<SystemTime>>,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState {
+            running: false,
+            connected: false,
+            reconnects: 0,
+            last_activity: SystemTime::now(),
+            last_frame_at: None,
+            expected: BTreeMap::new(),
+            acknowledged: Subscriptions::new(),
+            last_message_at: BTreeMap::new(),
+        }
+    }
+}
+
+fn age(now: SystemTime, then: SystemTime) -> Duration {
+    now.duration_since(then).unwrap_or_default()
+}
+
+/// Connection, subscription and per-product state of `AdvancedTradeWebSockets`
+///
+/// The feed keeps its `health` handle current. Clones share state, so a `HealthServer` can
+/// report on a feed running on another thread.
+#[derive(Debug, Clone, Default)]
+pub struct FeedHealth {
+    state: Arc<Mutex<HealthState>>,
+}
+
+impl FeedHealth {
+    /// Marks the event loop as started or stopped
+    pub fn set_running(&self, running: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.running = running;
+        state.last_activity = SystemTime::now();
+    }
+
+    /// Marks the WebSocket as connected, or as lost along with every acknowledgement
+    pub fn set_connected(&self, connected: bool) {
+        let mut state = self.state.lock().unwrap();
+        if connected {
+            state.last_activity = SystemTime::now();
+        } else if state.connected {
+            state.reconnects += 1;
+            state.acknowledged.clear();
+        }
+        state.connected = connected;
+    }
+
+    /// Replaces the subscriptions the feed waits to be acknowledged
+    pub fn expect_subscriptions(&self, channels: &[String], product_ids: &[String]) {
+        let mut state = self.state.lock().unwrap();
+        state.expected = channels
+            .iter()
+            .map(|channel| (channel.clone(), product_ids.iter().cloned().collect()))
+            .collect();
+        for product_id in product_ids {
+            state.last_message_at.entry(product_id.clone()).or_insert(None);
+        }
+        let expected: BTreeSet<&String> = product_ids.iter().collect();
+        state.last_message_at.retain(|product_id, _| expected.contains(product_id));
+    }
+
+    /// Records a raw frame as a sign of life
+    pub fn record_frame(&self, received_at: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        state.last_frame_at = Some(received_at);
+        state.last_activity = received_at;
+    }
+
+    /// Records the products of `message`, or the acknowledged subscriptions for `subscriptions`
+    pub fn record_message(&self, message: &GenericMessage, received_at: SystemTime) {
+        let mut state = self.state.lock().unwrap();
+        for event in &message.events {
+            if let WebsocketEvent::SubscriptionEvent(ack) = event {
+                // every acknowledgement carries all subscriptions of the connection
+                state.acknowledged = ack.subscriptions.clone();
+            }
+        }
+        for product_id in message.product_ids() {
+            if let Some(last) = state.last_message_at.get_mut(product_id) {
+                *last = Some(received_at);
+            }
+        }
+    }
+
+    pub fn report(&self, thresholds: &HealthThresholds) -> HealthReport {
+        let state = self.state.lock().unwrap();
+        let now = SystemTime::now();
+
+        let pending_subscriptions: BTreeMap<String, Vec<String>> = state
+            .expected
+            .iter()
+            .filter_map(|(channel, products)| {
+                let acknowledged = state.acknowledged.get(channel);
+                let pending: Vec<String> = products
+                    .iter()
+                    .filter(|p| !acknowledged.is_some_and(|a| a.contains(p)))
+                    .cloned()
+                    .collect();
+                (!pending.is_empty()).then(|| (channel.clone(), pending))
+            })
+            .collect();
+
+        let products = state
+            .last_message_at
+            .iter()
+            .map(|(product_id, last)| {
+                let last = *last;
+                let age_ms = last.map(|t| age(now, t).as_millis() as u64);
+                ProductHealth {
+                    product_id: product_id.clone(),
+                    last_message_at: last.map(|t| DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Millis, true)),
+                    age_ms,
+                    stale: last.is_none_or(|t| age(now, t) > thresholds.stale_after),
+                }
+            })
+            .collect();
+
+        HealthReport {
+            live: state.running && age(now, state.last_activity) <= thresholds.liveness_timeout,
+            ready: state.connected && pending_subscriptions.is_empty(),
+            running: state.running,
+            connected: state.connected,
+            reconnects: state.reconnects,
+            last_frame_age_ms: state.last_frame_at.map(|t| age(now, t).as_millis() as u64),
+            pending_subscriptions,
+            products,
+        }
+    }
+}
+
+/// HTTP server for Kubernetes style probes
+///
+/// * `GET /livez`: 200 while `HealthReport.live`, 503 otherwise
+/// * `GET /readyz`: 200 while `HealthReport.ready`, 503 otherwise
+/// * `GET /health`: always 200
+///
+/// Every route returns the full `HealthReport` as JSON. Serves on a background thread until
+/// dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::advanced_trade_websocket::{AdvancedTradeWebSockets, SubscribeProducts};
+/// use rs_coinbase_pairs_handler::health::{HealthServer, HealthThresholds};
+///
+/// let feed = AdvancedTradeWebSockets::new(vec!["ticker".to_string()], SubscribeProducts::All);
+/// let server = HealthServer::start("0.0.0.0:8080", feed.health(), HealthThresholds::default()).unwrap();
+/// ```
+pub struct HealthServer {
+    addr: SocketAddr,
+    http: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl HealthServer {
+    /// Binds `addr`, e.g. `"0.0.0.0:8080"` or `"127.0.0.1:0"` for an ephemeral port
+    pub fn start(addr: &str, health: FeedHealth, thresholds: HealthThresholds) -> Result<HealthServer> {
+        let http = Arc::new(Server::http(addr).map_err(|e| anyhow!(e))?);
+        let addr = http
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("Health server is not bound to an IP address"))?;
+        info!("Serving health on http://{}", addr);
+
+        let thread = {
+            let http = http.clone();
+            std::thread::spawn(move || {
+                while let Ok(request) = http.recv() {
+                    let report = health.report(&thresholds);
+                    let path = request.url().split('?').next().unwrap_or_default();
+                    let status = match (request.method().as_str(), path) {
+                        ("GET", "/livez") => if report.live { 200 } else { 503 },
+                        ("GET", "/readyz") => if report.ready { 200 } else { 503 },
+                        ("GET", "/health") => 200,
+                        _ => 404,
+                    };
+                    let response = match status {
+                        404 => Response::from_string("Not Found").with_status_code(404),
+                        _ => Response::from_string(serde_json::to_string(&report).unwrap_or_default())
+                            .with_status_code(status)
+                            .with_header(Header::from_bytes("Content-Type", "application/json").unwrap()),
+                    };
+                    if let Err(e) = request.respond(response) {
+                        error!("Error serving health: {}", e);
+                    }
+                }
+            })
+        };
+
+        Ok(HealthServer { addr, http, thread: Some(thread) })
+    }
+
+    /// Base URL of the probes, e.g. `http://127.0.0.1:8080`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+impl Drop for HealthServer {
+    fn drop(&mut self) {
+        self.http.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(frame: &str) -> GenericMessage {
+        serde_json::from_str(frame).unwrap()
+    }
+
+    #[test]
+    fn becomes_ready_once_every_subscription_is_acknowledged() {
+        let health = FeedHealth::default();
+        let thresholds = HealthThresholds::default();
+        health.set_running(true);
+        health.set_connected(true);
+        health.expect_subscriptions(&["ticker".to_string()], &["BTC-USD".to_string(), "ETH-USD".to_string()]);
+        assert!(health.report(&thresholds).live);
+        assert!(!health.report(&thresholds).ready);
+
+        health.record_message(&message(r#"{"channel":"subscriptions","client_id":"","timestamp":"","sequence_num":0,"events":[{"subscriptions":{"ticker":["BTC-USD"]}}]}"#), SystemTime::now());
+        assert_eq!(health.report(&thresholds).pending_subscriptions["ticker"], vec!["ETH-USD"]);
+
+        health.record_message(&message(r#"{"channel":"subscriptions","client_id":"","timestamp":"","sequence_num":1,"events":[{"subscriptions":{"ticker":["BTC-USD","ETH-USD"]}}]}"#), SystemTime::now());
+        assert!(health.report(&thresholds).ready);
+
+        health.set_connected(false);
+        let report = health.report(&thresholds);
+        assert!(!report.ready && report.live);
+        assert_eq!(report.reconnects, 1);
+    }
+
+    #[test]
+    fn reports_stale_products_and_dead_loops() {
+        let health = FeedHealth::default();
+        let thresholds = HealthThresholds { stale_after: Duration::from_secs(5), liveness_timeout: Duration::from_secs(30) };
+        health.expect_subscriptions(&["ticker".to_string()], &["BTC-USD".to_string(), "ETH-USD".to_string()]);
+        health.record_message(&message(r#"{"channel":"ticker","client_id":"","timestamp":"","sequence_num":0,"events":[{"type":"update","tickers":[{"type":"ticker","product_id":"ETH-USD","price":"1","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"1"}]}]}"#), SystemTime::now() - Duration::from_secs(10));
+
+        let report = health.report(&thresholds);
+        assert!(!report.live);
+        let stale: Vec<(&str, bool, bool)> = report
+            .products
+            .iter()
+            .map(|p| (p.product_id.as_str(), p.last_message_at.is_some(), p.stale))
+            .collect();
+        assert_eq!(stale, vec![("BTC-USD", false, true), ("ETH-USD", true, true)]);
+        assert!(report.products[1].age_ms.unwrap() >= 10_000);
+    }
+}
@@ -0,0 +1,32 @@
+//! Rust client for the Coinbase Advanced Trade REST and WebSocket APIs.
+
+pub mod advanced_trade_rest_client;
+pub mod advanced_trade_websocket;
+pub mod arbitrage;
+pub mod clock;
+pub mod config_builder;
+pub mod currency_graph;
+pub mod fees;
+pub mod futures;
+pub mod health;
+pub mod logging;
+pub mod metrics;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_server;
+pub mod models;
+pub mod oms;
+pub mod order_book;
+pub mod order_validation;
+#[cfg(feature = "parquet")]
+pub mod parquet_sink;
+pub mod product_filter;
+pub mod product_registry;
+pub mod recorder;
+pub mod replay;
+pub mod rest_client;
+pub mod risk;
+pub mod sig_gen;
+pub mod sink;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+pub mod websocket;
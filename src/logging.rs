@@ -0,0 +1,153 @@
+use anyhow::{anyhow, bail, Result};
+use std::env;
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// Filter used when neither `LoggingConfig.filter` nor `RUST_LOG` is set
+pub const DEFAULT_LOG_FILTER: &str = "info";
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human readable lines with the target and the fields of every enclosing span
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the event and of every enclosing span
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> Result<LogFormat> {
+        match format.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("Unknown log format {:?}, expected text or json", format),
+        }
+    }
+}
+
+/// Settings for `init_logging`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    /// `EnvFilter` directives e.g. `"info,rs_coinbase_pairs_handler::rest_client=debug"`
+    pub filter: String,
+}
+
+impl LoggingConfig {
+    /// Reads `COINBASE_LOG_FORMAT` (`text` or `json`) and `RUST_LOG`, falling back to text at `info`
+    pub fn from_env() -> Result<LoggingConfig> {
+        Ok(LoggingConfig {
+            format: match env::var("COINBASE_LOG_FORMAT") {
+                Ok(format) => format.parse()?,
+                Err(_) => LogFormat::default(),
+            },
+            filter: env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string()),
+        })
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            format: LogFormat::default(),
+            filter: DEFAULT_LOG_FILTER.to_string(),
+        }
+    }
+}
+
+/// Installs the global subscriber that writes every `tracing` event and span to stdout
+///
+/// Records of the `log` macros are forwarded too, so they carry the fields of the spans the
+/// crate opens per connection, message, subscription and REST request. Can only be called once
+/// per process.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::logging::{init_logging, LogFormat, LoggingConfig};
+///
+/// init_logging(&LoggingConfig { format: LogFormat::Json, ..LoggingConfig::default() }).unwrap();
+/// ```
+pub fn init_logging(config: &LoggingConfig) -> Result<()> {
+    subscriber(config, std::io::stdout)?
+        .try_init()
+        .map_err(|e| anyhow!("Unable to install the log subscriber: {}", e))
+}
+
+/// Builds the subscriber `init_logging` installs, writing to `writer`
+fn subscriber<W>(config: &LoggingConfig, writer: W) -> Result<Box<dyn Subscriber + Send + Sync>>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let registry = tracing_subscriber::registry().with(EnvFilter::try_new(&config.filter)?);
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer).with_target(true);
+    Ok(match config.format {
+        LogFormat::Text => Box::new(registry.with(layer)),
+        LogFormat::Json => Box::new(registry.with(layer.json().flatten_event(true).with_current_span(false))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+    use crate::config_builder::CoinbaseConfig;
+    use crate::mock_server::{MockCoinbase, MockConfig};
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_json_lines_with_the_fields_of_the_rest_request_span() {
+        let mock = MockCoinbase::start(MockConfig::new("key", "secret")).unwrap();
+        let client = AdvancedTradeRESTClient::from_config(&CoinbaseConfig {
+            rest_url: mock.rest_url(),
+            ..CoinbaseConfig::with_credentials("key", "secret")
+        }).unwrap();
+        let buffer = Buffer::default();
+        let config = LoggingConfig { format: LogFormat::Json, filter: "debug".to_string() };
+        let writer = buffer.clone();
+        let subscriber = subscriber(&config, move || writer.clone()).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(client.get_available_products()).unwrap();
+            runtime.block_on(client.get_product("ETH-BTC")).unwrap();
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|line| line["message"] == "REST request completed")
+            .collect();
+        assert_eq!(lines.len(), 2, "{}", output);
+        let line = &lines[0];
+        assert_eq!(line["target"], "rs_coinbase_pairs_handler::rest_client");
+        assert_eq!(line["spans"][0]["name"], "rest_request");
+        assert_eq!(line["spans"][0]["path"], "/brokerage/products/");
+        assert_eq!(line["spans"][0]["status"], 200);
+        // the path is the one requested, the route the template it was made from
+        assert_eq!(lines[1]["spans"][0]["path"], "/brokerage/products/ETH-BTC");
+        assert_eq!(lines[1]["spans"][0]["route"], "/brokerage/products/{product_id}");
+    }
+}
@@ -1,18 +1,12 @@
 use log::{info};
-
-mod advanced_trade_rest_client;
-mod advanced_trade_websocket;
-mod config_builder;
-mod models;
-mod rest_client;
-mod sig_gen;
-mod websocket;
+use rs_coinbase_pairs_handler::advanced_trade_websocket;
+use rs_coinbase_pairs_handler::logging::{init_logging, LoggingConfig};
 
 #[tokio::main]
 async fn main() {
-    // establish logging
-    log4rs::init_file("logconfig.yml", Default::default())
-        .expect("Log config file not found.");
+    // establish logging, configured through COINBASE_LOG_FORMAT and RUST_LOG
+    let logging = LoggingConfig::from_env().expect("Invalid logging config.");
+    init_logging(&logging).expect("Unable to set up logging.");
     info!("We now have nice logging!");
 
     // api testing
@@ -25,6 +19,6 @@ async fn main() {
         vec!["ticker".to_string()],
         advanced_trade_websocket::SubscribeProducts::Custom(vec![String::from("ETH-USD")]),
     );
-    
+
     coinbase_advanced_trade.run().await.unwrap();
 }
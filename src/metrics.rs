@@ -0,0 +1,289 @@
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+
+/// Upper bounds in seconds of the latency histogram buckets
+pub const LATENCY_BUCKETS: [f64; 12] = [0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Cumulative histogram over `LATENCY_BUCKETS`
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, bucket);
+        }
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsState {
+    /// Keyed by `(channel, product_id)`
+    messages: BTreeMap<(String, String), u64>,
+    parse_failures: u64,
+    reconnects: u64,
+    sequence_gaps: u64,
+    missed_messages: u64,
+    /// Server clock minus local clock, in seconds
+    clock_offset: Option<f64>,
+    /// Keyed by channel
+    message_latency: BTreeMap<String, Histogram>,
+    /// Keyed by `(method, route, status)`
+    rest_requests: BTreeMap<(String, String, String), u64>,
+    /// Keyed by `(method, route)`
+    rest_latency: BTreeMap<(String, String), Histogram>,
+}
+
+/// Escapes a label value for the Prometheus text format
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Counters and latency histograms of the feed and REST client
+///
+/// Set `CoinbaseConfig.metrics` to the same handle for the REST client and the feed, and
+/// serve it with `MetricsServer`. Clones share state.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    state: Arc<Mutex<MetricsState>>,
+}
+
+impl Metrics {
+    /// Counts a parsed channel message, once per product it refers to
+    pub fn record_message(&self, channel: &str, product_ids: &[&str]) {
+        let mut state = self.state.lock().unwrap();
+        if product_ids.is_empty() {
+            *state.messages.entry((channel.to_string(), String::new())).or_default() += 1;
+        }
+        for product_id in product_ids {
+            *state.messages.entry((channel.to_string(), product_id.to_string())).or_default() += 1;
+        }
+    }
+
+    /// Counts a frame that could not be parsed into a known message
+    pub fn record_parse_failure(&self) {
+        self.state.lock().unwrap().parse_failures += 1;
+    }
+
+    pub fn record_reconnect(&self) {
+        self.state.lock().unwrap().reconnects += 1;
+    }
+
+    /// Counts a jump in `sequence_num` that skipped `missed` messages
+    pub fn record_sequence_gap(&self, missed: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.sequence_gaps += 1;
+        state.missed_messages += missed;
+    }
+
+    /// Records the last measured offset of the server clock to the local clock
+    pub fn record_clock_offset(&self, offset: chrono::TimeDelta) {
+        self.state.lock().unwrap().clock_offset = Some(offset.num_milliseconds() as f64 / 1000.0);
+    }
+
+    /// Records the time between the exchange `timestamp` of a message and its receipt, in server time
+    pub fn record_message_latency(&self, channel: &str, latency: Duration) {
+        self.state
+            .lock()
+            .unwrap()
+            .message_latency
+            .entry(channel.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Records a REST request, with `status` `None` when no response was received
+    ///
+    /// `route` is the path template of the request such as `/brokerage/products/{product_id}`, never
+    /// the concrete path, so each endpoint is a single series whatever ids it is called with
+    pub fn record_rest_request(&self, method: &str, route: &str, status: Option<u16>, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let status = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+        *state.rest_requests.entry((method.to_string(), route.to_string(), status)).or_default() += 1;
+        state
+            .rest_latency
+            .entry((method.to_string(), route.to_string()))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders every metric in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        header(&mut out, "coinbase_ws_messages_total", "counter", "Channel messages received, per product");
+        for ((channel, product_id), count) in &state.messages {
+            let _ = writeln!(out, "coinbase_ws_messages_total{{channel=\"{}\",product_id=\"{}\"}} {}", label(channel), label(product_id), count);
+        }
+        header(&mut out, "coinbase_ws_parse_failures_total", "counter", "Frames that did not parse into a known message");
+        let _ = writeln!(out, "coinbase_ws_parse_failures_total {}", state.parse_failures);
+        header(&mut out, "coinbase_ws_reconnects_total", "counter", "WebSocket reconnections");
+        let _ = writeln!(out, "coinbase_ws_reconnects_total {}", state.reconnects);
+        header(&mut out, "coinbase_ws_sequence_gaps_total", "counter", "Jumps in sequence_num");
+        let _ = writeln!(out, "coinbase_ws_sequence_gaps_total {}", state.sequence_gaps);
+        header(&mut out, "coinbase_ws_missed_messages_total", "counter", "Messages skipped by sequence_num jumps");
+        let _ = writeln!(out, "coinbase_ws_missed_messages_total {}", state.missed_messages);
+
+        if let Some(offset) = state.clock_offset {
+            header(&mut out, "coinbase_clock_offset_seconds", "gauge", "Server clock minus local clock");
+            let _ = writeln!(out, "coinbase_clock_offset_seconds {}", offset);
+        }
+
+        header(&mut out, "coinbase_ws_message_latency_seconds", "histogram", "Time from the exchange timestamp to receipt");
+        for (channel, histogram) in &state.message_latency {
+            histogram.render(&mut out, "coinbase_ws_message_latency_seconds", &format!("channel=\"{}\"", label(channel)));
+        }
+
+        header(&mut out, "coinbase_rest_requests_total", "counter", "REST requests by response status");
+        for ((method, endpoint, status), count) in &state.rest_requests {
+            let _ = writeln!(
+                out,
+                "coinbase_rest_requests_total{{method=\"{}\",endpoint=\"{}\",status=\"{}\"}} {}",
+                label(method), label(endpoint), label(status), count
+            );
+        }
+        header(&mut out, "coinbase_rest_request_duration_seconds", "histogram", "REST request latency");
+        for ((method, endpoint), histogram) in &state.rest_latency {
+            histogram.render(
+                &mut out,
+                "coinbase_rest_request_duration_seconds",
+                &format!("method=\"{}\",endpoint=\"{}\"", label(method), label(endpoint)),
+            );
+        }
+
+        out
+    }
+}
+
+/// HTTP server exposing `Metrics` on `GET /metrics`
+///
+/// Serves on a background thread until dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::config_builder::CoinbaseConfig;
+/// use rs_coinbase_pairs_handler::metrics::{Metrics, MetricsServer};
+///
+/// let metrics = Metrics::default();
+/// let config = CoinbaseConfig { metrics: metrics.clone(), ..CoinbaseConfig::new() };
+/// let server = MetricsServer::start("0.0.0.0:9184", metrics).unwrap();
+/// println!("Scrape {}", server.url());
+/// ```
+pub struct MetricsServer {
+    addr: SocketAddr,
+    http: Arc<Server>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Binds `addr`, e.g. `"0.0.0.0:9184"` or `"127.0.0.1:0"` for an ephemeral port
+    pub fn start(addr: &str, metrics: Metrics) -> Result<MetricsServer> {
+        let http = Arc::new(Server::http(addr).map_err(|e| anyhow!(e))?);
+        let addr = http
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("Metrics server is not bound to an IP address"))?;
+        info!("Serving metrics on http://{}/metrics", addr);
+
+        let thread = {
+            let http = http.clone();
+            std::thread::spawn(move || {
+                while let Ok(request) = http.recv() {
+                    let path = request.url().split('?').next().unwrap_or_default();
+                    let response = match (request.method().as_str(), path) {
+                        ("GET", "/metrics") => Response::from_string(metrics.render()).with_header(
+                            Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                        ),
+                        _ => Response::from_string("Not Found").with_status_code(404),
+                    };
+                    if let Err(e) = request.respond(response) {
+                        error!("Error serving metrics: {}", e);
+                    }
+                }
+            })
+        };
+
+        Ok(MetricsServer { addr, http, thread: Some(thread) })
+    }
+
+    /// URL of the metrics page
+    pub fn url(&self) -> String {
+        format!("http://{}/metrics", self.addr)
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.http.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_prometheus_text_format() {
+        let metrics = Metrics::default();
+        metrics.record_message("ticker", &["ETH-USD", "BTC-USD"]);
+        metrics.record_message("ticker", &["ETH-USD"]);
+        metrics.record_message("heartbeats", &[]);
+        metrics.record_sequence_gap(3);
+        metrics.record_clock_offset(chrono::TimeDelta::milliseconds(-1500));
+        metrics.record_message_latency("ticker", Duration::from_millis(20));
+        metrics.record_rest_request("GET", "/brokerage/products/", Some(200), Duration::from_millis(150));
+        metrics.record_rest_request("GET", "/brokerage/products/", None, Duration::from_secs(10));
+
+        let text = metrics.render();
+        for line in [
+            "coinbase_ws_messages_total{channel=\"ticker\",product_id=\"ETH-USD\"} 2",
+            "coinbase_ws_messages_total{channel=\"heartbeats\",product_id=\"\"} 1",
+            "coinbase_ws_sequence_gaps_total 1",
+            "coinbase_ws_missed_messages_total 3",
+            "coinbase_clock_offset_seconds -1.5",
+            "coinbase_ws_message_latency_seconds_bucket{channel=\"ticker\",le=\"0.01\"} 0",
+            "coinbase_ws_message_latency_seconds_bucket{channel=\"ticker\",le=\"0.025\"} 1",
+            "coinbase_ws_message_latency_seconds_count{channel=\"ticker\"} 1",
+            "coinbase_rest_requests_total{method=\"GET\",endpoint=\"/brokerage/products/\",status=\"200\"} 1",
+            "coinbase_rest_requests_total{method=\"GET\",endpoint=\"/brokerage/products/\",status=\"error\"} 1",
+            "coinbase_rest_request_duration_seconds_bucket{method=\"GET\",endpoint=\"/brokerage/products/\",le=\"+Inf\"} 2",
+        ] {
+            assert!(text.contains(line), "missing {} in\n{}", line, text);
+        }
+    }
+}
@@ -0,0 +1,1320 @@
+use crate::{clock::MAX_CLOCK_SKEW, models::ChannelSubscriptionMessage, sig_gen};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use log::{debug, error};
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tiny_http::{Header, Request, Response, Server};
+use tungstenite::Message;
+
+/// Path prefix the mock serves the REST API under, mirroring `https://api.coinbase.com/api/v3`
+pub const MOCK_API_PREFIX: &str = "/api/v3";
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Number of orders per page of `GET /brokerage/orders/historical/batch`
+pub const MOCK_PAGE_SIZE: usize = 100;
+
+/// `uuid` of the portfolio the default accounts and orders belong to
+pub const MOCK_DEFAULT_PORTFOLIO: &str = "default-portfolio";
+
+/// USD prices the mock values portfolio breakdowns at
+const MOCK_USD_PRICES: [(&str, f64); 3] = [("USD", 1.0), ("BTC", 25000.0), ("ETH", 1675.14)];
+
+/// Currencies the mock converts between, one for one and without fees
+const MOCK_CONVERT_CURRENCIES: [&str; 2] = ["USD", "USDC"];
+
+/*
+MOCK SERVER - Local stand-in for Coinbase Advanced Trade
+
+Serves the REST endpoints from fixtures and plays a scripted WebSocket stream so the
+clients in this crate can be exercised without network access or real credentials.
+Every REST request and subscribe message is checked against the HMAC signatures from
+`sig_gen`, and its timestamp against the mock clock, exactly as Coinbase would.
+*/
+
+/// JSON bodies returned by the mock REST endpoints
+#[derive(Debug, Clone)]
+pub struct MockFixtures {
+    /// Body of `GET /brokerage/products`
+    pub products: Value,
+    /// Body of `GET /brokerage/accounts`
+    pub accounts: Value,
+    /// Body of `GET /brokerage/orders/historical/batch`
+    pub orders: Value,
+    /// Body of `GET /brokerage/orders/historical/fills`
+    pub fills: Value,
+    /// Body of `POST /brokerage/orders/preview`
+    pub preview: Value,
+    /// Body of `GET /brokerage/transaction_summary`
+    pub transaction_summary: Value,
+    /// Body of `GET /brokerage/portfolios`
+    pub portfolios: Value,
+    /// Convert trades quoted so far, under `"trades"`
+    pub convert_trades: Value,
+    /// Body of `GET /brokerage/cfm/balance_summary`
+    pub futures_balance_summary: Value,
+    /// Book of each product, `{"BTC-USD": {"bids": [...], "asks": [...]}}`, best levels first
+    pub product_books: Value,
+}
+
+impl Default for MockFixtures {
+    fn default() -> Self {
+        MockFixtures {
+            products: json!({
+                "num_products": 3,
+                "products": [
+                    mock_product("BTC-USD", "BTC", "USD", "0.00000001", "0.01", "25301.71283129"),
+                    mock_product("ETH-USD", "ETH", "USD", "0.00000001", "0.01", "185976.72526638"),
+                    mock_product("ETH-BTC", "ETH", "BTC", "0.00000001", "0.00001", "4507.1152"),
+                ],
+            }),
+            accounts: json!({
+                "accounts": [
+                    mock_account("USD", "10000.00"),
+                    mock_account("BTC", "1.5"),
+                    mock_account("ETH", "20"),
+                ],
+                "has_next": false,
+                "cursor": "",
+                "size": 3,
+            }),
+            orders: json!({
+                "orders": [{
+                    "order_id": "0000-000000-000000",
+                    "product_id": "ETH-USD",
+                    "client_order_id": "11111-000000-000000",
+                    "side": "BUY",
+                    "status": "FILLED",
+                    "order_type": "MARKET",
+                    "time_in_force": "IMMEDIATE_OR_CANCEL",
+                    "created_time": "2023-02-08T06:10:00Z",
+                    "filled_size": "0.5",
+                    "average_filled_price": "1675.14",
+                    "total_fees": "5.02542",
+                    "order_configuration": { "market_market_ioc": { "base_size": "0.5" } },
+                }],
+                "sequence": "0",
+                "has_next": false,
+                "cursor": "",
+            }),
+            fills: json!({
+                "fills": [{
+                    "entry_id": "22222-2222222-22222222",
+                    "trade_id": "1111-11111-111111",
+                    "order_id": "0000-000000-000000",
+                    "trade_time": "2023-02-08T06:10:00.5Z",
+                    "trade_type": "FILL",
+                    "price": "1675.14",
+                    "size": "0.5",
+                    "commission": "5.02542",
+                    "product_id": "ETH-USD",
+                    "sequence_timestamp": "2023-02-08T06:10:00.5Z",
+                    "liquidity_indicator": "TAKER",
+                    "size_in_quote": false,
+                    "user_id": "3333-333333-3333333",
+                    "side": "BUY",
+                }],
+                "cursor": "",
+            }),
+            preview: json!({
+                "preview_id": "44444-444444-444444",
+                "order_total": "842.59542",
+                "commission_total": "5.02542",
+                "errs": [],
+                "warning": [],
+                "quote_size": "837.57",
+                "base_size": "0.5",
+                "best_bid": "1675.13",
+                "best_ask": "1675.14",
+                "slippage": "0",
+            }),
+            portfolios: json!({
+                "portfolios": [{
+                    "name": "Default",
+                    "uuid": MOCK_DEFAULT_PORTFOLIO,
+                    "type": "DEFAULT",
+                    "deleted": false,
+                }],
+            }),
+            transaction_summary: json!({
+                "total_volume": 24000.5,
+                "total_fees": 96.0,
+                "fee_tier": {
+                    "pricing_tier": "$10K-$50K",
+                    "usd_from": "10000",
+                    "usd_to": "50000",
+                    "taker_fee_rate": "0.004",
+                    "maker_fee_rate": "0.0025",
+                },
+                "advanced_trade_only_volume": 24000.5,
+                "advanced_trade_only_fees": 96.0,
+            }),
+            convert_trades: json!({ "trades": [] }),
+            product_books: json!({
+                "BTC-USD": mock_book(&[("24999.5", "0.8"), ("24999", "1.2"), ("24990", "3")], &[("25000.5", "0.4"), ("25001", "2")]),
+                "ETH-USD": mock_book(&[("1675.13", "12"), ("1675", "30")], &[("1675.14", "8"), ("1675.5", "20")]),
+                "ETH-BTC": mock_book(&[("0.06699", "40")], &[("0.06701", "25")]),
+            }),
+            futures_balance_summary: json!({
+                "balance_summary": {
+                    "futures_buying_power": mock_usd("5000"),
+                    "total_usd_balance": mock_usd("12000"),
+                    "cbi_usd_balance": mock_usd("10000"),
+                    "cfm_usd_balance": mock_usd("2000"),
+                    "total_open_orders_hold_amount": mock_usd("0"),
+                    "unrealized_pnl": mock_usd("-35.5"),
+                    "daily_realized_pnl": mock_usd("12.25"),
+                    "initial_margin": mock_usd("700"),
+                    "available_margin": mock_usd("1300"),
+                    "liquidation_threshold": mock_usd("350"),
+                    "liquidation_buffer_amount": mock_usd("1650"),
+                    "liquidation_buffer_percentage": "471",
+                },
+            }),
+        }
+    }
+}
+
+fn mock_product(product_id: &str, base: &str, quote: &str, base_increment: &str, quote_increment: &str, volume_24h: &str) -> Value {
+    json!({
+        "product_id": product_id,
+        "product_type": "SPOT",
+        "base_currency_id": base,
+        "base_increment": base_increment,
+        "base_max_size": "3400",
+        "base_min_size": "0.00000001",
+        "quote_currency_id": quote,
+        "quote_increment": quote_increment,
+        "quote_max_size": "50000000",
+        "quote_min_size": "1",
+        "price_increment": quote_increment,
+        "status": "online",
+        "trading_disabled": false,
+        "volume_24h": volume_24h,
+    })
+}
+
+fn mock_book(bids: &[(&str, &str)], asks: &[(&str, &str)]) -> Value {
+    let levels = |levels: &[(&str, &str)]| -> Vec<Value> {
+        levels.iter().map(|(price, size)| json!({ "price": price, "size": size })).collect()
+    };
+    json!({ "bids": levels(bids), "asks": levels(asks) })
+}
+
+fn mock_usd(value: &str) -> Value {
+    json!({ "value": value, "currency": "USD" })
+}
+
+fn mock_account(currency: &str, available: &str) -> Value {
+    mock_portfolio_account(currency, available, MOCK_DEFAULT_PORTFOLIO)
+}
+
+fn mock_portfolio_account(currency: &str, available: &str, portfolio: &str) -> Value {
+    json!({
+        "uuid": match portfolio {
+            MOCK_DEFAULT_PORTFOLIO => format!("{}-account", currency.to_lowercase()),
+            portfolio => format!("{}-{}-account", portfolio, currency.to_lowercase()),
+        },
+        "name": format!("{} Wallet", currency),
+        "currency": currency,
+        "available_balance": { "value": available, "currency": currency },
+        "default": true,
+        "active": true,
+        "type": "ACCOUNT_TYPE_CRYPTO",
+        "ready": true,
+        "hold": { "value": "0", "currency": currency },
+        "retail_portfolio_id": portfolio,
+    })
+}
+
+/// One step of the scripted WebSocket stream
+///
+/// The script starts once the first subscribe message on a connection has been accepted and
+/// is shared between connections: after a `Disconnect` the next connection resumes where the
+/// previous one stopped.
+#[derive(Debug, Clone)]
+pub enum ScriptStep {
+    /// Sends a channel message. The mock fills in `client_id`, `timestamp` and `sequence_num`.
+    Message { channel: String, events: Value },
+    /// Skips the given number of sequence numbers to simulate dropped messages
+    Gap(u64),
+    /// Sends the text frame as-is
+    Raw(String),
+    /// Waits before playing the next step
+    Pause(Duration),
+    /// Drops the TCP connection without a close handshake
+    Disconnect,
+}
+
+impl ScriptStep {
+    /// Builds a `ticker` channel message with a single ticker
+    ///
+    /// # Arguments
+    /// * `msg_type`: Event type, `"snapshot"` or `"update"`
+    /// * `product_id`: Product the ticker belongs to e.g. `"ETH-USD"`
+    /// * `price`: Last traded price
+    pub fn ticker(msg_type: &str, product_id: &str, price: &str) -> ScriptStep {
+        ScriptStep::Message {
+            channel: "ticker".to_string(),
+            events: json!([{
+                "type": msg_type,
+                "tickers": [{
+                    "type": "ticker",
+                    "product_id": product_id,
+                    "price": price,
+                    "volume_24_h": "185976.72526638",
+                    "low_24_h": "1624.53",
+                    "high_24_h": "1699.66",
+                    "low_52_w": "879.8",
+                    "high_52_w": "3581.6",
+                    "price_percent_chg_24_h": "2.48094171775388",
+                }],
+            }]),
+        }
+    }
+
+    /// Builds a `user` channel message with a single order
+    ///
+    /// # Arguments
+    /// * `msg_type`: Event type, `"snapshot"` or `"update"`
+    /// * `order`: Fields of the order, e.g. `order_id`, `client_order_id`, `status` and
+    ///   `cumulative_quantity`. Missing fields are filled with those of a `BUY` of `ETH-USD`.
+    pub fn user(msg_type: &str, order: Value) -> ScriptStep {
+        let mut fields = json!({
+            "order_id": "",
+            "client_order_id": "",
+            "cumulative_quantity": "0",
+            "leaves_quantity": "0",
+            "avg_price": "0",
+            "total_fees": "0",
+            "status": "OPEN",
+            "product_id": "ETH-USD",
+            "creation_time": Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+            "order_side": "BUY",
+            "order_type": "Limit",
+        });
+        if let (Some(fields), Some(order)) = (fields.as_object_mut(), order.as_object()) {
+            fields.extend(order.clone());
+        }
+
+        ScriptStep::Message {
+            channel: "user".to_string(),
+            events: json!([{ "type": msg_type, "orders": [fields] }]),
+        }
+    }
+
+    /// Builds a `futures_balance_summary` channel message
+    ///
+    /// # Arguments
+    /// * `msg_type`: Event type, `"snapshot"` or `"update"`
+    /// * `summary`: Fields of the summary e.g. `available_margin`. Missing fields are `"0"`.
+    pub fn futures_balance(msg_type: &str, summary: Value) -> ScriptStep {
+        let mut fields = json!({
+            "futures_buying_power": "0",
+            "total_usd_balance": "0",
+            "cbi_usd_balance": "0",
+            "cfm_usd_balance": "0",
+            "total_open_orders_hold_amount": "0",
+            "unrealized_pnl": "0",
+            "daily_realized_pnl": "0",
+            "initial_margin": "0",
+            "available_margin": "0",
+            "liquidation_threshold": "0",
+            "liquidation_buffer_amount": "0",
+            "liquidation_buffer_percentage": "0",
+        });
+        if let (Some(fields), Some(summary)) = (fields.as_object_mut(), summary.as_object()) {
+            fields.extend(summary.clone());
+        }
+
+        ScriptStep::Message {
+            channel: "futures_balance_summary".to_string(),
+            events: json!([{ "type": msg_type, "fcm_balance_summary": fields }]),
+        }
+    }
+
+    /// Builds a `l2_data` channel message
+    ///
+    /// # Arguments
+    /// * `msg_type`: Event type, `"snapshot"` or `"update"`
+    /// * `product_id`: Product the book belongs to e.g. `"ETH-USD"`
+    /// * `updates`: Price levels as `(side, price_level, new_quantity)` where side is `"bid"` or `"offer"`
+    pub fn level2(msg_type: &str, product_id: &str, updates: &[(&str, &str, &str)]) -> ScriptStep {
+        let event_time = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        let updates: Vec<Value> = updates
+            .iter()
+            .map(|(side, price_level, new_quantity)| json!({
+                "side": side,
+                "event_time": event_time,
+                "price_level": price_level,
+                "new_quantity": new_quantity,
+            }))
+            .collect();
+
+        ScriptStep::Message {
+            channel: "l2_data".to_string(),
+            events: json!([{
+                "type": msg_type,
+                "product_id": product_id,
+                "updates": updates,
+            }]),
+        }
+    }
+}
+
+/// Startup configuration for `MockCoinbase`
+#[derive(Debug, Clone)]
+pub struct MockConfig {
+    /// API key the mock accepts in `CB-ACCESS-KEY` and subscribe messages
+    pub api_key: String,
+    /// Secret the mock verifies HMAC signatures with
+    pub api_secret: String,
+    pub fixtures: MockFixtures,
+    pub script: Vec<ScriptStep>,
+    /// How far the mock clock is ahead of the local clock, to simulate clock skew
+    pub clock_offset: TimeDelta,
+}
+
+impl MockConfig {
+    pub fn new(api_key: &str, api_secret: &str) -> Self {
+        MockConfig {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            fixtures: MockFixtures::default(),
+            script: Vec::new(),
+            clock_offset: TimeDelta::zero(),
+        }
+    }
+}
+
+/// A REST request as seen by the mock
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path including any query string e.g. `"/api/v3/brokerage/products?limit=1"`
+    pub url: String,
+    pub body: String,
+    /// Whether the key, signature and timestamp headers were valid
+    pub authorized: bool,
+}
+
+#[derive(Default)]
+struct MockState {
+    requests: Vec<RecordedRequest>,
+    subscriptions: Vec<ChannelSubscriptionMessage>,
+    rejected_subscriptions: usize,
+    connections: usize,
+    script: VecDeque<ScriptStep>,
+    fixtures: MockFixtures,
+    /// Remaining requests per route to answer with 503
+    failures: BTreeMap<String, usize>,
+}
+
+struct Shared {
+    config: MockConfig,
+    state: Mutex<MockState>,
+    shutdown: AtomicBool,
+}
+
+/// Local HTTP and WebSocket server that imitates the Coinbase Advanced Trade APIs
+///
+/// Both servers bind to an ephemeral port on `127.0.0.1` and are shut down when the value is
+/// dropped.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::mock_server::{MockCoinbase, MockConfig, ScriptStep};
+///
+/// let mut config = MockConfig::new("key", "secret");
+/// config.script = vec![ScriptStep::ticker("snapshot", "ETH-USD", "1675.14")];
+/// let mock = MockCoinbase::start(config).unwrap();
+/// println!("REST at {} and WebSocket at {}", mock.rest_url(), mock.websocket_url());
+/// ```
+pub struct MockCoinbase {
+    rest_addr: SocketAddr,
+    ws_addr: SocketAddr,
+    http: Arc<Server>,
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl MockCoinbase {
+    /// Binds the REST and WebSocket listeners and starts serving on background threads
+    pub fn start(config: MockConfig) -> Result<MockCoinbase> {
+        let http = Arc::new(Server::http("127.0.0.1:0").map_err(|e| anyhow!(e))?);
+        let rest_addr = http
+            .server_addr()
+            .to_ip()
+            .ok_or_else(|| anyhow!("Mock REST server is not bound to an IP address"))?;
+
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        listener.set_nonblocking(true)?;
+        let ws_addr = listener.local_addr()?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(MockState {
+                script: config.script.iter().cloned().collect(),
+                fixtures: config.fixtures.clone(),
+                ..Default::default()
+            }),
+            config,
+            shutdown: AtomicBool::new(false),
+        });
+
+        let rest_thread = {
+            let http = http.clone();
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                while let Ok(request) = http.recv() {
+                    if let Err(e) = serve_rest(request, &shared) {
+                        error!("[mock] Error serving REST request: {}", e);
+                    }
+                }
+            })
+        };
+
+        let ws_thread = {
+            let shared = shared.clone();
+            std::thread::spawn(move || accept_websockets(listener, shared))
+        };
+
+        Ok(MockCoinbase {
+            rest_addr,
+            ws_addr,
+            http,
+            shared,
+            threads: vec![rest_thread, ws_thread],
+        })
+    }
+
+    /// Base URL of the REST API, the local equivalent of `https://api.coinbase.com/api/v3`
+    pub fn rest_url(&self) -> String {
+        format!("http://{}{}", self.rest_addr, MOCK_API_PREFIX)
+    }
+
+    /// URL of the WebSocket feed, the local equivalent of `wss://advanced-trade-ws.coinbase.com`
+    pub fn websocket_url(&self) -> String {
+        format!("ws://{}", self.ws_addr)
+    }
+
+    /// All REST requests received so far
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.shared.state.lock().unwrap().requests.clone()
+    }
+
+    /// All subscribe and unsubscribe messages that passed the signature check, in order
+    pub fn subscriptions(&self) -> Vec<ChannelSubscriptionMessage> {
+        self.shared.state.lock().unwrap().subscriptions.clone()
+    }
+
+    /// Number of subscribe messages rejected for a bad key, signature or timestamp
+    pub fn rejected_subscriptions(&self) -> usize {
+        self.shared.state.lock().unwrap().rejected_subscriptions
+    }
+
+    /// Number of WebSocket connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.shared.state.lock().unwrap().connections
+    }
+
+    /// Changes the fixtures served by the REST endpoints from the next request on
+    pub fn update_fixtures(&self, update: impl FnOnce(&mut MockFixtures)) {
+        update(&mut self.shared.state.lock().unwrap().fixtures);
+    }
+
+    /// Answers the next `times` requests to `route` e.g. `"/brokerage/orders"` with 503
+    ///
+    /// The requests are still applied to the fixtures, like a response lost on the way back.
+    pub fn fail_next(&self, route: &str, times: usize) {
+        self.shared.state.lock().unwrap().failures.insert(route.to_string(), times);
+    }
+
+    /// Appends steps to the end of the WebSocket script
+    pub fn push_script(&self, steps: Vec<ScriptStep>) {
+        self.shared.state.lock().unwrap().script.extend(steps);
+    }
+}
+
+impl Drop for MockCoinbase {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        self.http.unblock();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.config.clock_offset
+    }
+
+    /// Whether a `CB-ACCESS-TIMESTAMP` or subscribe `timestamp` is within `MAX_CLOCK_SKEW` of the mock clock
+    fn accepts_timestamp(&self, timestamp: &str) -> bool {
+        let max_skew = MAX_CLOCK_SKEW.as_secs() as i64;
+        timestamp
+            .parse::<i64>()
+            .is_ok_and(|ts| (ts - self.now().timestamp()).abs() <= max_skew)
+    }
+}
+
+fn json_header() -> Header {
+    Header::from_bytes("Content-Type", "application/json").unwrap()
+}
+
+fn header_value<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn serve_rest(mut request: Request, shared: &Shared) -> Result<()> {
+    let method = request.method().as_str().to_string();
+    let url = request.url().to_string();
+    let path = url.split('?').next().unwrap_or_default().to_string();
+    let mut body = String::new();
+    request.as_reader().read_to_string(&mut body)?;
+
+    let authorized = match (
+        header_value(&request, "CB-ACCESS-KEY"),
+        header_value(&request, "CB-ACCESS-SIGN"),
+        header_value(&request, "CB-ACCESS-TIMESTAMP"),
+    ) {
+        (Some(key), Some(sign), Some(ts)) => {
+            // signed over the path with its query string and the body as received
+            let expected = sig_gen::create_rest_signature(
+                ts,
+                &method,
+                &url,
+                &body,
+                shared.config.api_secret.as_bytes(),
+            );
+            key == shared.config.api_key && sign == expected && shared.accepts_timestamp(ts)
+        },
+        _ => false,
+    };
+    debug!("[mock] {} {} (authorized: {})", method, url, authorized);
+
+    let query = url.split_once('?').map(|(_, query)| query.to_string()).unwrap_or_default();
+    shared.state.lock().unwrap().requests.push(RecordedRequest {
+        method: method.clone(),
+        url,
+        body: body.clone(),
+        authorized,
+    });
+
+    let route = path.strip_prefix(MOCK_API_PREFIX).unwrap_or(&path).trim_end_matches('/');
+    // the server time is public so clients can correct their clock before signing
+    if !authorized && route != "/brokerage/time" {
+        request.respond(Response::from_string("Unauthorized").with_status_code(401))?;
+        return Ok(());
+    }
+
+    let (response, fail) = {
+        let state = &mut *shared.state.lock().unwrap();
+        let fail = match state.failures.get_mut(route) {
+            Some(remaining) if *remaining > 0 => {
+                *remaining -= 1;
+                true
+            },
+            _ => false,
+        };
+        let fixtures = &mut state.fixtures;
+        let response = match (method.as_str(), route) {
+            ("GET", "/brokerage/time") => {
+                let now = shared.now();
+                Some(json!({
+                    "iso": now.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "epochSeconds": now.timestamp().to_string(),
+                    "epochMillis": now.timestamp_millis().to_string(),
+                }))
+            },
+            ("GET", "/brokerage/products") => Some(products_page(&fixtures.products, &query)),
+            ("GET", route) if route.starts_with("/brokerage/products/") => {
+                let product_id = percent_decode_str(&route["/brokerage/products/".len()..]).decode_utf8_lossy();
+                product(&fixtures.products, &product_id)
+            },
+            ("GET", "/brokerage/accounts") => Some(accounts_page(&fixtures.accounts, &query)),
+            ("GET", "/brokerage/transaction_summary") => Some(fixtures.transaction_summary.clone()),
+            ("GET", "/brokerage/best_bid_ask") => Some(best_bid_ask(&fixtures.product_books, &query, shared.now())),
+            ("GET", "/brokerage/product_book") => Some(product_book(&fixtures.product_books, &query, shared.now())),
+            ("GET", "/brokerage/cfm/balance_summary") => Some(fixtures.futures_balance_summary.clone()),
+            ("GET", "/brokerage/orders/historical/batch") => Some(orders_page(&fixtures.orders, &query)),
+            ("GET", "/brokerage/orders/historical/fills") => Some(fixtures.fills.clone()),
+            ("GET", "/brokerage/portfolios") => Some(list_portfolios(&fixtures.portfolios, &query)),
+            ("POST", "/brokerage/portfolios") => Some(create_portfolio(&mut fixtures.portfolios, &body)),
+            ("POST", "/brokerage/portfolios/move_funds") => Some(move_funds(fixtures, &body)),
+            ("GET", route) if route.starts_with("/brokerage/portfolios/") => {
+                portfolio_breakdown(fixtures, &route["/brokerage/portfolios/".len()..])
+            },
+            ("PUT", route) if route.starts_with("/brokerage/portfolios/") => {
+                edit_portfolio(&mut fixtures.portfolios, &route["/brokerage/portfolios/".len()..], &body)
+            },
+            ("DELETE", route) if route.starts_with("/brokerage/portfolios/") => {
+                delete_portfolio(fixtures, &route["/brokerage/portfolios/".len()..])
+            },
+            ("POST", "/brokerage/convert/quote") => Some(convert_quote(fixtures, &body)),
+            ("POST", route) if route.starts_with("/brokerage/convert/trade/") => {
+                commit_convert_trade(fixtures, &route["/brokerage/convert/trade/".len()..])
+            },
+            ("GET", route) if route.starts_with("/brokerage/convert/trade/") => {
+                get_convert_trade(fixtures, &route["/brokerage/convert/trade/".len()..])
+            },
+            ("POST", "/brokerage/orders") => Some(create_order(fixtures, &body, shared.now())),
+            ("POST", "/brokerage/orders/preview") => Some(fixtures.preview.clone()),
+            ("POST", "/brokerage/orders/batch_cancel") => Some(batch_cancel(&mut fixtures.orders, &body)),
+            ("POST", "/brokerage/orders/edit") => Some(edit_order(&mut fixtures.orders, &body, false)),
+            ("POST", "/brokerage/orders/edit_preview") => Some(edit_order(&mut fixtures.orders, &body, true)),
+            _ => None,
+        };
+        (response, fail)
+    };
+
+    if fail {
+        request.respond(Response::from_string("Service Unavailable").with_status_code(503))?;
+        return Ok(());
+    }
+
+    match response {
+        // like Coinbase, bodies describing an `error` are answered with 400
+        Some(body) if body.get("error").is_some() => request.respond(
+            Response::from_string(body.to_string())
+                .with_status_code(400)
+                .with_header(json_header())
+        )?,
+        Some(body) => request.respond(
+            Response::from_string(body.to_string()).with_header(json_header())
+        )?,
+        None => {
+            let body = json!({ "error": "NOT_FOUND", "message": format!("No mock route for {} {}", method, route) });
+            request.respond(
+                Response::from_string(body.to_string())
+                    .with_status_code(404)
+                    .with_header(json_header())
+            )?
+        },
+    }
+
+    Ok(())
+}
+
+/// `retail_portfolio_id` of `query`, `MOCK_DEFAULT_PORTFOLIO` when it is missing
+fn query_portfolio(query: &str) -> String {
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "retail_portfolio_id")
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_else(|| MOCK_DEFAULT_PORTFOLIO.to_string())
+}
+
+/// Whether `item` belongs to `portfolio`, items without `retail_portfolio_id` belonging to the default one
+fn in_portfolio(item: &Value, portfolio: &str) -> bool {
+    item["retail_portfolio_id"].as_str().unwrap_or(MOCK_DEFAULT_PORTFOLIO) == portfolio
+}
+
+/// Accounts of the portfolio in `query`
+fn accounts_page(accounts: &Value, query: &str) -> Value {
+    let portfolio = query_portfolio(query);
+    let mut page = accounts.clone();
+    if let Some(accounts) = page["accounts"].as_array_mut() {
+        accounts.retain(|account| in_portfolio(account, &portfolio));
+    }
+    page
+}
+
+/// Orders matching the `product_id`, `order_status` and portfolio filters of `query`, `MOCK_PAGE_SIZE` at a time
+fn orders_page(orders: &Value, query: &str) -> Value {
+    let mut product_ids = Vec::new();
+    let mut statuses = Vec::new();
+    let mut offset = 0;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "product_id" => product_ids.push(value.to_string()),
+            "order_status" => statuses.push(value.to_string()),
+            "cursor" => offset = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+    let portfolio = query_portfolio(query);
+
+    let matching: Vec<&Value> = orders["orders"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|o| product_ids.is_empty() || product_ids.iter().any(|p| o["product_id"] == p.as_str()))
+        .filter(|o| statuses.is_empty() || statuses.iter().any(|s| o["status"] == s.as_str()))
+        .filter(|o| in_portfolio(o, &portfolio))
+        .collect();
+    let page: Vec<&Value> = matching.iter().skip(offset).take(MOCK_PAGE_SIZE).copied().collect();
+    let has_next = offset + page.len() < matching.len();
+
+    json!({
+        "orders": page,
+        "sequence": "0",
+        "has_next": has_next,
+        "cursor": if has_next { (offset + page.len()).to_string() } else { String::new() },
+    })
+}
+
+/// Products matching the `product_type`, `product_ids` and `contract_expiry_type` filters of
+/// `query`, paged by `limit` and `offset`
+fn products_page(products: &Value, query: &str) -> Value {
+    let mut product_type = None;
+    let mut product_ids = Vec::new();
+    let mut contract_expiry_type = None;
+    let (mut limit, mut offset) = (usize::MAX, 0);
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "product_type" => product_type = Some(value.to_string()),
+            "product_ids" => product_ids.push(value.to_string()),
+            "contract_expiry_type" => contract_expiry_type = Some(value.to_string()),
+            "limit" => limit = value.parse().unwrap_or(usize::MAX),
+            "offset" => offset = value.parse().unwrap_or(0),
+            _ => (),
+        }
+    }
+
+    let matching: Vec<&Value> = products["products"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|p| product_type.as_ref().is_none_or(|t| p["product_type"] == t.as_str()))
+        .filter(|p| product_ids.is_empty() || product_ids.iter().any(|id| p["product_id"] == id.as_str()))
+        .filter(|p| {
+            contract_expiry_type
+                .as_ref()
+                .is_none_or(|t| p["future_product_details"]["contract_expiry_type"] == t.as_str())
+        })
+        .collect();
+    let page: Vec<&Value> = matching.iter().skip(offset).take(limit).copied().collect();
+    json!({ "products": page, "num_products": matching.len() })
+}
+
+fn product(products: &Value, product_id: &str) -> Option<Value> {
+    products["products"].as_array()?.iter().find(|p| p["product_id"] == product_id).cloned()
+}
+
+/// `pricebook` of `product_id` with at most `limit` levels per side
+fn pricebook(books: &Value, product_id: &str, limit: usize, now: DateTime<Utc>) -> Option<Value> {
+    let book = books.get(product_id)?;
+    let side = |side: &str| -> Vec<Value> { book[side].as_array().into_iter().flatten().take(limit).cloned().collect() };
+    Some(json!({
+        "product_id": product_id,
+        "bids": side("bids"),
+        "asks": side("asks"),
+        "time": now.to_rfc3339_opts(SecondsFormat::Micros, true),
+    }))
+}
+
+/// Top of book of the `product_ids` of `query`, every product when there are none
+fn best_bid_ask(books: &Value, query: &str, now: DateTime<Utc>) -> Value {
+    let mut product_ids: Vec<String> = url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == "product_ids")
+        .map(|(_, value)| value.to_string())
+        .collect();
+    if product_ids.is_empty() {
+        product_ids = books.as_object().into_iter().flat_map(|books| books.keys().cloned()).collect();
+    }
+    let pricebooks: Vec<Value> = product_ids.iter().filter_map(|p| pricebook(books, p, 1, now)).collect();
+    json!({ "pricebooks": pricebooks })
+}
+
+fn product_book(books: &Value, query: &str, now: DateTime<Utc>) -> Value {
+    let mut product_id = String::new();
+    let mut limit = usize::MAX;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match key.as_ref() {
+            "product_id" => product_id = value.to_string(),
+            "limit" => limit = value.parse().unwrap_or(usize::MAX),
+            _ => (),
+        }
+    }
+    match pricebook(books, &product_id, limit, now) {
+        Some(pricebook) => json!({ "pricebook": pricebook }),
+        None => json!({ "error": "INVALID_ARGUMENT", "message": format!("Unknown product {:?}", product_id) }),
+    }
+}
+
+/// Portfolios matching the `portfolio_type` filter of `query`
+fn list_portfolios(portfolios: &Value, query: &str) -> Value {
+    let portfolio_type = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "portfolio_type")
+        .map(|(_, value)| value.to_string());
+    let mut page = portfolios.clone();
+    if let (Some(portfolio_type), Some(portfolios)) = (portfolio_type, page["portfolios"].as_array_mut()) {
+        portfolios.retain(|portfolio| portfolio["type"] == portfolio_type.as_str());
+    }
+    page
+}
+
+fn create_portfolio(portfolios: &mut Value, body: &str) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let Some(portfolios) = portfolios["portfolios"].as_array_mut() else {
+        return json!({ "error": "INTERNAL", "message": "Invalid portfolios fixture" });
+    };
+    if portfolios.iter().any(|p| p["name"] == request["name"]) {
+        return json!({ "error": "INVALID_ARGUMENT", "message": "A portfolio with this name already exists" });
+    }
+    let portfolio = json!({
+        "name": request["name"],
+        "uuid": format!("{:04}-mock-portfolio", portfolios.len() + 1),
+        "type": "CONSUMER",
+        "deleted": false,
+    });
+    portfolios.push(portfolio.clone());
+    json!({ "portfolio": portfolio })
+}
+
+fn edit_portfolio(portfolios: &mut Value, uuid: &str, body: &str) -> Option<Value> {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let portfolios = portfolios["portfolios"].as_array_mut()?;
+    if portfolios.iter().any(|p| p["name"] == request["name"] && p["uuid"] != uuid) {
+        return Some(json!({ "error": "INVALID_ARGUMENT", "message": "A portfolio with this name already exists" }));
+    }
+    let portfolio = portfolios.iter_mut().find(|p| p["uuid"] == uuid)?;
+    portfolio["name"] = request["name"].clone();
+    Some(json!({ "portfolio": portfolio }))
+}
+
+/// Removes an empty portfolio other than the default one, and its accounts
+fn delete_portfolio(fixtures: &mut MockFixtures, uuid: &str) -> Option<Value> {
+    let portfolios = fixtures.portfolios["portfolios"].as_array_mut()?;
+    let index = portfolios.iter().position(|p| p["uuid"] == uuid)?;
+    let accounts = fixtures.accounts["accounts"].as_array_mut()?;
+    let funded = accounts
+        .iter()
+        .filter(|a| in_portfolio(a, uuid))
+        .any(|a| a["available_balance"]["value"].as_str().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0) > 0.0);
+    if uuid == MOCK_DEFAULT_PORTFOLIO || funded {
+        return Some(json!({ "error": "INVALID_ARGUMENT", "message": "Only empty portfolios can be deleted" }));
+    }
+    portfolios.remove(index);
+    accounts.retain(|a| !in_portfolio(a, uuid));
+    Some(json!({}))
+}
+
+/// Moves available balance between the accounts of two portfolios, opening the target account if needed
+fn move_funds(fixtures: &mut MockFixtures, body: &str) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let source = request["source_portfolio_uuid"].as_str().unwrap_or_default();
+    let target = request["target_portfolio_uuid"].as_str().unwrap_or_default();
+    let currency = request["funds"]["currency"].as_str().unwrap_or_default();
+    let value: f64 = request["funds"]["value"].as_str().and_then(|v| v.parse().ok()).unwrap_or(0.0);
+    let known = |uuid: &str| {
+        fixtures.portfolios["portfolios"]
+            .as_array()
+            .is_some_and(|portfolios| portfolios.iter().any(|p| p["uuid"] == uuid))
+    };
+    if !known(source) || !known(target) || source == target || value <= 0.0 {
+        return json!({ "error": "INVALID_ARGUMENT", "message": "Invalid portfolios or funds" });
+    }
+    let Some(accounts) = fixtures.accounts["accounts"].as_array_mut() else {
+        return json!({ "error": "INTERNAL", "message": "Invalid accounts fixture" });
+    };
+
+    let available = |account: &Value| account["available_balance"]["value"].as_str().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+    let Some(from) = accounts.iter_mut().find(|a| in_portfolio(a, source) && a["currency"] == currency) else {
+        return json!({ "error": "INSUFFICIENT_FUNDS", "message": "No account in the source portfolio" });
+    };
+    if available(from) < value {
+        return json!({ "error": "INSUFFICIENT_FUNDS", "message": "Insufficient funds in the source portfolio" });
+    }
+    from["available_balance"]["value"] = (available(from) - value).to_string().into();
+    match accounts.iter_mut().find(|a| in_portfolio(a, target) && a["currency"] == currency) {
+        Some(to) => to["available_balance"]["value"] = (available(to) + value).to_string().into(),
+        None => accounts.push(mock_portfolio_account(currency, &value.to_string(), target)),
+    }
+
+    json!({ "source_portfolio_uuid": source, "target_portfolio_uuid": target })
+}
+
+/// Balances and spot positions of a portfolio, valued at `MOCK_USD_PRICES`
+fn portfolio_breakdown(fixtures: &MockFixtures, uuid: &str) -> Option<Value> {
+    let portfolio = fixtures.portfolios["portfolios"].as_array()?.iter().find(|p| p["uuid"] == uuid)?.clone();
+    let usd = |amount: f64| json!({ "value": amount.to_string(), "currency": "USD" });
+
+    let positions: Vec<Value> = fixtures.accounts["accounts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|account| in_portfolio(account, uuid))
+        .map(|account| {
+            let asset = account["currency"].as_str().unwrap_or_default();
+            let crypto: f64 = account["available_balance"]["value"].as_str().and_then(|v| v.parse().ok()).unwrap_or(0.0);
+            let price = MOCK_USD_PRICES.iter().find(|(currency, _)| *currency == asset).map_or(0.0, |(_, price)| *price);
+            json!({
+                "asset": asset,
+                "account_uuid": account["uuid"],
+                "total_balance_fiat": crypto * price,
+                "total_balance_crypto": crypto,
+                "available_to_trade_fiat": crypto * price,
+                "allocation": 0.0,
+                "cost_basis": usd(crypto * price),
+                "is_cash": asset == "USD",
+            })
+        })
+        .collect();
+    let total: f64 = positions.iter().filter_map(|p| p["total_balance_fiat"].as_f64()).sum();
+    let cash: f64 = positions.iter().filter(|p| p["is_cash"] == true).filter_map(|p| p["total_balance_fiat"].as_f64()).sum();
+    let positions: Vec<Value> = positions
+        .into_iter()
+        .map(|mut p| {
+            if total > 0.0 {
+                p["allocation"] = (p["total_balance_fiat"].as_f64().unwrap_or(0.0) / total).into();
+            }
+            p
+        })
+        .collect();
+
+    Some(json!({
+        "breakdown": {
+            "portfolio": portfolio,
+            "portfolio_balances": {
+                "total_balance": usd(total),
+                "total_futures_balance": usd(0.0),
+                "total_cash_equivalent_balance": usd(cash),
+                "total_crypto_balance": usd(total - cash),
+            },
+            "spot_positions": positions,
+        },
+    }))
+}
+
+/// Quotes a conversion between `MOCK_CONVERT_CURRENCIES` of the default portfolio
+fn convert_quote(fixtures: &mut MockFixtures, body: &str) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let from = request["from_account"].as_str().unwrap_or_default();
+    let to = request["to_account"].as_str().unwrap_or_default();
+    let amount: f64 = request["amount"].as_str().and_then(|v| v.parse().ok()).unwrap_or(0.0);
+    if from == to || !MOCK_CONVERT_CURRENCIES.contains(&from) || !MOCK_CONVERT_CURRENCIES.contains(&to) {
+        return json!({ "error": "INVALID_ARGUMENT", "message": format!("Cannot convert {} to {}", from, to) });
+    }
+    let available = fixtures.accounts["accounts"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|a| in_portfolio(a, MOCK_DEFAULT_PORTFOLIO) && a["currency"] == from)
+        .and_then(|a| a["available_balance"]["value"].as_str())
+        .and_then(|v| v.parse::<f64>().ok())
+        .unwrap_or(0.0);
+    if amount <= 0.0 || available < amount {
+        return json!({ "error": "INSUFFICIENT_FUNDS", "message": format!("Insufficient {} to convert", from) });
+    }
+    let Some(trades) = fixtures.convert_trades["trades"].as_array_mut() else {
+        return json!({ "error": "INTERNAL", "message": "Invalid convert trades fixture" });
+    };
+
+    let balance = |value: f64, currency: &str| json!({ "value": value.to_string(), "currency": currency });
+    let trade = json!({
+        "id": format!("{:04}-mock-convert", trades.len() + 1),
+        "status": "TRADE_STATUS_CREATED",
+        "user_entered_amount": balance(amount, from),
+        "amount": balance(amount, to),
+        "subtotal": balance(amount, from),
+        "total": balance(amount, from),
+        "total_fee": { "title": "Fee", "description": "", "amount": balance(0.0, from) },
+        "exchange_rate": balance(1.0, to),
+        "source_currency": from,
+        "target_currency": to,
+    });
+    trades.push(trade.clone());
+    json!({ "trade": trade })
+}
+
+/// Commits a quoted trade, which settles on the next `get_convert_trade`
+fn commit_convert_trade(fixtures: &mut MockFixtures, trade_id: &str) -> Option<Value> {
+    let trade = fixtures.convert_trades["trades"].as_array_mut()?.iter_mut().find(|t| t["id"] == trade_id)?;
+    if trade["status"] != "TRADE_STATUS_CREATED" {
+        return Some(json!({ "error": "INVALID_ARGUMENT", "message": "Trade is not awaiting a commit" }));
+    }
+    trade["status"] = "TRADE_STATUS_STARTED".into();
+    Some(json!({ "trade": trade }))
+}
+
+/// Returns a trade, settling a started one into the default portfolio's accounts first
+fn get_convert_trade(fixtures: &mut MockFixtures, trade_id: &str) -> Option<Value> {
+    let trade = fixtures.convert_trades["trades"].as_array_mut()?.iter_mut().find(|t| t["id"] == trade_id)?;
+    if trade["status"] == "TRADE_STATUS_STARTED" {
+        let accounts = fixtures.accounts["accounts"].as_array_mut()?;
+        let available = |account: &Value| account["available_balance"]["value"].as_str().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+        let amount = |balance: &Value| balance["value"].as_str().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0);
+        let (debited, credited) = (amount(&trade["total"]), amount(&trade["amount"]));
+        let (from, to) = (&trade["source_currency"], &trade["target_currency"]);
+
+        let source = accounts.iter_mut().find(|a| in_portfolio(a, MOCK_DEFAULT_PORTFOLIO) && a["currency"] == *from);
+        match source {
+            Some(source) if available(source) >= debited => {
+                source["available_balance"]["value"] = (available(source) - debited).to_string().into();
+                match accounts.iter_mut().find(|a| in_portfolio(a, MOCK_DEFAULT_PORTFOLIO) && a["currency"] == *to) {
+                    Some(target) => target["available_balance"]["value"] = (available(target) + credited).to_string().into(),
+                    None => accounts.push(mock_account(to.as_str().unwrap_or_default(), &credited.to_string())),
+                }
+                trade["status"] = "TRADE_STATUS_COMPLETED".into();
+            },
+            _ => {
+                trade["status"] = "TRADE_STATUS_CANCELED".into();
+                trade["cancellation_reason"] = json!({ "message": "Insufficient funds", "code": "INSUFFICIENT_FUNDS" });
+            },
+        }
+    }
+    Some(json!({ "trade": trade }))
+}
+
+/// Places an `OPEN` order, or returns the one already placed with the same `client_order_id`
+fn create_order(fixtures: &mut MockFixtures, body: &str, now: DateTime<Utc>) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let orders = match fixtures.orders["orders"].as_array_mut() {
+        Some(orders) => orders,
+        None => return json!({ "success": false, "failure_reason": "UNKNOWN_FAILURE_REASON" }),
+    };
+    if let Some(existing) = orders.iter().find(|o| o["client_order_id"] == request["client_order_id"]) {
+        return json!({ "success": true, "failure_reason": "UNKNOWN_FAILURE_REASON", "order_id": existing["order_id"] });
+    }
+
+    let known_product = fixtures.products["products"]
+        .as_array()
+        .is_some_and(|products| products.iter().any(|p| p["product_id"] == request["product_id"]));
+    if !known_product {
+        return json!({
+            "success": false,
+            "failure_reason": "UNKNOWN_FAILURE_REASON",
+            "order_id": "",
+            "error_response": {
+                "error": "INVALID_PRODUCT_ID",
+                "message": "Invalid product_id",
+                "new_order_failure_reason": "INVALID_PRODUCT_ID",
+            },
+        });
+    }
+
+    let order_id = format!("{:04}-mock-order", orders.len() + 1);
+    let configuration = request["order_configuration"].as_object().and_then(|c| c.keys().next().cloned()).unwrap_or_default();
+    orders.push(json!({
+        "order_id": order_id,
+        "product_id": request["product_id"],
+        "client_order_id": request["client_order_id"],
+        "side": request["side"],
+        "status": "OPEN",
+        "order_type": if configuration.starts_with("market") { "MARKET" } else { "LIMIT" },
+        "time_in_force": "GOOD_UNTIL_CANCELLED",
+        "created_time": now.to_rfc3339_opts(SecondsFormat::Secs, true),
+        "filled_size": "0",
+        "average_filled_price": "0",
+        "total_fees": "0",
+        "order_configuration": request["order_configuration"],
+        "retail_portfolio_id": request["retail_portfolio_id"].as_str().unwrap_or(MOCK_DEFAULT_PORTFOLIO),
+    }));
+    json!({
+        "success": true,
+        "failure_reason": "UNKNOWN_FAILURE_REASON",
+        "order_id": order_id,
+        "success_response": {
+            "order_id": order_id,
+            "product_id": request["product_id"],
+            "side": request["side"],
+            "client_order_id": request["client_order_id"],
+        },
+    })
+}
+
+/// Cancels the `OPEN` orders among `order_ids`, failing unknown or already closed ones
+fn batch_cancel(orders: &mut Value, body: &str) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let order_ids: Vec<String> = request["order_ids"]
+        .as_array()
+        .map(|ids| ids.iter().filter_map(|id| id.as_str().map(str::to_string)).collect())
+        .unwrap_or_default();
+
+    let results: Vec<Value> = order_ids
+        .iter()
+        .map(|order_id| {
+            let order = orders["orders"]
+                .as_array_mut()
+                .and_then(|orders| orders.iter_mut().find(|o| o["order_id"] == order_id.as_str()));
+            match order {
+                Some(order) if order["status"] == "OPEN" => {
+                    order["status"] = "CANCELLED".into();
+                    json!({ "success": true, "failure_reason": "UNKNOWN_CANCEL_FAILURE_REASON", "order_id": order_id })
+                },
+                Some(_) => json!({ "success": false, "failure_reason": "INVALID_CANCEL_REQUEST", "order_id": order_id }),
+                None => json!({ "success": false, "failure_reason": "UNKNOWN_CANCEL_ORDER", "order_id": order_id }),
+            }
+        })
+        .collect();
+    json!({ "results": results })
+}
+
+/// Changes the price and size of an `OPEN` limit order, or only previews the change
+fn edit_order(orders: &mut Value, body: &str, preview: bool) -> Value {
+    let request: Value = serde_json::from_str(body).unwrap_or_default();
+    let order = orders["orders"]
+        .as_array_mut()
+        .and_then(|orders| orders.iter_mut().find(|o| o["order_id"] == request["order_id"]))
+        .filter(|o| o["status"] == "OPEN");
+    let Some(order) = order else {
+        let errors = json!([{ "edit_failure_reason": "EDIT_ORDER_FAILURE_REASON_ORDER_NOT_FOUND" }]);
+        return match preview {
+            true => json!({ "errors": errors }),
+            false => json!({ "success": false, "errors": errors }),
+        };
+    };
+
+    let price: f64 = request["price"].as_str().and_then(|p| p.parse().ok()).unwrap_or(0.0);
+    let size: f64 = request["size"].as_str().and_then(|s| s.parse().ok()).unwrap_or(0.0);
+    if preview {
+        let total = price * size;
+        return json!({
+            "errors": [],
+            "slippage": "0",
+            "order_total": (total * 1.006).to_string(),
+            "commission_total": (total * 0.006).to_string(),
+            "quote_size": total.to_string(),
+            "base_size": request["size"],
+            "best_bid": "1675.13",
+            "best_ask": "1675.14",
+            "average_filled_price": "0",
+        });
+    }
+
+    if let Some(limit) = order["order_configuration"]
+        .as_object_mut()
+        .and_then(|configuration| configuration.values_mut().next())
+    {
+        limit["limit_price"] = request["price"].clone();
+        limit["base_size"] = request["size"].clone();
+    }
+    json!({ "success": true, "errors": [] })
+}
+
+fn accept_websockets(listener: TcpListener, shared: Arc<Shared>) {
+    let mut connections: Vec<JoinHandle<()>> = Vec::new();
+    while !shared.shutdown.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                shared.state.lock().unwrap().connections += 1;
+                let shared = shared.clone();
+                connections.push(std::thread::spawn(move || {
+                    if let Err(e) = serve_websocket(stream, &shared) {
+                        debug!("[mock] WebSocket connection ended: {}", e);
+                    }
+                }));
+            },
+            Err(e) if e.kind() == ErrorKind::WouldBlock => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                error!("[mock] Error accepting WebSocket connection: {}", e);
+                break;
+            }
+        }
+    }
+    for connection in connections {
+        let _ = connection.join();
+    }
+}
+
+/// Per-connection WebSocket session state
+struct Session {
+    sequence_num: u64,
+    subscribed: BTreeMap<String, Vec<String>>,
+}
+
+impl Session {
+    fn envelope(&mut self, channel: &str, events: Value, now: DateTime<Utc>) -> String {
+        let msg = json!({
+            "channel": channel,
+            "client_id": "",
+            "timestamp": now.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            "sequence_num": self.sequence_num,
+            "events": events,
+        });
+        self.sequence_num += 1;
+        msg.to_string()
+    }
+}
+
+fn serve_websocket(stream: TcpStream, shared: &Shared) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut socket = tungstenite::accept(stream).map_err(|e| anyhow!("{}", e))?;
+    let mut session = Session { sequence_num: 0, subscribed: BTreeMap::new() };
+
+    while !shared.shutdown.load(Ordering::Relaxed) {
+        match socket.read_message() {
+            Ok(Message::Text(text)) => {
+                if let Some(reply) = handle_subscribe(&text, &mut session, shared) {
+                    socket.write_message(Message::Text(reply))?;
+                }
+            },
+            Ok(Message::Close(_)) => return Ok(()),
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(e) => return Err(anyhow!("{}", e)),
+        }
+
+        if session.subscribed.is_empty() {
+            continue;
+        }
+
+        let step = shared.state.lock().unwrap().script.pop_front();
+        match step {
+            Some(ScriptStep::Message { channel, events }) => {
+                let frame = session.envelope(&channel, events, shared.now());
+                socket.write_message(Message::Text(frame))?;
+            },
+            Some(ScriptStep::Gap(skipped)) => session.sequence_num += skipped,
+            Some(ScriptStep::Raw(frame)) => socket.write_message(Message::Text(frame))?,
+            Some(ScriptStep::Pause(duration)) => std::thread::sleep(duration),
+            Some(ScriptStep::Disconnect) => return Ok(()),
+            None => (),
+        }
+    }
+
+    let _ = socket.close(None);
+    Ok(())
+}
+
+/// Validates a subscribe or unsubscribe message and returns the frame to send back, if any
+fn handle_subscribe(text: &str, session: &mut Session, shared: &Shared) -> Option<String> {
+    let msg: ChannelSubscriptionMessage = match serde_json::from_str(text) {
+        Ok(msg) => msg,
+        Err(_) => {
+            return Some(json!({ "type": "error", "message": "failure to parse message" }).to_string());
+        }
+    };
+
+    let expected = sig_gen::create_ws_signature(
+        msg.timestamp.clone(),
+        msg.channel.clone(),
+        msg.product_ids.clone(),
+        shared.config.api_secret.as_bytes(),
+    );
+    if msg.api_key != shared.config.api_key || msg.signature != expected || !shared.accepts_timestamp(&msg.timestamp) {
+        shared.state.lock().unwrap().rejected_subscriptions += 1;
+        return Some(json!({ "type": "error", "message": "authentication failure" }).to_string());
+    }
+
+    let products = session.subscribed.entry(msg.channel.clone()).or_default();
+    if msg.msg_type == "unsubscribe" {
+        products.retain(|product| !msg.product_ids.contains(product));
+    } else {
+        for product in &msg.product_ids {
+            if !products.contains(product) {
+                products.push(product.clone());
+            }
+        }
+    }
+    let events = json!([{ "subscriptions": session.subscribed }]);
+    shared.state.lock().unwrap().subscriptions.push(msg);
+
+    Some(session.envelope("subscriptions", events, shared.now()))
+}
@@ -1,4 +1,7 @@
-use serde::{Deserialize, Serialize};
+use chrono::{DateTime, Utc};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
 
 /*
 REST - Models that store REST requests
@@ -7,7 +10,60 @@ REST - Models that store REST requests
 pub struct RestEndpoint {
     pub endpoint_url: String,
     pub method: String,
+    /// Query string, see `rest_client::QueryBuilder`
     pub resource: Option<String>,
+    /// JSON body, signed and sent byte for byte
+    #[serde(default)]
+    pub body: Option<String>,
+    /// Path template such as `/brokerage/products/{product_id}` of a request whose `endpoint_url`
+    /// holds an id, `endpoint_url` itself when `None`. Labels the metrics and the `route` field of
+    /// the `rest_request` span
+    #[serde(default)]
+    pub route: Option<String>,
+}
+
+impl RestEndpoint {
+    pub fn new(method: &str, endpoint_url: impl Into<String>) -> Self {
+        RestEndpoint {
+            endpoint_url: endpoint_url.into(),
+            method: method.to_string(),
+            resource: None,
+            body: None,
+            route: None,
+        }
+    }
+
+    pub fn get(endpoint_url: impl Into<String>) -> Self {
+        RestEndpoint::new("GET", endpoint_url)
+    }
+
+    pub fn post(endpoint_url: impl Into<String>) -> Self {
+        RestEndpoint::new("POST", endpoint_url)
+    }
+
+    pub fn put(endpoint_url: impl Into<String>) -> Self {
+        RestEndpoint::new("PUT", endpoint_url)
+    }
+
+    pub fn delete(endpoint_url: impl Into<String>) -> Self {
+        RestEndpoint::new("DELETE", endpoint_url)
+    }
+
+    pub fn with_resource(mut self, resource: Option<String>) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    pub fn with_route(mut self, route: &str) -> Self {
+        self.route = Some(route.to_string());
+        self
+    }
+
+    /// Serializes `body` to the JSON sent with the request
+    pub fn with_body<T: Serialize>(mut self, body: &T) -> serde_json::Result<Self> {
+        self.body = Some(serde_json::to_string(body)?);
+        Ok(self)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -17,7 +73,64 @@ pub struct Products {
     pub products: Vec<ProductData>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// Server-side filters of `GET /brokerage/products`
+///
+/// # Example
+///
+/// ```
+/// use rs_coinbase_pairs_handler::models::ProductsQuery;
+///
+/// let perpetuals = ProductsQuery::new()
+///     .with_product_type("FUTURE")
+///     .with_contract_expiry_type("PERPETUAL")
+///     .with_limit(50);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProductsQuery {
+    /// `"SPOT"` or `"FUTURE"`
+    pub product_type: Option<String>,
+    /// Only these products, every product when empty
+    pub product_ids: Vec<String>,
+    /// Most products per page
+    pub limit: Option<u32>,
+    /// Products skipped before the page
+    pub offset: Option<u32>,
+    /// `"EXPIRING"` or `"PERPETUAL"`, for futures
+    pub contract_expiry_type: Option<String>,
+}
+
+impl ProductsQuery {
+    pub fn new() -> Self {
+        ProductsQuery::default()
+    }
+
+    pub fn with_product_type(mut self, product_type: &str) -> Self {
+        self.product_type = Some(product_type.to_string());
+        self
+    }
+
+    pub fn with_product_ids<T: ToString>(mut self, product_ids: impl IntoIterator<Item = T>) -> Self {
+        self.product_ids = product_ids.into_iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u32) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn with_contract_expiry_type(mut self, contract_expiry_type: &str) -> Self {
+        self.contract_expiry_type = Some(contract_expiry_type.to_string());
+        self
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub struct ProductData {
     pub product_id: String,
@@ -27,18 +140,738 @@ pub struct ProductData {
     pub base_max_size: String,
     pub base_min_size: String,
     pub quote_currency_id: String,
+    /// Increment of `quote_size`, not of prices
     pub quote_increment: String,
     pub quote_max_size: String,
     pub quote_min_size: String,
+    /// Increment of limit and stop prices, which can differ from `quote_increment`
+    #[serde(default)]
+    pub price_increment: String,
     pub status: String,
     pub trading_disabled: bool,
+    /// Base currency traded over the last 24 hours
+    #[serde(default)]
+    pub volume_24h: Option<String>,
+    /// Contract details of `"FUTURE"` products, `None` for spot
+    #[serde(default)]
+    pub future_product_details: Option<FutureProductDetails>,
+}
+
+impl ProductData {
+    /// Online spot product `base`-`quote` with loose size limits, for tests to adjust with struct
+    /// update syntax
+    #[cfg(test)]
+    pub(crate) fn test(product_id: &str, base: &str, quote: &str) -> ProductData {
+        ProductData {
+            product_id: product_id.to_string(),
+            product_type: "SPOT".to_string(),
+            base_currency_id: base.to_string(),
+            base_increment: "0.00000001".to_string(),
+            base_max_size: "1000".to_string(),
+            base_min_size: "0.00000001".to_string(),
+            quote_currency_id: quote.to_string(),
+            quote_increment: "0.01".to_string(),
+            quote_max_size: "1000000".to_string(),
+            quote_min_size: "1".to_string(),
+            price_increment: "0.01".to_string(),
+            status: "online".to_string(),
+            trading_disabled: false,
+            volume_24h: None,
+            future_product_details: None,
+        }
+    }
+
+    pub fn is_future(&self) -> bool {
+        self.product_type == "FUTURE"
+    }
+
+    /// Whether the product is a perpetual future, which never expires and pays funding instead
+    pub fn is_perpetual(&self) -> bool {
+        self.future_product_details.as_ref().is_some_and(|d| d.contract_expiry_type == "PERPETUAL")
+    }
+
+    /// Expiry of a dated future, `None` for perpetuals and spot products
+    pub fn contract_expiry(&self) -> Option<DateTime<Utc>> {
+        let expiry = &self.future_product_details.as_ref()?.contract_expiry;
+        DateTime::parse_from_rfc3339(expiry.as_deref()?).ok().map(|t| t.with_timezone(&Utc))
+    }
+
+    /// Units of `contract_root_unit` per contract e.g. `0.01` BTC
+    pub fn contract_size(&self) -> Option<f64> {
+        self.future_product_details.as_ref()?.contract_size.parse().ok()
+    }
+
+    /// Funding rate of the current funding period of a perpetual
+    pub fn funding_rate(&self) -> Option<f64> {
+        self.future_product_details.as_ref()?.perpetual_details.as_ref()?.funding_rate.as_deref()?.parse().ok()
+    }
+
+    /// Contracts open, from the perpetual details when there are any
+    pub fn open_interest(&self) -> Option<f64> {
+        let details = self.future_product_details.as_ref()?;
+        details
+            .perpetual_details
+            .as_ref()
+            .and_then(|p| p.open_interest.as_deref())
+            .or(details.open_interest.as_deref())?
+            .parse()
+            .ok()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FutureProductDetails {
+    /// e.g. `"cde"`
+    #[serde(default)]
+    pub venue: String,
+    /// e.g. `"BIT"`
+    #[serde(default)]
+    pub contract_code: String,
+    /// RFC 3339 expiry, missing or empty for perpetuals
+    #[serde(default)]
+    pub contract_expiry: Option<String>,
+    /// e.g. `"0.01"`
+    pub contract_size: String,
+    /// Asset one contract is sized in e.g. `"BTC"`
+    #[serde(default)]
+    pub contract_root_unit: String,
+    /// `"EXPIRING"` or `"PERPETUAL"`
+    #[serde(default)]
+    pub contract_expiry_type: String,
+    #[serde(default)]
+    pub open_interest: Option<String>,
+    #[serde(default)]
+    pub perpetual_details: Option<PerpetualDetails>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PerpetualDetails {
+    #[serde(default)]
+    pub open_interest: Option<String>,
+    /// Fraction of notional paid by longs to shorts per funding period, negative the other way
+    #[serde(default)]
+    pub funding_rate: Option<String>,
+    /// Time of the next funding payment
+    #[serde(default)]
+    pub funding_time: Option<String>,
+    #[serde(default)]
+    pub max_leverage: Option<String>,
+}
+
+/// Page of `GET /brokerage/orders/historical/batch`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Orders {
+    pub orders: Vec<Order>,
+    #[serde(default)]
+    pub has_next: bool,
+    #[serde(default)]
+    pub cursor: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Order {
+    pub order_id: String,
+    pub product_id: String,
+    #[serde(default)]
+    pub client_order_id: String,
+    /// `"BUY"` or `"SELL"`
+    pub side: String,
+    /// e.g. `"OPEN"`, `"FILLED"`, `"CANCELLED"`
+    pub status: String,
+    #[serde(default)]
+    pub order_type: String,
+    #[serde(default)]
+    pub time_in_force: String,
+    #[serde(default)]
+    pub created_time: String,
+    #[serde(default)]
+    pub filled_size: String,
+    #[serde(default)]
+    pub average_filled_price: String,
+    #[serde(default)]
+    pub total_fees: String,
+    #[serde(default)]
+    pub order_configuration: serde_json::Value,
+}
+
+/// Page of `GET /brokerage/orders/historical/fills`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Fills {
+    pub fills: Vec<Fill>,
+    /// Cursor of the next page, empty on the last page
+    #[serde(default)]
+    pub cursor: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Fill {
+    pub entry_id: String,
+    pub trade_id: String,
+    pub order_id: String,
+    pub trade_time: String,
+    pub price: String,
+    pub size: String,
+    pub commission: String,
+    pub product_id: String,
+    #[serde(default)]
+    pub liquidity_indicator: String,
+    /// `"BUY"` or `"SELL"`
+    pub side: String,
+}
+
+/// Page of `GET /brokerage/accounts`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Accounts {
+    pub accounts: Vec<Account>,
+    #[serde(default)]
+    pub has_next: bool,
+    /// Cursor of the next page, empty on the last page
+    #[serde(default)]
+    pub cursor: String,
+}
+
+impl Accounts {
+    /// Available balance per currency, summed over the accounts of this page
+    pub fn available_balances(&self) -> BTreeMap<String, f64> {
+        let mut balances = BTreeMap::new();
+        for account in &self.accounts {
+            let available = account.available_balance.value.parse::<f64>().unwrap_or(0.0);
+            *balances.entry(account.currency.clone()).or_insert(0.0) += available;
+        }
+        balances
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Account {
+    pub uuid: String,
+    pub name: String,
+    pub currency: String,
+    pub available_balance: Balance,
+    #[serde(default)]
+    pub hold: Option<Balance>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Balance {
+    pub value: String,
+    pub currency: String,
+}
+
+/// Body of `GET /brokerage/portfolios`
+#[derive(Debug, Deserialize, Clone)]
+pub struct Portfolios {
+    pub portfolios: Vec<Portfolio>,
+}
+
+/// Body of `POST /brokerage/portfolios`
+#[derive(Debug, Deserialize, Clone)]
+pub struct PortfolioResponse {
+    pub portfolio: Portfolio,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Portfolio {
+    pub name: String,
+    pub uuid: String,
+    /// `"DEFAULT"`, `"CONSUMER"` or `"INTX"`
+    #[serde(rename = "type")]
+    pub portfolio_type: String,
+    #[serde(default)]
+    pub deleted: bool,
+}
+
+/// Body of `POST /brokerage/portfolios/move_funds`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct MoveFundsResponse {
+    pub source_portfolio_uuid: String,
+    pub target_portfolio_uuid: String,
+}
+
+/// Body of `GET /brokerage/portfolios/{portfolio_uuid}`
+#[derive(Debug, Deserialize, Clone)]
+pub struct PortfolioBreakdownResponse {
+    pub breakdown: PortfolioBreakdown,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortfolioBreakdown {
+    pub portfolio: Portfolio,
+    pub portfolio_balances: PortfolioBalances,
+    #[serde(default)]
+    pub spot_positions: Vec<SpotPosition>,
+}
+
+/// Totals of a portfolio in the user's fiat currency
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PortfolioBalances {
+    pub total_balance: Balance,
+    #[serde(default)]
+    pub total_futures_balance: Option<Balance>,
+    #[serde(default)]
+    pub total_cash_equivalent_balance: Option<Balance>,
+    #[serde(default)]
+    pub total_crypto_balance: Option<Balance>,
+}
+
+/// Holding of one asset in a portfolio
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SpotPosition {
+    /// Currency e.g. `"ETH"`
+    pub asset: String,
+    #[serde(default)]
+    pub account_uuid: String,
+    pub total_balance_fiat: f64,
+    pub total_balance_crypto: f64,
+    #[serde(default)]
+    pub available_to_trade_fiat: f64,
+    /// Share of the portfolio's value, from 0 to 1
+    #[serde(default)]
+    pub allocation: f64,
+    #[serde(default)]
+    pub cost_basis: Option<Balance>,
+    #[serde(default)]
+    pub is_cash: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum OrderSide {
+    Buy,
+    Sell,
+}
+
+/// `order_configuration` of an order, one variant per order type Coinbase accepts
+///
+/// Sizes and prices are decimal strings as the API expects them, e.g. `"0.015"`.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderConfiguration {
+    /// Market order, sized in either quote or base currency
+    MarketMarketIoc {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quote_size: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_size: Option<String>,
+    },
+    LimitLimitGtc {
+        base_size: String,
+        limit_price: String,
+        #[serde(default)]
+        post_only: bool,
+    },
+    LimitLimitGtd {
+        base_size: String,
+        limit_price: String,
+        /// RFC 3339 expiry
+        end_time: String,
+        #[serde(default)]
+        post_only: bool,
+    },
+    StopLimitStopLimitGtc {
+        base_size: String,
+        limit_price: String,
+        stop_price: String,
+        /// `"STOP_DIRECTION_STOP_UP"` or `"STOP_DIRECTION_STOP_DOWN"`
+        stop_direction: String,
+    },
+    StopLimitStopLimitGtd {
+        base_size: String,
+        limit_price: String,
+        stop_price: String,
+        end_time: String,
+        stop_direction: String,
+    },
+}
+
+impl OrderConfiguration {
+    pub fn base_size(&self) -> Option<&str> {
+        match self {
+            OrderConfiguration::MarketMarketIoc { base_size, .. } => base_size.as_deref(),
+            OrderConfiguration::LimitLimitGtc { base_size, .. }
+            | OrderConfiguration::LimitLimitGtd { base_size, .. }
+            | OrderConfiguration::StopLimitStopLimitGtc { base_size, .. }
+            | OrderConfiguration::StopLimitStopLimitGtd { base_size, .. } => Some(base_size),
+        }
+    }
+
+    pub fn quote_size(&self) -> Option<&str> {
+        match self {
+            OrderConfiguration::MarketMarketIoc { quote_size, .. } => quote_size.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// `None` for market orders
+    pub fn limit_price(&self) -> Option<&str> {
+        match self {
+            OrderConfiguration::MarketMarketIoc { .. } => None,
+            OrderConfiguration::LimitLimitGtc { limit_price, .. }
+            | OrderConfiguration::LimitLimitGtd { limit_price, .. }
+            | OrderConfiguration::StopLimitStopLimitGtc { limit_price, .. }
+            | OrderConfiguration::StopLimitStopLimitGtd { limit_price, .. } => Some(limit_price),
+        }
+    }
+
+    pub fn stop_price(&self) -> Option<&str> {
+        match self {
+            OrderConfiguration::StopLimitStopLimitGtc { stop_price, .. }
+            | OrderConfiguration::StopLimitStopLimitGtd { stop_price, .. } => Some(stop_price),
+            _ => None,
+        }
+    }
+}
+
+/// Order as sent to `POST /brokerage/orders` and, without `client_order_id`, to the preview
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OrderRequest {
+    pub client_order_id: String,
+    pub product_id: String,
+    pub side: OrderSide,
+    pub order_configuration: OrderConfiguration,
+}
+
+/// Body of `POST /brokerage/orders/preview`
+#[derive(Debug, Deserialize, Clone)]
+pub struct OrderPreview {
+    #[serde(default)]
+    pub preview_id: String,
+    pub order_total: String,
+    pub commission_total: String,
+    /// Reasons the order would be rejected, e.g. `"PREVIEW_INSUFFICIENT_FUND"`
+    #[serde(default)]
+    pub errs: Vec<String>,
+    #[serde(default)]
+    pub warning: Vec<String>,
+    #[serde(default)]
+    pub quote_size: String,
+    #[serde(default)]
+    pub base_size: String,
+    #[serde(default)]
+    pub best_bid: String,
+    #[serde(default)]
+    pub best_ask: String,
+    #[serde(default)]
+    pub slippage: String,
+}
+
+/// Body of `POST /brokerage/orders`
+#[derive(Debug, Deserialize, Clone)]
+pub struct CreateOrderResponse {
+    pub success: bool,
+    /// e.g. `"UNKNOWN_FAILURE_REASON"`, only meaningful when `success` is false
+    #[serde(default)]
+    pub failure_reason: String,
+    #[serde(default)]
+    pub order_id: String,
+    #[serde(default)]
+    pub error_response: Option<CreateOrderError>,
+}
+
+impl CreateOrderResponse {
+    /// Most specific reason Coinbase gave for refusing the order
+    pub fn reason(&self) -> String {
+        match &self.error_response {
+            Some(error) if !error.new_order_failure_reason.is_empty() => error.new_order_failure_reason.clone(),
+            Some(error) if !error.error.is_empty() => error.error.clone(),
+            _ => self.failure_reason.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct CreateOrderError {
+    #[serde(default)]
+    pub error: String,
+    #[serde(default)]
+    pub message: String,
+    /// e.g. `"INVALID_PRODUCT_ID"`
+    #[serde(default)]
+    pub new_order_failure_reason: String,
+}
+
+/// Body of `POST /brokerage/orders/edit`
+#[derive(Debug, Deserialize, Clone)]
+pub struct EditOrderResponse {
+    pub success: bool,
+    #[serde(default)]
+    pub errors: Vec<EditOrderError>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EditOrderError {
+    #[serde(default)]
+    pub edit_failure_reason: Option<String>,
+    #[serde(default)]
+    pub preview_failure_reason: Option<String>,
+}
+
+/// Body of `POST /brokerage/orders/edit_preview`
+#[derive(Debug, Deserialize, Clone)]
+pub struct EditOrderPreview {
+    /// Reasons the edit would fail, empty if it would be accepted
+    #[serde(default)]
+    pub errors: Vec<EditOrderError>,
+    #[serde(default)]
+    pub slippage: String,
+    #[serde(default)]
+    pub order_total: String,
+    #[serde(default)]
+    pub commission_total: String,
+    #[serde(default)]
+    pub quote_size: String,
+    #[serde(default)]
+    pub base_size: String,
+    #[serde(default)]
+    pub best_bid: String,
+    #[serde(default)]
+    pub best_ask: String,
+    #[serde(default)]
+    pub average_filled_price: String,
+}
+
+/// Body of `POST /brokerage/orders/batch_cancel`
+#[derive(Debug, Deserialize, Clone)]
+pub struct CancelOrdersResponse {
+    pub results: Vec<CancelOrderResult>,
+}
+
+/// Outcome of cancelling one order
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CancelOrderResult {
+    pub success: bool,
+    /// Only meaningful when `success` is false, e.g. `"UNKNOWN_CANCEL_ORDER"`
+    #[serde(default)]
+    pub failure_reason: String,
+    pub order_id: String,
+}
+
+/// Outcome of `AdvancedTradeRESTClient::cancel_all`, one result per open order found
+#[derive(Debug, Serialize, Clone, Default, PartialEq)]
+pub struct CancelReport {
+    pub results: Vec<CancelOrderResult>,
+}
+
+impl CancelReport {
+    /// `order_id`s that were cancelled
+    pub fn cancelled(&self) -> Vec<&str> {
+        self.results.iter().filter(|r| r.success).map(|r| r.order_id.as_str()).collect()
+    }
+
+    /// Results of the orders that are still open
+    pub fn failed(&self) -> Vec<&CancelOrderResult> {
+        self.results.iter().filter(|r| !r.success).collect()
+    }
+}
+
+/// Body of `GET /brokerage/best_bid_ask`
+#[derive(Debug, Deserialize, Clone)]
+pub struct BestBidAsk {
+    /// Top of book of each product requested
+    pub pricebooks: Vec<PriceBook>,
+}
+
+/// Body of `GET /brokerage/product_book`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProductBookResponse {
+    pub pricebook: PriceBook,
+}
+
+/// REST snapshot of the book of a product
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PriceBook {
+    pub product_id: String,
+    /// Highest first
+    pub bids: Vec<PriceLevel>,
+    /// Lowest first
+    pub asks: Vec<PriceLevel>,
+    #[serde(default)]
+    pub time: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PriceLevel {
+    pub price: String,
+    pub size: String,
+}
+
+impl PriceBook {
+    /// Levels as `(side, price, size)` for `OrderBook::apply_snapshot`
+    pub fn levels(&self) -> Result<Vec<(&'static str, f64, f64)>, std::num::ParseFloatError> {
+        let bids = self.bids.iter().map(|l| Ok(("bid", l.price.parse()?, l.size.parse()?)));
+        let asks = self.asks.iter().map(|l| Ok(("offer", l.price.parse()?, l.size.parse()?)));
+        bids.chain(asks).collect()
+    }
+
+    /// Best bid as `(price, size)`, `None` if the side is empty or malformed
+    pub fn best_bid(&self) -> Option<(f64, f64)> {
+        let level = self.bids.first()?;
+        Some((level.price.parse().ok()?, level.size.parse().ok()?))
+    }
+
+    /// Best ask as `(price, size)`, `None` if the side is empty or malformed
+    pub fn best_ask(&self) -> Option<(f64, f64)> {
+        let level = self.asks.first()?;
+        Some((level.price.parse().ok()?, level.size.parse().ok()?))
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_bid()?.0 + self.best_ask()?.0) / 2.0)
+    }
+}
+
+/// Body of `GET /brokerage/transaction_summary`
+#[derive(Debug, Deserialize, Clone)]
+pub struct TransactionSummary {
+    /// Volume in USD over the last 30 days, which decides the fee tier
+    pub total_volume: f64,
+    /// Fees paid in USD over the last 30 days
+    pub total_fees: f64,
+    pub fee_tier: FeeTier,
+    #[serde(default)]
+    pub advanced_trade_only_volume: Option<f64>,
+    #[serde(default)]
+    pub advanced_trade_only_fees: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FeeTier {
+    /// e.g. `"$10K-$50K"`
+    pub pricing_tier: String,
+    #[serde(default)]
+    pub usd_from: String,
+    #[serde(default)]
+    pub usd_to: String,
+    /// Fraction of notional charged on orders that take liquidity e.g. `"0.004"`
+    pub taker_fee_rate: String,
+    /// Fraction of notional charged on orders that rest on the book
+    pub maker_fee_rate: String,
+}
+
+/// Body of `GET /brokerage/cfm/balance_summary`
+#[derive(Debug, Deserialize, Clone)]
+pub struct FuturesBalanceSummaryResponse {
+    pub balance_summary: FuturesBalanceSummary,
+}
+
+/// Balances and margin of the futures commission merchant (FCM) account
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct FuturesBalanceSummary {
+    pub futures_buying_power: Balance,
+    /// USD across the spot (CBI) and futures (CFM) accounts
+    pub total_usd_balance: Balance,
+    pub cbi_usd_balance: Balance,
+    pub cfm_usd_balance: Balance,
+    pub total_open_orders_hold_amount: Balance,
+    pub unrealized_pnl: Balance,
+    pub daily_realized_pnl: Balance,
+    /// Margin held by open positions
+    pub initial_margin: Balance,
+    pub available_margin: Balance,
+    /// Balance below which positions are liquidated
+    pub liquidation_threshold: Balance,
+    pub liquidation_buffer_amount: Balance,
+    pub liquidation_buffer_percentage: String,
+}
+
+/// Status of a convert trade, from quote to settlement
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ConvertTradeStatus {
+    #[serde(rename = "TRADE_STATUS_UNSPECIFIED")]
+    Unspecified,
+    /// Quoted, waiting to be committed before the quote expires
+    #[serde(rename = "TRADE_STATUS_CREATED")]
+    Created,
+    /// Committed, settling
+    #[serde(rename = "TRADE_STATUS_STARTED")]
+    Started,
+    #[serde(rename = "TRADE_STATUS_COMPLETED")]
+    Completed,
+    #[serde(rename = "TRADE_STATUS_CANCELED")]
+    Canceled,
+    #[serde(other)]
+    Unknown,
+}
+
+impl ConvertTradeStatus {
+    /// Whether the trade settled or was cancelled and will not change anymore
+    pub fn is_terminal(&self) -> bool {
+        matches!(self, ConvertTradeStatus::Completed | ConvertTradeStatus::Canceled)
+    }
+}
+
+/// Body of the convert quote, commit and trade endpoints
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConvertTradeResponse {
+    pub trade: ConvertTrade,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConvertTrade {
+    pub id: String,
+    pub status: ConvertTradeStatus,
+    /// Amount requested in the quote, in `source_currency`
+    pub user_entered_amount: Balance,
+    /// Amount received, in `target_currency`
+    pub amount: Balance,
+    /// Amount converted before fees, in `source_currency`
+    pub subtotal: Balance,
+    /// Amount debited including fees, in `source_currency`
+    pub total: Balance,
+    #[serde(default)]
+    pub total_fee: Option<ConvertFee>,
+    #[serde(default)]
+    pub exchange_rate: Option<Balance>,
+    pub source_currency: String,
+    pub target_currency: String,
+    #[serde(default)]
+    pub cancellation_reason: Option<serde_json::Value>,
+}
+
+impl ConvertTrade {
+    /// Units of `target_currency` received per unit of `source_currency` debited, fees included
+    pub fn rate(&self) -> Option<f64> {
+        let received: f64 = self.amount.value.parse().ok()?;
+        let debited: f64 = self.total.value.parse().ok()?;
+        (debited > 0.0).then(|| received / debited)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ConvertFee {
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub amount: Balance,
+}
+
+/// Body of `GET /brokerage/time`
+#[derive(Debug, Deserialize, Clone)]
+pub struct ServerTime {
+    /// e.g. `"2023-05-10T14:12:01.341Z"`
+    pub iso: String,
+    #[serde(rename = "epochSeconds")]
+    pub epoch_seconds: String,
+    #[serde(rename = "epochMillis")]
+    pub epoch_millis: String,
+}
+
+impl ServerTime {
+    /// Server time at millisecond precision, `None` if the response is malformed
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        match self.epoch_millis.parse::<i64>() {
+            Ok(millis) => DateTime::from_timestamp_millis(millis),
+            Err(_) => DateTime::parse_from_rfc3339(&self.iso).ok().map(|t| t.with_timezone(&Utc)),
+        }
+    }
 }
 
 /*
 WEBSOCKETS - Models for handling websocket messages
 
-TODO: For some reason all the UpdateTickers are being classified as SnapshotTickers during run time
-    I should look into fixing this to ensure type safety and proper API usage
+Events are matched in declaration order. Snapshot and update events share the same shape, so
+their `type` field is checked during deserialization to tell them apart.
 */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
@@ -46,9 +879,33 @@ pub enum WebsocketEvent {
     SnapshotEvent(SnapshotMessage),
     UpdateEvent(UpdateMessage),
     SubscriptionEvent(SubscriptionMessage),
+    TradesEvent(MarketTradesMessage),
+    CandlesEvent(CandlesMessage),
+    Level2Event(Level2Message),
+    UserEvent(UserMessage),
+    FuturesBalanceEvent(Box<FuturesBalanceMessage>),
+    HeartbeatEvent(HeartbeatMessage),
     Unkown,
 }
 
+/// Accepts only `"snapshot"` for the `type` field
+fn snapshot_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    expect_type(deserializer, "snapshot")
+}
+
+/// Accepts only `"update"` for the `type` field
+fn update_type<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    expect_type(deserializer, "update")
+}
+
+fn expect_type<'de, D: Deserializer<'de>>(deserializer: D, expected: &str) -> Result<String, D::Error> {
+    let msg_type = String::deserialize(deserializer)?;
+    if msg_type != expected {
+        return Err(de::Error::custom(format!("expected type {}, found {}", expected, msg_type)));
+    }
+    Ok(msg_type)
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotTicker {
     #[serde(rename = "type")]
@@ -61,11 +918,19 @@ pub struct SnapshotTicker {
     pub low_52_w: String,
     pub high_52_w: String,
     pub price_percent_chg_24_h: String,
+    #[serde(default)]
+    pub best_bid: Option<String>,
+    #[serde(default)]
+    pub best_bid_quantity: Option<String>,
+    #[serde(default)]
+    pub best_ask: Option<String>,
+    #[serde(default)]
+    pub best_ask_quantity: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotMessage {
-    #[serde(rename = "type")]
+    #[serde(rename = "type", deserialize_with = "snapshot_type")]
     pub msg_type: String,
     pub tickers: Vec<SnapshotTicker>,
 }
@@ -82,25 +947,190 @@ pub struct UpdateTicker {
     pub low_52_w: String,
     pub high_52_w: String,
     pub price_percent_chg_24_h: String,
+    #[serde(default)]
+    pub best_bid: Option<String>,
+    #[serde(default)]
+    pub best_bid_quantity: Option<String>,
+    #[serde(default)]
+    pub best_ask: Option<String>,
+    #[serde(default)]
+    pub best_ask_quantity: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct UpdateMessage {
+    #[serde(rename = "type", deserialize_with = "update_type")]
+    pub msg_type: String,
+    pub tickers: Vec<UpdateTicker>,
+}
+
+/// Event of the `market_trades` channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MarketTradesMessage {
     #[serde(rename = "type")]
     pub msg_type: String,
-    pub tickers: Vec<SnapshotTicker>,
+    pub trades: Vec<MarketTrade>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct SubscriptionMessage {
-    pub subscriptions: Subscriptions,
+pub struct MarketTrade {
+    pub trade_id: String,
+    pub product_id: String,
+    pub price: String,
+    pub size: String,
+    /// Taker side, `"BUY"` or `"SELL"`
+    pub side: String,
+    pub time: String,
+}
+
+/// Event of the `candles` channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CandlesMessage {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub candles: Vec<Candle>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Candle {
+    /// Bucket start in seconds since the Unix epoch
+    pub start: String,
+    pub high: String,
+    pub low: String,
+    pub open: String,
+    pub close: String,
+    pub volume: String,
+    pub product_id: String,
+}
+
+/// Event of the `l2_data` channel, the response to a `level2` subscription
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Level2Message {
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub product_id: String,
+    pub updates: Vec<Level2Update>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Level2Update {
+    /// `"bid"` or `"offer"`
+    pub side: String,
+    pub event_time: String,
+    pub price_level: String,
+    /// New resting size at the level, `"0"` removes the level
+    pub new_quantity: String,
+}
+
+/// Event of the `user` channel, the state of the account's orders after a change
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserMessage {
+    /// `"snapshot"` with every open order on subscribe, then `"update"` per change
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub orders: Vec<UserOrder>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UserOrder {
+    pub order_id: String,
+    #[serde(default)]
+    pub client_order_id: String,
+    /// Base size filled so far
+    pub cumulative_quantity: String,
+    #[serde(default)]
+    pub leaves_quantity: String,
+    /// Average price of `cumulative_quantity`
+    pub avg_price: String,
+    #[serde(default)]
+    pub total_fees: String,
+    /// `"PENDING"`, `"OPEN"`, `"FILLED"`, `"CANCEL_QUEUED"`, `"CANCELLED"`, `"EXPIRED"` or `"FAILED"`
+    pub status: String,
+    pub product_id: String,
+    #[serde(default)]
+    pub creation_time: String,
+    /// `"BUY"` or `"SELL"`
+    pub order_side: String,
+    #[serde(default)]
+    pub order_type: String,
+}
+
+/// Event of the `futures_balance_summary` channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FuturesBalanceMessage {
+    /// `"snapshot"` on subscribe, then `"update"` per change
+    #[serde(rename = "type")]
+    pub msg_type: String,
+    pub fcm_balance_summary: FcmBalanceSummary,
+}
+
+/// Same balances as `FuturesBalanceSummary`, as plain USD amounts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FcmBalanceSummary {
+    pub futures_buying_power: String,
+    pub total_usd_balance: String,
+    pub cbi_usd_balance: String,
+    pub cfm_usd_balance: String,
+    pub total_open_orders_hold_amount: String,
+    pub unrealized_pnl: String,
+    pub daily_realized_pnl: String,
+    pub initial_margin: String,
+    pub available_margin: String,
+    pub liquidation_threshold: String,
+    pub liquidation_buffer_amount: String,
+    pub liquidation_buffer_percentage: String,
+}
+
+impl From<&FuturesBalanceSummary> for FcmBalanceSummary {
+    fn from(summary: &FuturesBalanceSummary) -> Self {
+        FcmBalanceSummary {
+            futures_buying_power: summary.futures_buying_power.value.clone(),
+            total_usd_balance: summary.total_usd_balance.value.clone(),
+            cbi_usd_balance: summary.cbi_usd_balance.value.clone(),
+            cfm_usd_balance: summary.cfm_usd_balance.value.clone(),
+            total_open_orders_hold_amount: summary.total_open_orders_hold_amount.value.clone(),
+            unrealized_pnl: summary.unrealized_pnl.value.clone(),
+            daily_realized_pnl: summary.daily_realized_pnl.value.clone(),
+            initial_margin: summary.initial_margin.value.clone(),
+            available_margin: summary.available_margin.value.clone(),
+            liquidation_threshold: summary.liquidation_threshold.value.clone(),
+            liquidation_buffer_amount: summary.liquidation_buffer_amount.value.clone(),
+            liquidation_buffer_percentage: summary.liquidation_buffer_percentage.clone(),
+        }
+    }
+}
+
+impl FcmBalanceSummary {
+    /// Margin left for new positions, `None` if the amount is malformed
+    pub fn available_margin(&self) -> Option<f64> {
+        self.available_margin.parse().ok()
+    }
+
+    pub fn unrealized_pnl(&self) -> Option<f64> {
+        self.unrealized_pnl.parse().ok()
+    }
+
+    /// Balance above `liquidation_threshold`, positions are liquidated when it reaches zero
+    pub fn liquidation_buffer(&self) -> Option<f64> {
+        self.liquidation_buffer_amount.parse().ok()
+    }
+}
+
+/// Event of the `heartbeats` channel
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HeartbeatMessage {
+    pub current_time: String,
+    pub heartbeat_counter: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Subscriptions {
-    pub ticker: Vec<String>,
+pub struct SubscriptionMessage {
+    pub subscriptions: Subscriptions,
 }
 
+/// Products subscribed to per channel, e.g. `{"ticker": ["ETH-USD"], "level2": ["BTC-USD"]}`
+pub type Subscriptions = BTreeMap<String, Vec<String>>;
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct GenericMessage {
     pub channel: String,
@@ -110,6 +1140,35 @@ pub struct GenericMessage {
     pub events: Vec<WebsocketEvent>,
 }
 
+impl GenericMessage {
+    /// Products the events of the message refer to, sorted and without duplicates
+    pub fn product_ids(&self) -> BTreeSet<&str> {
+        let mut product_ids = BTreeSet::new();
+        for event in &self.events {
+            match event {
+                WebsocketEvent::SnapshotEvent(snapshot) => product_ids.extend(snapshot.tickers.iter().map(|t| t.product_id.as_str())),
+                WebsocketEvent::UpdateEvent(update) => product_ids.extend(update.tickers.iter().map(|t| t.product_id.as_str())),
+                WebsocketEvent::TradesEvent(trades) => product_ids.extend(trades.trades.iter().map(|t| t.product_id.as_str())),
+                WebsocketEvent::CandlesEvent(candles) => product_ids.extend(candles.candles.iter().map(|c| c.product_id.as_str())),
+                WebsocketEvent::Level2Event(level2) => {
+                    product_ids.insert(level2.product_id.as_str());
+                },
+                WebsocketEvent::UserEvent(user) => product_ids.extend(user.orders.iter().map(|o| o.product_id.as_str())),
+                WebsocketEvent::SubscriptionEvent(_)
+                | WebsocketEvent::FuturesBalanceEvent(_)
+                | WebsocketEvent::HeartbeatEvent(_)
+                | WebsocketEvent::Unkown => (),
+            }
+        }
+        product_ids
+    }
+
+    /// `timestamp` as sent by the exchange, `None` if it is empty or not RFC 3339
+    pub fn exchange_time(&self) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc3339(&self.timestamp).ok().map(|t| t.with_timezone(&Utc))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ErrorMesage {
     #[serde(rename = "type")]
@@ -118,7 +1177,7 @@ pub struct ErrorMesage {
 }
 
 // actually not sure if this is correct impl
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChannelSubscriptionMessage {
     #[serde(rename = "type")]
     pub msg_type: String,
@@ -127,4 +1186,88 @@ pub struct ChannelSubscriptionMessage {
     pub api_key: String,
     pub timestamp: String,
     pub signature: String,
-}
\ No newline at end of file
+}
+
+/// Prints `api_key` and `signature` as `[redacted]` so the credentials never end up in logs
+impl fmt::Debug for ChannelSubscriptionMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelSubscriptionMessage")
+            .field("msg_type", &self.msg_type)
+            .field("product_ids", &self.product_ids)
+            .field("channel", &self.channel)
+            .field("api_key", &"[redacted]")
+            .field("timestamp", &self.timestamp)
+            .field("signature", &"[redacted]")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn events(frame: &str) -> Vec<WebsocketEvent> {
+        serde_json::from_str::<GenericMessage>(frame).unwrap().events
+    }
+
+    #[test]
+    fn tells_ticker_snapshots_from_updates() {
+        let ticker = r#"{"type":"ticker","product_id":"ETH-USD","price":"1","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"1"}"#;
+        let frame = |msg_type: &str| format!(
+            r#"{{"channel":"ticker","client_id":"","timestamp":"","sequence_num":0,"events":[{{"type":"{}","tickers":[{}]}}]}}"#,
+            msg_type, ticker
+        );
+
+        assert!(matches!(events(&frame("snapshot"))[0], WebsocketEvent::SnapshotEvent(_)));
+        assert!(matches!(events(&frame("update"))[0], WebsocketEvent::UpdateEvent(_)));
+    }
+
+    #[test]
+    fn parses_level2_subscription_and_heartbeat_events() {
+        let level2 = events(r#"{"channel":"l2_data","client_id":"","timestamp":"","sequence_num":0,"events":[{"type":"snapshot","product_id":"BTC-USD","updates":[{"side":"bid","event_time":"1970-01-01T00:00:00Z","price_level":"21921.73","new_quantity":"0.06317902"}]}]}"#);
+        match &level2[0] {
+            WebsocketEvent::Level2Event(book) => assert_eq!(book.updates[0].price_level, "21921.73"),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let ack = events(r#"{"channel":"subscriptions","client_id":"","timestamp":"","sequence_num":0,"events":[{"subscriptions":{"level2":["BTC-USD"],"ticker":["ETH-USD","BTC-USD"]}}]}"#);
+        match &ack[0] {
+            WebsocketEvent::SubscriptionEvent(ack) => assert_eq!(ack.subscriptions["ticker"], vec!["ETH-USD", "BTC-USD"]),
+            e => panic!("unexpected event {:?}", e),
+        }
+
+        let heartbeat = events(r#"{"channel":"heartbeats","client_id":"","timestamp":"","sequence_num":0,"events":[{"current_time":"2023-06-23 20:31:56.121961769 +0000 UTC","heartbeat_counter":3049}]}"#);
+        assert!(matches!(heartbeat[0], WebsocketEvent::HeartbeatEvent(_)));
+    }
+
+    #[test]
+    fn parses_futures_products_and_balance_events() {
+        let product: ProductData = serde_json::from_str(r#"{"product_id":"BIP-20DEC30-CDE","product_type":"FUTURE","base_currency_id":"","base_increment":"1","base_max_size":"100000","base_min_size":"1","quote_currency_id":"USD","quote_increment":"5","quote_max_size":"","quote_min_size":"","status":"","trading_disabled":false,"future_product_details":{"venue":"cde","contract_code":"BIP","contract_expiry":"2030-12-20T16:00:00Z","contract_size":"0.01","contract_root_unit":"BTC","contract_expiry_type":"PERPETUAL","perpetual_details":{"open_interest":"1520","funding_rate":"0.000004","funding_time":"2024-06-01T12:00:00Z","max_leverage":"10"}}}"#).unwrap();
+        assert!(product.is_future() && product.is_perpetual());
+        assert_eq!(product.contract_expiry().map(|t| t.to_rfc3339()), Some("2030-12-20T16:00:00+00:00".to_string()));
+        assert_eq!((product.contract_size(), product.funding_rate(), product.open_interest()), (Some(0.01), Some(0.000004), Some(1520.0)));
+
+        let balance = events(r#"{"channel":"futures_balance_summary","client_id":"","timestamp":"","sequence_num":0,"events":[{"type":"snapshot","fcm_balance_summary":{"futures_buying_power":"100.00","total_usd_balance":"200.00","cbi_usd_balance":"300.00","cfm_usd_balance":"400.00","total_open_orders_hold_amount":"500.00","unrealized_pnl":"600.00","daily_realized_pnl":"0","initial_margin":"700.00","available_margin":"800.00","liquidation_threshold":"900.00","liquidation_buffer_amount":"1000.00","liquidation_buffer_percentage":"1000"}}]}"#);
+        match &balance[0] {
+            WebsocketEvent::FuturesBalanceEvent(event) => assert_eq!(event.fcm_balance_summary.available_margin, "800.00"),
+            e => panic!("unexpected event {:?}", e),
+        }
+    }
+
+    #[test]
+    fn redacts_credentials_of_subscription_messages() {
+        let msg = ChannelSubscriptionMessage {
+            msg_type: "subscribe".to_string(),
+            product_ids: vec!["ETH-USD".to_string()],
+            channel: "ticker".to_string(),
+            api_key: "organizations/secret-key".to_string(),
+            timestamp: "1675836190".to_string(),
+            signature: "deadbeef".to_string(),
+        };
+
+        let debug = format!("{:?}", msg);
+        assert!(!debug.contains("secret-key") && !debug.contains("deadbeef"), "{}", debug);
+        assert!(debug.contains("ETH-USD") && debug.contains("[redacted]"));
+        assert!(serde_json::to_string(&msg).unwrap().contains("deadbeef"));
+    }
+}
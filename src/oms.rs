@@ -0,0 +1,586 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::models::{GenericMessage, OrderRequest, OrderSide, UserOrder, WebsocketEvent};
+use crate::risk::RiskEngine;
+use crate::sink::MessageSink;
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Default number of times `OrderManager::submit` sends an order before giving up
+pub const DEFAULT_SUBMIT_ATTEMPTS: u32 = 3;
+/// Default wait between two attempts to send an order
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Sizes below this are treated as zero, to absorb rounding of the decimal strings
+const EPSILON: f64 = 1e-12;
+
+/*
+OMS - Local order management
+
+Tracks every order by `client_order_id` from the moment it is submitted, through the REST
+acknowledgement and the `user` channel updates, to a terminal state. Fills are booked into
+per-product positions with average-cost accounting as they are reported.
+*/
+
+/// Lifecycle of an order as tracked by `OrderManager`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderState {
+    /// Submitted but not acknowledged by Coinbase yet
+    Pending,
+    Open,
+    PartiallyFilled,
+    Filled,
+    /// Cancelled or expired, possibly after partial fills
+    Cancelled,
+    Rejected,
+}
+
+impl OrderState {
+    /// Whether the order can no longer change
+    pub fn is_terminal(self) -> bool {
+        matches!(self, OrderState::Filled | OrderState::Cancelled | OrderState::Rejected)
+    }
+
+    /// Whether an order in this state may move to `next`
+    ///
+    /// Orders only move forward, so a late REST acknowledgement cannot reopen an order the
+    /// `user` channel already reported as filled.
+    pub fn can_transition_to(self, next: OrderState) -> bool {
+        match (self, next) {
+            (current, next) if current == next => true,
+            (OrderState::Pending, _) => true,
+            (OrderState::Open, next) => next != OrderState::Pending,
+            (OrderState::PartiallyFilled, next) => matches!(next, OrderState::Filled | OrderState::Cancelled),
+            _ => false,
+        }
+    }
+
+    /// State of an order Coinbase reports with `status` after `filled_size` was filled
+    ///
+    /// `None` for statuses that do not change the state, like `"CANCEL_QUEUED"`.
+    pub fn from_exchange(status: &str, filled_size: f64) -> Option<OrderState> {
+        match status {
+            "PENDING" => Some(OrderState::Pending),
+            "OPEN" if filled_size > EPSILON => Some(OrderState::PartiallyFilled),
+            "OPEN" => Some(OrderState::Open),
+            "FILLED" => Some(OrderState::Filled),
+            "CANCELLED" | "EXPIRED" => Some(OrderState::Cancelled),
+            "FAILED" => Some(OrderState::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// An order as known to `OrderManager`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ManagedOrder {
+    pub client_order_id: String,
+    /// Assigned by Coinbase once the order is acknowledged
+    pub order_id: Option<String>,
+    pub product_id: String,
+    pub side: OrderSide,
+    pub state: OrderState,
+    /// Base size ordered, unknown for market orders sized in the quote currency
+    pub base_size: Option<f64>,
+    pub limit_price: Option<f64>,
+    /// Base size filled so far
+    pub filled_size: f64,
+    pub average_filled_price: f64,
+    pub fees: f64,
+    /// Why Coinbase refused the order, e.g. `"INVALID_PRODUCT_ID"`
+    pub reject_reason: Option<String>,
+    /// Number of times the order was sent to Coinbase
+    pub attempts: u32,
+}
+
+impl ManagedOrder {
+    fn new(client_order_id: &str, product_id: &str, side: OrderSide) -> ManagedOrder {
+        ManagedOrder {
+            client_order_id: client_order_id.to_string(),
+            order_id: None,
+            product_id: product_id.to_string(),
+            side,
+            state: OrderState::Pending,
+            base_size: None,
+            limit_price: None,
+            filled_size: 0.0,
+            average_filled_price: 0.0,
+            fees: 0.0,
+            reject_reason: None,
+            attempts: 0,
+        }
+    }
+
+    fn from_request(order: &OrderRequest) -> ManagedOrder {
+        let parse = |value: Option<&str>| value.and_then(|v| v.parse::<f64>().ok());
+        ManagedOrder {
+            base_size: parse(order.order_configuration.base_size()),
+            limit_price: parse(order.order_configuration.limit_price()),
+            ..ManagedOrder::new(&order.client_order_id, &order.product_id, order.side)
+        }
+    }
+
+    /// Whether the order is, or may still become, resting on the book
+    pub fn is_active(&self) -> bool {
+        !self.state.is_terminal()
+    }
+
+    /// Base size still to be filled, `None` if the ordered size is unknown
+    pub fn remaining_size(&self) -> Option<f64> {
+        self.base_size.map(|size| (size - self.filled_size).max(0.0))
+    }
+
+    /// Moves to `next` if the state machine allows it, returning whether it did
+    fn transition(&mut self, next: OrderState) -> bool {
+        if !self.state.can_transition_to(next) {
+            debug!("Ignoring {:?} for order {} in state {:?}", next, self.client_order_id, self.state);
+            return false;
+        }
+        if next != self.state {
+            info!("Order {} moved from {:?} to {:?}", self.client_order_id, self.state, next);
+        }
+        self.state = next;
+        true
+    }
+}
+
+/// Net holding of one product, booked at average cost
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Position {
+    pub product_id: String,
+    /// Base size held, negative when short
+    pub size: f64,
+    /// Average price `size` was built at, 0 when flat
+    pub average_cost: f64,
+    /// Profit of the closed part of the position in the quote currency, before fees
+    pub realized_pnl: f64,
+    /// Fees paid in the quote currency
+    pub fees: f64,
+    /// UTC day of the last fill, which `day_realized_pnl` and `day_fees` cover
+    pub day: Option<NaiveDate>,
+    /// Part of `realized_pnl` realized on `day`
+    pub day_realized_pnl: f64,
+    /// Part of `fees` paid on `day`
+    pub day_fees: f64,
+}
+
+impl Position {
+    /// Books a fill of `size` base at `price`, now
+    ///
+    /// Adding to the position moves the average cost, reducing it realizes the difference to
+    /// the average cost, and crossing zero opens the remainder at `price`.
+    pub fn apply_fill(&mut self, side: OrderSide, size: f64, price: f64, fee: f64) {
+        self.apply_fill_at(side, size, price, fee, Utc::now());
+    }
+
+    /// Same as `apply_fill` for a fill booked at `time`, which decides the day it counts towards
+    pub fn apply_fill_at(&mut self, side: OrderSide, size: f64, price: f64, fee: f64, time: DateTime<Utc>) {
+        let realized_before = self.realized_pnl;
+        self.book(side, size, price, fee);
+
+        let day = time.date_naive();
+        if self.day != Some(day) {
+            self.day = Some(day);
+            self.day_realized_pnl = 0.0;
+            self.day_fees = 0.0;
+        }
+        self.day_realized_pnl += self.realized_pnl - realized_before;
+        self.day_fees += fee;
+    }
+
+    /// Realized profit after fees of `day`, 0 unless `day` is the day of the last fill
+    pub fn daily_pnl(&self, day: NaiveDate) -> f64 {
+        match self.day == Some(day) {
+            true => self.day_realized_pnl - self.day_fees,
+            false => 0.0,
+        }
+    }
+
+    fn book(&mut self, side: OrderSide, size: f64, price: f64, fee: f64) {
+        let signed = match side {
+            OrderSide::Buy => size,
+            OrderSide::Sell => -size,
+        };
+        self.fees += fee;
+
+        if self.size.abs() < EPSILON || self.size.signum() == signed.signum() {
+            self.average_cost = (self.average_cost * self.size.abs() + price * size) / (self.size.abs() + size);
+            self.size += signed;
+            return;
+        }
+
+        let closed = size.min(self.size.abs());
+        self.realized_pnl += closed * (price - self.average_cost) * self.size.signum();
+        let previous = self.size;
+        self.size += signed;
+        if self.size.abs() < EPSILON {
+            self.size = 0.0;
+            self.average_cost = 0.0;
+        } else if self.size.signum() != previous.signum() {
+            self.average_cost = price;
+        }
+    }
+}
+
+/// Orders and positions of an `OrderManager` at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct OmsSnapshot {
+    /// By `client_order_id`, or `order_id` for orders placed outside the manager without one
+    pub orders: BTreeMap<String, ManagedOrder>,
+    /// By `product_id`
+    pub positions: BTreeMap<String, Position>,
+}
+
+/// Settings for `OrderManager`
+#[derive(Debug, Clone)]
+pub struct OmsConfig {
+    /// Times `submit` sends an order before giving up, at least 1
+    pub submit_attempts: u32,
+    pub retry_delay: Duration,
+}
+
+impl Default for OmsConfig {
+    fn default() -> Self {
+        OmsConfig {
+            submit_attempts: DEFAULT_SUBMIT_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+#[derive(Default)]
+struct OmsState {
+    orders: BTreeMap<String, ManagedOrder>,
+    positions: BTreeMap<String, Position>,
+}
+
+impl OmsState {
+    /// Applies one order of a `user` channel event, booking any new fill
+    fn apply_update(&mut self, update: &UserOrder) {
+        let parse = |value: &str| if value.is_empty() { Ok(0.0) } else { value.parse::<f64>() };
+        let (filled_size, average_price, fees) = match (
+            parse(&update.cumulative_quantity),
+            parse(&update.avg_price),
+            parse(&update.total_fees),
+        ) {
+            (Ok(filled_size), Ok(average_price), Ok(fees)) => (filled_size, average_price, fees),
+            _ => {
+                warn!("Ignoring update of order {} with invalid quantities: {:?}", update.order_id, update);
+                return;
+            }
+        };
+        let side = match update.order_side.as_str() {
+            "BUY" => OrderSide::Buy,
+            "SELL" => OrderSide::Sell,
+            side => {
+                warn!("Ignoring update of order {} with unknown side {:?}", update.order_id, side);
+                return;
+            }
+        };
+
+        let key = if update.client_order_id.is_empty() { &update.order_id } else { &update.client_order_id };
+        let order = self
+            .orders
+            .entry(key.clone())
+            .or_insert_with(|| ManagedOrder::new(key, &update.product_id, side));
+        if order.order_id.is_none() && !update.order_id.is_empty() {
+            order.order_id = Some(update.order_id.clone());
+        }
+        if order.base_size.is_none() {
+            order.base_size = update.leaves_quantity.parse::<f64>().ok().map(|leaves| leaves + filled_size);
+        }
+        if filled_size < order.filled_size - EPSILON {
+            debug!("Ignoring stale update of order {} filled {} of {}", key, filled_size, order.filled_size);
+            return;
+        }
+
+        // fills are booked even if the state can no longer change, they happened either way
+        let fill = filled_size - order.filled_size;
+        if fill > EPSILON {
+            let price = (average_price * filled_size - order.average_filled_price * order.filled_size) / fill;
+            let position = self.positions.entry(order.product_id.clone()).or_insert_with(|| Position {
+                product_id: order.product_id.clone(),
+                ..Position::default()
+            });
+            position.apply_fill(order.side, fill, price, (fees - order.fees).max(0.0));
+            order.filled_size = filled_size;
+            order.average_filled_price = average_price;
+            order.fees = fees;
+        }
+        if let Some(next) = OrderState::from_exchange(&update.status, filled_size) {
+            order.transition(next);
+        }
+    }
+}
+
+/// Tracks orders from submission to completion and the positions their fills build up
+///
+/// Clones share the same state. Register a clone as a sink of a feed subscribed to the `user`
+/// channel to follow fills and cancellations as they happen.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+/// use rs_coinbase_pairs_handler::advanced_trade_websocket::{AdvancedTradeWebSockets, SubscribeProducts};
+/// use rs_coinbase_pairs_handler::oms::{OmsConfig, OrderManager};
+/// use std::sync::Arc;
+///
+/// let client = Arc::new(AdvancedTradeRESTClient::new("https://api.coinbase.com/api/v3"));
+/// let oms = OrderManager::new(client, OmsConfig::default());
+/// let mut feed = AdvancedTradeWebSockets::new(
+///     vec!["user".to_string()],
+///     SubscribeProducts::Custom(vec!["ETH-USD".to_string()]),
+/// );
+/// feed.add_sink(Box::new(oms.clone()));
+/// ```
+#[derive(Clone)]
+pub struct OrderManager {
+    client: Arc<AdvancedTradeRESTClient>,
+    config: OmsConfig,
+    risk: Option<RiskEngine>,
+    state: Arc<Mutex<OmsState>>,
+}
+
+impl OrderManager {
+    pub fn new(client: Arc<AdvancedTradeRESTClient>, config: OmsConfig) -> OrderManager {
+        OrderManager {
+            client,
+            config,
+            risk: None,
+            state: Arc::new(Mutex::new(OmsState::default())),
+        }
+    }
+
+    /// Checks every new order against `risk` before it is sent
+    pub fn with_risk(mut self, risk: RiskEngine) -> Self {
+        self.risk = Some(risk);
+        self
+    }
+
+    /// Places `order`, retrying with the same `client_order_id` until Coinbase answers
+    ///
+    /// Submitting a `client_order_id` that was already acknowledged returns the tracked order
+    /// without sending anything. Coinbase deduplicates by `client_order_id` as well, so a retry
+    /// after a lost response cannot place the order twice.
+    ///
+    /// New orders are checked against the `RiskEngine` of `with_risk` first, under the same lock
+    /// that adds them, so concurrent submissions cannot pass the limits together.
+    ///
+    /// # Returns
+    ///
+    /// `Result<ManagedOrder>` - the order, `Rejected` if Coinbase refused it. A `RiskRejection`
+    /// error if it breaches a limit, in which case it is not tracked. Any other error if every
+    /// attempt failed, in which case the order stays `Pending` and can be submitted again.
+    pub async fn submit(&self, order: &OrderRequest) -> Result<ManagedOrder> {
+        if order.client_order_id.is_empty() {
+            bail!("Orders need a client_order_id to be tracked");
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            match state.orders.get(&order.client_order_id) {
+                Some(existing) if existing.order_id.is_some() || existing.state != OrderState::Pending => {
+                    return Ok(existing.clone());
+                },
+                Some(_) => (),
+                None => {
+                    if let Some(risk) = &self.risk {
+                        let snapshot = OmsSnapshot {
+                            orders: state.orders.clone(),
+                            positions: state.positions.clone(),
+                        };
+                        risk.check(order, &snapshot)?;
+                    }
+                    state.orders.insert(
+                        order.client_order_id.clone(),
+                        ManagedOrder::from_request(order),
+                    );
+                },
+            }
+        }
+
+        let attempts = self.config.submit_attempts.max(1);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.update(&order.client_order_id, |managed| managed.attempts += 1);
+            match self.client.create_order(order).await {
+                Ok(response) if response.success => {
+                    return Ok(self.update(&order.client_order_id, |managed| {
+                        managed.order_id.get_or_insert(response.order_id.clone());
+                        managed.transition(OrderState::Open);
+                    }));
+                },
+                Ok(response) => {
+                    let reason = response.reason();
+                    warn!("Order {} was rejected: {}", order.client_order_id, reason);
+                    return Ok(self.update(&order.client_order_id, |managed| {
+                        if managed.transition(OrderState::Rejected) {
+                            managed.reject_reason = Some(reason);
+                        }
+                    }));
+                },
+                Err(e) if attempt < attempts => {
+                    warn!("Attempt {} of {} to place order {} failed: {}", attempt, attempts, order.client_order_id, e);
+                    tokio::time::sleep(self.config.retry_delay).await;
+                },
+                Err(e) => bail!("Unable to place order {} after {} attempts: {}", order.client_order_id, attempts, e),
+            }
+        }
+    }
+
+    /// Cancels an acknowledged order
+    ///
+    /// # Returns
+    ///
+    /// `Result<ManagedOrder>` - the `Cancelled` order, an error if it is unknown, not
+    /// acknowledged yet or Coinbase refused to cancel it
+    pub async fn cancel(&self, client_order_id: &str) -> Result<ManagedOrder> {
+        let order_id = match self.order(client_order_id) {
+            Some(ManagedOrder { order_id: Some(order_id), .. }) => order_id,
+            Some(_) => bail!("Order {} is not acknowledged yet", client_order_id),
+            None => bail!("Unknown order {}", client_order_id),
+        };
+
+        let results = self.client.cancel_orders(std::slice::from_ref(&order_id)).await?;
+        match results.iter().find(|r| r.order_id == order_id) {
+            Some(result) if result.success => Ok(self.update(client_order_id, |managed| {
+                managed.transition(OrderState::Cancelled);
+            })),
+            Some(result) => bail!("Unable to cancel order {}: {}", client_order_id, result.failure_reason),
+            None => bail!("No cancel result for order {}", client_order_id),
+        }
+    }
+
+    /// Applies the `user` channel events of `message`, ignoring any other channel
+    pub fn apply(&self, message: &GenericMessage) {
+        let mut state = self.state.lock().unwrap();
+        for event in &message.events {
+            if let WebsocketEvent::UserEvent(user) = event {
+                for update in &user.orders {
+                    state.apply_update(update);
+                }
+            }
+        }
+    }
+
+    pub fn order(&self, client_order_id: &str) -> Option<ManagedOrder> {
+        self.state.lock().unwrap().orders.get(client_order_id).cloned()
+    }
+
+    pub fn position(&self, product_id: &str) -> Option<Position> {
+        self.state.lock().unwrap().positions.get(product_id).cloned()
+    }
+
+    /// Copies every order and position under one lock, so fills are never half applied
+    pub fn snapshot(&self) -> OmsSnapshot {
+        let state = self.state.lock().unwrap();
+        OmsSnapshot {
+            orders: state.orders.clone(),
+            positions: state.positions.clone(),
+        }
+    }
+
+    /// Changes a tracked order and returns it as changed
+    fn update(&self, client_order_id: &str, change: impl FnOnce(&mut ManagedOrder)) -> ManagedOrder {
+        let mut state = self.state.lock().unwrap();
+        let order = state
+            .orders
+            .get_mut(client_order_id)
+            .expect("orders are never removed once submitted");
+        change(order);
+        order.clone()
+    }
+}
+
+impl MessageSink for OrderManager {
+    fn on_message(&mut self, _received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        self.apply(message);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(client_order_id: &str, status: &str, cumulative_quantity: &str, avg_price: &str) -> UserOrder {
+        UserOrder {
+            order_id: format!("{}-id", client_order_id),
+            client_order_id: client_order_id.to_string(),
+            cumulative_quantity: cumulative_quantity.to_string(),
+            leaves_quantity: String::new(),
+            avg_price: avg_price.to_string(),
+            total_fees: "0".to_string(),
+            status: status.to_string(),
+            product_id: "ETH-USD".to_string(),
+            creation_time: String::new(),
+            order_side: "BUY".to_string(),
+            order_type: "Limit".to_string(),
+        }
+    }
+
+    #[test]
+    fn orders_only_move_forward() {
+        assert!(OrderState::Pending.can_transition_to(OrderState::Filled));
+        assert!(OrderState::Open.can_transition_to(OrderState::PartiallyFilled));
+        assert!(OrderState::PartiallyFilled.can_transition_to(OrderState::Cancelled));
+        assert!(!OrderState::PartiallyFilled.can_transition_to(OrderState::Open));
+        assert!(!OrderState::Filled.can_transition_to(OrderState::Open));
+        assert!(!OrderState::Cancelled.can_transition_to(OrderState::Filled));
+        assert_eq!(OrderState::from_exchange("OPEN", 0.5), Some(OrderState::PartiallyFilled));
+        assert_eq!(OrderState::from_exchange("EXPIRED", 0.0), Some(OrderState::Cancelled));
+        assert_eq!(OrderState::from_exchange("CANCEL_QUEUED", 0.0), None);
+    }
+
+    #[test]
+    fn books_fills_at_average_cost() {
+        let mut position = Position::default();
+        position.apply_fill(OrderSide::Buy, 1.0, 100.0, 0.5);
+        position.apply_fill(OrderSide::Buy, 1.0, 200.0, 0.5);
+        assert_eq!((position.size, position.average_cost), (2.0, 150.0));
+
+        position.apply_fill(OrderSide::Sell, 1.5, 300.0, 0.0);
+        assert_eq!((position.size, position.average_cost, position.realized_pnl), (0.5, 150.0, 225.0));
+
+        position.apply_fill(OrderSide::Sell, 1.0, 100.0, 0.0);
+        assert_eq!((position.size, position.average_cost, position.realized_pnl), (-0.5, 100.0, 200.0));
+        assert_eq!(position.fees, 1.0);
+    }
+
+    #[test]
+    fn keeps_the_realized_pnl_of_the_day() {
+        let yesterday: DateTime<Utc> = "2023-02-07T23:00:00Z".parse().unwrap();
+        let today: DateTime<Utc> = "2023-02-08T00:30:00Z".parse().unwrap();
+        let mut position = Position::default();
+        position.apply_fill_at(OrderSide::Buy, 2.0, 100.0, 1.0, yesterday);
+        position.apply_fill_at(OrderSide::Sell, 1.0, 150.0, 0.0, yesterday);
+        position.apply_fill_at(OrderSide::Sell, 1.0, 80.0, 0.5, today);
+
+        assert_eq!(position.realized_pnl, 30.0);
+        assert_eq!(position.daily_pnl(today.date_naive()), -20.5);
+        assert_eq!(position.daily_pnl(yesterday.date_naive()), 0.0);
+    }
+
+    #[test]
+    fn applies_user_updates_once_and_in_order() {
+        let mut state = OmsState::default();
+        state.apply_update(&update("a", "OPEN", "0", "0"));
+        state.apply_update(&update("a", "OPEN", "0.4", "100"));
+        state.apply_update(&update("a", "FILLED", "1", "160"));
+        // a late partial fill neither reopens the order nor books the fill twice
+        state.apply_update(&update("a", "OPEN", "0.4", "100"));
+
+        let order = &state.orders["a"];
+        assert_eq!(order.state, OrderState::Filled);
+        assert_eq!(order.order_id.as_deref(), Some("a-id"));
+        let position = &state.positions["ETH-USD"];
+        assert!((position.size - 1.0).abs() < EPSILON);
+        assert!((position.average_cost - 160.0).abs() < EPSILON);
+    }
+}
@@ -0,0 +1,374 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::currency_graph::CurrencyGraph;
+use crate::models::{GenericMessage, Level2Message, PriceBook, WebsocketEvent};
+use crate::sink::MessageSink;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// Price key of a book side, ordered numerically
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Price(pub f64);
+
+impl Eq for Price {}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Price level of a book side as `(price, size)`
+pub type Level = (f64, f64);
+
+/// Level 2 book of a single product
+#[derive(Debug, Clone, Default)]
+pub struct OrderBook {
+    pub product_id: String,
+    bids: BTreeMap<Price, f64>,
+    asks: BTreeMap<Price, f64>,
+    /// `false` until a snapshot arrives, and again after a sequence gap
+    pub in_sync: bool,
+    /// Seeded from a REST snapshot, which no `sequence_num` ties to the `level2` stream, so the
+    /// book is readable but neither `in_sync` nor published until the next `level2` snapshot
+    pub provisional: bool,
+    pub updated_at: Option<SystemTime>,
+}
+
+impl OrderBook {
+    pub fn new(product_id: &str) -> Self {
+        OrderBook { product_id: product_id.to_string(), ..Default::default() }
+    }
+
+    /// Replaces the whole book with `levels` of a `level2` snapshot, as `(side, price, size)`
+    pub fn apply_snapshot(&mut self, levels: &[(&str, f64, f64)]) -> Result<()> {
+        self.replace(levels)?;
+        self.in_sync = true;
+        self.provisional = false;
+        Ok(())
+    }
+
+    /// Replaces the whole book with `levels` of a REST snapshot, leaving it `provisional`
+    pub fn apply_provisional(&mut self, levels: &[(&str, f64, f64)]) -> Result<()> {
+        self.replace(levels)?;
+        self.in_sync = false;
+        self.provisional = true;
+        Ok(())
+    }
+
+    fn replace(&mut self, levels: &[(&str, f64, f64)]) -> Result<()> {
+        self.bids.clear();
+        self.asks.clear();
+        self.apply_updates(levels)
+    }
+
+    /// Sets the size of each level, as `(side, price, size)`, removing levels with size 0
+    pub fn apply_updates(&mut self, levels: &[(&str, f64, f64)]) -> Result<()> {
+        for (side, price, size) in levels {
+            let book_side = match *side {
+                "bid" => &mut self.bids,
+                "offer" | "ask" => &mut self.asks,
+                s => bail!("Unknown book side {}", s),
+            };
+            if *size > 0.0 {
+                book_side.insert(Price(*price), *size);
+            } else {
+                book_side.remove(&Price(*price));
+            }
+        }
+        self.updated_at = Some(SystemTime::now());
+        Ok(())
+    }
+
+    pub fn best_bid(&self) -> Option<Level> {
+        self.bids.iter().next_back().map(|(p, s)| (p.0, *s))
+    }
+
+    pub fn best_ask(&self) -> Option<Level> {
+        self.asks.iter().next().map(|(p, s)| (p.0, *s))
+    }
+
+    /// Best `depth` bids, highest first
+    pub fn bids(&self, depth: usize) -> Vec<Level> {
+        self.bids.iter().rev().take(depth).map(|(p, s)| (p.0, *s)).collect()
+    }
+
+    /// Best `depth` asks, lowest first
+    pub fn asks(&self, depth: usize) -> Vec<Level> {
+        self.asks.iter().take(depth).map(|(p, s)| (p.0, *s)).collect()
+    }
+}
+
+fn parse_levels(message: &Level2Message) -> Result<Vec<(&str, f64, f64)>> {
+    message
+        .updates
+        .iter()
+        .map(|u| Ok((u.side.as_str(), u.price_level.parse()?, u.new_quantity.parse()?)))
+        .collect()
+}
+
+#[derive(Default)]
+struct BooksState {
+    books: HashMap<String, OrderBook>,
+    last_sequence_num: Option<u64>,
+}
+
+/// Level 2 books of every product on the `level2` channel
+///
+/// A gap in `sequence_num`, or a restart of it on a new connection, means updates were lost, so
+/// every book is marked out of sync until its next snapshot and its quote is cleared from the
+/// graph of `publish_to`. Clones share state, so one clone can be registered as a sink with
+/// `AdvancedTradeWebSockets::add_sink` while others read the books.
+#[derive(Clone, Default)]
+pub struct OrderBooks {
+    state: Arc<RwLock<BooksState>>,
+    graph: Option<CurrencyGraph>,
+}
+
+impl OrderBooks {
+    pub fn new() -> Self {
+        OrderBooks::default()
+    }
+
+    /// Also pushes the top of book of every in-sync book to `graph` after each update
+    pub fn publish_to(mut self, graph: CurrencyGraph) -> Self {
+        self.graph = Some(graph);
+        self
+    }
+
+    pub fn book(&self, product_id: &str) -> Option<OrderBook> {
+        self.state.read().unwrap().books.get(product_id).cloned()
+    }
+
+    pub fn product_ids(&self) -> Vec<String> {
+        self.state.read().unwrap().books.keys().cloned().collect()
+    }
+
+    /// Replaces the book of `product_id` from a REST snapshot, as `(side, price, size)`
+    ///
+    /// The book stays `provisional` until the next `level2` snapshot: updates of the stream
+    /// cannot be lined up with the REST snapshot, so it is not published to the graph meanwhile.
+    pub fn seed(&self, product_id: &str, levels: &[(&str, f64, f64)]) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+        let book = state.books.entry(product_id.to_string()).or_insert_with(|| OrderBook::new(product_id));
+        book.apply_provisional(levels)?;
+        if let Some(graph) = &self.graph {
+            // the quote of a book that was out of sync is no better than the seed
+            graph.clear_quote(product_id);
+        }
+        Ok(())
+    }
+
+    /// Replaces the book of `snapshot.product_id` from `get_product_book`
+    pub fn seed_from(&self, snapshot: &PriceBook) -> Result<()> {
+        self.seed(&snapshot.product_id, &snapshot.levels()?)
+    }
+
+    /// Seeds every book of `product_ids` that is missing or out of sync from `get_product_book`
+    ///
+    /// A fallback for products whose `level2` snapshot has not arrived, e.g. after a sequence gap.
+    /// The books seeded are `provisional` until that snapshot, see `seed`.
+    ///
+    /// # Returns
+    ///
+    /// `Result<Vec<String>>` - The products seeded
+    pub async fn seed_from_rest(
+        &self,
+        client: &AdvancedTradeRESTClient,
+        product_ids: &[String],
+        limit: Option<u32>,
+    ) -> Result<Vec<String>> {
+        let mut seeded = Vec::new();
+        for product_id in product_ids {
+            if self.book(product_id).is_some_and(|book| book.in_sync) {
+                continue;
+            }
+            self.seed_from(&client.get_product_book(product_id, limit).await?)?;
+            seeded.push(product_id.clone());
+        }
+        if !seeded.is_empty() {
+            info!("Seeded {} order books from REST snapshots", seeded.len());
+        }
+        Ok(seeded)
+    }
+
+    /// Compares the top of the book of `snapshot.product_id` with a REST snapshot, e.g. from `get_best_bid_ask`
+    ///
+    /// A book whose best bid or ask is more than `tolerance`, a fraction of the price, away from
+    /// the snapshot's is marked out of sync until its next snapshot, and its quote is cleared
+    /// from the graph.
+    ///
+    /// # Returns
+    ///
+    /// `bool` - Whether the book agrees with the snapshot, `false` for unknown, provisional or out of sync books
+    pub fn cross_check(&self, snapshot: &PriceBook, tolerance: f64) -> bool {
+        let mut state = self.state.write().unwrap();
+        let book = match state.books.get_mut(&snapshot.product_id) {
+            Some(book) if book.in_sync => book,
+            _ => return false,
+        };
+        let agrees = |local: Option<Level>, remote: Option<(f64, f64)>| match (local, remote) {
+            (Some((local, _)), Some((remote, _))) => (local - remote).abs() <= remote.abs() * tolerance,
+            (None, None) => true,
+            _ => false,
+        };
+        if agrees(book.best_bid(), snapshot.best_bid()) && agrees(book.best_ask(), snapshot.best_ask()) {
+            return true;
+        }
+        warn!(
+            "Order book of {} diverged from the REST snapshot: {:?}/{:?} against {:?}/{:?}",
+            book.product_id, book.best_bid(), book.best_ask(), snapshot.best_bid(), snapshot.best_ask()
+        );
+        self.invalidate(book);
+        false
+    }
+
+    /// Marks `book` out of sync, so the graph stops quoting a book that may be wrong
+    fn invalidate(&self, book: &mut OrderBook) {
+        book.in_sync = false;
+        if let Some(graph) = &self.graph {
+            graph.clear_quote(&book.product_id);
+        }
+    }
+
+    fn publish(&self, book: &OrderBook) {
+        if let Some(graph) = &self.graph {
+            if book.in_sync {
+                graph.update_quote(
+                    &book.product_id,
+                    book.best_bid().map(|(p, s)| (p, Some(s))),
+                    book.best_ask().map(|(p, s)| (p, Some(s))),
+                );
+            }
+        }
+    }
+
+    /// Applies the `l2_data` events of a channel message
+    pub fn apply(&self, message: &GenericMessage) -> Result<()> {
+        let mut state = self.state.write().unwrap();
+
+        // sequence numbers restart with every connection, which loses updates just the same
+        if let Some(last) = state.last_sequence_num {
+            if message.sequence_num != last + 1 {
+                warn!("Sequence gap {} -> {}, order books out of sync", last, message.sequence_num);
+                state.books.values_mut().for_each(|book| self.invalidate(book));
+            }
+        }
+        state.last_sequence_num = Some(message.sequence_num);
+
+        for event in &message.events {
+            if let WebsocketEvent::Level2Event(level2) = event {
+                let levels = parse_levels(level2)?;
+                let book = state
+                    .books
+                    .entry(level2.product_id.clone())
+                    .or_insert_with(|| OrderBook::new(&level2.product_id));
+                match level2.msg_type.as_str() {
+                    "snapshot" => book.apply_snapshot(&levels)?,
+                    _ => book.apply_updates(&levels)?,
+                }
+                self.publish(book);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MessageSink for OrderBooks {
+    fn on_message(&mut self, _received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        self.apply(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn l2(sequence_num: u64, msg_type: &str, updates: &[(&str, &str, &str)]) -> GenericMessage {
+        let updates: Vec<String> = updates
+            .iter()
+            .map(|(side, price, size)| format!(
+                r#"{{"side":"{}","event_time":"","price_level":"{}","new_quantity":"{}"}}"#,
+                side, price, size
+            ))
+            .collect();
+        serde_json::from_str(&format!(
+            r#"{{"channel":"l2_data","client_id":"","timestamp":"","sequence_num":{},"events":[{{"type":"{}","product_id":"BTC-USD","updates":[{}]}}]}}"#,
+            sequence_num, msg_type, updates.join(",")
+        )).unwrap()
+    }
+
+    #[test]
+    fn applies_snapshots_and_updates() {
+        let books = OrderBooks::new();
+        books.apply(&l2(1, "snapshot", &[
+            ("bid", "20000.00", "1"), ("bid", "19999.50", "2"),
+            ("offer", "20001.00", "0.5"), ("offer", "20002.00", "3"),
+        ])).unwrap();
+        books.apply(&l2(2, "update", &[("bid", "20000.00", "0"), ("offer", "20000.50", "0.1")])).unwrap();
+
+        let book = books.book("BTC-USD").unwrap();
+        assert!(book.in_sync);
+        assert_eq!(book.best_bid(), Some((19_999.5, 2.0)));
+        assert_eq!(book.best_ask(), Some((20_000.5, 0.1)));
+        assert_eq!(book.asks(5), vec![(20_000.5, 0.1), (20_001.0, 0.5), (20_002.0, 3.0)]);
+    }
+
+    #[test]
+    fn seeds_and_cross_checks_against_rest_snapshots() {
+        let snapshot: PriceBook = serde_json::from_str(r#"{"product_id":"BTC-USD","bids":[{"price":"20000","size":"1"},{"price":"19999","size":"2"}],"asks":[{"price":"20001","size":"0.5"}],"time":"2023-06-23T20:31:56Z"}"#).unwrap();
+        let graph = CurrencyGraph::default();
+        graph.insert_pair(crate::currency_graph::PairQuote::new("BTC-USD", "BTC", "USD"));
+        let books = OrderBooks::new().publish_to(graph.clone());
+        assert!(!books.cross_check(&snapshot, 0.001));
+
+        books.seed_from(&snapshot).unwrap();
+        let book = books.book("BTC-USD").unwrap();
+        assert_eq!(book.bids(5), vec![(20_000.0, 1.0), (19_999.0, 2.0)]);
+        assert!(book.provisional && !book.in_sync);
+        assert_eq!(graph.pair("BTC-USD").unwrap().bid, None);
+        assert!(!books.cross_check(&snapshot, 0.0));
+
+        books.apply(&l2(1, "snapshot", &[("bid", "20000", "1"), ("bid", "19999", "2"), ("offer", "20001", "0.5")])).unwrap();
+        assert!(!books.book("BTC-USD").unwrap().provisional);
+        assert!(books.cross_check(&snapshot, 0.0));
+
+        books.apply(&l2(2, "update", &[("offer", "20001", "0"), ("offer", "20100", "1")])).unwrap();
+        assert!(books.cross_check(&snapshot, 0.01));
+        assert!(!books.cross_check(&snapshot, 0.001));
+        assert!(!books.book("BTC-USD").unwrap().in_sync);
+        assert_eq!(graph.pair("BTC-USD").unwrap().ask, None);
+    }
+
+    #[test]
+    fn marks_books_out_of_sync_on_gaps() {
+        let graph = CurrencyGraph::default();
+        graph.insert_pair(crate::currency_graph::PairQuote::new("BTC-USD", "BTC", "USD"));
+        let books = OrderBooks::new().publish_to(graph.clone());
+
+        books.apply(&l2(1, "snapshot", &[("bid", "20000", "1"), ("offer", "20001", "1")])).unwrap();
+        assert_eq!(graph.pair("BTC-USD").unwrap().bid, Some(20_000.0));
+
+        books.apply(&l2(5, "update", &[("bid", "20000.5", "1")])).unwrap();
+        assert!(!books.book("BTC-USD").unwrap().in_sync);
+        assert_eq!(graph.pair("BTC-USD").unwrap().bid, None);
+
+        books.apply(&l2(6, "snapshot", &[("bid", "20001", "1"), ("offer", "20002", "1")])).unwrap();
+        assert!(books.book("BTC-USD").unwrap().in_sync);
+        assert_eq!(graph.pair("BTC-USD").unwrap().bid, Some(20_001.0));
+
+        // a new connection starts over at a lower sequence number
+        books.apply(&l2(1, "update", &[("bid", "20001.5", "1")])).unwrap();
+        assert!(!books.book("BTC-USD").unwrap().in_sync);
+        assert_eq!(graph.pair("BTC-USD").unwrap().best_bid(), None);
+    }
+}
@@ -0,0 +1,411 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::currency_graph::CurrencyGraph;
+use crate::fees::{FeeModel, DEFAULT_TAKER_FEE};
+use crate::models::{OrderRequest, OrderSide, ProductData};
+use crate::product_registry::ProductRegistry;
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Default distance a limit price may cross the live ticker by, as a fraction
+pub const DEFAULT_MAX_PRICE_DEVIATION: f64 = 0.05;
+
+/// Size or price of an order a `Rejection` refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OrderField {
+    BaseSize,
+    QuoteSize,
+    LimitPrice,
+    StopPrice,
+    /// `base_size` times `limit_price`
+    Notional,
+}
+
+impl fmt::Display for OrderField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            OrderField::BaseSize => "base_size",
+            OrderField::QuoteSize => "quote_size",
+            OrderField::LimitPrice => "limit_price",
+            OrderField::StopPrice => "stop_price",
+            OrderField::Notional => "notional",
+        })
+    }
+}
+
+/// Reason `OrderValidator` expects Coinbase to reject an order
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Rejection {
+    UnknownProduct { product_id: String },
+    ProductNotOnline { product_id: String, status: String },
+    TradingDisabled { product_id: String },
+    /// A market order without `base_size` or `quote_size`, or with both
+    InvalidSize,
+    InvalidNumber { field: OrderField, value: String },
+    NotAnIncrement { field: OrderField, value: String, increment: String },
+    BelowMinimum { field: OrderField, value: String, minimum: String },
+    AboveMaximum { field: OrderField, value: String, maximum: String },
+    InsufficientBalance { currency: String, required: f64, available: f64 },
+    /// The limit price crosses the live ticker by more than `max_deviation`
+    OutsidePriceBand { limit_price: f64, reference_price: f64, max_deviation: f64 },
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::UnknownProduct { product_id } => write!(f, "unknown product {}", product_id),
+            Rejection::ProductNotOnline { product_id, status } => write!(f, "{} is {}", product_id, status),
+            Rejection::TradingDisabled { product_id } => write!(f, "trading is disabled for {}", product_id),
+            Rejection::InvalidSize => f.write_str("market orders need exactly one of base_size and quote_size"),
+            Rejection::InvalidNumber { field, value } => write!(f, "{} {:?} is not a number", field, value),
+            Rejection::NotAnIncrement { field, value, increment } => write!(f, "{} {} is not a multiple of {}", field, value, increment),
+            Rejection::BelowMinimum { field, value, minimum } => write!(f, "{} {} is below the minimum of {}", field, value, minimum),
+            Rejection::AboveMaximum { field, value, maximum } => write!(f, "{} {} is above the maximum of {}", field, value, maximum),
+            Rejection::InsufficientBalance { currency, required, available } => {
+                write!(f, "{} {} required but only {} available", required, currency, available)
+            },
+            Rejection::OutsidePriceBand { limit_price, reference_price, max_deviation } => write!(
+                f,
+                "limit price {} is more than {}% through the live price {}",
+                limit_price,
+                max_deviation * 100.0,
+                reference_price
+            ),
+        }
+    }
+}
+
+/// Parses a decimal string into an integer mantissa and its number of fraction digits
+fn decimal(value: &str) -> Option<(i128, u32)> {
+    let (int, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+    if int.is_empty() && fraction.is_empty() || fraction.len() > 18 || int.len() > 18 {
+        return None;
+    }
+    if !int.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let digits = format!("{}{}", int, fraction);
+    Some((digits.parse().ok()?, fraction.len() as u32))
+}
+
+/// Whether `value` is a whole multiple of `increment`, computed without floating point error
+fn is_multiple(value: &str, increment: &str) -> Option<bool> {
+    let (value, value_scale) = decimal(value)?;
+    let (increment, increment_scale) = decimal(increment)?;
+    if increment == 0 {
+        return Some(true);
+    }
+    let scale = value_scale.max(increment_scale);
+    let value = value * 10i128.pow(scale - value_scale);
+    let increment = increment * 10i128.pow(scale - increment_scale);
+    Some(value % increment == 0)
+}
+
+/// Limits `OrderValidator` checks orders against beyond the product rules
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValidationConfig {
+    /// How far a buy may be priced above the ask, or a sell below the bid, e.g. `0.05` for 5%
+    pub max_price_deviation: f64,
+    /// Fee added to the quote currency a buy needs
+    pub fee_rate: f64,
+}
+
+impl ValidationConfig {
+    /// Adds the taker rate of the account's fee tier to buys
+    pub fn from_fees(fees: &FeeModel) -> Self {
+        ValidationConfig { fee_rate: fees.taker_rate, ..ValidationConfig::default() }
+    }
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            max_price_deviation: DEFAULT_MAX_PRICE_DEVIATION,
+            fee_rate: DEFAULT_TAKER_FEE,
+        }
+    }
+}
+
+/// Pre-trade checks that catch the orders Coinbase would reject, before they are sent
+///
+/// Checks the product status, the increments and min and max sizes of `ProductData`, the
+/// available balance, and, given a `CurrencyGraph` fed by the live ticker, that limit prices
+/// stay within `max_price_deviation` of the market. Checks that need a live price are skipped
+/// while the product has none.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::models::{OrderConfiguration, OrderRequest, OrderSide};
+/// use rs_coinbase_pairs_handler::order_validation::{OrderValidator, ValidationConfig};
+/// # use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+/// # use rs_coinbase_pairs_handler::product_registry::ProductRegistry;
+///
+/// # async fn example(client: &AdvancedTradeRESTClient, registry: &ProductRegistry) {
+/// let order = OrderRequest {
+///     client_order_id: "my-order-1".to_string(),
+///     product_id: "ETH-USD".to_string(),
+///     side: OrderSide::Buy,
+///     order_configuration: OrderConfiguration::LimitLimitGtc {
+///         base_size: "0.5".to_string(),
+///         limit_price: "1675.14".to_string(),
+///         post_only: true,
+///     },
+/// };
+/// let validator = OrderValidator::new(ValidationConfig::default());
+/// for rejection in validator.check(client, registry, &order).await.unwrap() {
+///     println!("{}", rejection);
+/// }
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct OrderValidator {
+    config: ValidationConfig,
+    graph: Option<CurrencyGraph>,
+}
+
+impl OrderValidator {
+    pub fn new(config: ValidationConfig) -> OrderValidator {
+        OrderValidator { config, graph: None }
+    }
+
+    /// Checks limit prices and sizes market orders against the live prices of `graph`
+    pub fn with_graph(mut self, graph: CurrencyGraph) -> Self {
+        self.graph = Some(graph);
+        self
+    }
+
+    /// Validates `order` against its product and the available balance per currency
+    ///
+    /// # Arguments
+    /// * `product`: `ProductData` of `order.product_id`, `None` if it is not listed
+    /// * `balances`: Available balance per currency, e.g. from `AdvancedTradeRESTClient::available_balances`
+    ///
+    /// # Returns
+    ///
+    /// `Vec<Rejection>` - every reason the order would be rejected, empty if it passes
+    pub fn validate(&self, order: &OrderRequest, product: Option<&ProductData>, balances: &BTreeMap<String, f64>) -> Vec<Rejection> {
+        let Some(product) = product else {
+            return vec![Rejection::UnknownProduct { product_id: order.product_id.clone() }];
+        };
+        let mut rejections = Vec::new();
+
+        if !product.status.eq_ignore_ascii_case("online") {
+            rejections.push(Rejection::ProductNotOnline { product_id: product.product_id.clone(), status: product.status.clone() });
+        }
+        if product.trading_disabled {
+            rejections.push(Rejection::TradingDisabled { product_id: product.product_id.clone() });
+        }
+
+        let config = &order.order_configuration;
+        let (base_size, quote_size) = (config.base_size(), config.quote_size());
+        if base_size.is_some() == quote_size.is_some() {
+            rejections.push(Rejection::InvalidSize);
+        }
+        let base = base_size.and_then(|size| {
+            check_amount(&mut rejections, OrderField::BaseSize, size, &product.base_increment, &product.base_min_size, &product.base_max_size)
+        });
+        let quote = quote_size.and_then(|size| {
+            check_amount(&mut rejections, OrderField::QuoteSize, size, &product.quote_increment, &product.quote_min_size, &product.quote_max_size)
+        });
+        // products stored before `price_increment` was known only have the quote increment
+        let price_increment = match product.price_increment.as_str() {
+            "" => &product.quote_increment,
+            increment => increment,
+        };
+        let limit = config
+            .limit_price()
+            .and_then(|price| check_price(&mut rejections, OrderField::LimitPrice, price, price_increment));
+        if let Some(stop) = config.stop_price() {
+            check_price(&mut rejections, OrderField::StopPrice, stop, price_increment);
+        }
+
+        if let (Some(base), Some(limit)) = (base, limit) {
+            let notional = base * limit;
+            if let Ok(minimum) = product.quote_min_size.parse::<f64>() {
+                if notional < minimum {
+                    rejections.push(Rejection::BelowMinimum {
+                        field: OrderField::Notional,
+                        value: notional.to_string(),
+                        minimum: product.quote_min_size.clone(),
+                    });
+                }
+            }
+        }
+
+        let quote_live = self.graph.as_ref().and_then(|graph| graph.pair(&product.product_id));
+        let reference = quote_live.and_then(|pair| match order.side {
+            OrderSide::Buy => pair.best_ask(),
+            OrderSide::Sell => pair.best_bid(),
+        });
+
+        if let (Some(limit), Some(reference)) = (limit, reference) {
+            let band = self.config.max_price_deviation;
+            let crossed = match order.side {
+                OrderSide::Buy => limit > reference * (1.0 + band),
+                OrderSide::Sell => limit < reference * (1.0 - band),
+            };
+            if crossed {
+                rejections.push(Rejection::OutsidePriceBand { limit_price: limit, reference_price: reference, max_deviation: band });
+            }
+        }
+
+        let price = limit.or(reference);
+        let required = match order.side {
+            OrderSide::Buy => quote
+                .or_else(|| Some(base? * price?))
+                .map(|notional| (product.quote_currency_id.as_str(), notional * (1.0 + self.config.fee_rate))),
+            OrderSide::Sell => base
+                .or_else(|| Some(quote? / price?))
+                .map(|size| (product.base_currency_id.as_str(), size)),
+        };
+        if let Some((currency, required)) = required {
+            let available = balances.get(currency).copied().unwrap_or(0.0);
+            if required > available {
+                rejections.push(Rejection::InsufficientBalance { currency: currency.to_string(), required, available });
+            }
+        }
+
+        rejections
+    }
+
+    /// Fetches the product from `registry` and the balances through `client`, then validates
+    pub async fn check(&self, client: &AdvancedTradeRESTClient, registry: &ProductRegistry, order: &OrderRequest) -> Result<Vec<Rejection>> {
+        let products = registry.products().await?;
+        let product = products.iter().find(|p| p.product_id == order.product_id);
+        let balances = client.available_balances().await?;
+        Ok(self.validate(order, product, &balances))
+    }
+}
+
+/// Checks a size against its increment and limits, returning it as a number if it parses
+fn check_amount(rejections: &mut Vec<Rejection>, field: OrderField, value: &str, increment: &str, minimum: &str, maximum: &str) -> Option<f64> {
+    let amount = check_price(rejections, field, value, increment)?;
+    if minimum.parse::<f64>().is_ok_and(|minimum| amount < minimum) {
+        rejections.push(Rejection::BelowMinimum { field, value: value.to_string(), minimum: minimum.to_string() });
+    }
+    if maximum.parse::<f64>().is_ok_and(|maximum| amount > maximum) {
+        rejections.push(Rejection::AboveMaximum { field, value: value.to_string(), maximum: maximum.to_string() });
+    }
+    Some(amount)
+}
+
+/// Checks a positive decimal against its increment, returning it as a number if it parses
+fn check_price(rejections: &mut Vec<Rejection>, field: OrderField, value: &str, increment: &str) -> Option<f64> {
+    let amount = match value.parse::<f64>() {
+        Ok(amount) if amount > 0.0 && decimal(value).is_some() => amount,
+        _ => {
+            rejections.push(Rejection::InvalidNumber { field, value: value.to_string() });
+            return None;
+        }
+    };
+    if is_multiple(value, increment) == Some(false) {
+        rejections.push(Rejection::NotAnIncrement { field, value: value.to_string(), increment: increment.to_string() });
+    }
+    Some(amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::OrderConfiguration;
+
+    fn product() -> ProductData {
+        ProductData {
+            base_max_size: "3400".to_string(),
+            base_min_size: "0.00022".to_string(),
+            quote_max_size: "50000000".to_string(),
+            ..ProductData::test("ETH-USD", "ETH", "USD")
+        }
+    }
+
+    fn limit(side: OrderSide, base_size: &str, limit_price: &str) -> OrderRequest {
+        OrderRequest {
+            client_order_id: "1".to_string(),
+            product_id: "ETH-USD".to_string(),
+            side,
+            order_configuration: OrderConfiguration::LimitLimitGtc {
+                base_size: base_size.to_string(),
+                limit_price: limit_price.to_string(),
+                post_only: false,
+            },
+        }
+    }
+
+    fn balances() -> BTreeMap<String, f64> {
+        BTreeMap::from([("USD".to_string(), 10_000.0), ("ETH".to_string(), 2.0)])
+    }
+
+    #[test]
+    fn checks_increments_exactly() {
+        assert_eq!(is_multiple("0.3", "0.1"), Some(true));
+        assert_eq!(is_multiple("1675.14", "0.01"), Some(true));
+        assert_eq!(is_multiple("1675.145", "0.01"), Some(false));
+        assert_eq!(is_multiple("10", "0.00000001"), Some(true));
+        assert_eq!(is_multiple("1e3", "0.01"), None);
+    }
+
+    #[test]
+    fn rejects_bad_increments_sizes_and_balances() {
+        let validator = OrderValidator::default();
+        assert!(validator.validate(&limit(OrderSide::Buy, "0.5", "1675.14"), Some(&product()), &balances()).is_empty());
+
+        let rejections = validator.validate(&limit(OrderSide::Buy, "0.0001", "1675.145"), Some(&product()), &balances());
+        assert_eq!(rejections, vec![
+            Rejection::BelowMinimum { field: OrderField::BaseSize, value: "0.0001".to_string(), minimum: "0.00022".to_string() },
+            Rejection::NotAnIncrement { field: OrderField::LimitPrice, value: "1675.145".to_string(), increment: "0.01".to_string() },
+            Rejection::BelowMinimum { field: OrderField::Notional, value: (0.0001 * 1675.145).to_string(), minimum: "1".to_string() },
+        ]);
+
+        let rejections = validator.validate(&limit(OrderSide::Sell, "2.5", "1675.14"), Some(&product()), &balances());
+        assert_eq!(rejections, vec![Rejection::InsufficientBalance { currency: "ETH".to_string(), required: 2.5, available: 2.0 }]);
+
+        let mut halted = product();
+        halted.status = "delisted".to_string();
+        halted.trading_disabled = true;
+        assert_eq!(validator.validate(&limit(OrderSide::Sell, "1", "1675.14"), Some(&halted), &balances()).len(), 2);
+        assert!(matches!(validator.validate(&limit(OrderSide::Sell, "1", "1"), None, &balances())[..], [Rejection::UnknownProduct { .. }]));
+    }
+
+    #[test]
+    fn checks_prices_against_the_price_increment() {
+        let validator = OrderValidator::default();
+        let mut product = product();
+        product.quote_increment = "0.000001".to_string();
+        product.price_increment = "0.05".to_string();
+
+        assert!(validator.validate(&limit(OrderSide::Buy, "0.5", "1675.15"), Some(&product), &balances()).is_empty());
+        let rejections = validator.validate(&limit(OrderSide::Buy, "0.5", "1675.14"), Some(&product), &balances());
+        assert_eq!(rejections, vec![
+            Rejection::NotAnIncrement { field: OrderField::LimitPrice, value: "1675.14".to_string(), increment: "0.05".to_string() },
+        ]);
+
+        let market = OrderRequest {
+            order_configuration: OrderConfiguration::MarketMarketIoc { quote_size: Some("10.123456".to_string()), base_size: None },
+            ..limit(OrderSide::Buy, "1", "1")
+        };
+        assert!(validator.validate(&market, Some(&product), &balances()).is_empty());
+    }
+
+    #[test]
+    fn applies_the_price_band_and_sizes_market_orders_at_the_live_price() {
+        let graph = CurrencyGraph::new(&[product()]);
+        graph.update_quote("ETH-USD", Some((1675.0, None)), Some((1676.0, None)));
+        let validator = OrderValidator::default().with_graph(graph);
+
+        let rejections = validator.validate(&limit(OrderSide::Buy, "1", "1800"), Some(&product()), &balances());
+        assert_eq!(rejections, vec![Rejection::OutsidePriceBand { limit_price: 1800.0, reference_price: 1676.0, max_deviation: 0.05 }]);
+        // resting far from the market is fine
+        assert!(validator.validate(&limit(OrderSide::Buy, "1", "1500"), Some(&product()), &balances()).is_empty());
+
+        let market = OrderRequest {
+            order_configuration: OrderConfiguration::MarketMarketIoc { quote_size: None, base_size: Some("6".to_string()) },
+            ..limit(OrderSide::Buy, "1", "1")
+        };
+        let rejections = validator.validate(&market, Some(&product()), &balances());
+        assert!(matches!(&rejections[..], [Rejection::InsufficientBalance { currency, .. }] if currency == "USD"), "{:?}", rejections);
+    }
+}
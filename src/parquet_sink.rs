@@ -0,0 +1,447 @@
+use crate::models::{GenericMessage, WebsocketEvent};
+use crate::sink::MessageSink;
+use anyhow::{bail, Result};
+use arrow_array::{ArrayRef, Decimal128Array, RecordBatch, StringArray, TimestampNanosecondArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Precision of every decimal column, the maximum of `Decimal128`
+pub const DECIMAL_PRECISION: u8 = 38;
+/// Fractional digits kept for every decimal column, extra digits are truncated
+pub const DECIMAL_SCALE: i8 = 18;
+
+/*
+PARQUET - Columnar export of the typed WebSocket events
+
+One table per event kind, each written to its own directory:
+
+    <directory>/tickers/tickers_<YYYYMMDDTHHMMSS>_<n>.parquet
+    <directory>/trades/trades_<YYYYMMDDTHHMMSS>_<n>.parquet
+    <directory>/candles/candles_<YYYYMMDDTHHMMSS>_<n>.parquet
+
+Every table starts with the same columns: `exchange_timestamp`, `received_at`, `sequence_num`,
+`event_type` and `product_id`. Prices and sizes are Decimal128(38, 18) and timestamps are
+nanoseconds in UTC, so the schema never depends on the values seen.
+*/
+
+#[derive(Debug, Clone)]
+pub struct ParquetConfig {
+    pub directory: PathBuf,
+    /// Rows buffered in memory before they are handed to the writer as one record batch
+    pub batch_size: usize,
+    /// Maximum rows per row group
+    pub row_group_size: usize,
+    /// Rows after which a file is closed and a new one started
+    pub max_rows_per_file: usize,
+}
+
+impl ParquetConfig {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        ParquetConfig {
+            directory: directory.into(),
+            batch_size: 1024,
+            row_group_size: 128 * 1024,
+            max_rows_per_file: 10_000_000,
+        }
+    }
+}
+
+/// Parses a decimal string such as `"-1675.14"` into a `Decimal128` value at `DECIMAL_SCALE`
+///
+/// Returns `None` for anything that is not a plain decimal number.
+pub fn parse_decimal(value: &str) -> Option<i128> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if integer.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    if !integer.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let scale = DECIMAL_SCALE as usize;
+    let fraction: String = fraction.chars().chain(std::iter::repeat('0')).take(scale).collect();
+    let integer = if integer.is_empty() { "0" } else { integer };
+    let unscaled: i128 = format!("{}{}", integer, fraction).parse().ok()?;
+
+    Some(if negative { -unscaled } else { unscaled })
+}
+
+fn parse_timestamp(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value).ok()?.timestamp_nanos_opt()
+}
+
+fn parse_epoch_seconds(value: &str) -> Option<i64> {
+    value.parse::<i64>().ok()?.checked_mul(1_000_000_000)
+}
+
+enum Column {
+    Timestamp(Vec<Option<i64>>),
+    UInt64(Vec<u64>),
+    Utf8(Vec<String>),
+    Decimal(Vec<Option<i128>>),
+}
+
+enum Cell {
+    Timestamp(Option<i64>),
+    UInt64(u64),
+    Utf8(String),
+    Decimal(Option<i128>),
+}
+
+impl Column {
+    fn for_type(data_type: &DataType) -> Column {
+        match data_type {
+            DataType::Timestamp(_, _) => Column::Timestamp(Vec::new()),
+            DataType::UInt64 => Column::UInt64(Vec::new()),
+            DataType::Decimal128(_, _) => Column::Decimal(Vec::new()),
+            _ => Column::Utf8(Vec::new()),
+        }
+    }
+
+    fn push(&mut self, cell: Cell) -> Result<()> {
+        match (self, cell) {
+            (Column::Timestamp(values), Cell::Timestamp(v)) => values.push(v),
+            (Column::UInt64(values), Cell::UInt64(v)) => values.push(v),
+            (Column::Utf8(values), Cell::Utf8(v)) => values.push(v),
+            (Column::Decimal(values), Cell::Decimal(v)) => values.push(v),
+            _ => bail!("Cell does not match the column type"),
+        }
+        Ok(())
+    }
+
+    fn take(&mut self) -> Result<ArrayRef> {
+        Ok(match self {
+            Column::Timestamp(values) => Arc::new(
+                TimestampNanosecondArray::from(std::mem::take(values)).with_timezone("UTC")
+            ),
+            Column::UInt64(values) => Arc::new(UInt64Array::from(std::mem::take(values))),
+            Column::Utf8(values) => Arc::new(StringArray::from(std::mem::take(values))),
+            Column::Decimal(values) => Arc::new(
+                Decimal128Array::from(std::mem::take(values))
+                    .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE)?
+            ),
+        })
+    }
+}
+
+/// Buffers the rows of one table and writes them to rotating Parquet files
+struct TableWriter {
+    name: &'static str,
+    schema: SchemaRef,
+    columns: Vec<Column>,
+    buffered: usize,
+    writer: Option<ArrowWriter<File>>,
+    rows_in_file: usize,
+}
+
+impl TableWriter {
+    fn new(name: &'static str, columns: Vec<(&str, DataType)>) -> TableWriter {
+        let mut fields = vec![
+            Field::new("exchange_timestamp", timestamp_type(), true),
+            Field::new("received_at", timestamp_type(), true),
+            Field::new("sequence_num", DataType::UInt64, false),
+            Field::new("event_type", DataType::Utf8, false),
+            Field::new("product_id", DataType::Utf8, false),
+        ];
+        fields.extend(columns.into_iter().map(|(name, data_type)| {
+            let nullable = !matches!(data_type, DataType::Utf8);
+            Field::new(name, data_type, nullable)
+        }));
+        let schema = Arc::new(Schema::new(fields));
+        let columns = schema.fields().iter().map(|f| Column::for_type(f.data_type())).collect();
+
+        TableWriter { name, schema, columns, buffered: 0, writer: None, rows_in_file: 0 }
+    }
+
+    fn push_row(&mut self, row: Vec<Cell>, config: &ParquetConfig) -> Result<()> {
+        for (column, cell) in self.columns.iter_mut().zip(row) {
+            column.push(cell)?;
+        }
+        self.buffered += 1;
+        if self.buffered >= config.batch_size {
+            self.write_batch(config)?;
+        }
+        Ok(())
+    }
+
+    fn write_batch(&mut self, config: &ParquetConfig) -> Result<()> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+
+        let arrays = self.columns.iter_mut().map(|c| c.take()).collect::<Result<Vec<_>>>()?;
+        let batch = RecordBatch::try_new(self.schema.clone(), arrays)?;
+        self.buffered = 0;
+
+        if self.writer.is_none() {
+            self.writer = Some(self.open_file(config)?);
+        }
+        self.writer.as_mut().unwrap().write(&batch)?;
+        self.rows_in_file += batch.num_rows();
+
+        if self.rows_in_file >= config.max_rows_per_file {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    fn open_file(&self, config: &ParquetConfig) -> Result<ArrowWriter<File>> {
+        let directory = config.directory.join(self.name);
+        fs::create_dir_all(&directory)?;
+
+        let stamp = Utc::now().format("%Y%m%dT%H%M%S");
+        let mut n = 0;
+        let path = loop {
+            let candidate = directory.join(format!("{}_{}_{}.parquet", self.name, stamp, n));
+            if !candidate.exists() {
+                break candidate;
+            }
+            n += 1;
+        };
+        info!("Writing {} to {}", self.name, path.display());
+
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(config.row_group_size)
+            .set_compression(Compression::ZSTD(ZstdLevel::default()))
+            .build();
+        Ok(ArrowWriter::try_new(File::create(path)?, self.schema.clone(), Some(properties))?)
+    }
+
+    /// Writes the footer of the current file, making it readable
+    fn close(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        self.rows_in_file = 0;
+        Ok(())
+    }
+}
+
+fn timestamp_type() -> DataType {
+    DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into()))
+}
+
+/// `MessageSink` that exports tickers, market trades and candles to Parquet
+///
+/// Rows are buffered per table and written every `ParquetConfig::batch_size` rows. A file only
+/// becomes readable once its footer is written, which happens on rotation, on `flush` (the
+/// feed flushes sinks when it stops) and on drop.
+pub struct ParquetSink {
+    config: ParquetConfig,
+    tickers: TableWriter,
+    trades: TableWriter,
+    candles: TableWriter,
+}
+
+impl ParquetSink {
+    pub fn new(config: ParquetConfig) -> Result<ParquetSink> {
+        fs::create_dir_all(&config.directory)?;
+        let decimal = DataType::Decimal128(DECIMAL_PRECISION, DECIMAL_SCALE);
+
+        Ok(ParquetSink {
+            config,
+            tickers: TableWriter::new("tickers", vec![
+                ("price", decimal.clone()),
+                ("volume_24_h", decimal.clone()),
+                ("low_24_h", decimal.clone()),
+                ("high_24_h", decimal.clone()),
+                ("low_52_w", decimal.clone()),
+                ("high_52_w", decimal.clone()),
+                ("price_percent_chg_24_h", decimal.clone()),
+            ]),
+            trades: TableWriter::new("trades", vec![
+                ("trade_id", DataType::Utf8),
+                ("side", DataType::Utf8),
+                ("price", decimal.clone()),
+                ("size", decimal.clone()),
+                ("trade_time", timestamp_type()),
+            ]),
+            candles: TableWriter::new("candles", vec![
+                ("start", timestamp_type()),
+                ("open", decimal.clone()),
+                ("high", decimal.clone()),
+                ("low", decimal.clone()),
+                ("close", decimal.clone()),
+                ("volume", decimal),
+            ]),
+        })
+    }
+
+    /// Schemas of the `tickers`, `trades` and `candles` tables, in that order
+    pub fn schemas(&self) -> [SchemaRef; 3] {
+        [self.tickers.schema.clone(), self.trades.schema.clone(), self.candles.schema.clone()]
+    }
+
+    /// Writes every buffered row and closes the current files
+    pub fn close(&mut self) -> Result<()> {
+        for table in [&mut self.tickers, &mut self.trades, &mut self.candles] {
+            table.write_batch(&self.config)?;
+            table.close()?;
+        }
+        Ok(())
+    }
+}
+
+fn decimal(value: &str) -> Cell {
+    let parsed = parse_decimal(value);
+    if parsed.is_none() {
+        warn!("Unable to parse decimal {:?}", value);
+    }
+    Cell::Decimal(parsed)
+}
+
+impl MessageSink for ParquetSink {
+    fn on_message(&mut self, received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        let received_at = received_at
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .and_then(|d| i64::try_from(d.as_nanos()).ok());
+        let header = |event_type: &str, product_id: &str| vec![
+            Cell::Timestamp(parse_timestamp(&message.timestamp)),
+            Cell::Timestamp(received_at),
+            Cell::UInt64(message.sequence_num),
+            Cell::Utf8(event_type.to_string()),
+            Cell::Utf8(product_id.to_string()),
+        ];
+        // snapshots and updates carry the same ticker fields in different types
+        let mut push_ticker = |event_type: &str, product_id: &str, fields: [&str; 7]| {
+            let mut row = header(event_type, product_id);
+            row.extend(fields.map(decimal));
+            self.tickers.push_row(row, &self.config)
+        };
+
+        for event in &message.events {
+            match event {
+                WebsocketEvent::SnapshotEvent(snapshot) => {
+                    for t in &snapshot.tickers {
+                        push_ticker(&snapshot.msg_type, &t.product_id, [
+                            &t.price, &t.volume_24_h, &t.low_24_h, &t.high_24_h,
+                            &t.low_52_w, &t.high_52_w, &t.price_percent_chg_24_h,
+                        ])?;
+                    }
+                },
+                WebsocketEvent::UpdateEvent(update) => {
+                    for t in &update.tickers {
+                        push_ticker(&update.msg_type, &t.product_id, [
+                            &t.price, &t.volume_24_h, &t.low_24_h, &t.high_24_h,
+                            &t.low_52_w, &t.high_52_w, &t.price_percent_chg_24_h,
+                        ])?;
+                    }
+                },
+                WebsocketEvent::TradesEvent(trades) => {
+                    for t in &trades.trades {
+                        let mut row = header(&trades.msg_type, &t.product_id);
+                        row.extend([
+                            Cell::Utf8(t.trade_id.clone()), Cell::Utf8(t.side.clone()),
+                            decimal(&t.price), decimal(&t.size),
+                            Cell::Timestamp(parse_timestamp(&t.time)),
+                        ]);
+                        self.trades.push_row(row, &self.config)?;
+                    }
+                },
+                WebsocketEvent::CandlesEvent(candles) => {
+                    for c in &candles.candles {
+                        let mut row = header(&candles.msg_type, &c.product_id);
+                        row.extend([
+                            Cell::Timestamp(parse_epoch_seconds(&c.start)),
+                            decimal(&c.open), decimal(&c.high), decimal(&c.low),
+                            decimal(&c.close), decimal(&c.volume),
+                        ]);
+                        self.candles.push_row(row, &self.config)?;
+                    }
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.close()
+    }
+}
+
+impl Drop for ParquetSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("Error closing Parquet files: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::Array;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_decimals_at_fixed_scale() {
+        let one = 10i128.pow(DECIMAL_SCALE as u32);
+        assert_eq!(parse_decimal("1675.14"), Some(167_514 * one / 100));
+        assert_eq!(parse_decimal("-0.5"), Some(-one / 2));
+        assert_eq!(parse_decimal("3"), Some(3 * one));
+        assert_eq!(parse_decimal(".25"), Some(one / 4));
+        assert_eq!(parse_decimal("0.0000000000000000019"), Some(1));
+        assert_eq!(parse_decimal("1e5"), None);
+        assert_eq!(parse_decimal(""), None);
+    }
+
+    #[test]
+    fn writes_tickers_trades_and_candles() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ParquetConfig { batch_size: 2, ..ParquetConfig::new(dir.path()) };
+        let mut sink = ParquetSink::new(config).unwrap();
+        let received_at = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+
+        let frames = [
+            r#"{"channel":"ticker","client_id":"","timestamp":"2023-02-08T06:12:20.838410617Z","sequence_num":0,"events":[{"type":"snapshot","tickers":[{"type":"ticker","product_id":"ETH-USD","price":"1675.14","volume_24_h":"185976.72526638","low_24_h":"1624.53","high_24_h":"1699.66","low_52_w":"879.8","high_52_w":"3581.6","price_percent_chg_24_h":"2.48094171775388"}]}]}"#,
+            r#"{"channel":"ticker","client_id":"","timestamp":"2023-02-08T06:12:21Z","sequence_num":1,"events":[{"type":"update","tickers":[{"type":"ticker","product_id":"ETH-USD","price":"1675.2","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"-0.1"}]}]}"#,
+            r#"{"channel":"market_trades","client_id":"","timestamp":"2023-02-08T06:12:21Z","sequence_num":2,"events":[{"type":"update","trades":[{"trade_id":"42","product_id":"ETH-USD","price":"1675.2","size":"0.3","side":"BUY","time":"2023-02-08T06:12:21.5Z"}]}]}"#,
+            r#"{"channel":"candles","client_id":"","timestamp":"2023-02-08T06:12:21Z","sequence_num":3,"events":[{"type":"snapshot","candles":[{"start":"1675836600","high":"1680","low":"1670","open":"1671","close":"1675.2","volume":"12.5","product_id":"ETH-USD"}]}]}"#,
+        ];
+        for frame in frames {
+            let message: GenericMessage = serde_json::from_str(frame).unwrap();
+            sink.on_message(received_at, &message).unwrap();
+        }
+        sink.flush().unwrap();
+
+        let read = |table: &str| {
+            let path = fs::read_dir(dir.path().join(table)).unwrap().next().unwrap().unwrap().path();
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap()).unwrap().build().unwrap();
+            reader.map(|b| b.unwrap()).collect::<Vec<_>>()
+        };
+
+        let tickers = read("tickers");
+        assert_eq!(tickers.iter().map(|b| b.num_rows()).sum::<usize>(), 2);
+        let batch = &tickers[0];
+        assert_eq!(batch.schema(), sink.schemas()[0]);
+        let event_types = batch.column_by_name("event_type").unwrap().as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(event_types.value(0), "snapshot");
+        assert_eq!(event_types.value(1), "update");
+        let prices = batch.column_by_name("price").unwrap().as_any().downcast_ref::<Decimal128Array>().unwrap();
+        assert_eq!(prices.value_as_string(0), "1675.140000000000000000");
+
+        let trades = read("trades");
+        let trade_time = trades[0].column_by_name("trade_time").unwrap().as_any().downcast_ref::<TimestampNanosecondArray>().unwrap();
+        assert_eq!(trade_time.value(0), 1_675_836_741_500_000_000);
+
+        let candles = read("candles");
+        let start = candles[0].column_by_name("start").unwrap().as_any().downcast_ref::<TimestampNanosecondArray>().unwrap();
+        assert_eq!(start.value(0), 1_675_836_600_000_000_000);
+        assert!(!start.is_null(0));
+    }
+}
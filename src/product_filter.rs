@@ -0,0 +1,283 @@
+use crate::models::ProductData;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/* FILTER - Selects products by their metadata, e.g. `quote=USD,volume_24h>1M` */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TextField {
+    ProductId,
+    Base,
+    Quote,
+    ProductType,
+    Status,
+}
+
+impl TextField {
+    fn value(self, product: &ProductData) -> &str {
+        match self {
+            TextField::ProductId => &product.product_id,
+            TextField::Base => &product.base_currency_id,
+            TextField::Quote => &product.quote_currency_id,
+            TextField::ProductType => &product.product_type,
+            TextField::Status => &product.status,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl Comparison {
+    fn holds(self, left: f64, right: f64) -> bool {
+        match self {
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Clause {
+    /// Matches when any pattern matches, or when none does if `negate`
+    Text { field: TextField, negate: bool, patterns: Vec<String> },
+    TradingDisabled(bool),
+    Volume24h { comparison: Comparison, threshold: f64 },
+}
+
+impl Clause {
+    fn matches(&self, product: &ProductData) -> bool {
+        match self {
+            Clause::Text { field, negate, patterns } => {
+                let value = field.value(product);
+                patterns.iter().any(|p| glob_match(p, value)) != *negate
+            },
+            Clause::TradingDisabled(disabled) => product.trading_disabled == *disabled,
+            Clause::Volume24h { comparison, threshold } => product
+                .volume_24h
+                .as_deref()
+                .and_then(|v| v.parse::<f64>().ok())
+                .is_some_and(|volume| comparison.holds(volume, *threshold)),
+        }
+    }
+}
+
+/// Case-insensitive match of `value` against a pattern where `*` is any run and `?` any character
+pub fn glob_match(pattern: &str, value: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let value: Vec<char> = value.to_uppercase().chars().collect();
+    let (mut p, mut v) = (0, 0);
+    // position of the last `*` and of the value character it is matching up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while v < value.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+            p += 1;
+            v += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, v));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            v = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Parses `1500`, `2.5K`, `1M` or `3B`
+fn parse_amount(value: &str) -> Result<f64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1e3),
+        Some('M') => (&value[..value.len() - 1], 1e6),
+        Some('B') => (&value[..value.len() - 1], 1e9),
+        _ => (value, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(number) => Ok(number * multiplier),
+        Err(_) => bail!("Invalid amount {:?}", value),
+    }
+}
+
+fn parse_clause(clause: &str) -> Result<Clause> {
+    let operators = [("!=", Comparison::Ne), (">=", Comparison::Ge), ("<=", Comparison::Le), ("=", Comparison::Eq), (">", Comparison::Gt), ("<", Comparison::Lt)];
+    let Some((index, operator, comparison)) = operators
+        .iter()
+        .filter_map(|(op, comparison)| clause.find(op).map(|i| (i, *op, *comparison)))
+        .min_by_key(|(i, op, _)| (*i, std::cmp::Reverse(op.len())))
+    else {
+        bail!("Missing operator in filter clause {:?}", clause);
+    };
+    let key = clause[..index].trim().to_lowercase();
+    let value = clause[index + operator.len()..].trim();
+    if value.is_empty() {
+        bail!("Missing value in filter clause {:?}", clause);
+    }
+
+    let text_field = match key.as_str() {
+        "product_id" | "id" => Some(TextField::ProductId),
+        "base" => Some(TextField::Base),
+        "quote" => Some(TextField::Quote),
+        "type" | "product_type" => Some(TextField::ProductType),
+        "status" => Some(TextField::Status),
+        _ => None,
+    };
+
+    match (text_field, key.as_str(), comparison) {
+        (Some(field), _, Comparison::Eq | Comparison::Ne) => Ok(Clause::Text {
+            field,
+            negate: comparison == Comparison::Ne,
+            patterns: value.split('|').map(|p| p.trim().to_string()).collect(),
+        }),
+        (None, "trading_disabled", Comparison::Eq | Comparison::Ne) => match value.parse::<bool>() {
+            Ok(disabled) => Ok(Clause::TradingDisabled(disabled == (comparison == Comparison::Eq))),
+            Err(_) => bail!("trading_disabled expects true or false, got {:?}", value),
+        },
+        (None, "volume_24h", _) => Ok(Clause::Volume24h { comparison, threshold: parse_amount(value)? }),
+        (None, "trading_disabled", _) | (Some(_), _, _) => bail!("Operator {} is not supported for {}", operator, key),
+        (None, _, _) => bail!("Unknown filter key {:?}", key),
+    }
+}
+
+/// Selection of products by metadata, used by `SubscribeProducts::Filter`
+///
+/// An expression is a comma separated list of clauses that must all hold. Keys are
+/// `product_id`, `base`, `quote`, `product_type` and `status`, which take `=` or `!=` and
+/// case-insensitive glob patterns separated by `|`, `trading_disabled` which takes `true` or
+/// `false`, and `volume_24h` which takes any comparison and a `K`, `M` or `B` suffix.
+///
+/// # Example
+///
+/// ```
+/// use rs_coinbase_pairs_handler::product_filter::ProductFilter;
+///
+/// let filter: ProductFilter = "quote=USD|USDC,product_type=spot,trading_disabled=false,volume_24h>1M".parse().unwrap();
+/// assert!("base=BTC*,status!=delisted".parse::<ProductFilter>().is_ok());
+/// assert!("volume_24h~1M".parse::<ProductFilter>().is_err());
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProductFilter {
+    expression: String,
+    clauses: Vec<Clause>,
+}
+
+impl ProductFilter {
+    pub fn matches(&self, product: &ProductData) -> bool {
+        self.clauses.iter().all(|clause| clause.matches(product))
+    }
+
+    /// `product_id`s of the matching products, in the order given
+    pub fn select(&self, products: &[ProductData]) -> Vec<String> {
+        products
+            .iter()
+            .filter(|product| self.matches(product))
+            .map(|product| product.product_id.clone())
+            .collect()
+    }
+}
+
+impl FromStr for ProductFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(expression: &str) -> Result<ProductFilter> {
+        let clauses = expression
+            .split(',')
+            .filter(|clause| !clause.trim().is_empty())
+            .map(parse_clause)
+            .collect::<Result<Vec<Clause>>>()?;
+        Ok(ProductFilter { expression: expression.trim().to_string(), clauses })
+    }
+}
+
+impl TryFrom<String> for ProductFilter {
+    type Error = anyhow::Error;
+
+    fn try_from(expression: String) -> Result<ProductFilter> {
+        expression.parse()
+    }
+}
+
+impl From<ProductFilter> for String {
+    fn from(filter: ProductFilter) -> String {
+        filter.expression
+    }
+}
+
+impl fmt::Display for ProductFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(product_id: &str, product_type: &str, volume_24h: Option<&str>, trading_disabled: bool) -> ProductData {
+        let (base, quote) = product_id.split_once('-').unwrap();
+        ProductData {
+            product_type: product_type.to_string(),
+            trading_disabled,
+            volume_24h: volume_24h.map(str::to_string),
+            ..ProductData::test(product_id, base, quote)
+        }
+    }
+
+    fn products() -> Vec<ProductData> {
+        vec![
+            product("BTC-USD", "SPOT", Some("25000000"), false),
+            product("ETH-USD", "SPOT", Some("900000"), false),
+            product("ETH-BTC", "SPOT", Some("5000000"), false),
+            product("BTC-USDC", "SPOT", None, true),
+            product("BIT-28JUL23-CDE", "FUTURE", Some("1200"), false),
+        ]
+    }
+
+    #[test]
+    fn selects_by_every_key() {
+        let select = |expression: &str| expression.parse::<ProductFilter>().unwrap().select(&products());
+
+        assert_eq!(select("quote=USD,volume_24h>1M"), vec!["BTC-USD"]);
+        assert_eq!(select("quote=usd|usdc"), vec!["BTC-USD", "ETH-USD", "BTC-USDC"]);
+        assert_eq!(select("base=ETH,quote!=USD"), vec!["ETH-BTC"]);
+        assert_eq!(select("product_type=future"), vec!["BIT-28JUL23-CDE"]);
+        assert_eq!(select("product_id=BTC-*,trading_disabled=false"), vec!["BTC-USD"]);
+        assert_eq!(select("trading_disabled!=false"), vec!["BTC-USDC"]);
+        assert_eq!(select("volume_24h<=900K"), vec!["ETH-USD", "BIT-28JUL23-CDE"]);
+        assert_eq!(select("status=online").len(), 5);
+        assert_eq!(select("").len(), 5);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expression in ["quote", "quote=", "quote>USD", "volume_24h>lots", "trading_disabled=maybe", "colour=red"] {
+            assert!(expression.parse::<ProductFilter>().is_err(), "{} should not parse", expression);
+        }
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*-US?", "eth-usd"));
+        assert!(glob_match("B*T*", "BIT-28JUL23-CDE"));
+        assert!(!glob_match("BTC-*", "ETH-BTC"));
+        assert!(glob_match("*", ""));
+    }
+}
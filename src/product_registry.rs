@@ -0,0 +1,294 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::models::ProductData;
+use anyhow::Result;
+use log::{error, info};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+/// Default time products are served from the cache before they are fetched again
+pub const DEFAULT_PRODUCT_TTL: Duration = Duration::from_secs(300);
+
+/// Difference between two fetches of the product list
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProductChange {
+    /// Product that was not in the previous list
+    Listed(ProductData),
+    /// Product that is no longer in the list, as last seen
+    Delisted(ProductData),
+    StatusChanged {
+        product_id: String,
+        previous: String,
+        current: String,
+    },
+    TradingDisabledChanged {
+        product_id: String,
+        trading_disabled: bool,
+    },
+    /// Any of the increments or the min or max sizes changed
+    LimitsChanged {
+        previous: Box<ProductData>,
+        current: Box<ProductData>,
+    },
+}
+
+impl ProductChange {
+    pub fn product_id(&self) -> &str {
+        match self {
+            ProductChange::Listed(product) | ProductChange::Delisted(product) => &product.product_id,
+            ProductChange::StatusChanged { product_id, .. } => product_id,
+            ProductChange::TradingDisabledChanged { product_id, .. } => product_id,
+            ProductChange::LimitsChanged { current, .. } => &current.product_id,
+        }
+    }
+}
+
+fn limits(product: &ProductData) -> [&str; 7] {
+    [
+        &product.base_increment,
+        &product.base_min_size,
+        &product.base_max_size,
+        &product.quote_increment,
+        &product.quote_min_size,
+        &product.quote_max_size,
+        &product.price_increment,
+    ]
+}
+
+/// Changes that turn `previous` into `current`, in `product_id` order
+pub fn diff_products(previous: &[ProductData], current: &[ProductData]) -> Vec<ProductChange> {
+    let by_id = |products: &'_ [ProductData]| -> BTreeMap<String, ProductData> {
+        products.iter().map(|p| (p.product_id.clone(), p.clone())).collect()
+    };
+    let (previous, current) = (by_id(previous), by_id(current));
+    let mut changes = Vec::new();
+
+    for (product_id, product) in &current {
+        let Some(before) = previous.get(product_id) else {
+            changes.push(ProductChange::Listed(product.clone()));
+            continue;
+        };
+        if before.status != product.status {
+            changes.push(ProductChange::StatusChanged {
+                product_id: product_id.clone(),
+                previous: before.status.clone(),
+                current: product.status.clone(),
+            });
+        }
+        if before.trading_disabled != product.trading_disabled {
+            changes.push(ProductChange::TradingDisabledChanged {
+                product_id: product_id.clone(),
+                trading_disabled: product.trading_disabled,
+            });
+        }
+        if limits(before) != limits(product) {
+            changes.push(ProductChange::LimitsChanged {
+                previous: Box::new(before.clone()),
+                current: Box::new(product.clone()),
+            });
+        }
+    }
+    for (product_id, product) in &previous {
+        if !current.contains_key(product_id) {
+            changes.push(ProductChange::Delisted(product.clone()));
+        }
+    }
+
+    changes.sort_by(|a, b| a.product_id().cmp(b.product_id()));
+    changes
+}
+
+#[derive(Default)]
+struct RegistryState {
+    /// Products in the order the API lists them
+    products: Vec<ProductData>,
+    fetched_at: Option<Instant>,
+    generation: u64,
+}
+
+/// Cache of `ProductData` that reports what changed between fetches
+///
+/// Products are served from memory for `ttl` and fetched again on the next read after that, or
+/// on a timer with `spawn_refresh`. The first fetch only fills the cache, every later one sends
+/// its `ProductChange`s to each receiver returned by `subscribe`. Clones share the cache.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+/// use rs_coinbase_pairs_handler::product_registry::{ProductChange, ProductRegistry, DEFAULT_PRODUCT_TTL};
+/// use std::sync::Arc;
+///
+/// # async fn example() {
+/// let client = Arc::new(AdvancedTradeRESTClient::new("https://api.coinbase.com/api/v3"));
+/// let registry = ProductRegistry::new(client, DEFAULT_PRODUCT_TTL);
+/// let mut changes = registry.subscribe();
+/// registry.spawn_refresh();
+///
+/// while let Some(change) = changes.recv().await {
+///     if let ProductChange::TradingDisabledChanged { product_id, trading_disabled: true } = change {
+///         println!("{} stopped trading", product_id);
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ProductRegistry {
+    client: Arc<AdvancedTradeRESTClient>,
+    ttl: Duration,
+    state: Arc<RwLock<RegistryState>>,
+    subscribers: Arc<Mutex<Vec<UnboundedSender<ProductChange>>>>,
+    /// Serializes fetches so concurrent refreshes cannot report the same change twice
+    refreshing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl ProductRegistry {
+    pub fn new(client: Arc<AdvancedTradeRESTClient>, ttl: Duration) -> ProductRegistry {
+        ProductRegistry {
+            client,
+            ttl,
+            state: Arc::new(RwLock::new(RegistryState::default())),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            refreshing: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Returns the client products are fetched with
+    pub fn client(&self) -> Arc<AdvancedTradeRESTClient> {
+        self.client.clone()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Receives the changes found by every refresh from now on
+    pub fn subscribe(&self) -> UnboundedReceiver<ProductChange> {
+        let (sender, receiver) = unbounded_channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.state.read().unwrap().fetched_at.is_some_and(|t| t.elapsed() < self.ttl)
+    }
+
+    /// Fetches the products now, regardless of the TTL
+    ///
+    /// # Returns
+    ///
+    /// `Result<Vec<ProductChange>>` - what changed since the previous fetch, empty on the first one
+    pub async fn refresh(&self) -> Result<Vec<ProductChange>> {
+        let _guard = self.refreshing.lock().await;
+        let current = self.client.get_available_products().await?.products;
+
+        let mut state = self.state.write().unwrap();
+        let changes = match state.fetched_at {
+            Some(_) => diff_products(&state.products, &current),
+            None => Vec::new(),
+        };
+        state.products = current;
+        state.fetched_at = Some(Instant::now());
+        state.generation += 1;
+        drop(state);
+
+        if !changes.is_empty() {
+            info!("Product list changed: {} changes", changes.len());
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|sender| changes.iter().all(|change| sender.send(change.clone()).is_ok()));
+        }
+        Ok(changes)
+    }
+
+    /// Cached products, fetched first when the cache is empty or older than the TTL
+    pub async fn products(&self) -> Result<Vec<ProductData>> {
+        if !self.is_fresh() {
+            self.refresh().await?;
+        }
+        Ok(self.cached())
+    }
+
+    /// Same as `products` but only the `product_id`s
+    pub async fn product_ids(&self) -> Result<Vec<String>> {
+        Ok(self.products().await?.into_iter().map(|p| p.product_id).collect())
+    }
+
+    /// Number of successful fetches so far, to tell whether the cache was replaced
+    ///
+    /// Changes to fields that do not produce a `ProductChange`, such as `volume_24h`, are only
+    /// visible this way.
+    pub fn generation(&self) -> u64 {
+        self.state.read().unwrap().generation
+    }
+
+    /// Products as of the last fetch, without fetching
+    pub fn cached(&self) -> Vec<ProductData> {
+        self.state.read().unwrap().products.clone()
+    }
+
+    /// Product as of the last fetch, without fetching
+    pub fn get(&self, product_id: &str) -> Option<ProductData> {
+        self.state.read().unwrap().products.iter().find(|p| p.product_id == product_id).cloned()
+    }
+
+    /// Refreshes the products every `ttl` on the current tokio runtime
+    ///
+    /// Failed fetches are logged and retried on the next tick. Abort the returned handle to stop.
+    pub fn spawn_refresh(&self) -> JoinHandle<()> {
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(registry.ttl);
+            loop {
+                interval.tick().await;
+                if let Err(e) = registry.refresh().await {
+                    error!("Error refreshing products: {}", e);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(product_id: &str, status: &str, trading_disabled: bool, base_min_size: &str) -> ProductData {
+        ProductData {
+            base_min_size: base_min_size.to_string(),
+            status: status.to_string(),
+            trading_disabled,
+            ..ProductData::test(product_id, &product_id[..3], "USD")
+        }
+    }
+
+    #[test]
+    fn reports_every_kind_of_change() {
+        let previous = vec![
+            product("BTC-USD", "online", false, "0.0001"),
+            product("ETH-USD", "online", false, "0.001"),
+            product("LTC-USD", "online", false, "0.01"),
+        ];
+        let current = vec![
+            product("BTC-USD", "online", false, "0.00001"),
+            product("ETH-USD", "delisted", true, "0.001"),
+            product("SOL-USD", "online", false, "0.01"),
+        ];
+
+        let changes = diff_products(&previous, &current);
+        assert_eq!(changes, vec![
+            ProductChange::LimitsChanged {
+                previous: Box::new(previous[0].clone()),
+                current: Box::new(current[0].clone()),
+            },
+            ProductChange::StatusChanged { product_id: "ETH-USD".to_string(), previous: "online".to_string(), current: "delisted".to_string() },
+            ProductChange::TradingDisabledChanged { product_id: "ETH-USD".to_string(), trading_disabled: true },
+            ProductChange::Delisted(previous[2].clone()),
+            ProductChange::Listed(current[2].clone()),
+        ]);
+        assert!(diff_products(&current, &current).is_empty());
+    }
+}
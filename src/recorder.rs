@@ -0,0 +1,474 @@
+use crate::sink::MessageSink;
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zstd::stream::write::Encoder;
+
+/// File name of the manifest at the root of a recording directory
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/*
+RECORDER - Append-only capture of the raw WebSocket feed
+
+Layout of a recording directory:
+
+    <directory>/manifest.json
+    <directory>/<YYYY-MM-DD>/<channel>/<product_id>/<channel>_<product_id>_<HHMMSS>_<n>.jsonl.zst
+
+Frames that reference several products go to the `_mixed` partition, frames without a
+product (subscriptions, heartbeats, errors) to `_none`, so every frame is stored exactly once.
+*/
+
+/// One line of a recording file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RecordedFrame {
+    /// Local receive time in nanoseconds since the Unix epoch
+    pub received_at_ns: u64,
+    /// The text frame exactly as received
+    pub frame: String,
+}
+
+impl RecordedFrame {
+    pub fn received_at(&self) -> SystemTime {
+        UNIX_EPOCH + std::time::Duration::from_nanos(self.received_at_ns)
+    }
+}
+
+/// Entry of `manifest.json` describing one recording file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManifestEntry {
+    /// Path relative to the recording directory
+    pub path: String,
+    pub date: NaiveDate,
+    pub channel: String,
+    pub product_id: String,
+    pub frames: u64,
+    /// Sequence number range of the file, `None` when no frame carried one
+    pub min_sequence_num: Option<u64>,
+    pub max_sequence_num: Option<u64>,
+    pub first_received_at_ns: u64,
+    pub last_received_at_ns: u64,
+    /// `false` while the recorder is still appending to the file
+    pub complete: bool,
+    /// The file was left unfinished by a recorder that stopped without closing it, e.g. a crash,
+    /// and only holds the frames up to its last flush
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Reads `manifest.json` from a recording directory
+    pub fn load(directory: &Path) -> Result<Manifest> {
+        let path = directory.join(MANIFEST_FILE);
+        let file = File::open(&path).with_context(|| format!("Unable to open {}", path.display()))?;
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    fn store(&self, directory: &Path) -> Result<()> {
+        // write to a temporary file first so readers never see a half written manifest
+        let tmp = directory.join(format!("{}.tmp", MANIFEST_FILE));
+        serde_json::to_writer_pretty(BufWriter::new(File::create(&tmp)?), self)?;
+        fs::rename(tmp, directory.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    pub directory: PathBuf,
+    /// Uncompressed bytes after which a file is closed and a new one started
+    pub max_file_bytes: u64,
+    /// zstd compression level, 1 (fastest) to 22 (smallest)
+    pub compression_level: i32,
+}
+
+impl RecorderConfig {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        RecorderConfig {
+            directory: directory.into(),
+            max_file_bytes: 256 * 1024 * 1024,
+            compression_level: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Partition {
+    date: NaiveDate,
+    channel: String,
+    product_id: String,
+}
+
+struct OpenFile {
+    encoder: Encoder<'static, BufWriter<File>>,
+    bytes: u64,
+    /// Index of the file in the manifest
+    entry: usize,
+}
+
+/// `MessageSink` that records every raw frame to rotating zstd-compressed JSONL files
+///
+/// Files are partitioned by UTC receive date, channel and product, and rotated once they reach
+/// `RecorderConfig::max_file_bytes`. The manifest is rewritten whenever a file is opened or
+/// closed and on `flush`. Files still listed as incomplete can be read up to the last flush.
+pub struct MarketDataRecorder {
+    config: RecorderConfig,
+    manifest: Manifest,
+    files: HashMap<Partition, OpenFile>,
+    current_date: Option<NaiveDate>,
+}
+
+impl MarketDataRecorder {
+    /// Creates the recording directory and picks up an existing manifest, if any
+    ///
+    /// Files an earlier recorder left incomplete, because it stopped without closing them, are
+    /// finalized first: their frames are counted again up to the last readable one and they are
+    /// marked complete and `truncated`.
+    pub fn new(config: RecorderConfig) -> Result<MarketDataRecorder> {
+        fs::create_dir_all(&config.directory)?;
+        let mut manifest = if config.directory.join(MANIFEST_FILE).exists() {
+            Manifest::load(&config.directory)?
+        } else {
+            Manifest::default()
+        };
+
+        let mut recovered = false;
+        for entry in manifest.files.iter_mut().filter(|entry| !entry.complete) {
+            recover(&config.directory, entry);
+            recovered = true;
+        }
+        if recovered {
+            manifest.store(&config.directory)?;
+        }
+
+        Ok(MarketDataRecorder {
+            config,
+            manifest,
+            files: HashMap::new(),
+            current_date: None,
+        })
+    }
+
+    /// Appends a frame to the file of its partition
+    pub fn record(&mut self, received_at: SystemTime, frame: &str) -> Result<()> {
+        let received_at_ns = received_at.duration_since(UNIX_EPOCH)?.as_nanos() as u64;
+        let date = DateTime::<Utc>::from(received_at).date_naive();
+        if self.current_date.is_some_and(|current| current != date) {
+            self.close_files(|partition| partition.date != date)?;
+        }
+        self.current_date = Some(date);
+
+        let (channel, product_id, sequence_num) = classify(frame);
+        let partition = Partition { date, channel, product_id };
+
+        let mut line = serde_json::to_vec(&RecordedFrame { received_at_ns, frame: frame.to_string() })?;
+        line.push(b'\n');
+
+        if !self.files.contains_key(&partition) {
+            let file = self.open_file(&partition, received_at_ns)?;
+            self.files.insert(partition.clone(), file);
+        }
+        let file = self.files.get_mut(&partition).unwrap();
+        file.encoder.write_all(&line)?;
+        file.bytes += line.len() as u64;
+
+        let entry = &mut self.manifest.files[file.entry];
+        entry.frames += 1;
+        entry.last_received_at_ns = received_at_ns;
+        if let Some(sequence_num) = sequence_num {
+            entry.min_sequence_num = Some(entry.min_sequence_num.map_or(sequence_num, |s| s.min(sequence_num)));
+            entry.max_sequence_num = Some(entry.max_sequence_num.map_or(sequence_num, |s| s.max(sequence_num)));
+        }
+
+        if file.bytes >= self.config.max_file_bytes {
+            self.close_files(|p| *p == partition)?;
+        }
+        Ok(())
+    }
+
+    /// Finishes every open file and writes the final manifest
+    pub fn close(&mut self) -> Result<()> {
+        self.close_files(|_| true)
+    }
+
+    fn open_file(&mut self, partition: &Partition, received_at_ns: u64) -> Result<OpenFile> {
+        let relative_dir = PathBuf::from(partition.date.to_string())
+            .join(&partition.channel)
+            .join(&partition.product_id);
+        fs::create_dir_all(self.config.directory.join(&relative_dir))?;
+
+        let time = DateTime::<Utc>::from(UNIX_EPOCH + std::time::Duration::from_nanos(received_at_ns))
+            .format("%H%M%S");
+        let mut n = 0;
+        let relative_path = loop {
+            let candidate = relative_dir.join(format!(
+                "{}_{}_{}_{}.jsonl.zst",
+                partition.channel, partition.product_id, time, n
+            ));
+            if !self.config.directory.join(&candidate).exists() {
+                break candidate;
+            }
+            n += 1;
+        };
+
+        let path = self.config.directory.join(&relative_path);
+        debug!("Recording {} {} to {}", partition.channel, partition.product_id, path.display());
+        let encoder = Encoder::new(BufWriter::new(File::create(&path)?), self.config.compression_level)?;
+
+        self.manifest.files.push(ManifestEntry {
+            path: relative_path.to_string_lossy().replace('\\', "/"),
+            date: partition.date,
+            channel: partition.channel.clone(),
+            product_id: partition.product_id.clone(),
+            frames: 0,
+            min_sequence_num: None,
+            max_sequence_num: None,
+            first_received_at_ns: received_at_ns,
+            last_received_at_ns: received_at_ns,
+            complete: false,
+            truncated: false,
+        });
+        self.manifest.store(&self.config.directory)?;
+
+        Ok(OpenFile {
+            encoder,
+            bytes: 0,
+            entry: self.manifest.files.len() - 1,
+        })
+    }
+
+    fn close_files(&mut self, mut predicate: impl FnMut(&Partition) -> bool) -> Result<()> {
+        let closing: Vec<Partition> = self.files.keys().filter(|p| predicate(p)).cloned().collect();
+        if closing.is_empty() {
+            return Ok(());
+        }
+
+        for partition in closing {
+            let file = self.files.remove(&partition).unwrap();
+            file.encoder.finish()?.flush()?;
+            let entry = &mut self.manifest.files[file.entry];
+            entry.complete = true;
+            info!("Closed recording {} ({} frames)", entry.path, entry.frames);
+        }
+        self.manifest.store(&self.config.directory)
+    }
+}
+
+impl MessageSink for MarketDataRecorder {
+    fn on_frame(&mut self, received_at: SystemTime, frame: &str) -> Result<()> {
+        self.record(received_at, frame)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        for file in self.files.values_mut() {
+            file.encoder.flush()?;
+        }
+        self.manifest.store(&self.config.directory)
+    }
+}
+
+impl Drop for MarketDataRecorder {
+    fn drop(&mut self) {
+        if let Err(e) = self.close() {
+            log::error!("Error closing recordings: {}", e);
+        }
+    }
+}
+
+/// Recounts the readable frames of a file left incomplete and marks it complete and truncated
+fn recover(directory: &Path, entry: &mut ManifestEntry) {
+    let path = directory.join(&entry.path);
+    let decoder = match File::open(&path).and_then(zstd::stream::read::Decoder::new) {
+        Ok(decoder) => decoder,
+        Err(e) => {
+            warn!("Unable to read incomplete recording {}: {}", path.display(), e);
+            entry.complete = true;
+            entry.truncated = true;
+            return;
+        }
+    };
+
+    entry.frames = 0;
+    entry.min_sequence_num = None;
+    entry.max_sequence_num = None;
+    // the tail after the last flush is cut short, everything before it is intact
+    let frames = BufReader::new(decoder)
+        .lines()
+        .map_while(|line| serde_json::from_str::<RecordedFrame>(&line.ok()?).ok());
+    for frame in frames {
+        if entry.frames == 0 {
+            entry.first_received_at_ns = frame.received_at_ns;
+        }
+        entry.frames += 1;
+        entry.last_received_at_ns = frame.received_at_ns;
+        if let (_, _, Some(sequence_num)) = classify(&frame.frame) {
+            entry.min_sequence_num = Some(entry.min_sequence_num.map_or(sequence_num, |s| s.min(sequence_num)));
+            entry.max_sequence_num = Some(entry.max_sequence_num.map_or(sequence_num, |s| s.max(sequence_num)));
+        }
+    }
+    entry.complete = true;
+    entry.truncated = true;
+    info!("Recovered incomplete recording {} ({} frames)", entry.path, entry.frames);
+}
+
+/// Extracts the partition channel, product and sequence number of a raw frame
+fn classify(frame: &str) -> (String, String, Option<u64>) {
+    let value: Value = match serde_json::from_str(frame) {
+        Ok(value) => value,
+        Err(_) => return ("_unknown".to_string(), "_none".to_string(), None),
+    };
+
+    let channel = value["channel"]
+        .as_str()
+        .or_else(|| value["type"].as_str())
+        .unwrap_or("_unknown");
+
+    let mut products = BTreeSet::new();
+    collect_product_ids(&value["events"], &mut products);
+    let product_id = match products.len() {
+        0 => "_none".to_string(),
+        1 => products.into_iter().next().unwrap(),
+        _ => "_mixed".to_string(),
+    };
+
+    (sanitize(channel), sanitize(&product_id), value["sequence_num"].as_u64())
+}
+
+fn collect_product_ids(value: &Value, products: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("product_id", Value::String(product_id)) => {
+                        products.insert(product_id.clone());
+                    },
+                    _ => collect_product_ids(value, products),
+                }
+            }
+        },
+        Value::Array(values) => values.iter().for_each(|v| collect_product_ids(v, products)),
+        _ => (),
+    }
+}
+
+/// Keeps partition names safe to use as path components
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ticker(sequence_num: u64, product_id: &str) -> String {
+        format!(
+            r#"{{"channel":"ticker","client_id":"","timestamp":"2023-02-08T06:12:20Z","sequence_num":{},"events":[{{"type":"update","tickers":[{{"product_id":"{}"}}]}}]}}"#,
+            sequence_num, product_id
+        )
+    }
+
+    fn read_frames(directory: &Path, entry: &ManifestEntry) -> Vec<RecordedFrame> {
+        let decoder = zstd::stream::read::Decoder::new(File::open(directory.join(&entry.path)).unwrap()).unwrap();
+        BufReader::new(decoder)
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn partitions_frames_by_channel_and_product() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+
+        recorder.record(t0, &ticker(3, "ETH-USD")).unwrap();
+        recorder.record(t0, &ticker(4, "BTC-USD")).unwrap();
+        recorder.record(t0 + Duration::from_millis(5), &ticker(7, "ETH-USD")).unwrap();
+        recorder.record(t0, r#"{"type":"error","message":"authentication failure"}"#).unwrap();
+        recorder.close().unwrap();
+
+        let manifest = Manifest::load(dir.path()).unwrap();
+        assert_eq!(manifest.files.len(), 3);
+
+        let eth = manifest.files.iter().find(|f| f.product_id == "ETH-USD").unwrap();
+        assert_eq!(eth.path, "2023-02-08/ticker/ETH-USD/ticker_ETH-USD_061220_0.jsonl.zst");
+        assert_eq!((eth.min_sequence_num, eth.max_sequence_num), (Some(3), Some(7)));
+        assert_eq!(eth.frames, 2);
+        assert!(eth.complete);
+
+        let frames = read_frames(dir.path(), eth);
+        assert_eq!(frames[1].frame, ticker(7, "ETH-USD"));
+        assert_eq!(frames[1].received_at(), t0 + Duration::from_millis(5));
+
+        let error = manifest.files.iter().find(|f| f.channel == "error").unwrap();
+        assert_eq!(error.product_id, "_none");
+    }
+
+    #[test]
+    fn rotates_files_by_size_and_date() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = RecorderConfig { max_file_bytes: 1, ..RecorderConfig::new(dir.path()) };
+        let mut recorder = MarketDataRecorder::new(config).unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_675_900_000);
+
+        recorder.record(t0, &ticker(1, "ETH-USD")).unwrap();
+        recorder.record(t0, &ticker(2, "ETH-USD")).unwrap();
+        recorder.record(t0 + Duration::from_secs(86_400), &ticker(3, "ETH-USD")).unwrap();
+        drop(recorder);
+
+        let manifest = Manifest::load(dir.path()).unwrap();
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "2023-02-08/ticker/ETH-USD/ticker_ETH-USD_234640_0.jsonl.zst",
+            "2023-02-08/ticker/ETH-USD/ticker_ETH-USD_234640_1.jsonl.zst",
+            "2023-02-09/ticker/ETH-USD/ticker_ETH-USD_234640_0.jsonl.zst",
+        ]);
+        assert!(manifest.files.iter().all(|f| f.complete && f.frames == 1));
+    }
+
+    #[test]
+    fn finalizes_files_left_incomplete_by_a_crash() {
+        let dir = tempfile::tempdir().unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        recorder.record(t0, &ticker(3, "ETH-USD")).unwrap();
+        recorder.record(t0 + Duration::from_millis(5), &ticker(4, "ETH-USD")).unwrap();
+        recorder.flush().unwrap();
+        // a crash skips close, and the manifest may lag behind the frames already flushed
+        std::mem::forget(recorder);
+        let mut manifest = Manifest::load(dir.path()).unwrap();
+        assert!(!manifest.files[0].complete);
+        manifest.files[0].frames = 0;
+        manifest.files[0].max_sequence_num = None;
+        manifest.store(dir.path()).unwrap();
+
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        let manifest = Manifest::load(dir.path()).unwrap();
+        let entry = &manifest.files[0];
+        assert!(entry.complete && entry.truncated);
+        assert_eq!(entry.frames, 2);
+        assert_eq!((entry.min_sequence_num, entry.max_sequence_num), (Some(3), Some(4)));
+        assert_eq!(entry.last_received_at_ns, 1_675_836_740_005_000_000);
+
+        recorder.record(t0, &ticker(5, "ETH-USD")).unwrap();
+        recorder.close().unwrap();
+        let manifest = Manifest::load(dir.path()).unwrap();
+        assert_eq!(manifest.files[1].path, "2023-02-08/ticker/ETH-USD/ticker_ETH-USD_061220_1.jsonl.zst");
+        assert!(manifest.files[1].complete && !manifest.files[1].truncated);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::recorder::{Manifest, ManifestEntry, RecordedFrame};
+use anyhow::{bail, Result};
+use log::warn;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Lines};
+use std::path::Path;
+use std::time::Duration;
+use zstd::stream::read::Decoder;
+
+/// How fast recorded frames are played back
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Keeps the original gaps between frames
+    RealTime,
+    /// Divides the original gaps by the factor e.g. `Accelerated(10.0)` plays an hour in six minutes
+    ///
+    /// The factor must be finite and above zero, `validate` rejects any other.
+    Accelerated(f64),
+    /// Plays frames back to back without waiting
+    AsFastAsPossible,
+}
+
+impl ReplaySpeed {
+    /// Fails for an `Accelerated` factor that is not finite or not above zero
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ReplaySpeed::Accelerated(factor) if !factor.is_finite() || *factor <= 0.0 => {
+                bail!("Invalid replay speed factor {}, it must be finite and above zero", factor)
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Wall clock time to wait between frames recorded `recorded_gap` apart
+    ///
+    /// `None` for `AsFastAsPossible` and for factors that fail `validate`.
+    pub fn delay(&self, recorded_gap: Duration) -> Option<Duration> {
+        self.validate().ok()?;
+        match self {
+            ReplaySpeed::RealTime => Some(recorded_gap),
+            ReplaySpeed::Accelerated(factor) => Some(recorded_gap.div_f64(*factor)),
+            ReplaySpeed::AsFastAsPossible => None,
+        }
+    }
+}
+
+type FrameLines = Lines<BufReader<Decoder<'static, BufReader<File>>>>;
+
+struct ReplayFile {
+    path: String,
+    lines: FrameLines,
+}
+
+impl ReplayFile {
+    /// Reads the next frame, treating a truncated or corrupt tail as the end of the file
+    fn next_frame(&mut self) -> Option<RecordedFrame> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Stopped reading {} early: {}", self.path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str(&line) {
+            Ok(frame) => Some(frame),
+            Err(e) => {
+                warn!("Stopped reading {} early: {}", self.path, e);
+                None
+            }
+        }
+    }
+}
+
+/// Frames read back from a `MarketDataRecorder` directory in receive order
+///
+/// Files are merged lazily by `received_at_ns`, so a full day of recordings is never held in
+/// memory at once. Frames received at the same instant keep manifest order and then file
+/// order, which makes every replay of the same directory identical.
+pub struct ReplaySource {
+    files: Vec<ReplayFile>,
+    /// Next frame of each file, keyed for a min-heap on receive time then file index
+    heads: BinaryHeap<Reverse<(u64, usize, FrameSlot)>>,
+}
+
+/// Wrapper that keeps frames out of the heap ordering
+struct FrameSlot(RecordedFrame);
+
+impl PartialEq for FrameSlot {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for FrameSlot {}
+
+impl PartialOrd for FrameSlot {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FrameSlot {
+    fn cmp(&self, _: &Self) -> std::cmp::Ordering {
+        std::cmp::Ordering::Equal
+    }
+}
+
+impl ReplaySource {
+    /// Opens every file listed in the manifest of `directory`
+    pub fn open(directory: &Path) -> Result<ReplaySource> {
+        ReplaySource::open_filtered(directory, |_| true)
+    }
+
+    /// Opens the files of `directory` whose manifest entry matches `filter`
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use rs_coinbase_pairs_handler::replay::ReplaySource;
+    /// use std::path::Path;
+    ///
+    /// let source = ReplaySource::open_filtered(Path::new("recordings"), |entry| {
+    ///     entry.channel == "l2_data" && entry.product_id == "BTC-USD"
+    /// }).unwrap();
+    /// ```
+    pub fn open_filtered(directory: &Path, filter: impl Fn(&ManifestEntry) -> bool) -> Result<ReplaySource> {
+        let manifest = Manifest::load(directory)?;
+        let mut source = ReplaySource { files: Vec::new(), heads: BinaryHeap::new() };
+
+        for entry in manifest.files.iter().filter(|entry| filter(entry)) {
+            let decoder = Decoder::new(File::open(directory.join(&entry.path))?)?;
+            let mut file = ReplayFile {
+                path: entry.path.clone(),
+                lines: BufReader::new(decoder).lines(),
+            };
+            let index = source.files.len();
+            if let Some(frame) = file.next_frame() {
+                source.heads.push(Reverse((frame.received_at_ns, index, FrameSlot(frame))));
+            }
+            source.files.push(file);
+        }
+
+        Ok(source)
+    }
+}
+
+impl Iterator for ReplaySource {
+    type Item = RecordedFrame;
+
+    fn next(&mut self) -> Option<RecordedFrame> {
+        let Reverse((_, index, FrameSlot(frame))) = self.heads.pop()?;
+        if let Some(next) = self.files[index].next_frame() {
+            self.heads.push(Reverse((next.received_at_ns, index, FrameSlot(next))));
+        }
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::{MarketDataRecorder, RecorderConfig};
+    use std::time::UNIX_EPOCH;
+
+    fn frame(channel: &str, product_id: &str, sequence_num: u64) -> String {
+        format!(
+            r#"{{"channel":"{}","sequence_num":{},"events":[{{"product_id":"{}"}}]}}"#,
+            channel, sequence_num, product_id
+        )
+    }
+
+    #[test]
+    fn merges_partitions_in_receive_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+        recorder.record(t0, &frame("ticker", "ETH-USD", 1)).unwrap();
+        recorder.record(t0 + Duration::from_millis(2), &frame("ticker", "BTC-USD", 3)).unwrap();
+        recorder.record(t0 + Duration::from_millis(1), &frame("l2_data", "ETH-USD", 2)).unwrap();
+        recorder.record(t0 + Duration::from_millis(2), &frame("ticker", "ETH-USD", 4)).unwrap();
+        recorder.close().unwrap();
+
+        let sequence: Vec<u64> = ReplaySource::open(dir.path())
+            .unwrap()
+            .map(|f| serde_json::from_str::<serde_json::Value>(&f.frame).unwrap()["sequence_num"].as_u64().unwrap())
+            .collect();
+        assert_eq!(sequence, vec![1, 2, 4, 3]);
+
+        let filtered = ReplaySource::open_filtered(dir.path(), |e| e.channel == "ticker").unwrap();
+        assert_eq!(filtered.count(), 3);
+    }
+
+    #[test]
+    fn scales_delays_by_speed() {
+        let gap = Duration::from_secs(2);
+        assert_eq!(ReplaySpeed::RealTime.delay(gap), Some(gap));
+        assert_eq!(ReplaySpeed::Accelerated(4.0).delay(gap), Some(Duration::from_millis(500)));
+        assert_eq!(ReplaySpeed::AsFastAsPossible.delay(gap), None);
+    }
+
+    #[test]
+    fn rejects_factors_that_are_not_finite_and_positive() {
+        for factor in [0.0, -2.0, f64::NAN, f64::INFINITY] {
+            assert!(ReplaySpeed::Accelerated(factor).validate().is_err(), "{}", factor);
+            assert_eq!(ReplaySpeed::Accelerated(factor).delay(Duration::from_secs(1)), None);
+        }
+        assert!(ReplaySpeed::Accelerated(0.5).validate().is_ok());
+        assert!(ReplaySpeed::RealTime.validate().is_ok());
+    }
+}
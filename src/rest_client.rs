@@ -1,14 +1,104 @@
-use anyhow::{bail, Result};
-use reqwest::header::HeaderMap;
-use reqwest::{Response, StatusCode};
+use crate::config_builder::TransportConfig;
+use crate::metrics::Metrics;
+use anyhow::{bail, Context, Result};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Certificate, Method, Proxy, Response, StatusCode};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::fmt;
+use std::time::Instant;
+use tracing::{debug, field, info_span, Instrument, Span};
+use url::Url;
+
+/// Builds the URL-encoded query string of a `RestEndpoint.resource`
+///
+/// Parameters are kept in the order they are added, and a parameter may repeat, e.g.
+/// `product_ids`.
+///
+/// # Example
+///
+/// ```
+/// use rs_coinbase_pairs_handler::rest_client::QueryBuilder;
+///
+/// let query = QueryBuilder::new()
+///     .param("product_type", "SPOT")
+///     .repeated("product_ids", ["BTC-USD", "ETH-EUR"])
+///     .optional("limit", Some(50))
+///     .optional("cursor", None::<&str>)
+///     .param("name", "a&b c");
+/// assert_eq!(query.build().as_deref(), Some("product_type=SPOT&product_ids=BTC-USD&product_ids=ETH-EUR&limit=50&name=a%26b+c"));
+/// assert_eq!(QueryBuilder::new().build(), None);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryBuilder {
+    pairs: Vec<(String, String)>,
+}
+
+impl QueryBuilder {
+    pub fn new() -> Self {
+        QueryBuilder::default()
+    }
+
+    pub fn param(mut self, key: &str, value: impl ToString) -> Self {
+        self.pairs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Adds `key` only when `value` is `Some`
+    pub fn optional(self, key: &str, value: Option<impl ToString>) -> Self {
+        match value {
+            Some(value) => self.param(key, value),
+            None => self,
+        }
+    }
+
+    /// Adds `key` once per value
+    pub fn repeated<T: ToString>(self, key: &str, values: impl IntoIterator<Item = T>) -> Self {
+        values.into_iter().fold(self, |query, value| query.param(key, value))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// The encoded query, without the leading `?`, or `None` without parameters
+    pub fn build(&self) -> Option<String> {
+        if self.pairs.is_empty() {
+            return None;
+        }
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        for (key, value) in &self.pairs {
+            serializer.append_pair(key, value);
+        }
+        Some(serializer.finish())
+    }
+}
+
+/// Characters escaped in a path segment: everything but the unreserved set, so an id can
+/// neither end the path nor start a query or fragment
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~');
+
+/// Percent-encodes `value` as a single path segment, e.g. an id interpolated into an endpoint
+///
+/// # Example
+///
+/// ```
+/// use rs_coinbase_pairs_handler::rest_client::path_segment;
+///
+/// assert_eq!(path_segment("BTC-USD"), "BTC-USD");
+/// assert_eq!(path_segment("A/B?c d"), "A%2FB%3Fc%20d");
+/// ```
+pub fn path_segment(value: &str) -> String {
+    utf8_percent_encode(value, PATH_SEGMENT).to_string()
+}
 
 /// Generic REST API Client
 pub struct Client {
     host: String,
+    request_path: Option<String>,
     inner_client: reqwest::Client,
+    metrics: Metrics,
 }
 
 /* 
@@ -23,13 +113,82 @@ impl Client {
     /// Implements a new function for the REST API Client with a host and inner client
     /// `inner_client` is automatically constructed with the `reqwest` library using `reqwest::Client::builder()`
     pub fn new(host: String) -> Self {
+        let inner_client = reqwest::Client::builder()
+            .pool_idle_timeout(None)
+            .build()
+            .unwrap();
+        Client::with_inner_client(host, inner_client)
+    }
+
+    /// Same as `new` but builds `inner_client` from the proxy, CA certificates and timeouts
+    /// in `transport`
+    pub fn with_transport(host: String, transport: &TransportConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(None)
+            .danger_accept_invalid_certs(transport.accept_invalid_certs);
+
+        if let Some(proxy) = &transport.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        for path in &transport.root_certificates {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Unable to read CA certificate {}", path.display()))?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        if let Some(timeout) = transport.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = transport.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        Ok(Client::with_inner_client(host, builder.build()?))
+    }
+
+    fn with_inner_client(host: String, inner_client: reqwest::Client) -> Self {
+        let request_path = Url::parse(&host)
+            .ok()
+            .map(|url| url.path().trim_end_matches('/').to_string());
+
         Client {
             host,
-            inner_client: reqwest::Client::builder()
-                .pool_idle_timeout(None)
-                .build()
-                .unwrap(),
+            request_path,
+            inner_client,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Records the latency and status code of every request to `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Metrics every request is recorded to
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Sends `request`, recording its latency and status code under `method` and `endpoint`
+    ///
+    /// Runs inside the `rest_request` span of the caller, whose `status` field it fills in.
+    async fn send(&self, request: reqwest::RequestBuilder, method: &str, route: &str) -> Result<Response> {
+        let started = Instant::now();
+        let response = request.send().await;
+        match &response {
+            Ok(response) => {
+                Span::current().record("status", response.status().as_u16());
+                debug!(elapsed_ms = started.elapsed().as_millis() as u64, "REST request completed");
+            },
+            Err(e) => debug!(elapsed_ms = started.elapsed().as_millis() as u64, "REST request failed: {}", e),
         }
+        self.metrics.record_rest_request(
+            method,
+            route,
+            response.as_ref().ok().map(|r| r.status().as_u16()),
+            started.elapsed(),
+        );
+        Ok(response?)
     }
 
     /// Implemention of a response handler function.
@@ -60,50 +219,101 @@ impl Client {
 
     /// Extracts the request path from the `host` parameter
     /// 
-    /// Utilizes string search for `".com"` within the `host` parameter and returns the string to the 
-    /// right of the `".com"` index
+    /// The host is parsed as a URL when the client is built, so any scheme, domain or port works
+    /// and only the path component is kept, without a trailing `/`
     /// 
     /// # Returns
     /// 
-    /// `Option<&str>` - which is the optional reference to the string described after `".com"`
+    /// `Option<&str>` - the path of `host`, or `None` if `host` is not a valid URL
     /// 
     /// # Example
     /// 
-    /// TODO: Insert an example here
+    /// ```
+    /// use rs_coinbase_pairs_handler::rest_client::Client;
+    /// 
+    /// let client = Client::new("https://api.coinbase.com/api/v3".to_string());
+    /// assert_eq!(client.extract_request_path(), Some("/api/v3"));
+    /// 
+    /// let client = Client::new("http://localhost:8080".to_string());
+    /// assert_eq!(client.extract_request_path(), Some(""));
+    /// ```
     pub fn extract_request_path(&self) -> Option<&str> {
-        let idx = self.host.find(".com").unwrap() + ".com".len();
-        Some(&self.host[idx..])
+        self.request_path.as_deref()
     }
 
-    pub async fn get<T: DeserializeOwned>(&self, endpoint: &str, headers: HeaderMap, request: Option<String>) -> Result<T> {
-        let mut url: String = format!("{}{}", self.host, endpoint);
-        if let Some(request) = request {  // handle request payload if necessary
-            if !request.is_empty() {
-                url.push_str(format!("?{}", request).as_str());
-            }
-        }
-
-        let client = &self.inner_client;
-        let response = client
-            .get(url.as_str())
-            .headers(headers)
-            .send()
-            .await?;
+    /// Absolute URL of `endpoint` with `query` as built by `QueryBuilder`
+    pub fn url(&self, endpoint: &str, query: Option<&str>) -> Result<Url> {
+        let mut url = Url::parse(&format!("{}{}", self.host, endpoint))
+            .with_context(|| format!("Invalid REST url {}{}", self.host, endpoint))?;
+        url.set_query(query.filter(|q| !q.is_empty()));
+        Ok(url)
+    }
 
-        self.handler(response).await
+    /// Path and query string of the request for `endpoint`, exactly as sent and as signed
+    ///
+    /// The signature covers the query string, so a query changed on the way invalidates it. The
+    /// query is encoded once, here, so the signed and the sent string cannot differ.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use rs_coinbase_pairs_handler::rest_client::Client;
+    ///
+    /// let client = Client::new("https://api.coinbase.com/api/v3".to_string());
+    /// assert_eq!(
+    ///     client.canonical_path("/brokerage/products", Some("product_ids=BTC-USD&limit=5")).unwrap(),
+    ///     "/api/v3/brokerage/products?product_ids=BTC-USD&limit=5"
+    /// );
+    /// ```
+    pub fn canonical_path(&self, endpoint: &str, query: Option<&str>) -> Result<String> {
+        let url = self.url(endpoint, query)?;
+        Ok(match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        })
     }
 
-    #[allow(dead_code)]
-    pub async fn post<T: DeserializeOwned>(&self, endpoint: &str, headers: HeaderMap) -> Result<T> {
-        let url: String = format!("{}{}", self.host, endpoint);
+    /// Sends a request for `endpoint`, the single path every method goes through
+    ///
+    /// # Arguments
+    /// * `method`: HTTP method e.g. `Method::DELETE`
+    /// * `endpoint`: Path below the host e.g. `"/brokerage/orders"`
+    /// * `route`: `endpoint` with its ids left as placeholders, the metrics label and the `route` of
+    ///   the span, whose `path` is `endpoint`
+    /// * `query`: Query string as built by `QueryBuilder`
+    /// * `headers`: Signature headers, made over `canonical_path` and `body`
+    /// * `body`: JSON body, sent byte for byte as the signature was made over it
+    pub async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        endpoint: &str,
+        route: &str,
+        query: Option<&str>,
+        headers: HeaderMap,
+        body: Option<String>,
+    ) -> Result<T> {
+        let url = self.url(endpoint, query)?;
         let client = &self.inner_client;
-        let response = client
-            .post(url.as_str())
-            .headers(headers)
-            .send()
-            .await?;
+        async {
+            let mut request = client.request(method.clone(), url).headers(headers);
+            if let Some(body) = body {
+                request = request.header(CONTENT_TYPE, "application/json").body(body);
+            }
+            let response = self.send(request, method.as_str(), route).await?;
+            self.handler(response).await
+        }
+        .instrument(info_span!("rest_request", method = method.as_str(), path = endpoint, route, status = field::Empty))
+        .await
+    }
+
+    /// Sends a `GET` request for `endpoint`, with `query` as built by `QueryBuilder`
+    pub async fn get<T: DeserializeOwned>(&self, endpoint: &str, headers: HeaderMap, query: Option<String>) -> Result<T> {
+        self.request(Method::GET, endpoint, endpoint, query.as_deref(), headers, None).await
+    }
 
-        self.handler(response).await
+    /// Sends `body` as JSON, it must be the exact string the signature in `headers` was made over
+    pub async fn post<T: DeserializeOwned>(&self, endpoint: &str, headers: HeaderMap, body: String) -> Result<T> {
+        self.request(Method::POST, endpoint, endpoint, None, headers, Some(body)).await
     }
 
 }
@@ -0,0 +1,509 @@
+use crate::currency_graph::CurrencyGraph;
+use crate::models::{OrderRequest, OrderSide};
+use crate::oms::OmsSnapshot;
+use crate::order_validation::OrderField;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/*
+RISK - Pre-trade limits
+
+Every order `OrderManager` submits is checked against the limits below before it is sent.
+Notionals and losses are converted to `reporting_currency` through the graph, or compared in
+the quote currency of each product without one. Positions are in the base currency, and
+orders that are still open count towards the limits as if they were filled.
+*/
+
+/// Limits `RiskEngine` enforces, `None` or a missing entry disables the limit
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RiskLimits {
+    /// Largest notional of a single order
+    pub max_order_notional: Option<f64>,
+    /// Largest notional of the position and the open orders of one product, including the new order
+    pub max_product_notional: Option<f64>,
+    /// Most orders open at the same time, including the new order
+    pub max_open_orders: Option<usize>,
+    /// Largest absolute position per base currency e.g. `{"ETH": 10.0}`, summed across products
+    pub max_position: BTreeMap<String, f64>,
+    /// Largest realized loss after fees since 00:00 UTC, summed across products
+    pub daily_loss_limit: Option<f64>,
+    /// Largest distance of a limit price from the live mid price, as a fraction
+    pub max_price_deviation: Option<f64>,
+    /// Currency the notional and loss limits are in e.g. `"USD"`, amounts in other currencies
+    /// are converted at the mid rate of the graph. Without one every quote currency is held to
+    /// the limits on its own.
+    pub reporting_currency: Option<String>,
+}
+
+/// A limit an order would breach
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "breach", rename_all = "snake_case")]
+pub enum RiskBreach {
+    OrderNotional { notional: f64, limit: f64 },
+    ProductNotional { product_id: String, notional: f64, limit: f64 },
+    OpenOrders { open: usize, limit: usize },
+    Position { currency: String, projected: f64, limit: f64 },
+    /// Loss in `currency`, the reporting currency or a quote currency
+    DailyLoss { currency: String, loss: f64, limit: f64 },
+    PriceDeviation { limit_price: f64, reference_price: f64, deviation: f64, limit: f64 },
+    /// A limit needs the live price of the product, which is not known
+    NoReferencePrice { product_id: String },
+    /// An amount in `from` cannot be converted to the reporting currency `to`
+    NoConversionRate { from: String, to: String },
+    InvalidNumber { field: OrderField, value: String },
+}
+
+impl fmt::Display for RiskBreach {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RiskBreach::OrderNotional { notional, limit } => write!(f, "order notional {} exceeds {}", notional, limit),
+            RiskBreach::ProductNotional { product_id, notional, limit } => {
+                write!(f, "{} notional {} would exceed {}", product_id, notional, limit)
+            },
+            RiskBreach::OpenOrders { open, limit } => write!(f, "{} open orders would exceed {}", open, limit),
+            RiskBreach::Position { currency, projected, limit } => {
+                write!(f, "{} position {} would exceed {}", currency, projected, limit)
+            },
+            RiskBreach::DailyLoss { currency, loss, limit } => {
+                write!(f, "daily loss {} {} reached the limit of {}", loss, currency, limit)
+            },
+            RiskBreach::PriceDeviation { limit_price, reference_price, deviation, limit } => write!(
+                f,
+                "limit price {} is {:.2}% away from the live price {}, more than {}%",
+                limit_price,
+                deviation * 100.0,
+                reference_price,
+                limit * 100.0
+            ),
+            RiskBreach::NoReferencePrice { product_id } => write!(f, "no live price for {}", product_id),
+            RiskBreach::NoConversionRate { from, to } => write!(f, "no rate from {} to {}", from, to),
+            RiskBreach::InvalidNumber { field, value } => write!(f, "{} {:?} is not a number", field, value),
+        }
+    }
+}
+
+/// Error `OrderManager::submit` returns for an order that breaches any limit
+///
+/// Recover it from the `anyhow::Error` with `downcast_ref::<RiskRejection>()`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RiskRejection {
+    pub client_order_id: String,
+    pub product_id: String,
+    pub breaches: Vec<RiskBreach>,
+}
+
+impl fmt::Display for RiskRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let breaches: Vec<String> = self.breaches.iter().map(|b| b.to_string()).collect();
+        write!(f, "order {} for {} breaches risk limits: {}", self.client_order_id, self.product_id, breaches.join("; "))
+    }
+}
+
+impl std::error::Error for RiskRejection {}
+
+/// Outcome of one risk check, kept for the audit trail
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AuditEvent {
+    pub time: DateTime<Utc>,
+    pub client_order_id: String,
+    pub product_id: String,
+    pub side: OrderSide,
+    /// Notional of the order in the reporting currency, or the quote currency without one, if it could be priced
+    pub notional: Option<f64>,
+    pub approved: bool,
+    /// Empty when `approved`
+    pub breaches: Vec<RiskBreach>,
+}
+
+#[derive(Default)]
+struct RiskState {
+    subscribers: Vec<UnboundedSender<AuditEvent>>,
+}
+
+/// Checks orders against `RiskLimits` and reports every decision as an `AuditEvent`
+///
+/// Clones share the limits and the audit subscribers. Attach it to an
+/// `OrderManager` with `OrderManager::with_risk` so every submission passes through it.
+///
+/// # Example
+///
+/// ```no_run
+/// use rs_coinbase_pairs_handler::risk::{RiskEngine, RiskLimits};
+///
+/// let risk = RiskEngine::new(RiskLimits {
+///     max_order_notional: Some(10_000.0),
+///     max_open_orders: Some(50),
+///     daily_loss_limit: Some(2_500.0),
+///     ..RiskLimits::default()
+/// });
+/// let mut audit = risk.subscribe();
+/// ```
+#[derive(Clone)]
+pub struct RiskEngine {
+    limits: Arc<RwLock<RiskLimits>>,
+    graph: Option<CurrencyGraph>,
+    state: Arc<Mutex<RiskState>>,
+}
+
+impl RiskEngine {
+    pub fn new(limits: RiskLimits) -> RiskEngine {
+        RiskEngine {
+            limits: Arc::new(RwLock::new(limits)),
+            graph: None,
+            state: Arc::new(Mutex::new(RiskState::default())),
+        }
+    }
+
+    /// Prices orders and positions at the live prices of `graph`, and converts to the reporting currency through it
+    ///
+    /// Without a graph, and for products the graph has no price for, limits that need a live
+    /// price reject the order with `RiskBreach::NoReferencePrice`, and amounts that need
+    /// converting with `RiskBreach::NoConversionRate`.
+    pub fn with_graph(mut self, graph: CurrencyGraph) -> Self {
+        self.graph = Some(graph);
+        self
+    }
+
+    pub fn limits(&self) -> RiskLimits {
+        self.limits.read().unwrap().clone()
+    }
+
+    /// Replaces the limits from the next check on
+    pub fn set_limits(&self, limits: RiskLimits) {
+        info!("Risk limits changed to {:?}", limits);
+        *self.limits.write().unwrap() = limits;
+    }
+
+    /// Receives the `AuditEvent` of every check from now on
+    pub fn subscribe(&self) -> UnboundedReceiver<AuditEvent> {
+        let (sender, receiver) = unbounded_channel();
+        self.state.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    /// Checks `order` against the limits, given the orders and positions in `snapshot`
+    ///
+    /// # Returns
+    ///
+    /// `Result<(), RiskRejection>` - every limit the order breaches
+    pub fn check(&self, order: &OrderRequest, snapshot: &OmsSnapshot) -> Result<(), RiskRejection> {
+        let limits = self.limits();
+        let reference = self.reference_price(&order.product_id);
+        let mut breaches = Vec::new();
+
+        let mut number = |field: OrderField, value: Option<&str>| -> Option<f64> {
+            let value = value?;
+            match value.parse::<f64>() {
+                Ok(number) => Some(number),
+                Err(_) => {
+                    breaches.push(RiskBreach::InvalidNumber { field, value: value.to_string() });
+                    None
+                },
+            }
+        };
+        let configuration = &order.order_configuration;
+        let base_size = number(OrderField::BaseSize, configuration.base_size());
+        let quote_size = number(OrderField::QuoteSize, configuration.quote_size());
+        let limit_price = number(OrderField::LimitPrice, configuration.limit_price());
+        let price = limit_price.or(reference);
+        let size = base_size.or_else(|| Some(quote_size? / price?));
+        let quote_currency = self.quote_currency(&order.product_id);
+        let notional = quote_size.or_else(|| Some(base_size? * price?));
+        let notional_limited = limits.max_order_notional.is_some() || limits.max_product_notional.is_some();
+        // the rate to the reporting currency, 1 without one
+        let rate = match self.conversion_rate(&limits, &quote_currency) {
+            Ok(rate) => Some(rate),
+            Err(breach) => {
+                if notional_limited {
+                    breaches.push(breach);
+                }
+                None
+            },
+        };
+        let reported = |amount: f64| Some(amount * rate?);
+
+        let unpriced = (notional_limited && notional.is_none())
+            || (limits.max_position.contains_key(base_currency(&order.product_id)) && size.is_none())
+            || (limits.max_price_deviation.is_some() && limit_price.is_some() && reference.is_none());
+        if unpriced {
+            breaches.push(RiskBreach::NoReferencePrice { product_id: order.product_id.clone() });
+        }
+
+        if let (Some(limit), Some(notional)) = (limits.max_order_notional, notional.and_then(reported)) {
+            if notional > limit {
+                breaches.push(RiskBreach::OrderNotional { notional, limit });
+            }
+        }
+
+        if let (Some(limit), Some(limit_price), Some(reference)) = (limits.max_price_deviation, limit_price, reference) {
+            let deviation = (limit_price / reference - 1.0).abs();
+            if deviation > limit {
+                breaches.push(RiskBreach::PriceDeviation { limit_price, reference_price: reference, deviation, limit });
+            }
+        }
+
+        let active: Vec<_> = snapshot.orders.values().filter(|o| o.is_active()).collect();
+        if let Some(limit) = limits.max_open_orders {
+            if active.len() + 1 > limit {
+                breaches.push(RiskBreach::OpenOrders { open: active.len() + 1, limit });
+            }
+        }
+
+        if let (Some(limit), Some(notional), Some(rate)) = (limits.max_product_notional, notional, rate) {
+            let position = snapshot.positions.get(&order.product_id).map_or(0.0, |p| {
+                p.size.abs() * reference.unwrap_or(p.average_cost)
+            });
+            let open: f64 = active
+                .iter()
+                .filter(|o| o.product_id == order.product_id)
+                .filter_map(|o| Some(o.remaining_size()? * o.limit_price.or(reference)?))
+                .sum();
+            let total = (position + open + notional) * rate;
+            if total > limit {
+                breaches.push(RiskBreach::ProductNotional { product_id: order.product_id.clone(), notional: total, limit });
+            }
+        }
+
+        let currency = base_currency(&order.product_id);
+        if let (Some(limit), Some(size)) = (limits.max_position.get(currency), size) {
+            let held: f64 = snapshot
+                .positions
+                .values()
+                .filter(|p| base_currency(&p.product_id) == currency)
+                .map(|p| p.size)
+                .sum();
+            // the worst case is every open order on the same side filling as well
+            let open: f64 = active
+                .iter()
+                .filter(|o| o.side == order.side && base_currency(&o.product_id) == currency)
+                .filter_map(|o| o.remaining_size())
+                .sum();
+            let projected = match order.side {
+                OrderSide::Buy => held + open + size,
+                OrderSide::Sell => held - open - size,
+            };
+            if projected.abs() > *limit {
+                breaches.push(RiskBreach::Position { currency: currency.to_string(), projected, limit: *limit });
+            }
+        }
+
+        if let Some(limit) = limits.daily_loss_limit {
+            match self.daily_losses(&limits, snapshot) {
+                Ok(losses) => breaches.extend(
+                    losses
+                        .into_iter()
+                        .filter(|(_, loss)| *loss >= limit)
+                        .map(|(currency, loss)| RiskBreach::DailyLoss { currency, loss, limit }),
+                ),
+                Err(breach) => breaches.push(breach),
+            }
+        }
+
+        self.audit(AuditEvent {
+            time: Utc::now(),
+            client_order_id: order.client_order_id.clone(),
+            product_id: order.product_id.clone(),
+            side: order.side,
+            notional: notional.and_then(reported),
+            approved: breaches.is_empty(),
+            breaches: breaches.clone(),
+        });
+        match breaches.is_empty() {
+            true => Ok(()),
+            false => Err(RiskRejection {
+                client_order_id: order.client_order_id.clone(),
+                product_id: order.product_id.clone(),
+                breaches,
+            }),
+        }
+    }
+
+    /// Mid price of `product_id`, or its last trade if one side of the book is unknown
+    fn reference_price(&self, product_id: &str) -> Option<f64> {
+        let pair = self.graph.as_ref()?.pair(product_id)?;
+        pair.mid().or(pair.last).filter(|p| *p > 0.0)
+    }
+
+    /// Quote currency of `product_id`, from the graph when it knows the product
+    fn quote_currency(&self, product_id: &str) -> String {
+        match self.graph.as_ref().and_then(|graph| graph.pair(product_id)) {
+            Some(pair) => pair.quote,
+            None => product_id.split('-').nth(1).unwrap_or(product_id).to_string(),
+        }
+    }
+
+    /// Mid rate from `currency` to the reporting currency of `limits`, 1 without one
+    fn conversion_rate(&self, limits: &RiskLimits, currency: &str) -> Result<f64, RiskBreach> {
+        let Some(reporting) = limits.reporting_currency.as_deref().filter(|r| *r != currency) else {
+            return Ok(1.0);
+        };
+        self.graph
+            .as_ref()
+            .and_then(|graph| graph.rate(currency, reporting))
+            .map(|rate| rate.mid_rate)
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+            .ok_or_else(|| RiskBreach::NoConversionRate { from: currency.to_string(), to: reporting.to_string() })
+    }
+
+    /// Realized loss after fees of the fills of the current UTC day, per currency it is reported in
+    fn daily_losses(&self, limits: &RiskLimits, snapshot: &OmsSnapshot) -> Result<BTreeMap<String, f64>, RiskBreach> {
+        let today = Utc::now().date_naive();
+        let mut losses = BTreeMap::new();
+        for position in snapshot.positions.values() {
+            let pnl = position.daily_pnl(today);
+            if pnl == 0.0 {
+                continue;
+            }
+            let quote_currency = self.quote_currency(&position.product_id);
+            let currency = limits.reporting_currency.clone().unwrap_or_else(|| quote_currency.clone());
+            *losses.entry(currency).or_insert(0.0) -= pnl * self.conversion_rate(limits, &quote_currency)?;
+        }
+        Ok(losses)
+    }
+
+    fn audit(&self, event: AuditEvent) {
+        match event.approved {
+            true => info!("Risk check passed for order {} of {}", event.client_order_id, event.product_id),
+            false => warn!(
+                "Risk check rejected order {} of {}: {:?}",
+                event.client_order_id,
+                event.product_id,
+                event.breaches
+            ),
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .subscribers
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+/// Base currency of a `BASE-QUOTE` product id e.g. `"ETH"` for `"ETH-USD"`
+fn base_currency(product_id: &str) -> &str {
+    product_id.split('-').next().unwrap_or(product_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{OrderConfiguration, ProductData};
+    use crate::oms::Position;
+
+    fn graph() -> CurrencyGraph {
+        let graph = CurrencyGraph::new(&[ProductData::test("ETH-USD", "ETH", "USD")]);
+        graph.update_quote("ETH-USD", Some((1999.0, None)), Some((2001.0, None)));
+        graph
+    }
+
+    fn limit_buy(base_size: &str, limit_price: &str) -> OrderRequest {
+        OrderRequest {
+            client_order_id: "order-1".to_string(),
+            product_id: "ETH-USD".to_string(),
+            side: OrderSide::Buy,
+            order_configuration: OrderConfiguration::LimitLimitGtc {
+                base_size: base_size.to_string(),
+                limit_price: limit_price.to_string(),
+                post_only: false,
+            },
+        }
+    }
+
+    #[test]
+    fn reports_every_breached_limit_and_audits_it() {
+        let risk = RiskEngine::new(RiskLimits {
+            max_order_notional: Some(10_000.0),
+            max_position: BTreeMap::from([("ETH".to_string(), 5.0)]),
+            max_price_deviation: Some(0.05),
+            ..RiskLimits::default()
+        })
+        .with_graph(graph());
+        let mut audit = risk.subscribe();
+        let mut snapshot = OmsSnapshot::default();
+        snapshot.positions.insert("ETH-USD".to_string(), Position {
+            product_id: "ETH-USD".to_string(),
+            size: 4.0,
+            average_cost: 1800.0,
+            ..Position::default()
+        });
+
+        assert!(risk.check(&limit_buy("0.5", "2000"), &snapshot).is_ok());
+        let rejection = risk.check(&limit_buy("6", "2500"), &snapshot).unwrap_err();
+        assert_eq!(rejection.breaches, vec![
+            RiskBreach::OrderNotional { notional: 15_000.0, limit: 10_000.0 },
+            RiskBreach::PriceDeviation { limit_price: 2500.0, reference_price: 2000.0, deviation: 0.25, limit: 0.05 },
+            RiskBreach::Position { currency: "ETH".to_string(), projected: 10.0, limit: 5.0 },
+        ]);
+
+        assert!(audit.try_recv().unwrap().approved);
+        let event = audit.try_recv().unwrap();
+        assert_eq!((event.approved, event.notional), (false, Some(15_000.0)));
+        assert_eq!(event.breaches, rejection.breaches);
+    }
+
+    #[test]
+    fn fails_closed_without_a_live_price() {
+        let risk = RiskEngine::new(RiskLimits { max_price_deviation: Some(0.05), ..RiskLimits::default() });
+        let rejection = risk.check(&limit_buy("1", "2000"), &OmsSnapshot::default()).unwrap_err();
+        assert_eq!(rejection.breaches, vec![RiskBreach::NoReferencePrice { product_id: "ETH-USD".to_string() }]);
+    }
+
+    #[test]
+    fn halts_trading_once_the_daily_loss_is_reached() {
+        let risk = RiskEngine::new(RiskLimits { daily_loss_limit: Some(100.0), ..RiskLimits::default() });
+        let mut snapshot = OmsSnapshot::default();
+        assert!(risk.check(&limit_buy("1", "2000"), &snapshot).is_ok());
+
+        // yesterday's losses are not held against today
+        let mut position = Position { product_id: "ETH-USD".to_string(), ..Position::default() };
+        let yesterday = Utc::now() - chrono::Duration::days(1);
+        position.apply_fill_at(OrderSide::Buy, 1.0, 2000.0, 0.0, yesterday);
+        position.apply_fill_at(OrderSide::Sell, 1.0, 1000.0, 0.0, yesterday);
+        snapshot.positions.insert("ETH-USD".to_string(), position.clone());
+        assert!(risk.check(&limit_buy("1", "2000"), &snapshot).is_ok());
+
+        // losses realized today count even before the first check of the day
+        position.apply_fill(OrderSide::Buy, 1.0, 2000.0, 2.5);
+        position.apply_fill(OrderSide::Sell, 1.0, 1905.0, 2.5);
+        snapshot.positions.insert("ETH-USD".to_string(), position);
+        let rejection = RiskEngine::new(risk.limits()).check(&limit_buy("1", "2000"), &snapshot).unwrap_err();
+        assert_eq!(rejection.breaches, vec![RiskBreach::DailyLoss { currency: "USD".to_string(), loss: 100.0, limit: 100.0 }]);
+    }
+
+    #[test]
+    fn converts_notionals_and_losses_to_the_reporting_currency() {
+        let graph = CurrencyGraph::default();
+        graph.insert_pair(crate::currency_graph::PairQuote::new("ETH-BTC", "ETH", "BTC"));
+        graph.insert_pair(crate::currency_graph::PairQuote::new("BTC-USD", "BTC", "USD"));
+        graph.update_quote("ETH-BTC", Some((0.0999, None)), Some((0.1001, None)));
+        graph.update_quote("BTC-USD", Some((19_990.0, None)), Some((20_010.0, None)));
+        let limits = RiskLimits {
+            max_order_notional: Some(10_000.0),
+            daily_loss_limit: Some(1_000.0),
+            reporting_currency: Some("USD".to_string()),
+            ..RiskLimits::default()
+        };
+        let risk = RiskEngine::new(limits.clone()).with_graph(graph);
+        let order = |base_size: &str| OrderRequest { product_id: "ETH-BTC".to_string(), ..limit_buy(base_size, "0.125") };
+
+        assert!(risk.check(&order("4"), &OmsSnapshot::default()).is_ok());
+        let rejection = risk.check(&order("6"), &OmsSnapshot::default()).unwrap_err();
+        assert_eq!(rejection.breaches, vec![RiskBreach::OrderNotional { notional: 15_000.0, limit: 10_000.0 }]);
+
+        // a 0.06 BTC loss is 1,200 USD
+        let mut position = Position { product_id: "ETH-BTC".to_string(), ..Position::default() };
+        position.apply_fill(OrderSide::Buy, 1.0, 0.1, 0.0);
+        position.apply_fill(OrderSide::Sell, 1.0, 0.04, 0.0);
+        let snapshot = OmsSnapshot { positions: BTreeMap::from([("ETH-BTC".to_string(), position)]), ..OmsSnapshot::default() };
+        let rejection = risk.check(&order("1"), &snapshot).unwrap_err();
+        assert!(matches!(&rejection.breaches[..], [RiskBreach::DailyLoss { currency, loss, .. }] if currency == "USD" && (loss - 1_200.0).abs() < 1e-6));
+
+        let rejection = RiskEngine::new(limits).check(&order("1"), &OmsSnapshot::default()).unwrap_err();
+        assert_eq!(rejection.breaches, vec![RiskBreach::NoConversionRate { from: "BTC".to_string(), to: "USD".to_string() }]);
+    }
+}
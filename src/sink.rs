@@ -0,0 +1,25 @@
+use crate::models::GenericMessage;
+use anyhow::Result;
+use std::time::SystemTime;
+
+/// Consumer of the messages received by `AdvancedTradeWebSockets`
+///
+/// Sinks are registered with `AdvancedTradeWebSockets::add_sink` and called in registration
+/// order for every text frame. Errors are logged by the feed and never stop it, so a failing
+/// sink cannot take market data down with it.
+pub trait MessageSink: Send {
+    /// Called with every raw text frame, before it is parsed
+    fn on_frame(&mut self, _received_at: SystemTime, _frame: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with every frame that parsed into a channel message
+    fn on_message(&mut self, _received_at: SystemTime, _message: &GenericMessage) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called when the feed stops, and should persist anything still buffered
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,511 @@
+use crate::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use crate::models::{Fill, GenericMessage, Order, ProductData, WebsocketEvent};
+use crate::sink::MessageSink;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+SQLITE - Embedded persistence for products, market data and order history
+
+Migrations are applied in order on open and tracked through `PRAGMA user_version`, so an
+existing database is upgraded in place. Only append new entries to `MIGRATIONS`; never edit
+one that has shipped.
+*/
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    "CREATE TABLE products (
+        product_id TEXT PRIMARY KEY,
+        product_type TEXT NOT NULL,
+        base_currency_id TEXT NOT NULL,
+        base_increment TEXT NOT NULL,
+        base_max_size TEXT NOT NULL,
+        base_min_size TEXT NOT NULL,
+        quote_currency_id TEXT NOT NULL,
+        quote_increment TEXT NOT NULL,
+        quote_max_size TEXT NOT NULL,
+        quote_min_size TEXT NOT NULL,
+        status TEXT NOT NULL,
+        trading_disabled INTEGER NOT NULL,
+        updated_at_ns INTEGER NOT NULL
+    );
+    CREATE TABLE tickers (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        product_id TEXT NOT NULL,
+        event_type TEXT NOT NULL,
+        price TEXT NOT NULL,
+        volume_24_h TEXT NOT NULL,
+        sequence_num INTEGER NOT NULL,
+        exchange_timestamp TEXT NOT NULL,
+        received_at_ns INTEGER NOT NULL
+    );
+    CREATE INDEX tickers_product_received ON tickers (product_id, received_at_ns);
+    CREATE TABLE market_trades (
+        product_id TEXT NOT NULL,
+        trade_id TEXT NOT NULL,
+        side TEXT NOT NULL,
+        price TEXT NOT NULL,
+        size TEXT NOT NULL,
+        trade_time TEXT NOT NULL,
+        received_at_ns INTEGER NOT NULL,
+        PRIMARY KEY (product_id, trade_id)
+    );
+    CREATE TABLE orders (
+        order_id TEXT PRIMARY KEY,
+        client_order_id TEXT NOT NULL,
+        product_id TEXT NOT NULL,
+        side TEXT NOT NULL,
+        status TEXT NOT NULL,
+        order_type TEXT NOT NULL,
+        time_in_force TEXT NOT NULL,
+        created_time TEXT NOT NULL,
+        filled_size TEXT NOT NULL,
+        average_filled_price TEXT NOT NULL,
+        total_fees TEXT NOT NULL,
+        order_configuration TEXT NOT NULL,
+        updated_at_ns INTEGER NOT NULL
+    );
+    CREATE TABLE fills (
+        entry_id TEXT PRIMARY KEY,
+        trade_id TEXT NOT NULL,
+        order_id TEXT NOT NULL,
+        product_id TEXT NOT NULL,
+        side TEXT NOT NULL,
+        price TEXT NOT NULL,
+        size TEXT NOT NULL,
+        commission TEXT NOT NULL,
+        liquidity_indicator TEXT NOT NULL,
+        trade_time TEXT NOT NULL,
+        trade_time_ns INTEGER NOT NULL
+    );
+    CREATE INDEX fills_trade_time ON fills (trade_time_ns);",
+    // 2: 24 hour volume and contract details of products, the latter as JSON
+    "ALTER TABLE products ADD COLUMN volume_24h TEXT;
+    ALTER TABLE products ADD COLUMN future_product_details TEXT;",
+    // 3: price increment of products, empty for rows stored before it
+    "ALTER TABLE products ADD COLUMN price_increment TEXT NOT NULL DEFAULT '';",
+];
+
+/// Most recent ticker price stored for a product
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatestPrice {
+    pub product_id: String,
+    pub price: String,
+    pub exchange_timestamp: String,
+    pub received_at_ns: i64,
+}
+
+/// Row counts written by `SqliteStore::sync_from_rest`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncSummary {
+    pub products: usize,
+    pub orders: usize,
+    pub fills: usize,
+}
+
+/// SQLite backed store of products, tickers, market trades, orders and fills
+///
+/// Clones share one connection, so the same store can be registered as a sink with
+/// `AdvancedTradeWebSockets::add_sink` and queried from elsewhere.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+fn now_ns() -> i64 {
+    system_time_ns(SystemTime::now())
+}
+
+fn system_time_ns(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as i64)
+}
+
+fn timestamp_ns(value: &str) -> Option<i64> {
+    DateTime::parse_from_rfc3339(value).ok()?.timestamp_nanos_opt()
+}
+
+impl SqliteStore {
+    /// Opens or creates the database at `path` and applies any pending migrations
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteStore> {
+        SqliteStore::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteStore> {
+        SqliteStore::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> Result<SqliteStore> {
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", i + 1)?;
+            tx.commit()?;
+            info!("Applied SQLite migration {}", i + 1);
+        }
+
+        Ok(SqliteStore { conn: Arc::new(Mutex::new(conn)) })
+    }
+
+    /// Number of migrations applied to the database
+    pub fn schema_version(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.pragma_query_value(None, "user_version", |row| row.get(0))?)
+    }
+
+    /// Inserts or replaces products by `product_id`
+    pub fn upsert_products(&self, products: &[ProductData]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO products (product_id, product_type, base_currency_id, base_increment,
+                    base_max_size, base_min_size, quote_currency_id, quote_increment, quote_max_size,
+                    quote_min_size, status, trading_disabled, updated_at_ns, volume_24h, future_product_details,
+                    price_increment)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)"
+            )?;
+            let updated_at_ns = now_ns();
+            for p in products {
+                let future_product_details = p.future_product_details.as_ref().map(serde_json::to_string).transpose()?;
+                stmt.execute(params![
+                    p.product_id, p.product_type, p.base_currency_id, p.base_increment,
+                    p.base_max_size, p.base_min_size, p.quote_currency_id, p.quote_increment,
+                    p.quote_max_size, p.quote_min_size, p.status, p.trading_disabled, updated_at_ns,
+                    p.volume_24h, future_product_details, p.price_increment,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(products.len())
+    }
+
+    /// Returns every stored product, ordered by `product_id`
+    pub fn products(&self) -> Result<Vec<ProductData>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT product_id, product_type, base_currency_id, base_increment, base_max_size,
+                base_min_size, quote_currency_id, quote_increment, quote_max_size, quote_min_size,
+                status, trading_disabled, volume_24h, future_product_details, price_increment
+            FROM products ORDER BY product_id"
+        )?;
+        let products = stmt.query_map([], |row| Ok(ProductData {
+            product_id: row.get(0)?,
+            product_type: row.get(1)?,
+            base_currency_id: row.get(2)?,
+            base_increment: row.get(3)?,
+            base_max_size: row.get(4)?,
+            base_min_size: row.get(5)?,
+            quote_currency_id: row.get(6)?,
+            quote_increment: row.get(7)?,
+            quote_max_size: row.get(8)?,
+            quote_min_size: row.get(9)?,
+            price_increment: row.get(14)?,
+            status: row.get(10)?,
+            trading_disabled: row.get(11)?,
+            volume_24h: row.get(12)?,
+            future_product_details: row
+                .get::<_, Option<String>>(13)?
+                .map(|details| serde_json::from_str(&details))
+                .transpose()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(13, rusqlite::types::Type::Text, Box::new(e)))?,
+        }))?;
+        Ok(products.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Inserts or replaces orders by `order_id`, so re-syncing picks up status changes
+    pub fn upsert_orders(&self, orders: &[Order]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO orders VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)"
+            )?;
+            let updated_at_ns = now_ns();
+            for o in orders {
+                stmt.execute(params![
+                    o.order_id, o.client_order_id, o.product_id, o.side, o.status, o.order_type,
+                    o.time_in_force, o.created_time, o.filled_size, o.average_filled_price,
+                    o.total_fees, o.order_configuration.to_string(), updated_at_ns,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(orders.len())
+    }
+
+    /// Inserts fills that are not stored yet, keyed by `entry_id`
+    ///
+    /// Fills whose `trade_time` does not parse are skipped with a warning, as they could never
+    /// be selected by `fills_between`.
+    ///
+    /// # Returns
+    ///
+    /// `Result<usize>` - the number of new fills
+    pub fn insert_fills(&self, fills: &[Fill]) -> Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO fills VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)"
+            )?;
+            for f in fills {
+                let Some(trade_time_ns) = timestamp_ns(&f.trade_time) else {
+                    warn!("Skipping fill {} with unparseable trade_time {:?}", f.entry_id, f.trade_time);
+                    continue;
+                };
+                inserted += stmt.execute(params![
+                    f.entry_id, f.trade_id, f.order_id, f.product_id, f.side, f.price, f.size,
+                    f.commission, f.liquidity_indicator, f.trade_time, trade_time_ns,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Stores the ticker and market trade events of a channel message
+    pub fn insert_message(&self, received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        let received_at_ns = system_time_ns(received_at);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut ticker = tx.prepare_cached(
+                "INSERT INTO tickers (product_id, event_type, price, volume_24_h, sequence_num, exchange_timestamp, received_at_ns)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+            )?;
+            let mut trade = tx.prepare_cached(
+                "INSERT OR IGNORE INTO market_trades VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+            )?;
+            let sequence_num = message.sequence_num as i64;
+
+            for event in &message.events {
+                match event {
+                    WebsocketEvent::SnapshotEvent(snapshot) => {
+                        for t in &snapshot.tickers {
+                            ticker.execute(params![
+                                t.product_id, snapshot.msg_type, t.price, t.volume_24_h,
+                                sequence_num, message.timestamp, received_at_ns,
+                            ])?;
+                        }
+                    },
+                    WebsocketEvent::UpdateEvent(update) => {
+                        for t in &update.tickers {
+                            ticker.execute(params![
+                                t.product_id, update.msg_type, t.price, t.volume_24_h,
+                                sequence_num, message.timestamp, received_at_ns,
+                            ])?;
+                        }
+                    },
+                    WebsocketEvent::TradesEvent(trades) => {
+                        for t in &trades.trades {
+                            trade.execute(params![
+                                t.product_id, t.trade_id, t.side, t.price, t.size, t.time, received_at_ns,
+                            ])?;
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the most recently received ticker price of `product_id`
+    pub fn latest_price(&self, product_id: &str) -> Result<Option<LatestPrice>> {
+        let conn = self.conn.lock().unwrap();
+        Ok(conn.query_row(
+            "SELECT product_id, price, exchange_timestamp, received_at_ns FROM tickers
+            WHERE product_id = ?1 ORDER BY received_at_ns DESC, id DESC LIMIT 1",
+            [product_id],
+            latest_price_row,
+        ).optional()?)
+    }
+
+    /// Returns the most recently received ticker price of every product
+    pub fn latest_prices(&self) -> Result<Vec<LatestPrice>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT t.product_id, t.price, t.exchange_timestamp, t.received_at_ns FROM tickers t
+            WHERE t.id = (
+                SELECT id FROM tickers WHERE product_id = t.product_id
+                ORDER BY received_at_ns DESC, id DESC LIMIT 1
+            )
+            ORDER BY t.product_id"
+        )?;
+        let prices = stmt.query_map([], latest_price_row)?;
+        Ok(prices.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Returns fills with a `trade_time` in `[start, end)`, oldest first
+    pub fn fills_between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<Fill>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT entry_id, trade_id, order_id, trade_time, price, size, commission, product_id,
+                liquidity_indicator, side
+            FROM fills WHERE trade_time_ns >= ?1 AND trade_time_ns < ?2
+            ORDER BY trade_time_ns, entry_id"
+        )?;
+        let fills = stmt.query_map(
+            params![start.timestamp_nanos_opt(), end.timestamp_nanos_opt()],
+            |row| Ok(Fill {
+                entry_id: row.get(0)?,
+                trade_id: row.get(1)?,
+                order_id: row.get(2)?,
+                trade_time: row.get(3)?,
+                price: row.get(4)?,
+                size: row.get(5)?,
+                commission: row.get(6)?,
+                product_id: row.get(7)?,
+                liquidity_indicator: row.get(8)?,
+                side: row.get(9)?,
+            }),
+        )?;
+        Ok(fills.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Pulls products, every page of orders and every page of fills from the REST API
+    pub async fn sync_from_rest(&self, client: &AdvancedTradeRESTClient) -> Result<SyncSummary> {
+        let mut summary = SyncSummary {
+            products: self.upsert_products(&client.get_available_products().await?.products)?,
+            ..Default::default()
+        };
+
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = client.list_orders(None, cursor.as_deref()).await?;
+            summary.orders += self.upsert_orders(&page.orders)?;
+            if !page.has_next || page.cursor.is_empty() || cursor.as_deref() == Some(page.cursor.as_str()) {
+                break;
+            }
+            cursor = Some(page.cursor);
+        }
+
+        let mut cursor: Option<String> = None;
+        loop {
+            let page = client.list_fills(None, cursor.as_deref()).await?;
+            summary.fills += self.insert_fills(&page.fills)?;
+            if page.cursor.is_empty() || cursor.as_deref() == Some(page.cursor.as_str()) {
+                break;
+            }
+            cursor = Some(page.cursor);
+        }
+
+        info!("Synced {:?} from REST", summary);
+        Ok(summary)
+    }
+}
+
+fn latest_price_row(row: &rusqlite::Row) -> rusqlite::Result<LatestPrice> {
+    Ok(LatestPrice {
+        product_id: row.get(0)?,
+        price: row.get(1)?,
+        exchange_timestamp: row.get(2)?,
+        received_at_ns: row.get(3)?,
+    })
+}
+
+impl MessageSink for SqliteStore {
+    fn on_message(&mut self, received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        self.insert_message(received_at, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ticker(sequence_num: u64, product_id: &str, price: &str) -> GenericMessage {
+        serde_json::from_str(&format!(
+            r#"{{"channel":"ticker","client_id":"","timestamp":"2023-02-08T06:12:20Z","sequence_num":{},"events":[{{"type":"update","tickers":[{{"type":"ticker","product_id":"{}","price":"{}","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"1"}}]}}]}}"#,
+            sequence_num, product_id, price
+        )).unwrap()
+    }
+
+    fn fill(entry_id: &str, trade_time: &str) -> Fill {
+        Fill {
+            entry_id: entry_id.to_string(),
+            trade_id: format!("trade-{}", entry_id),
+            order_id: "order".to_string(),
+            trade_time: trade_time.to_string(),
+            price: "1675.14".to_string(),
+            size: "0.5".to_string(),
+            commission: "1".to_string(),
+            product_id: "ETH-USD".to_string(),
+            liquidity_indicator: "MAKER".to_string(),
+            side: "BUY".to_string(),
+        }
+    }
+
+    #[test]
+    fn migrates_a_new_database_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("coinbase.db");
+
+        assert_eq!(SqliteStore::open(&path).unwrap().schema_version().unwrap(), MIGRATIONS.len());
+        assert_eq!(SqliteStore::open(&path).unwrap().schema_version().unwrap(), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn keeps_the_latest_price_per_product() {
+        let mut store = SqliteStore::open_in_memory().unwrap();
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+
+        store.on_message(t0, &ticker(1, "ETH-USD", "1675.14")).unwrap();
+        store.on_message(t0, &ticker(2, "BTC-USD", "22900.01")).unwrap();
+        store.on_message(t0 + Duration::from_secs(1), &ticker(3, "ETH-USD", "1676.00")).unwrap();
+
+        assert_eq!(store.latest_price("ETH-USD").unwrap().unwrap().price, "1676.00");
+        assert_eq!(store.latest_price("SOL-USD").unwrap(), None);
+        let prices: Vec<(String, String)> = store.latest_prices().unwrap()
+            .into_iter()
+            .map(|p| (p.product_id, p.price))
+            .collect();
+        assert_eq!(prices, vec![
+            ("BTC-USD".to_string(), "22900.01".to_string()),
+            ("ETH-USD".to_string(), "1676.00".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn round_trips_products() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let products: Vec<ProductData> = serde_json::from_str(r#"[
+            {"product_id":"BIP-20DEC30-CDE","product_type":"FUTURE","base_currency_id":"","base_increment":"1","base_max_size":"100000","base_min_size":"1","quote_currency_id":"USD","quote_increment":"5","quote_max_size":"","quote_min_size":"","status":"","trading_disabled":false,"future_product_details":{"contract_size":"0.01","contract_root_unit":"BTC","contract_expiry_type":"PERPETUAL","perpetual_details":{"open_interest":"1520","funding_rate":"0.000004"}}},
+            {"product_id":"ETH-USD","product_type":"SPOT","base_currency_id":"ETH","base_increment":"0.00000001","base_max_size":"2800","base_min_size":"0.00022","quote_currency_id":"USD","quote_increment":"0.01","quote_max_size":"50000000","quote_min_size":"1","price_increment":"0.01","status":"online","trading_disabled":false,"volume_24h":"185976.72526638"}
+        ]"#).unwrap();
+
+        store.upsert_products(&products).unwrap();
+        assert_eq!(store.products().unwrap(), products);
+    }
+
+    #[test]
+    fn selects_fills_between_dates() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let fills = [
+            fill("a", "2023-02-07T23:59:59Z"),
+            fill("b", "2023-02-08T06:10:00.5Z"),
+            fill("c", "2023-02-09T00:00:00Z"),
+        ];
+
+        assert_eq!(store.insert_fills(&fills).unwrap(), 3);
+        assert_eq!(store.insert_fills(&fills).unwrap(), 0);
+        assert_eq!(store.insert_fills(&[fill("d", "yesterday")]).unwrap(), 0);
+
+        let start = "2023-02-08T00:00:00Z".parse().unwrap();
+        let end = "2023-02-09T00:00:00Z".parse().unwrap();
+        let between: Vec<String> = store.fills_between(start, end).unwrap()
+            .into_iter()
+            .map(|f| f.entry_id)
+            .collect();
+        assert_eq!(between, vec!["b"]);
+    }
+}
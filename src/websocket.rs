@@ -1,14 +1,32 @@
-use anyhow::{bail, Result};
+use crate::config_builder::TransportConfig;
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use log::{info, error};
+use native_tls::{Certificate, TlsConnector};
+use percent_encoding::percent_decode_str;
+use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 use tungstenite::handshake::client::Response;
 use tungstenite::protocol::WebSocket;
 use tungstenite::stream::MaybeTlsStream;
+use tungstenite::Connector;
 use url::Url;
 
 pub fn connect_wss(
     exchange: &str, 
     websocket_urls: Vec<String>,
+) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
+    connect_wss_with_transport(exchange, websocket_urls, &TransportConfig::default())
+}
+
+/// Same as `connect_wss` but applies the proxy, CA certificates, certificate validation and
+/// connect timeout from `transport`
+pub fn connect_wss_with_transport(
+    exchange: &str, 
+    websocket_urls: Vec<String>,
+    transport: &TransportConfig,
 ) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
     let max_retry = 5;
     for i in 0..max_retry {
@@ -16,7 +34,7 @@ pub fn connect_wss(
             info!("[{}] connecting to {} (try {})", exchange, wss, i);
             let url = Url::parse(wss)?;
 
-            match tungstenite::connect(url) {
+            match connect_url(&url, transport) {
                 Ok(answer) => {
                     return Ok(answer);
                 }
@@ -26,4 +44,120 @@ pub fn connect_wss(
     }
 
     bail!(format!("Max connection retry reached!"));
-}
\ No newline at end of file
+}
+
+fn connect_url(
+    url: &Url,
+    transport: &TransportConfig,
+) -> Result<(WebSocket<MaybeTlsStream<TcpStream>>, Response)> {
+    let stream = match &transport.proxy {
+        Some(proxy) => connect_proxy(&Url::parse(proxy)?, url, transport.connect_timeout)?,
+        None => connect_tcp(url, transport.connect_timeout)?,
+    };
+
+    let connector = if transport.root_certificates.is_empty() && !transport.accept_invalid_certs {
+        None
+    } else {
+        Some(Connector::NativeTls(tls_connector(transport)?))
+    };
+
+    tungstenite::client_tls_with_config(url.as_str(), stream, None, connector)
+        .map_err(|e| anyhow!("{}", e))
+}
+
+/// Connects to the host of `url`, trying every address it resolves to until one accepts
+///
+/// Resolves through `Url::socket_addrs`, which also takes IPv6 literals such as `[::1]`.
+fn connect_tcp(url: &Url, timeout: Option<Duration>) -> Result<TcpStream> {
+    let mut last_error = None;
+    for addr in url.socket_addrs(|| None)? {
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                info!("Unable to connect to {}: {}", addr, e);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    // the host alone, a proxy URL can carry credentials
+    let host = url.host_str().unwrap_or_default();
+    match last_error {
+        Some(e) => bail!("Unable to connect to {}: {}", host, e),
+        None => bail!("Unable to resolve {}", host),
+    }
+}
+
+/// Opens a tunnel to the host of `target` through the HTTP proxy `proxy` with a `CONNECT`
+/// request, authenticated by the user and password of `proxy` if it has any
+fn connect_proxy(proxy: &Url, target: &Url, timeout: Option<Duration>) -> Result<TcpStream> {
+    if proxy.scheme() != "http" {
+        bail!("Unsupported WebSocket proxy scheme {}, only http proxies can tunnel", proxy.scheme());
+    }
+    let mut stream = connect_tcp(proxy, timeout)?;
+
+    // `host_str` keeps the brackets of IPv6 literals, as the authority form requires
+    let host = target.host_str().ok_or_else(|| anyhow!("No host in {}", target))?;
+    let port = target.port_or_known_default().ok_or_else(|| anyhow!("No port in {}", target))?;
+    let authority = format!("{}:{}", host, port);
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if !proxy.username().is_empty() {
+        let credentials = format!(
+            "{}:{}",
+            percent_decode_str(proxy.username()).decode_utf8()?,
+            percent_decode_str(proxy.password().unwrap_or_default()).decode_utf8()?,
+        );
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", STANDARD.encode(credentials)));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    stream.set_read_timeout(timeout)?;
+    let head = read_response_head(&mut stream)?;
+    stream.set_read_timeout(None)?;
+
+    let status_line = head.lines().next().unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(stream),
+        _ => bail!("Proxy {} refused the tunnel to {}: {}", proxy.host_str().unwrap_or_default(), authority, status_line),
+    }
+}
+
+/// Reads the status line and headers of an HTTP response, leaving anything after them unread
+fn read_response_head(stream: &mut TcpStream) -> Result<String> {
+    const MAX_HEAD: usize = 8192;
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            bail!("Proxy response head longer than {} bytes", MAX_HEAD);
+        }
+        stream.read_exact(&mut byte)?;
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Makes reads on `socket` give up after `timeout` with a `WouldBlock` or `TimedOut` error, so
+/// the caller can act without waiting for the next frame
+pub fn set_read_timeout(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, timeout: Option<Duration>) -> Result<()> {
+    match socket.get_mut() {
+        MaybeTlsStream::Plain(stream) => stream.set_read_timeout(timeout)?,
+        MaybeTlsStream::NativeTls(stream) => stream.get_mut().set_read_timeout(timeout)?,
+        _ => bail!("Unsupported WebSocket stream"),
+    }
+    Ok(())
+}
+
+fn tls_connector(transport: &TransportConfig) -> Result<TlsConnector> {
+    let mut builder = TlsConnector::builder();
+    builder.danger_accept_invalid_certs(transport.accept_invalid_certs);
+    for path in &transport.root_certificates {
+        builder.add_root_certificate(Certificate::from_pem(&std::fs::read(path)?)?);
+    }
+    Ok(builder.build()?)
+}
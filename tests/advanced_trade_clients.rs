@@ -0,0 +1,1002 @@
+use rs_coinbase_pairs_handler::advanced_trade_rest_client::{AdvancedTradeRESTClient, BATCH_CANCEL_LIMIT};
+use rs_coinbase_pairs_handler::advanced_trade_websocket::{AdvancedTradeWebSockets, SubscribeProducts};
+use rs_coinbase_pairs_handler::arbitrage::{ArbitrageConfig, ArbitrageDetector};
+use rs_coinbase_pairs_handler::config_builder::{CoinbaseConfig, TransportConfig};
+use rs_coinbase_pairs_handler::currency_graph::{CurrencyGraph, PairQuote, Venue};
+use rs_coinbase_pairs_handler::fees::{FeeModel, Liquidity};
+use rs_coinbase_pairs_handler::futures::FuturesBalances;
+use rs_coinbase_pairs_handler::health::{HealthServer, HealthThresholds};
+use rs_coinbase_pairs_handler::metrics::{Metrics, MetricsServer};
+use rs_coinbase_pairs_handler::mock_server::{MockCoinbase, MockConfig, ScriptStep, MOCK_DEFAULT_PORTFOLIO};
+use rs_coinbase_pairs_handler::models::{
+    ConvertTradeStatus, GenericMessage, OrderConfiguration, OrderRequest, OrderSide, Products, ProductsQuery, RestEndpoint,
+};
+use rs_coinbase_pairs_handler::oms::{OmsConfig, OrderManager, OrderState};
+use rs_coinbase_pairs_handler::order_book::OrderBooks;
+use rs_coinbase_pairs_handler::order_validation::{OrderField, OrderValidator, Rejection, ValidationConfig};
+use rs_coinbase_pairs_handler::product_filter::ProductFilter;
+use rs_coinbase_pairs_handler::product_registry::{ProductChange, ProductRegistry};
+use rs_coinbase_pairs_handler::recorder::{Manifest, MarketDataRecorder, RecorderConfig};
+use rs_coinbase_pairs_handler::rest_client::Client;
+use rs_coinbase_pairs_handler::risk::{RiskBreach, RiskEngine, RiskLimits, RiskRejection};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const KEY: &str = "test-key";
+const SECRET: &str = "test-secret";
+
+fn mock_config(mock: &MockCoinbase) -> CoinbaseConfig {
+    CoinbaseConfig {
+        rest_url: mock.rest_url(),
+        websocket_url: mock.websocket_url(),
+        ..CoinbaseConfig::with_credentials(KEY, SECRET)
+    }
+}
+
+#[tokio::test]
+async fn rest_client_lists_symbols_from_a_local_host() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let symbols = client.get_available_symbols().await.unwrap();
+
+    assert_eq!(symbols, vec!["BTC-USD", "ETH-USD", "ETH-BTC"]);
+    assert_eq!(mock.requests()[0].url, "/api/v3/brokerage/products/");
+}
+
+#[test]
+fn transport_rejects_missing_ca_certificate() {
+    let transport = TransportConfig {
+        root_certificates: vec!["does/not/exist.pem".into()],
+        timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+
+    let result = Client::with_transport("http://localhost:8080".to_string(), &transport);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn websocket_subscribes_to_every_product_on_a_local_host() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::All,
+        mock_config(&mock),
+    ).unwrap();
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while mock.subscriptions().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let subscriptions = mock.subscriptions();
+    assert_eq!(subscriptions.len(), 1);
+    assert_eq!(subscriptions[0].product_ids, vec!["BTC-USD", "ETH-USD", "ETH-BTC"]);
+    assert_eq!(mock.rejected_subscriptions(), 0);
+
+    // shutting the mock down makes the feed give up reconnecting and return
+    drop(mock);
+    handle.join().unwrap();
+}
+
+#[test]
+fn recorder_captures_the_live_feed() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::ticker("update", "ETH-USD", "1675.20"),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::Custom(vec!["ETH-USD".to_string()]),
+        mock_config(&mock),
+    ).unwrap();
+    feed.add_sink(Box::new(MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap()));
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let recorded_ticker = || Manifest::load(dir.path())
+        .map(|m| m.files.iter().any(|f| f.channel == "ticker"))
+        .unwrap_or(false);
+    while !recorded_ticker() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    std::thread::sleep(Duration::from_millis(100));
+    drop(mock);
+    handle.join().unwrap();
+
+    let manifest = Manifest::load(dir.path()).unwrap();
+    let ticker = manifest.files.iter().find(|f| f.channel == "ticker").unwrap();
+    assert_eq!(ticker.product_id, "ETH-USD");
+    assert_eq!(ticker.frames, 2);
+    assert_eq!((ticker.min_sequence_num, ticker.max_sequence_num), (Some(1), Some(2)));
+    assert!(manifest.files.iter().all(|f| f.complete));
+}
+
+#[tokio::test]
+async fn rest_client_lists_orders_and_fills() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let orders = client.list_orders(Some("ETH-USD"), None).await.unwrap();
+    let fills = client.list_fills(None, Some("abc")).await.unwrap();
+
+    assert_eq!(orders.orders[0].status, "FILLED");
+    assert_eq!(fills.fills[0].liquidity_indicator, "TAKER");
+    let urls: Vec<String> = mock.requests().into_iter().map(|r| r.url).collect();
+    assert_eq!(urls, vec![
+        "/api/v3/brokerage/orders/historical/batch?product_id=ETH-USD",
+        "/api/v3/brokerage/orders/historical/fills?cursor=abc",
+    ]);
+    assert!(mock.requests().iter().all(|r| r.authorized));
+}
+
+#[tokio::test]
+async fn product_registry_caches_and_reports_changes() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    let registry = ProductRegistry::new(Arc::new(client), Duration::from_secs(60));
+    let mut changes = registry.subscribe();
+
+    assert_eq!(registry.product_ids().await.unwrap(), vec!["BTC-USD", "ETH-USD", "ETH-BTC"]);
+    registry.products().await.unwrap();
+    assert_eq!(mock.requests().len(), 1);
+
+    mock.update_fixtures(|fixtures| {
+        let products = fixtures.products["products"].as_array_mut().unwrap();
+        products.retain(|p| p["product_id"] != "ETH-BTC");
+        products[0]["trading_disabled"] = true.into();
+    });
+    let found = registry.refresh().await.unwrap();
+
+    assert_eq!(found.len(), 2);
+    assert_eq!(changes.recv().await.unwrap(), ProductChange::TradingDisabledChanged {
+        product_id: "BTC-USD".to_string(),
+        trading_disabled: true,
+    });
+    assert!(matches!(changes.recv().await.unwrap(), ProductChange::Delisted(p) if p.product_id == "ETH-BTC"));
+    assert!(registry.get("ETH-BTC").is_none());
+}
+
+#[tokio::test]
+async fn arbitrage_cycles_follow_registry_delistings() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    let registry = ProductRegistry::new(Arc::new(client), Duration::from_secs(60));
+    registry.refresh().await.unwrap();
+
+    let graph = CurrencyGraph::default();
+    let follow = graph.follow_registry(&registry);
+    let mut config = ArbitrageConfig::new(0.0);
+    config.start_currencies = vec!["USD".to_string()];
+    let (mut detector, _) = ArbitrageDetector::new(graph.clone(), config);
+    let touched = ["ETH-USD".to_string()].into();
+
+    let wait_for_generation = |generation: u64| {
+        let graph = graph.clone();
+        async move {
+            let deadline = Instant::now() + Duration::from_secs(2);
+            while graph.generation() == generation && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+    wait_for_generation(0).await;
+    detector.on_update(&touched);
+    assert_eq!(detector.cycles(), vec!["USD>BTC>ETH>USD", "USD>ETH>BTC>USD"]);
+
+    let generation = graph.generation();
+    mock.update_fixtures(|fixtures| {
+        fixtures.products["products"].as_array_mut().unwrap().retain(|p| p["product_id"] != "ETH-BTC");
+    });
+    registry.refresh().await.unwrap();
+    wait_for_generation(generation).await;
+    detector.on_update(&touched);
+    assert!(detector.cycles().is_empty());
+    assert!(graph.pair("ETH-BTC").is_none());
+    follow.abort();
+}
+
+#[test]
+fn websocket_filter_follows_product_changes() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = (0..250)
+        .flat_map(|_| [ScriptStep::Pause(Duration::from_millis(20)), ScriptStep::ticker("update", "ETH-USD", "1675.14")])
+        .collect();
+    let mock = MockCoinbase::start(config).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::Filter("quote=USD,volume_24h>100K".parse().unwrap()),
+        mock_config(&mock),
+    ).unwrap();
+    let mut config = mock_config(&mock);
+    config.transport.timeout = Some(Duration::from_secs(2));
+    let client = AdvancedTradeRESTClient::from_config(&config).unwrap();
+    feed.set_product_registry(ProductRegistry::new(Arc::new(client), Duration::from_millis(100)));
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    let wait_for = |count: usize| {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while mock.subscriptions().len() < count && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        mock.subscriptions()
+            .into_iter()
+            .map(|s| (s.msg_type, s.product_ids.join(",")))
+            .collect::<Vec<(String, String)>>()
+    };
+
+    assert_eq!(wait_for(1), vec![("subscribe".to_string(), "ETH-USD".to_string())]);
+
+    mock.update_fixtures(|fixtures| {
+        let products = fixtures.products["products"].as_array_mut().unwrap();
+        products[0]["volume_24h"] = "500000".into();
+        products[1]["volume_24h"] = "1000".into();
+    });
+    assert_eq!(wait_for(3), vec![
+        ("subscribe".to_string(), "ETH-USD".to_string()),
+        ("unsubscribe".to_string(), "ETH-USD".to_string()),
+        ("subscribe".to_string(), "BTC-USD".to_string()),
+    ]);
+
+    drop(mock);
+    handle.join().unwrap();
+}
+
+#[test]
+fn websocket_filter_follows_products_on_a_quiet_feed() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::Filter("quote=USD,volume_24h>1M".parse().unwrap()),
+        mock_config(&mock),
+    ).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    feed.set_product_registry(ProductRegistry::new(Arc::new(client), Duration::from_millis(100)));
+    let stop = feed.stop_handle();
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    // nothing matches, so nothing is subscribed and no frame ever arrives
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(mock.subscriptions().is_empty());
+
+    mock.update_fixtures(|fixtures| {
+        fixtures.products["products"].as_array_mut().unwrap()[0]["volume_24h"] = "5000000".into();
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while mock.subscriptions().is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let subscriptions = mock.subscriptions();
+    assert_eq!(subscriptions.len(), 1);
+    assert_eq!((subscriptions[0].msg_type.as_str(), subscriptions[0].product_ids.join(",")), ("subscribe", "BTC-USD".to_string()));
+
+    stop.store(false, std::sync::atomic::Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn websocket_filter_keeps_reading_while_swapping_in_many_products() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = (0..500)
+        .flat_map(|_| [ScriptStep::Pause(Duration::from_millis(20)), ScriptStep::ticker("update", "ETH-USD", "1675.14")])
+        .collect();
+    let mock = MockCoinbase::start(config).unwrap();
+    let metrics = Metrics::default();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string(), "level2".to_string()],
+        SubscribeProducts::Filter("quote=USD,base=ETH|NEW*".parse().unwrap()),
+        CoinbaseConfig { metrics: metrics.clone(), ..mock_config(&mock) },
+    ).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    feed.set_product_registry(ProductRegistry::new(Arc::new(client), Duration::from_millis(100)));
+    let stop = feed.stop_handle();
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    let received = || {
+        metrics
+            .render()
+            .lines()
+            .find_map(|l| l.strip_prefix("coinbase_ws_messages_total{channel=\"ticker\",product_id=\"ETH-USD\"} "))
+            .map_or(0, |n| n.parse::<u64>().unwrap())
+    };
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while received() == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    mock.update_fixtures(|fixtures| {
+        let products = fixtures.products["products"].as_array_mut().unwrap();
+        let template = products[1].clone();
+        products.extend((0..200).map(|i| {
+            let mut product = template.clone();
+            product["product_id"] = format!("NEW{}-USD", i).into();
+            product["base_currency_id"] = format!("NEW{}", i).into();
+            product
+        }));
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while mock.subscriptions().len() < 4 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    let subscriptions = mock.subscriptions();
+    assert_eq!(subscriptions.len(), 4);
+    assert!(subscriptions[2..].iter().all(|s| s.msg_type == "subscribe" && s.product_ids.len() == 200));
+
+    // the swap did not hold up the loop, frames were read right after it
+    let before = received();
+    std::thread::sleep(Duration::from_millis(500));
+    assert!(received() > before + 5, "{} frames after the swap", received() - before);
+
+    stop.store(false, std::sync::atomic::Ordering::Relaxed);
+    handle.join().unwrap();
+}
+
+#[test]
+fn metrics_endpoint_reports_feed_and_rest_activity() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::Gap(2),
+        ScriptStep::ticker("update", "ETH-USD", "1675.20"),
+        ScriptStep::Raw("not json".to_string()),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let metrics = Metrics::default();
+    let server = MetricsServer::start("127.0.0.1:0", metrics.clone()).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::All,
+        CoinbaseConfig { metrics: metrics.clone(), ..mock_config(&mock) },
+    ).unwrap();
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !metrics.render().contains("coinbase_ws_parse_failures_total 1") && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let page = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(async { reqwest::get(server.url()).await?.text().await })
+        .unwrap();
+    for line in [
+        "coinbase_ws_messages_total{channel=\"ticker\",product_id=\"ETH-USD\"} 2",
+        "coinbase_ws_parse_failures_total 1",
+        "coinbase_ws_sequence_gaps_total 1",
+        "coinbase_ws_missed_messages_total 2",
+        "coinbase_ws_message_latency_seconds_count{channel=\"ticker\"} 2",
+        "coinbase_rest_requests_total{method=\"GET\",endpoint=\"/brokerage/products/\",status=\"200\"} 1",
+    ] {
+        assert!(page.contains(line), "missing {} in\n{}", line, page);
+    }
+
+    drop(mock);
+    handle.join().unwrap();
+}
+
+#[test]
+fn health_endpoint_tracks_readiness_across_reconnects() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::Disconnect,
+        ScriptStep::ticker("update", "ETH-USD", "1675.20"),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::All,
+        mock_config(&mock),
+    ).unwrap();
+    let server = HealthServer::start("127.0.0.1:0", feed.health(), HealthThresholds::default()).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let probe = |path: &str| -> (u16, serde_json::Value) {
+        runtime.block_on(async {
+            let response = reqwest::get(format!("{}{}", server.url(), path)).await.unwrap();
+            (response.status().as_u16(), response.json().await.unwrap())
+        })
+    };
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while (mock.subscriptions().len() < 2 || probe("/readyz").0 != 200) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    let (status, report) = probe("/readyz");
+    assert_eq!(status, 200);
+    assert_eq!(mock.connections(), 2);
+    assert_eq!(report["reconnects"], 1);
+    assert_eq!(report["pending_subscriptions"], serde_json::json!({}));
+    let eth = report["products"].as_array().unwrap().iter().find(|p| p["product_id"] == "ETH-USD").unwrap();
+    assert!(eth["last_message_at"].is_string());
+    assert_eq!(probe("/livez").0, 200);
+
+    drop(mock);
+    handle.join().unwrap();
+    let (status, report) = probe("/livez");
+    assert_eq!(status, 503);
+    assert_eq!(report["ready"], false);
+}
+
+#[tokio::test]
+async fn rest_client_corrects_a_skewed_clock() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.clock_offset = chrono::TimeDelta::seconds(-90);
+    let mock = MockCoinbase::start(config).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    assert!(client.get_available_symbols().await.is_err());
+
+    let sample = client.sync_clock().await.unwrap();
+    assert!((sample.offset.num_seconds() + 90).abs() <= 1, "{:?}", sample);
+    assert_eq!(client.get_available_symbols().await.unwrap().len(), 3);
+    let authorized: Vec<bool> = mock.requests().into_iter().map(|r| r.authorized).collect();
+    assert_eq!(authorized, vec![false, false, true]);
+}
+
+#[test]
+fn websocket_signs_and_measures_latency_in_server_time() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.clock_offset = chrono::TimeDelta::seconds(120);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::ticker("update", "ETH-USD", "1675.20"),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let metrics = Metrics::default();
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::Custom(vec!["ETH-USD".to_string()]),
+        CoinbaseConfig { metrics: metrics.clone(), ..mock_config(&mock) },
+    ).unwrap();
+
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !metrics.render().contains("coinbase_ws_message_latency_seconds_count{channel=\"ticker\"} 2") && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(mock.rejected_subscriptions(), 0);
+    drop(mock);
+    handle.join().unwrap();
+
+    let text = metrics.render();
+    assert!(text.contains("coinbase_ws_message_latency_seconds_bucket{channel=\"ticker\",le=\"1\"} 2"), "{}", text);
+    let offset = text
+        .lines()
+        .find_map(|line| line.strip_prefix("coinbase_clock_offset_seconds "))
+        .and_then(|offset| offset.parse::<f64>().ok())
+        .unwrap();
+    assert!((offset - 120.0).abs() <= 1.0, "{}", offset);
+}
+
+#[tokio::test]
+async fn previews_and_validates_orders() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = Arc::new(AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap());
+    let registry = ProductRegistry::new(client.clone(), Duration::from_secs(60));
+    let order = |base_size: &str, limit_price: &str| OrderRequest {
+        client_order_id: "11111-000000-000000".to_string(),
+        product_id: "ETH-USD".to_string(),
+        side: OrderSide::Buy,
+        order_configuration: OrderConfiguration::LimitLimitGtc {
+            base_size: base_size.to_string(),
+            limit_price: limit_price.to_string(),
+            post_only: true,
+        },
+    };
+
+    let preview = client.preview_order(&order("0.5", "1675.14")).await.unwrap();
+    assert_eq!(preview.commission_total, "5.02542");
+    let request = mock.requests().pop().unwrap();
+    assert!(request.authorized);
+    let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+    assert_eq!(body["order_configuration"]["limit_limit_gtc"]["limit_price"], "1675.14");
+    assert!(body.get("client_order_id").is_none());
+
+    let validator = OrderValidator::new(ValidationConfig::default());
+    assert!(validator.check(&client, &registry, &order("0.5", "1675.14")).await.unwrap().is_empty());
+    let rejections = validator.check(&client, &registry, &order("10", "1675.145")).await.unwrap();
+    assert!(matches!(
+        &rejections[..],
+        [Rejection::NotAnIncrement { field: OrderField::LimitPrice, .. }, Rejection::InsufficientBalance { .. }]
+    ), "{:?}", rejections);
+}
+
+#[tokio::test]
+async fn cancel_all_pages_and_batches_open_orders() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    mock.update_fixtures(|fixtures| {
+        let filled = fixtures.orders["orders"][0].clone();
+        let orders = fixtures.orders["orders"].as_array_mut().unwrap();
+        for i in 0..250 {
+            let mut order = filled.clone();
+            order["order_id"] = format!("open-{:03}", i).into();
+            order["product_id"] = if i % 5 == 0 { "BTC-USD" } else { "ETH-USD" }.into();
+            order["status"] = "OPEN".into();
+            order["order_configuration"] = serde_json::json!({
+                "limit_limit_gtc": { "base_size": "0.5", "limit_price": "1500.00", "post_only": true }
+            });
+            orders.push(order);
+        }
+    });
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let btc = client.cancel_all(Some(&"base=BTC".parse::<ProductFilter>().unwrap())).await.unwrap();
+    assert_eq!(btc.cancelled().len(), 50);
+    assert!(btc.failed().is_empty());
+
+    let rest = client.cancel_all(None).await.unwrap();
+    assert_eq!(rest.cancelled().len(), 200);
+    let batches: Vec<usize> = mock
+        .requests()
+        .iter()
+        .filter(|r| r.url.ends_with("/batch_cancel"))
+        .map(|r| serde_json::from_str::<serde_json::Value>(&r.body).unwrap()["order_ids"].as_array().unwrap().len())
+        .collect();
+    assert_eq!(batches, vec![50, 100, 100]);
+    assert!(client.list_open_orders(None, None).await.unwrap().orders.is_empty());
+
+    let results = client.cancel_orders(&["open-001".to_string(), "missing".to_string()]).await.unwrap();
+    assert_eq!(results.iter().map(|r| (r.success, r.failure_reason.as_str())).collect::<Vec<_>>(), vec![
+        (false, "INVALID_CANCEL_REQUEST"),
+        (false, "UNKNOWN_CANCEL_ORDER"),
+    ]);
+    let too_many: Vec<String> = (0..=BATCH_CANCEL_LIMIT).map(|i| i.to_string()).collect();
+    assert!(client.cancel_orders(&too_many).await.is_err());
+}
+
+#[tokio::test]
+async fn edits_open_orders() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    mock.update_fixtures(|fixtures| {
+        let order = &mut fixtures.orders["orders"][0];
+        order["status"] = "OPEN".into();
+        order["order_configuration"] = serde_json::json!({
+            "limit_limit_gtc": { "base_size": "0.5", "limit_price": "1500.00", "post_only": true }
+        });
+    });
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let preview = client.edit_order_preview("0000-000000-000000", "1600.00", "1").await.unwrap();
+    assert!(preview.errors.is_empty());
+    assert_eq!(preview.base_size, "1");
+    let edit = client.edit_order("0000-000000-000000", "1600.00", "1").await.unwrap();
+    assert!(edit.success);
+    let order = &client.list_open_orders(Some("ETH-USD"), None).await.unwrap().orders[0];
+    assert_eq!(order.order_configuration["limit_limit_gtc"]["limit_price"], "1600.00");
+    assert_eq!(order.order_configuration["limit_limit_gtc"]["base_size"], "1");
+
+    let missing = client.edit_order("missing", "1600.00", "1").await.unwrap();
+    assert!(!missing.success);
+    assert_eq!(missing.errors[0].edit_failure_reason.as_deref(), Some("EDIT_ORDER_FAILURE_REASON_ORDER_NOT_FOUND"));
+}
+
+#[test]
+fn order_manager_tracks_orders_through_rest_and_the_user_channel() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = Arc::new(AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap());
+    let oms = OrderManager::new(client.clone(), OmsConfig { retry_delay: Duration::from_millis(10), ..OmsConfig::default() });
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let limit_buy = |client_order_id: &str, product_id: &str| OrderRequest {
+        client_order_id: client_order_id.to_string(),
+        product_id: product_id.to_string(),
+        side: OrderSide::Buy,
+        order_configuration: OrderConfiguration::LimitLimitGtc {
+            base_size: "1".to_string(),
+            limit_price: "1500.00".to_string(),
+            post_only: true,
+        },
+    };
+
+    // the first response is lost, the retry gets the order the first attempt placed
+    mock.fail_next("/brokerage/orders", 1);
+    let placed = runtime.block_on(oms.submit(&limit_buy("buy-1", "ETH-USD"))).unwrap();
+    assert_eq!((placed.state, placed.attempts), (OrderState::Open, 2));
+    assert_eq!(runtime.block_on(oms.submit(&limit_buy("buy-1", "ETH-USD"))).unwrap(), placed);
+    let creates = mock.requests().iter().filter(|r| r.url.ends_with("/brokerage/orders")).count();
+    assert_eq!(creates, 2);
+    let open = runtime.block_on(client.list_open_orders(None, None)).unwrap();
+    assert_eq!(open.orders.iter().filter(|o| o.client_order_id == "buy-1").count(), 1);
+
+    let rejected = runtime.block_on(oms.submit(&limit_buy("buy-2", "DOGE-USD"))).unwrap();
+    assert_eq!(rejected.state, OrderState::Rejected);
+    assert_eq!(rejected.reject_reason.as_deref(), Some("INVALID_PRODUCT_ID"));
+
+    runtime.block_on(oms.submit(&limit_buy("buy-3", "ETH-USD"))).unwrap();
+    assert_eq!(runtime.block_on(oms.cancel("buy-3")).unwrap().state, OrderState::Cancelled);
+    assert!(runtime.block_on(oms.cancel("buy-2")).is_err());
+
+    let order_id = placed.order_id.clone().unwrap();
+    mock.push_script(vec![
+        ScriptStep::user("update", serde_json::json!({
+            "order_id": order_id, "client_order_id": "buy-1", "status": "OPEN",
+            "cumulative_quantity": "0.4", "avg_price": "1500", "total_fees": "3.6",
+        })),
+        ScriptStep::user("update", serde_json::json!({
+            "order_id": order_id, "client_order_id": "buy-1", "status": "FILLED",
+            "cumulative_quantity": "1", "avg_price": "1500", "total_fees": "9",
+        })),
+    ]);
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["user".to_string()],
+        SubscribeProducts::Custom(vec!["ETH-USD".to_string()]),
+        mock_config(&mock),
+    ).unwrap();
+    feed.add_sink(Box::new(oms.clone()));
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while oms.order("buy-1").unwrap().state != OrderState::Filled && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    drop(mock);
+    handle.join().unwrap();
+
+    let snapshot = oms.snapshot();
+    assert_eq!(snapshot.orders["buy-1"].state, OrderState::Filled);
+    assert_eq!(snapshot.orders["buy-3"].state, OrderState::Cancelled);
+    let position = &snapshot.positions["ETH-USD"];
+    assert_eq!((position.size, position.average_cost, position.fees), (1.0, 1500.0, 9.0));
+}
+
+#[tokio::test]
+async fn order_manager_stops_orders_that_breach_risk_limits() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = Arc::new(AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap());
+    let risk = RiskEngine::new(RiskLimits {
+        max_order_notional: Some(5_000.0),
+        max_open_orders: Some(1),
+        ..RiskLimits::default()
+    });
+    let mut audit = risk.subscribe();
+    let oms = OrderManager::new(client, OmsConfig::default()).with_risk(risk);
+    let limit_buy = |client_order_id: &str, base_size: &str| OrderRequest {
+        client_order_id: client_order_id.to_string(),
+        product_id: "ETH-USD".to_string(),
+        side: OrderSide::Buy,
+        order_configuration: OrderConfiguration::LimitLimitGtc {
+            base_size: base_size.to_string(),
+            limit_price: "1500.00".to_string(),
+            post_only: true,
+        },
+    };
+
+    assert_eq!(oms.submit(&limit_buy("buy-1", "1")).await.unwrap().state, OrderState::Open);
+    let error = oms.submit(&limit_buy("buy-2", "4")).await.unwrap_err();
+    let rejection = error.downcast_ref::<RiskRejection>().unwrap();
+    assert_eq!(rejection.breaches, vec![
+        RiskBreach::OrderNotional { notional: 6_000.0, limit: 5_000.0 },
+        RiskBreach::OpenOrders { open: 2, limit: 1 },
+    ]);
+    assert!(oms.order("buy-2").is_none());
+    assert_eq!(mock.requests().iter().filter(|r| r.url.ends_with("/brokerage/orders")).count(), 1);
+
+    assert!(audit.recv().await.unwrap().approved);
+    let rejected = audit.recv().await.unwrap();
+    assert_eq!((rejected.client_order_id.as_str(), rejected.approved), ("buy-2", false));
+}
+
+#[tokio::test]
+async fn fee_model_follows_the_transaction_summary() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let summary = client.get_transaction_summary(Some("SPOT")).await.unwrap();
+    assert_eq!(summary.total_volume, 24000.5);
+    assert_eq!(summary.fee_tier.pricing_tier, "$10K-$50K");
+    assert_eq!(mock.requests()[0].url, "/api/v3/brokerage/transaction_summary?product_type=SPOT");
+
+    let fees = FeeModel::fetch(&client).await.unwrap();
+    assert_eq!((fees.maker_rate, fees.taker_rate), (0.0025, 0.004));
+    let cost = fees.execution_cost(OrderSide::Buy, 0.5, 1600.0, Liquidity::Taker);
+    assert_eq!((cost.fee, cost.total), (3.2, 803.2));
+    assert_eq!(ArbitrageConfig::from_fees(&fees).taker_fee, 0.004);
+    assert_eq!(ValidationConfig::from_fees(&fees).fee_rate, 0.004);
+}
+
+#[tokio::test]
+async fn portfolios_scope_balances_and_orders() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let portfolios = client.list_portfolios(Some("DEFAULT")).await.unwrap();
+    assert_eq!(portfolios.iter().map(|p| p.uuid.as_str()).collect::<Vec<_>>(), vec![MOCK_DEFAULT_PORTFOLIO]);
+    let momentum = client.create_portfolio("Momentum").await.unwrap();
+    assert_eq!((momentum.name.as_str(), momentum.portfolio_type.as_str()), ("Momentum", "CONSUMER"));
+    assert_eq!(client.list_portfolios(None).await.unwrap().len(), 2);
+
+    client.move_funds("2500", "USD", MOCK_DEFAULT_PORTFOLIO, &momentum.uuid).await.unwrap();
+    assert!(client.move_funds("1000000", "USD", MOCK_DEFAULT_PORTFOLIO, &momentum.uuid).await.is_err());
+    let scoped = Arc::new(AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap().with_portfolio(&momentum.uuid));
+    assert_eq!(scoped.available_balances().await.unwrap(), BTreeMap::from([("USD".to_string(), 2500.0)]));
+    assert_eq!(client.available_balances().await.unwrap()["USD"], 7500.0);
+
+    let breakdown = client.get_portfolio_breakdown(&momentum.uuid).await.unwrap();
+    assert_eq!(breakdown.portfolio.uuid, momentum.uuid);
+    assert_eq!(breakdown.portfolio_balances.total_balance.value, "2500");
+    assert_eq!(breakdown.spot_positions.len(), 1);
+    assert_eq!((breakdown.spot_positions[0].asset.as_str(), breakdown.spot_positions[0].allocation), ("USD", 1.0));
+
+    let oms = OrderManager::new(scoped.clone(), OmsConfig::default());
+    oms.submit(&OrderRequest {
+        client_order_id: "momentum-1".to_string(),
+        product_id: "ETH-USD".to_string(),
+        side: OrderSide::Buy,
+        order_configuration: OrderConfiguration::LimitLimitGtc {
+            base_size: "1".to_string(),
+            limit_price: "1500.00".to_string(),
+            post_only: true,
+        },
+    }).await.unwrap();
+    let body: serde_json::Value = serde_json::from_str(&mock.requests().pop().unwrap().body).unwrap();
+    assert_eq!(body["retail_portfolio_id"], momentum.uuid.as_str());
+    assert_eq!(scoped.list_open_orders(None, None).await.unwrap().orders.len(), 1);
+    assert!(client.list_open_orders(None, None).await.unwrap().orders.is_empty());
+}
+
+#[tokio::test]
+async fn converts_stablecoins_and_routes_through_the_quote() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let quote = client.create_convert_quote("USD", "USDC", "1000").await.unwrap();
+    assert_eq!(quote.status, ConvertTradeStatus::Created);
+    assert_eq!((quote.amount.value.as_str(), quote.amount.currency.as_str()), ("1000", "USDC"));
+    assert_eq!(quote.rate(), Some(1.0));
+    assert!(client.create_convert_quote("USD", "BTC", "1000").await.is_err());
+    assert!(client.create_convert_quote("USD", "USDC", "20000").await.is_err());
+
+    let graph = CurrencyGraph::default();
+    graph.insert_pair(PairQuote::new("USDT-USD", "USDT", "USD"));
+    graph.insert_pair(PairQuote::new("USDT-USDC", "USDT", "USDC"));
+    graph.update_quote("USDT-USD", Some((0.9999, None)), Some((1.0001, None)));
+    graph.update_quote("USDT-USDC", Some((1.0, None)), Some((1.0002, None)));
+    graph.insert_conversion(&quote.source_currency, &quote.target_currency, quote.rate().unwrap());
+    let fees = FeeModel::fetch(&client).await.unwrap();
+    let route = graph.route("USD", "USDC", &fees).unwrap();
+    assert_eq!(route.legs.iter().map(|l| l.venue).collect::<Vec<_>>(), vec![Venue::Convert]);
+    assert_eq!(route.net_rate(&fees), 1.0);
+
+    let committed = client.commit_convert_trade(&quote.id, "USD", "USDC").await.unwrap();
+    assert_eq!(committed.status, ConvertTradeStatus::Started);
+    assert!(client.commit_convert_trade(&quote.id, "USD", "USDC").await.is_err());
+    let settled = client.wait_for_convert_trade(&quote.id, "USD", "USDC", Duration::from_secs(5)).await.unwrap();
+    assert_eq!(settled.status, ConvertTradeStatus::Completed);
+    assert_eq!(
+        mock.requests().pop().unwrap().url,
+        format!("/api/v3/brokerage/convert/trade/{}?from_account=USD&to_account=USDC", quote.id)
+    );
+
+    let back = client.convert("USDC", "USD", "250", Duration::from_secs(5)).await.unwrap();
+    assert!(back.status.is_terminal());
+    let balances = client.available_balances().await.unwrap();
+    assert_eq!((balances["USD"], balances["USDC"]), (9250.0, 750.0));
+}
+
+#[test]
+fn futures_balances_follow_the_rest_summary_and_the_channel() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::futures_balance("snapshot", serde_json::json!({ "available_margin": "1300", "unrealized_pnl": "-35.5" })),
+        ScriptStep::futures_balance("update", serde_json::json!({ "available_margin": "1100", "unrealized_pnl": "-235.5" })),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let summary = runtime.block_on(client.get_futures_balance_summary()).unwrap();
+    assert_eq!((summary.available_margin.value.as_str(), summary.liquidation_buffer_percentage.as_str()), ("1300", "471"));
+    let balances = runtime.block_on(FuturesBalances::fetch(&client)).unwrap();
+    assert_eq!(balances.latest().unwrap().liquidation_buffer(), Some(1650.0));
+    let mut updates = balances.subscribe();
+
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["futures_balance_summary".to_string()],
+        SubscribeProducts::Custom(vec!["BIP-20DEC30-CDE".to_string()]),
+        mock_config(&mock),
+    ).unwrap();
+    feed.add_sink(Box::new(balances.clone()));
+    let handle = std::thread::spawn(move || {
+        tokio::runtime::Runtime::new().unwrap().block_on(feed.run()).unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while balances.latest().and_then(|b| b.available_margin()) != Some(1100.0) && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    drop(mock);
+    handle.join().unwrap();
+
+    assert_eq!(balances.latest().unwrap().unrealized_pnl(), Some(-235.5));
+    assert_eq!(updates.try_recv().unwrap().available_margin, "1300");
+    assert_eq!(updates.try_recv().unwrap().available_margin, "1100");
+}
+
+#[tokio::test]
+async fn rest_snapshots_seed_and_cross_check_order_books() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let product_ids = vec!["BTC-USD".to_string(), "ETH-USD".to_string()];
+    let quotes = client.get_best_bid_ask(&product_ids).await.unwrap();
+    assert_eq!(mock.requests()[0].url, "/api/v3/brokerage/best_bid_ask?product_ids=BTC-USD&product_ids=ETH-USD");
+    assert_eq!(quotes.iter().map(|q| q.product_id.as_str()).collect::<Vec<_>>(), vec!["BTC-USD", "ETH-USD"]);
+    assert_eq!((quotes[1].best_bid(), quotes[1].best_ask()), (Some((1675.13, 12.0)), Some((1675.14, 8.0))));
+    assert_eq!(client.get_best_bid_ask(&[]).await.unwrap().len(), 3);
+
+    let book = client.get_product_book("BTC-USD", Some(2)).await.unwrap();
+    assert_eq!((book.bids.len(), book.asks.len()), (2, 2));
+    assert!(client.get_product_book("DOGE-USD", None).await.is_err());
+
+    let graph = CurrencyGraph::default();
+    graph.insert_pair(PairQuote::new("BTC-USD", "BTC", "USD"));
+    graph.insert_pair(PairQuote::new("ETH-USD", "ETH", "USD"));
+    let books = OrderBooks::new().publish_to(graph.clone());
+    books.seed_from(&book).unwrap();
+    assert_eq!(books.book("BTC-USD").unwrap().best_ask(), Some((25000.5, 0.4)));
+    let seeded = books.seed_from_rest(&client, &product_ids, Some(10)).await.unwrap();
+    assert_eq!(seeded, vec!["BTC-USD", "ETH-USD"]);
+    // seeded books are provisional, neither published nor cross-checked
+    assert!(books.book("ETH-USD").is_some_and(|book| book.provisional && !book.in_sync));
+    assert_eq!(graph.pair("BTC-USD").unwrap().ask, None);
+    assert!(!books.cross_check(&quotes[1], 0.0));
+
+    let snapshot: GenericMessage = serde_json::from_value(serde_json::json!({
+        "channel": "l2_data", "client_id": "", "timestamp": "", "sequence_num": 0,
+        "events": [{ "type": "snapshot", "product_id": "ETH-USD", "updates": [
+            { "side": "bid", "event_time": "", "price_level": "1675.13", "new_quantity": "12" },
+            { "side": "offer", "event_time": "", "price_level": "1675.14", "new_quantity": "8" },
+        ]}],
+    })).unwrap();
+    books.apply(&snapshot).unwrap();
+    assert_eq!(graph.pair("ETH-USD").unwrap().ask, Some(1675.14));
+    assert!(books.cross_check(&quotes[1], 0.0));
+
+    mock.update_fixtures(|fixtures| fixtures.product_books["ETH-USD"]["bids"][0]["price"] = "1675.1".into());
+    let moved = client.get_best_bid_ask(&["ETH-USD".to_string()]).await.unwrap();
+    assert!(books.cross_check(&moved[0], 0.001));
+    assert!(!books.cross_check(&moved[0], 0.0));
+    assert_eq!(graph.pair("ETH-USD").unwrap().bid, None);
+    assert_eq!(books.seed_from_rest(&client, &product_ids, None).await.unwrap(), vec!["BTC-USD", "ETH-USD"]);
+    assert_eq!(books.book("ETH-USD").unwrap().best_bid(), Some((1675.1, 12.0)));
+}
+
+#[tokio::test]
+async fn products_are_filtered_and_paged_by_the_server() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    mock.update_fixtures(|fixtures| {
+        let perpetual = serde_json::json!({
+            "product_id": "BIP-20DEC30-CDE", "product_type": "FUTURE", "base_currency_id": "", "base_increment": "1",
+            "base_max_size": "100000", "base_min_size": "1", "quote_currency_id": "USD", "quote_increment": "5",
+            "quote_max_size": "", "quote_min_size": "", "status": "", "trading_disabled": false,
+            "future_product_details": {
+                "contract_size": "0.01", "contract_root_unit": "BTC", "contract_expiry_type": "PERPETUAL",
+                "perpetual_details": { "open_interest": "1520", "funding_rate": "0.000004" },
+            },
+        });
+        fixtures.products["products"].as_array_mut().unwrap().push(perpetual);
+    });
+
+    let perpetuals = client
+        .list_products(&ProductsQuery::new().with_product_type("FUTURE").with_contract_expiry_type("PERPETUAL"))
+        .await
+        .unwrap();
+    assert_eq!(perpetuals.products.len(), 1);
+    assert!(perpetuals.products[0].is_perpetual());
+    assert_eq!(
+        mock.requests()[0].url,
+        "/api/v3/brokerage/products/?product_type=FUTURE&contract_expiry_type=PERPETUAL"
+    );
+
+    let page = client
+        .list_products(&ProductsQuery::new().with_product_ids(["BTC-USD", "ETH-USD", "ETH-BTC"]).with_limit(2).with_offset(1))
+        .await
+        .unwrap();
+    assert_eq!(page.num_products, 3);
+    assert_eq!(page.products.iter().map(|p| p.product_id.as_str()).collect::<Vec<_>>(), vec!["ETH-USD", "ETH-BTC"]);
+    assert_eq!(client.list_products(&ProductsQuery::new().with_product_type("SPOT")).await.unwrap().products.len(), 3);
+
+    let product = client.get_product("ETH-BTC").await.unwrap();
+    assert_eq!((product.base_currency_id.as_str(), product.quote_currency_id.as_str()), ("ETH", "BTC"));
+    assert_eq!(client.get_product("BIP-20DEC30-CDE").await.unwrap().funding_rate(), Some(0.000004));
+    assert!(client.get_product("DOGE-USD").await.is_err());
+}
+
+#[tokio::test]
+async fn get_product_escapes_the_product_id() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+    mock.update_fixtures(|fixtures| {
+        let products = fixtures.products["products"].as_array_mut().unwrap();
+        let mut odd = products[0].clone();
+        odd["product_id"] = "BTC/USD?expiry=30 DEC".into();
+        products.push(odd);
+    });
+
+    let product = client.get_product("BTC/USD?expiry=30 DEC").await.unwrap();
+    assert_eq!(product.product_id, "BTC/USD?expiry=30 DEC");
+    let request = mock.requests().pop().unwrap();
+    assert_eq!(request.url, "/api/v3/brokerage/products/BTC%2FUSD%3Fexpiry%3D30%20DEC");
+    assert!(request.authorized);
+    // an id cannot reach another endpoint
+    assert!(client.get_product("../accounts").await.is_err());
+    assert_eq!(mock.requests().pop().unwrap().url, "/api/v3/brokerage/products/..%2Faccounts");
+}
+
+#[tokio::test]
+async fn rest_metrics_label_requests_by_route() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let metrics = Metrics::default();
+    let client = AdvancedTradeRESTClient::from_config(&CoinbaseConfig { metrics: metrics.clone(), ..mock_config(&mock) }).unwrap();
+
+    client.get_product("ETH-BTC").await.unwrap();
+    client.get_product("ETH-USD").await.unwrap();
+
+    let text = metrics.render();
+    assert!(
+        text.contains("coinbase_rest_requests_total{method=\"GET\",endpoint=\"/brokerage/products/{product_id}\",status=\"200\"} 2"),
+        "{}",
+        text
+    );
+    assert!(!text.contains("ETH-BTC"), "{}", text);
+}
+
+#[tokio::test]
+async fn signed_requests_cover_every_method() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&mock_config(&mock)).unwrap();
+
+    let momentum = client.create_portfolio("Momentum").await.unwrap();
+    let renamed = client.edit_portfolio(&momentum.uuid, "Mean reversion").await.unwrap();
+    assert_eq!((renamed.uuid.as_str(), renamed.name.as_str()), (momentum.uuid.as_str(), "Mean reversion"));
+    client.move_funds("100", "USD", MOCK_DEFAULT_PORTFOLIO, &momentum.uuid).await.unwrap();
+    assert!(client.delete_portfolio(&momentum.uuid).await.is_err());
+    assert!(client.delete_portfolio(MOCK_DEFAULT_PORTFOLIO).await.is_err());
+    client.move_funds("100", "USD", &momentum.uuid, MOCK_DEFAULT_PORTFOLIO).await.unwrap();
+    client.delete_portfolio(&momentum.uuid).await.unwrap();
+    assert_eq!(client.list_portfolios(None).await.unwrap().len(), 1);
+    let methods = mock.requests().iter().map(|r| r.method.clone()).collect::<Vec<_>>();
+    assert!(["POST", "PUT", "DELETE", "GET"].iter().all(|m| methods.iter().any(|r| r == m)));
+    assert!(mock.requests().iter().all(|r| r.authorized));
+
+    let endpoint = RestEndpoint::get("/brokerage/products").with_resource(Some("product_type=SPOT&limit=1".to_string()));
+    let products: Products = client.send_endpoint(endpoint).await.unwrap();
+    assert_eq!(products.products.len(), 1);
+
+    // a signature over the path alone does not cover the query string
+    let headers = client.build_headers_with_signature("GET", "/api/v3/brokerage/products", "").unwrap();
+    let rest = Client::new(mock.rest_url());
+    let unsigned_query = rest.get::<Products>("/brokerage/products", headers, Some("limit=1".to_string())).await;
+    assert!(unsigned_query.is_err());
+    assert!(!mock.requests().pop().unwrap().authorized);
+    let headers = client.build_headers_with_signature("GET", "/api/v3/brokerage/products?limit=1", "").unwrap();
+    assert!(rest.get::<Products>("/brokerage/products", headers, Some("limit=1".to_string())).await.is_ok());
+}
@@ -0,0 +1,50 @@
+use rs_coinbase_pairs_handler::advanced_trade_websocket::{AdvancedTradeWebSockets, SubscribeProducts};
+use rs_coinbase_pairs_handler::arbitrage::{ArbitrageConfig, ArbitrageDetector};
+use rs_coinbase_pairs_handler::config_builder::CoinbaseConfig;
+use rs_coinbase_pairs_handler::currency_graph::{CurrencyGraph, PairQuote};
+use rs_coinbase_pairs_handler::order_book::OrderBooks;
+use rs_coinbase_pairs_handler::recorder::{MarketDataRecorder, RecorderConfig};
+use rs_coinbase_pairs_handler::replay::{ReplaySource, ReplaySpeed};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn snapshot(sequence_num: u64, product_id: &str, bid: (&str, &str), offer: (&str, &str)) -> String {
+    format!(
+        r#"{{"channel":"l2_data","client_id":"","timestamp":"2023-02-08T06:12:20.838410617Z","sequence_num":{},"events":[{{"type":"snapshot","product_id":"{}","updates":[{{"side":"bid","event_time":"","price_level":"{}","new_quantity":"{}"}},{{"side":"offer","event_time":"","price_level":"{}","new_quantity":"{}"}}]}}]}}"#,
+        sequence_num, product_id, bid.0, bid.1, offer.0, offer.1
+    )
+}
+
+#[tokio::test]
+async fn detects_triangles_on_level2_books() {
+    let dir = tempfile::tempdir().unwrap();
+    let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+    {
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        recorder.record(t0, &snapshot(1, "BTC-USD", ("19990", "2"), ("20000", "0.5"))).unwrap();
+        recorder.record(t0, &snapshot(2, "ETH-BTC", ("0.0699", "50"), ("0.07", "40"))).unwrap();
+        recorder.record(t0, &snapshot(3, "ETH-USD", ("1410", "3"), ("1411", "10"))).unwrap();
+    }
+
+    let graph = CurrencyGraph::default();
+    for (product_id, base, quote) in [("BTC-USD", "BTC", "USD"), ("ETH-BTC", "ETH", "BTC"), ("ETH-USD", "ETH", "USD")] {
+        graph.insert_pair(PairQuote::new(product_id, base, quote));
+    }
+    let mut config = ArbitrageConfig::new(0.001);
+    config.start_currencies = vec!["USD".to_string()];
+    let (detector, mut opportunities) = ArbitrageDetector::new(graph.clone(), config);
+
+    let mut feed = AdvancedTradeWebSockets::from_config(
+        vec!["level2".to_string()],
+        SubscribeProducts::All,
+        CoinbaseConfig::with_credentials("", ""),
+    ).unwrap();
+    feed.add_sink(Box::new(OrderBooks::new().publish_to(graph)));
+    feed.add_sink(Box::new(detector));
+    feed.replay(ReplaySource::open(dir.path()).unwrap(), ReplaySpeed::AsFastAsPossible).await.unwrap();
+
+    let opportunity = opportunities.try_recv().unwrap();
+    assert_eq!(opportunity.cycle, "USD>BTC>ETH>USD");
+    assert!(opportunity.edge > 0.0);
+    assert!((opportunity.legs[2].size - 3.0).abs() < 1e-9);
+    assert!(opportunities.try_recv().is_err());
+}
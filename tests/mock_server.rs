@@ -0,0 +1,233 @@
+use reqwest::header::{HeaderMap, HeaderValue};
+use rs_coinbase_pairs_handler::config_builder::TransportConfig;
+use rs_coinbase_pairs_handler::mock_server::{MockCoinbase, MockConfig, ScriptStep, MOCK_API_PREFIX};
+use rs_coinbase_pairs_handler::models::{ChannelSubscriptionMessage, GenericMessage, Products};
+use rs_coinbase_pairs_handler::{rest_client::Client, sig_gen, websocket};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread::JoinHandle;
+use tungstenite::protocol::WebSocket;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::Message;
+
+const KEY: &str = "test-key";
+const SECRET: &str = "test-secret";
+
+fn now() -> String {
+    chrono::Utc::now().timestamp().to_string()
+}
+
+fn signed_headers(secret: &str, method: &str, path: &str) -> HeaderMap {
+    let ts = now();
+    let sign = sig_gen::create_rest_signature(&ts, method, path, "", secret.as_bytes());
+    let mut headers = HeaderMap::new();
+    headers.insert("CB-ACCESS-KEY", HeaderValue::from_static(KEY));
+    headers.insert("CB-ACCESS-SIGN", HeaderValue::from_str(&sign).unwrap());
+    headers.insert("CB-ACCESS-TIMESTAMP", HeaderValue::from_str(&ts).unwrap());
+    headers
+}
+
+fn subscribe(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>, secret: &str, channel: &str, product: &str) {
+    let ts = now();
+    let msg = ChannelSubscriptionMessage {
+        msg_type: "subscribe".to_string(),
+        product_ids: vec![product.to_string()],
+        channel: channel.to_string(),
+        api_key: KEY.to_string(),
+        timestamp: ts.clone(),
+        signature: sig_gen::create_ws_signature(
+            ts,
+            channel.to_string(),
+            vec![product.to_string()],
+            secret.as_bytes(),
+        ),
+    };
+    socket.write_message(Message::Text(serde_json::to_string(&msg).unwrap())).unwrap();
+}
+
+fn read_json(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> serde_json::Value {
+    match socket.read_message().unwrap() {
+        Message::Text(text) => serde_json::from_str(&text).unwrap(),
+        m => panic!("unexpected frame {:?}", m),
+    }
+}
+
+fn read_generic(socket: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> GenericMessage {
+    serde_json::from_value(read_json(socket)).unwrap()
+}
+
+/// HTTP proxy that tunnels one `CONNECT` request, returning its URL and the request it received
+fn connect_proxy(bind: &str) -> (String, JoinHandle<String>) {
+    let listener = TcpListener::bind(bind).unwrap();
+    let url = format!("http://user:pa%20ss@{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let (mut client, _) = listener.accept().unwrap();
+        let mut head = Vec::new();
+        let mut byte = [0u8];
+        while !head.ends_with(b"\r\n\r\n") {
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        let upstream = TcpStream::connect(head.split_whitespace().nth(1).unwrap()).unwrap();
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+
+        let (mut client_read, mut upstream_write) = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+        std::thread::spawn(move || std::io::copy(&mut client_read, &mut upstream_write));
+        let (mut upstream_read, mut client_write) = (upstream, client);
+        std::thread::spawn(move || std::io::copy(&mut upstream_read, &mut client_write));
+        head
+    });
+    (url, handle)
+}
+
+#[tokio::test]
+async fn serves_product_fixture_to_signed_requests() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = Client::new(mock.rest_url());
+    let path = format!("{}/brokerage/products/", MOCK_API_PREFIX);
+
+    let products: Products = client
+        .get("/brokerage/products/", signed_headers(SECRET, "GET", &path), None)
+        .await
+        .unwrap();
+
+    assert_eq!(products.num_products, 3);
+    assert_eq!(products.products[0].product_id, "BTC-USD");
+    assert!(mock.requests()[0].authorized);
+}
+
+#[tokio::test]
+async fn rejects_requests_with_a_bad_signature() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let client = Client::new(mock.rest_url());
+    let path = format!("{}/brokerage/accounts", MOCK_API_PREFIX);
+
+    let result: anyhow::Result<serde_json::Value> = client
+        .get("/brokerage/accounts", signed_headers("wrong-secret", "GET", &path), None)
+        .await;
+
+    assert_eq!(result.unwrap_err().to_string(), "Unauthorized");
+    assert!(!mock.requests()[0].authorized);
+}
+
+#[test]
+fn acknowledges_subscriptions_and_replays_script() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::Gap(2),
+        ScriptStep::level2("update", "ETH-USD", &[("bid", "1675.00", "1.5")]),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+    let (mut socket, _) = websocket::connect_wss("mock", vec![mock.websocket_url()]).unwrap();
+
+    subscribe(&mut socket, SECRET, "ticker", "ETH-USD");
+    let ack = read_generic(&mut socket);
+    assert_eq!(ack.channel, "subscriptions");
+    assert_eq!(ack.sequence_num, 0);
+
+    let ticker = read_generic(&mut socket);
+    assert_eq!(ticker.channel, "ticker");
+    assert_eq!(ticker.sequence_num, 1);
+
+    let level2 = read_json(&mut socket);
+    assert_eq!(level2["channel"], "l2_data");
+    assert_eq!(level2["sequence_num"], 4);
+    assert_eq!(mock.subscriptions().len(), 1);
+}
+
+#[test]
+fn rejects_subscriptions_with_a_bad_signature() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let (mut socket, _) = websocket::connect_wss("mock", vec![mock.websocket_url()]).unwrap();
+
+    subscribe(&mut socket, "wrong-secret", "ticker", "ETH-USD");
+
+    match socket.read_message().unwrap() {
+        Message::Text(text) => assert!(text.contains("authentication failure")),
+        m => panic!("unexpected frame {:?}", m),
+    }
+    assert_eq!(mock.rejected_subscriptions(), 1);
+}
+
+#[test]
+fn resumes_script_after_disconnect() {
+    let mut config = MockConfig::new(KEY, SECRET);
+    config.script = vec![
+        ScriptStep::ticker("snapshot", "ETH-USD", "1675.14"),
+        ScriptStep::Disconnect,
+        ScriptStep::ticker("update", "ETH-USD", "1676.00"),
+    ];
+    let mock = MockCoinbase::start(config).unwrap();
+
+    let (mut socket, _) = websocket::connect_wss("mock", vec![mock.websocket_url()]).unwrap();
+    subscribe(&mut socket, SECRET, "ticker", "ETH-USD");
+    read_generic(&mut socket);
+    read_generic(&mut socket);
+    assert!(socket.read_message().is_err());
+
+    let (mut socket, _) = websocket::connect_wss("mock", vec![mock.websocket_url()]).unwrap();
+    subscribe(&mut socket, SECRET, "ticker", "ETH-USD");
+    read_generic(&mut socket);
+    let ticker = read_generic(&mut socket);
+    assert!(serde_json::to_string(&ticker.events).unwrap().contains("1676.00"));
+    assert_eq!(mock.connections(), 2);
+}
+
+#[test]
+fn connects_through_an_http_proxy() {
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let (proxy, handle) = connect_proxy("127.0.0.1:0");
+    let transport = TransportConfig { proxy: Some(proxy), ..Default::default() };
+
+    let (mut socket, _) = websocket::connect_wss_with_transport("mock", vec![mock.websocket_url()], &transport).unwrap();
+    subscribe(&mut socket, SECRET, "ticker", "ETH-USD");
+    assert_eq!(read_generic(&mut socket).channel, "subscriptions");
+
+    let head = handle.join().unwrap();
+    let authority = mock.websocket_url().trim_start_matches("ws://").to_string();
+    assert!(head.starts_with(&format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority)), "{}", head);
+    assert!(head.contains("Proxy-Authorization: Basic dXNlcjpwYSBzcw==\r\n"), "{}", head);
+}
+
+#[test]
+fn fails_when_the_proxy_refuses_the_tunnel() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let transport = TransportConfig {
+        proxy: Some(format!("http://{}", listener.local_addr().unwrap())),
+        ..Default::default()
+    };
+    std::thread::spawn(move || {
+        for client in listener.incoming() {
+            let mut client = client.unwrap();
+            let _ = client.read(&mut [0u8; 1024]);
+            let _ = client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n");
+        }
+    });
+
+    let result = websocket::connect_wss_with_transport("mock", vec!["ws://127.0.0.1:1".to_string()], &transport);
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn connects_to_ipv6_literals() {
+    let listener = TcpListener::bind("[::1]:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut socket = tungstenite::accept(stream).unwrap();
+        socket.write_message(Message::Text("hello".to_string())).unwrap();
+    });
+    let (mut socket, _) = websocket::connect_wss("mock", vec![url]).unwrap();
+    assert_eq!(socket.read_message().unwrap(), Message::Text("hello".to_string()));
+
+    let mock = MockCoinbase::start(MockConfig::new(KEY, SECRET)).unwrap();
+    let (proxy, handle) = connect_proxy("[::1]:0");
+    let transport = TransportConfig { proxy: Some(proxy), ..Default::default() };
+    let (mut socket, _) = websocket::connect_wss_with_transport("mock", vec![mock.websocket_url()], &transport).unwrap();
+    subscribe(&mut socket, SECRET, "ticker", "ETH-USD");
+    assert_eq!(read_generic(&mut socket).channel, "subscriptions");
+    assert!(handle.join().unwrap().starts_with("CONNECT 127.0.0.1:"));
+}
@@ -0,0 +1,108 @@
+use anyhow::Result;
+use rs_coinbase_pairs_handler::advanced_trade_websocket::{AdvancedTradeWebSockets, SubscribeProducts};
+use rs_coinbase_pairs_handler::config_builder::CoinbaseConfig;
+use rs_coinbase_pairs_handler::health::HealthThresholds;
+use rs_coinbase_pairs_handler::models::GenericMessage;
+use rs_coinbase_pairs_handler::recorder::{MarketDataRecorder, RecorderConfig};
+use rs_coinbase_pairs_handler::replay::{ReplaySource, ReplaySpeed};
+use rs_coinbase_pairs_handler::sink::MessageSink;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Default)]
+struct CollectingSink {
+    messages: Arc<Mutex<Vec<(SystemTime, GenericMessage)>>>,
+}
+
+impl MessageSink for CollectingSink {
+    fn on_message(&mut self, received_at: SystemTime, message: &GenericMessage) -> Result<()> {
+        self.messages.lock().unwrap().push((received_at, message.clone()));
+        Ok(())
+    }
+}
+
+fn ticker(sequence_num: u64, price: &str) -> String {
+    format!(
+        r#"{{"channel":"ticker","client_id":"","timestamp":"2023-02-08T06:12:20.838410617Z","sequence_num":{},"events":[{{"type":"update","tickers":[{{"type":"ticker","product_id":"ETH-USD","price":"{}","volume_24_h":"1","low_24_h":"1","high_24_h":"1","low_52_w":"1","high_52_w":"1","price_percent_chg_24_h":"1"}}]}}]}}"#,
+        sequence_num, price
+    )
+}
+
+fn offline_feed() -> AdvancedTradeWebSockets {
+    AdvancedTradeWebSockets::from_config(
+        vec!["ticker".to_string()],
+        SubscribeProducts::Custom(vec!["ETH-USD".to_string()]),
+        CoinbaseConfig::with_credentials("", ""),
+    ).unwrap()
+}
+
+#[tokio::test]
+async fn replays_recorded_frames_through_the_feed() {
+    let dir = tempfile::tempdir().unwrap();
+    let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+    {
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        recorder.record(t0, &ticker(1, "1675.14")).unwrap();
+        recorder.record(t0 + Duration::from_millis(500), "not json").unwrap();
+        recorder.record(t0 + Duration::from_secs(1), &ticker(2, "1675.20")).unwrap();
+    }
+
+    let sink = CollectingSink::default();
+    let mut feed = offline_feed();
+    feed.add_sink(Box::new(sink.clone()));
+
+    let started = Instant::now();
+    let frames = feed
+        .replay(ReplaySource::open(dir.path()).unwrap(), ReplaySpeed::Accelerated(20.0))
+        .await
+        .unwrap();
+
+    assert_eq!(frames, 3);
+    assert!(started.elapsed() >= Duration::from_millis(50));
+    let messages = sink.messages.lock().unwrap();
+    let received: Vec<(SystemTime, u64)> = messages.iter().map(|(t, m)| (*t, m.sequence_num)).collect();
+    assert_eq!(received, vec![(t0, 1), (t0 + Duration::from_secs(1), 2)]);
+
+    let metrics = feed.metrics().render();
+    assert!(!metrics.contains("coinbase_ws_messages_total{"), "{}", metrics);
+    assert!(metrics.contains("coinbase_ws_parse_failures_total 0"), "{}", metrics);
+    assert_eq!(feed.health().report(&HealthThresholds::default()).last_frame_age_ms, None);
+}
+
+#[tokio::test]
+async fn stops_replay_from_the_stop_handle() {
+    let dir = tempfile::tempdir().unwrap();
+    let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+    {
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        recorder.record(t0, &ticker(1, "1675.14")).unwrap();
+    }
+
+    let mut feed = offline_feed();
+    feed.stop_handle().store(false, std::sync::atomic::Ordering::Relaxed);
+    let frames = feed
+        .replay(ReplaySource::open(dir.path()).unwrap(), ReplaySpeed::AsFastAsPossible)
+        .await
+        .unwrap();
+
+    assert_eq!(frames, 0);
+}
+
+#[tokio::test]
+async fn rejects_invalid_speed_factors() {
+    let dir = tempfile::tempdir().unwrap();
+    let t0 = UNIX_EPOCH + Duration::from_secs(1_675_836_740);
+    {
+        let mut recorder = MarketDataRecorder::new(RecorderConfig::new(dir.path())).unwrap();
+        recorder.record(t0, &ticker(1, "1675.14")).unwrap();
+    }
+
+    let sink = CollectingSink::default();
+    let mut feed = offline_feed();
+    feed.add_sink(Box::new(sink.clone()));
+    for factor in [0.0, -1.0, f64::NAN] {
+        let result = feed.replay(ReplaySource::open(dir.path()).unwrap(), ReplaySpeed::Accelerated(factor)).await;
+        assert!(result.is_err(), "{}", factor);
+    }
+    assert!(sink.messages.lock().unwrap().is_empty());
+}
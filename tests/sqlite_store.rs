@@ -0,0 +1,29 @@
+#![cfg(feature = "sqlite")]
+
+use rs_coinbase_pairs_handler::advanced_trade_rest_client::AdvancedTradeRESTClient;
+use rs_coinbase_pairs_handler::config_builder::CoinbaseConfig;
+use rs_coinbase_pairs_handler::mock_server::{MockCoinbase, MockConfig};
+use rs_coinbase_pairs_handler::sqlite_store::{SqliteStore, SyncSummary};
+
+#[tokio::test]
+async fn syncs_products_orders_and_fills_from_rest() {
+    let mock = MockCoinbase::start(MockConfig::new("test-key", "test-secret")).unwrap();
+    let client = AdvancedTradeRESTClient::from_config(&CoinbaseConfig {
+        rest_url: mock.rest_url(),
+        ..CoinbaseConfig::with_credentials("test-key", "test-secret")
+    }).unwrap();
+    let store = SqliteStore::open_in_memory().unwrap();
+
+    let summary = store.sync_from_rest(&client).await.unwrap();
+    assert_eq!(summary, SyncSummary { products: 3, orders: 1, fills: 1 });
+
+    // syncing again updates in place instead of duplicating
+    let summary = store.sync_from_rest(&client).await.unwrap();
+    assert_eq!(summary.fills, 0);
+    assert_eq!(store.products().unwrap().len(), 3);
+    let day = store.fills_between(
+        "2023-02-08T00:00:00Z".parse().unwrap(),
+        "2023-02-09T00:00:00Z".parse().unwrap(),
+    ).unwrap();
+    assert_eq!(day[0].order_id, "0000-000000-000000");
+}